@@ -13,6 +13,12 @@ use std::time::Duration;
 /// The interval at which the agent performs candidate checks in the connecting phase.
 pub(crate) const DEFAULT_CHECK_INTERVAL: Duration = Duration::from_millis(200);
 
+/// The default pacing interval (Ta) of connectivity checks.
+pub(crate) const DEFAULT_CHECK_PACING_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The lower bound of the pacing interval (Ta) of connectivity checks (RFC 8445 Section 14.2).
+pub(crate) const MIN_CHECK_PACING_INTERVAL: Duration = Duration::from_millis(5);
+
 /// The interval used to keep candidates alive.
 pub(crate) const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(2);
 
@@ -37,10 +43,23 @@ pub(crate) const DEFAULT_RELAY_ACCEPTANCE_MIN_WAIT: Duration = Duration::from_mi
 /// Max binding request before considering a pair failed.
 pub(crate) const DEFAULT_MAX_BINDING_REQUESTS: u16 = 7;
 
+/// The initial retransmission timeout of a binding request, doubled after each retransmission.
+pub(crate) const DEFAULT_BINDING_REQUEST_RTO: Duration = Duration::from_millis(500);
+
+/// The average interval of consent freshness checks on the selected pair.
+pub(crate) const DEFAULT_CONSENT_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The default time without a consent check response until consent to send expires.
+pub(crate) const DEFAULT_CONSENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Max retransmissions of an unanswered binding request, the last one goes out 3.5 seconds
+/// after the request with the default RTO, within `MAX_BINDING_REQUEST_TIMEOUT`.
+pub(crate) const DEFAULT_MAX_BINDING_REQUEST_RETRANSMITS: u16 = 3;
+
 /// The number of bytes that can be buffered before we start to error.
 pub(crate) const MAX_BUFFER_SIZE: usize = 1000 * 1000; // 1MB
 
-/// Wait time before binding requests can be deleted.
+/// Wait time before binding requests can be deleted, unless their retransmissions take longer.
 pub(crate) const MAX_BINDING_REQUEST_TIMEOUT: Duration = Duration::from_millis(4000);
 
 pub(crate) fn default_candidate_types() -> Vec<CandidateType> {
@@ -51,8 +70,57 @@ pub(crate) fn default_candidate_types() -> Vec<CandidateType> {
     ]
 }
 
+/// Controls how the controlling agent nominates the selected candidate pair.
+///
+/// Regular nomination first validates pairs with ordinary checks and then nominates the best valid
+/// one with a single USE-CANDIDATE request, so the selected pair is the best one found. Aggressive
+/// nomination sets USE-CANDIDATE on every check and selects the first pair that succeeds, which
+/// connects faster but may settle on a lower priority pair.
+///
+/// reference: (IETF ref-5245)[https://tools.ietf.org/html/rfc5245#section-8.1.1.2].
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum NominationMode {
+    /// Nominate the best valid pair once checks have completed.
+    Regular,
+
+    /// Include USE-CANDIDATE on every check and select the first pair that succeeds.
+    Aggressive,
+}
+
+impl Default for NominationMode {
+    fn default() -> Self {
+        Self::Regular
+    }
+}
+
+/// How the binding requests the agent sends are authenticated.
+///
+/// reference: (IETF ref-8489)[https://tools.ietf.org/html/rfc8489#section-9].
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum CredentialMode {
+    /// Short-term credentials (RFC 8445 Section 7.2.2): USERNAME is "remote ufrag:local ufrag" and
+    /// the remote password is the key.
+    ShortTerm,
+
+    /// Long-term credentials, for checks that go through servers requiring them: USERNAME is
+    /// `username`, REALM and NONCE are included and the key is MD5(username:realm:password).
+    LongTerm {
+        username: String,
+        realm: String,
+        password: String,
+        nonce: String,
+    },
+}
+
+impl Default for CredentialMode {
+    fn default() -> Self {
+        Self::ShortTerm
+    }
+}
+
 pub type InterfaceFilterFn = Box<dyn (Fn(&str) -> bool) + Send + Sync>;
 pub type IpFilterFn = Box<dyn (Fn(IpAddr) -> bool) + Send + Sync>;
+pub type CandidateFilterFn = Box<dyn (Fn(&(dyn Candidate + Send + Sync)) -> bool) + Send + Sync>;
 
 /// Collects the arguments to `ice::Agent` construction into a single structure, for
 /// future-proofness of the interface.
@@ -90,11 +158,28 @@ pub struct AgentConfig {
     /// If the duration is 0, we will never go to failed.
     pub failed_timeout: Option<Duration>,
 
+    /// How long the agent keeps checking before it gives up on establishing any pair, if no pair
+    /// is selected within it the agent goes to failed and stops sending checks. Defaults to
+    /// disconnected_timeout + failed_timeout when this property is nil, or to 0 when
+    /// failed_timeout is 0.
+    /// If the duration is 0, the agent keeps checking until a pair is selected.
+    pub connectivity_check_timeout: Option<Duration>,
+
     /// Determines how often should we send ICE keepalives (should be less then connectiontimeout
     /// above) when this is nil, it defaults to 10 seconds.
     /// A keepalive interval of 0 means we never send keepalive packets
     pub keepalive_interval: Option<Duration>,
 
+    /// The average interval of consent freshness checks on the selected pair, each interval is
+    /// randomized to between 0.8 and 1.2 times this value. Defaults to 5 seconds when this
+    /// property is nil.
+    pub consent_check_interval: Option<Duration>,
+
+    /// How long the selected pair may go without a response to a consent check before consent
+    /// expires and the pair is dropped. Defaults to 30 seconds when this property is nil.
+    /// A consent timeout of 0 disables consent freshness.
+    pub consent_timeout: Option<Duration>,
+
     /// An optional configuration for disabling or enabling support for specific network types.
     pub network_types: Vec<NetworkType>,
 
@@ -106,13 +191,72 @@ pub struct AgentConfig {
     /// Only useful for testing.
     pub check_interval: Duration,
 
+    /// Randomizes each run of the internal task loop to within this fraction of its interval,
+    /// e.g. 0.1 spreads it over ±10%, so that many agents don't send their checks in bursts
+    /// (RFC 8445 Section 14). Must be in [0, 1), 0 disables the jitter.
+    pub check_interval_jitter: f64,
+
+    /// The pacing interval (Ta) of connectivity checks: across all candidate pairs, at most one
+    /// check is sent every Ta (RFC 8445 Section 14.2). Checks that don't get a slot are deferred to
+    /// the next run of the task loop. Defaults to 50 milliseconds when this property is nil and
+    /// can't be less than 5 milliseconds.
+    pub check_pacing_interval: Option<Duration>,
+
+    /// The max amount of candidate pairs in the checklist. Once it is exceeded the pairs with the
+    /// lowest priority are pruned (RFC 8445 Section 6.1.2.5), except the selected and the
+    /// nominated pair. No limit when this property is nil.
+    pub max_candidate_pairs: Option<usize>,
+
+    /// A function that decides which local candidates are paired with remote candidates, the
+    /// candidates it rejects never enter the checklist and are never pinged. Unlike
+    /// `interface_filter` and `ip_filter` it also applies to candidates that were not gathered by
+    /// the agent, e.g. to leave out those of a metered interface by their address.
+    pub candidate_filter: Arc<Option<CandidateFilterFn>>,
+
+    /// How long after a pair is selected the agent keeps checking the pairs of higher priority
+    /// than the selected one, so that a better pair can still be found. Pairs of lower priority
+    /// are no longer checked once a pair is selected, only the selected pair is kept alive. No
+    /// pairs but the selected one are checked after selection when this property is nil.
+    pub better_pair_check_window: Option<Duration>,
+
     /// The max amount of binding requests the agent will send over a candidate pair for validation
     /// or nomination, if after max_binding_requests the candidate is yet to answer a binding
     /// request or a nomination we set the pair as failed.
     pub max_binding_requests: Option<u16>,
 
+    /// The initial retransmission timeout (RTO) of an unanswered binding request. Each
+    /// retransmission doubles the interval. Defaults to 500 milliseconds when this property is nil.
+    pub binding_request_rto: Option<Duration>,
+
+    /// The max amount of times an unanswered binding request is retransmitted with the same
+    /// transaction ID. Defaults to 3 when this property is nil, 0 disables retransmissions.
+    /// A request is given up on one initial RTO after its last retransmission, or after 4
+    /// seconds if that is later.
+    pub max_binding_request_retransmits: Option<u16>,
+
     pub is_controlling: bool,
 
+    /// The tie-breaker used to resolve role conflicts with the remote agent (RFC 8445 Section
+    /// 7.3.1.1). Defaults to a random value when this property is nil. Only useful for testing.
+    pub tie_breaker: Option<u64>,
+
+    /// Seeds the random number generator of the internal task loop, so that the check interval
+    /// jitter can be reproduced. Seeded from the OS when this property is nil. Only useful for
+    /// testing.
+    pub rng_seed: Option<u64>,
+
+    /// Added as a SOFTWARE attribute to the binding requests and responses the agent sends, so
+    /// the remote side can tell which implementation it talks to. Omitted when this property is
+    /// nil.
+    pub software: Option<String>,
+
+    /// Controls how a controlling agent nominates candidate pairs, see [`NominationMode`].
+    pub nomination_mode: NominationMode,
+
+    /// Selects the credentials the agent's binding requests are authenticated with, and the
+    /// responses to them are verified with.
+    pub credential_mode: CredentialMode,
+
     /// lite agents do not perform connectivity check and only provide host candidates.
     pub lite: bool,
 
@@ -155,6 +299,30 @@ pub struct AgentConfig {
 }
 
 impl AgentConfig {
+    /// Sets the minimum wait time before selecting host candidates, defaults to 0.
+    pub fn with_host_acceptance_min_wait(mut self, wait: Duration) -> Self {
+        self.host_acceptance_min_wait = Some(wait);
+        self
+    }
+
+    /// Sets the minimum wait time before selecting srflx candidates, defaults to 500 milliseconds.
+    pub fn with_srflx_acceptance_min_wait(mut self, wait: Duration) -> Self {
+        self.srflx_acceptance_min_wait = Some(wait);
+        self
+    }
+
+    /// Sets the minimum wait time before selecting prflx candidates, defaults to 1 second.
+    pub fn with_prflx_acceptance_min_wait(mut self, wait: Duration) -> Self {
+        self.prflx_acceptance_min_wait = Some(wait);
+        self
+    }
+
+    /// Sets the minimum wait time before selecting relay candidates, defaults to 2 seconds.
+    pub fn with_relay_acceptance_min_wait(mut self, wait: Duration) -> Self {
+        self.relay_acceptance_min_wait = Some(wait);
+        self
+    }
+
     /// Populates an agent and falls back to defaults if fields are unset.
     pub(crate) fn init_with_defaults(&self, a: &mut AgentInternal) {
         if let Some(max_binding_requests) = self.max_binding_requests {
@@ -163,6 +331,18 @@ impl AgentConfig {
             a.max_binding_requests = DEFAULT_MAX_BINDING_REQUESTS;
         }
 
+        if let Some(binding_request_rto) = self.binding_request_rto {
+            a.binding_request_rto = binding_request_rto;
+        } else {
+            a.binding_request_rto = DEFAULT_BINDING_REQUEST_RTO;
+        }
+
+        if let Some(max_binding_request_retransmits) = self.max_binding_request_retransmits {
+            a.max_binding_request_retransmits = max_binding_request_retransmits;
+        } else {
+            a.max_binding_request_retransmits = DEFAULT_MAX_BINDING_REQUEST_RETRANSMITS;
+        }
+
         if let Some(host_acceptance_min_wait) = self.host_acceptance_min_wait {
             a.host_acceptance_min_wait = host_acceptance_min_wait;
         } else {
@@ -199,10 +379,28 @@ impl AgentConfig {
             a.failed_timeout = DEFAULT_FAILED_TIMEOUT;
         }
 
-        if let Some(keepalive_interval) = self.keepalive_interval {
-            a.keepalive_interval = keepalive_interval;
+        a.connectivity_check_timeout = self.connectivity_check_timeout.unwrap_or_else(|| {
+            if a.failed_timeout == Duration::from_secs(0) {
+                Duration::from_secs(0)
+            } else {
+                a.disconnected_timeout + a.failed_timeout
+            }
+        });
+
+        *a.keepalive_interval.lock() = self
+            .keepalive_interval
+            .unwrap_or(DEFAULT_KEEPALIVE_INTERVAL);
+
+        if let Some(consent_check_interval) = self.consent_check_interval {
+            a.consent_check_interval = consent_check_interval;
+        } else {
+            a.consent_check_interval = DEFAULT_CONSENT_CHECK_INTERVAL;
+        }
+
+        if let Some(consent_timeout) = self.consent_timeout {
+            a.consent_timeout = consent_timeout;
         } else {
-            a.keepalive_interval = DEFAULT_KEEPALIVE_INTERVAL;
+            a.consent_timeout = DEFAULT_CONSENT_TIMEOUT;
         }
 
         if self.check_interval == Duration::from_secs(0) {
@@ -210,6 +408,16 @@ impl AgentConfig {
         } else {
             a.check_interval = self.check_interval;
         }
+
+        a.check_interval_jitter = self.check_interval_jitter;
+
+        a.check_pacing_interval = self
+            .check_pacing_interval
+            .unwrap_or(DEFAULT_CHECK_PACING_INTERVAL)
+            .max(MIN_CHECK_PACING_INTERVAL);
+        // Slots not used between two runs of the task loop are saved up, up to one run's worth
+        a.check_pacing_burst =
+            (a.check_interval.as_nanos() / a.check_pacing_interval.as_nanos()).max(1) as u32;
     }
 
     pub(crate) fn init_ext_ip_mapping(
@@ -2,11 +2,19 @@ use super::agent_transport::*;
 use super::*;
 use crate::candidate::candidate_base::CandidateBaseConfig;
 use crate::candidate::candidate_peer_reflexive::CandidatePeerReflexiveConfig;
+use crate::priority::PriorityAttr;
 use crate::util::*;
 use arc_swap::ArcSwapOption;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use std::sync::atomic::{AtomicBool, AtomicU64};
+use stun::error_code::CODE_ROLE_CONFLICT;
+use stun::textattrs::{Nonce, Realm, Software, Username};
 use util::sync::Mutex as SyncMutex;
 
+// The previously and the newly selected candidate pair
+pub(crate) type SelectedPairTransition = (Option<Arc<CandidatePair>>, Option<Arc<CandidatePair>>);
+
 pub type ChanCandidateTx =
     Arc<Mutex<Option<mpsc::Sender<Option<Arc<dyn Candidate + Send + Sync>>>>>>;
 
@@ -31,20 +39,30 @@ pub struct AgentInternal {
         Mutex<Option<(mpsc::Receiver<()>, mpsc::Receiver<bool>)>>,
 
     pub(crate) chan_candidate_tx: ChanCandidateTx,
-    pub(crate) chan_candidate_pair_tx: Mutex<Option<mpsc::Sender<()>>>,
+    pub(crate) chan_candidate_pair_tx: Mutex<Option<mpsc::Sender<SelectedPairTransition>>>,
     pub(crate) chan_state_tx: Mutex<Option<mpsc::Sender<ConnectionState>>>,
 
     pub(crate) on_connection_state_change_hdlr: ArcSwapOption<Mutex<OnConnectionStateChangeHdlrFn>>,
     pub(crate) on_selected_candidate_pair_change_hdlr:
         ArcSwapOption<Mutex<OnSelectedCandidatePairChangeHdlrFn>>,
+    pub(crate) on_selected_candidate_pair_transition_hdlr:
+        ArcSwapOption<Mutex<OnSelectedCandidatePairTransitionHdlrFn>>,
     pub(crate) on_candidate_hdlr: ArcSwapOption<Mutex<OnCandidateHdlrFn>>,
 
     pub(crate) tie_breaker: AtomicU64,
     pub(crate) is_controlling: AtomicBool,
     pub(crate) lite: AtomicBool,
+    // While paused the check loop sends nothing and no timeout advances the connection state
+    pub(crate) paused: AtomicBool,
 
     pub(crate) start_time: SyncMutex<Instant>,
     pub(crate) nominated_pair: Mutex<Option<Arc<CandidatePair>>>,
+    // When the selected pair last changed
+    pub(crate) selected_at: SyncMutex<Option<Instant>>,
+
+    // When the remote last granted consent on the selected pair, and when to check it next
+    pub(crate) consent_granted: SyncMutex<Instant>,
+    pub(crate) next_consent_check: SyncMutex<Instant>,
 
     pub(crate) connection_state: AtomicU8, //ConnectionState,
 
@@ -58,12 +76,23 @@ pub struct AgentInternal {
 
     // LRU of outbound Binding request Transaction IDs
     pub(crate) pending_binding_requests: Mutex<Vec<BindingRequest>>,
+    // Inbound STUN messages discarded, by reason
+    pub(crate) discards: DiscardCounters,
 
     pub(crate) agent_conn: Arc<AgentConn>,
 
     // the following variables won't be changed after init_with_defaults()
     pub(crate) insecure_skip_verify: bool,
+    pub(crate) nomination_mode: NominationMode,
+    pub(crate) credential_mode: CredentialMode,
+    pub(crate) software: Option<Software>,
+    pub(crate) max_candidate_pairs: Option<usize>,
+    pub(crate) candidate_filter: Arc<Option<CandidateFilterFn>>,
+    pub(crate) better_pair_check_window: Duration,
     pub(crate) max_binding_requests: u16,
+    // Initial retransmission timeout of a binding request and how often it may be retransmitted
+    pub(crate) binding_request_rto: Duration,
+    pub(crate) max_binding_request_retransmits: u16,
     pub(crate) host_acceptance_min_wait: Duration,
     pub(crate) srflx_acceptance_min_wait: Duration,
     pub(crate) prflx_acceptance_min_wait: Duration,
@@ -74,11 +103,26 @@ pub struct AgentInternal {
     // How long connectivity checks can fail before the ICE Agent
     // goes to failed
     pub(crate) failed_timeout: Duration,
+    // How long checking can go on without a selected pair before the ICE Agent
+    // goes to failed, 0 means never
+    pub(crate) connectivity_check_timeout: Duration,
     // How often should we send keepalive packets?
-    // 0 means never
-    pub(crate) keepalive_interval: Duration,
+    // 0 means never, can be changed while the agent runs
+    pub(crate) keepalive_interval: SyncMutex<Duration>,
     // How often should we run our internal taskLoop to check for state changes when connecting
     pub(crate) check_interval: Duration,
+    // Fraction by which every run of the task loop is randomized, and the RNG drawing it
+    pub(crate) check_interval_jitter: f64,
+    pub(crate) check_interval_rng: SyncMutex<StdRng>,
+    // Connectivity checks are paced to one every check_pacing_interval (Ta), next_check is when
+    // the next one may be sent
+    pub(crate) check_pacing_interval: Duration,
+    pub(crate) check_pacing_burst: u32,
+    pub(crate) next_check: SyncMutex<Option<Instant>>,
+    // How often consent is checked on the selected pair, and how long it lasts without a response
+    // 0 timeout means never expire
+    pub(crate) consent_check_interval: Duration,
+    pub(crate) consent_timeout: Duration,
 }
 
 impl AgentInternal {
@@ -108,23 +152,40 @@ impl AgentInternal {
 
             on_connection_state_change_hdlr: ArcSwapOption::empty(),
             on_selected_candidate_pair_change_hdlr: ArcSwapOption::empty(),
+            on_selected_candidate_pair_transition_hdlr: ArcSwapOption::empty(),
             on_candidate_hdlr: ArcSwapOption::empty(),
 
-            tie_breaker: AtomicU64::new(rand::random::<u64>()),
+            tie_breaker: AtomicU64::new(config.tie_breaker.unwrap_or_else(rand::random::<u64>)),
             is_controlling: AtomicBool::new(config.is_controlling),
             lite: AtomicBool::new(config.lite),
+            paused: AtomicBool::new(false),
 
             start_time: SyncMutex::new(Instant::now()),
             nominated_pair: Mutex::new(None),
+            selected_at: SyncMutex::new(None),
+
+            consent_granted: SyncMutex::new(Instant::now()),
+            next_consent_check: SyncMutex::new(Instant::now()),
 
             connection_state: AtomicU8::new(ConnectionState::New as u8),
 
             insecure_skip_verify: config.insecure_skip_verify,
+            nomination_mode: config.nomination_mode,
+            credential_mode: config.credential_mode.clone(),
+            software: config
+                .software
+                .as_ref()
+                .map(|software| Software::new(ATTR_SOFTWARE, software.clone())),
+            max_candidate_pairs: config.max_candidate_pairs,
+            candidate_filter: Arc::clone(&config.candidate_filter),
+            better_pair_check_window: config.better_pair_check_window.unwrap_or_default(),
 
             started_ch_tx: Mutex::new(Some(started_ch_tx)),
 
             //won't change after init_with_defaults()
             max_binding_requests: 0,
+            binding_request_rto: Duration::from_secs(0),
+            max_binding_request_retransmits: 0,
             host_acceptance_min_wait: Duration::from_secs(0),
             srflx_acceptance_min_wait: Duration::from_secs(0),
             prflx_acceptance_min_wait: Duration::from_secs(0),
@@ -138,12 +199,26 @@ impl AgentInternal {
             // goes to failed
             failed_timeout: Duration::from_secs(0),
 
+            connectivity_check_timeout: Duration::from_secs(0),
+
             // How often should we send keepalive packets?
             // 0 means never
-            keepalive_interval: Duration::from_secs(0),
+            keepalive_interval: SyncMutex::new(Duration::from_secs(0)),
 
             // How often should we run our internal taskLoop to check for state changes when connecting
             check_interval: Duration::from_secs(0),
+            check_interval_jitter: 0.0,
+            check_pacing_interval: Duration::from_secs(0),
+            check_pacing_burst: 0,
+            next_check: SyncMutex::new(None),
+            check_interval_rng: SyncMutex::new(
+                config
+                    .rng_seed
+                    .map_or_else(StdRng::from_entropy, StdRng::seed_from_u64),
+            ),
+
+            consent_check_interval: Duration::from_secs(0),
+            consent_timeout: Duration::from_secs(0),
 
             ufrag_pwd: Mutex::new(UfragPwd::default()),
 
@@ -152,6 +227,7 @@ impl AgentInternal {
 
             // LRU of outbound Binding request Transaction IDs
             pending_binding_requests: Mutex::new(vec![]),
+            discards: DiscardCounters::default(),
 
             // AgentConn
             agent_conn: Arc::new(AgentConn::new()),
@@ -185,7 +261,7 @@ impl AgentInternal {
         );
         self.set_remote_credentials(remote_ufrag, remote_pwd)
             .await?;
-        self.is_controlling.store(is_controlling, Ordering::SeqCst);
+        self.set_controlling(is_controlling).await;
         self.start().await;
         {
             let mut started_ch_tx = self.started_ch_tx.lock().await;
@@ -207,6 +283,12 @@ impl AgentInternal {
         last_connection_state: &mut ConnectionState,
         checking_duration: &mut Instant,
     ) {
+        if self.paused.load(Ordering::SeqCst) {
+            // Forget the last state so that a Checking agent restarts its checking timer once
+            // resumed, the time spent paused doesn't count against it
+            *last_connection_state = ConnectionState::Unspecified;
+            return;
+        }
         if self.connection_state.load(Ordering::SeqCst) == ConnectionState::Failed as u8 {
             // The connection is currently failed so don't send any checks
             // In the future it may be restarted though
@@ -219,11 +301,13 @@ impl AgentInternal {
                 *checking_duration = Instant::now();
             }
 
-            // We have been in checking longer then Disconnect+Failed timeout, set the connection to Failed
-            if Instant::now()
-                .checked_duration_since(*checking_duration)
-                .unwrap_or_else(|| Duration::from_secs(0))
-                > self.disconnected_timeout + self.failed_timeout
+            // We have been in checking longer then the connectivity check timeout without
+            // selecting a pair, set the connection to Failed
+            if self.connectivity_check_timeout != Duration::from_secs(0)
+                && Instant::now()
+                    .checked_duration_since(*checking_duration)
+                    .unwrap_or_else(|| Duration::from_secs(0))
+                    > self.connectivity_check_timeout
             {
                 self.update_connection_state(ConnectionState::Failed).await;
                 *last_connection_state = self.connection_state.load(Ordering::SeqCst).into();
@@ -231,6 +315,7 @@ impl AgentInternal {
             }
         }
 
+        self.retransmit_pending_binding_requests().await;
         self.contact_candidates().await;
 
         *last_connection_state = self.connection_state.load(Ordering::SeqCst).into();
@@ -240,11 +325,11 @@ impl AgentInternal {
         const ZERO_DURATION: Duration = Duration::from_secs(0);
         let mut last_connection_state = ConnectionState::Unspecified;
         let mut checking_duration = Instant::now();
-        let (check_interval, keepalive_interval, disconnected_timeout, failed_timeout) = (
+        let (check_interval, disconnected_timeout, failed_timeout, connectivity_check_timeout) = (
             self.check_interval,
-            self.keepalive_interval,
             self.disconnected_timeout,
             self.failed_timeout,
+            self.connectivity_check_timeout,
         );
 
         let done_and_force_candidate_contact_rx = {
@@ -273,15 +358,16 @@ impl AgentInternal {
                             update_interval(check_interval);
                         }
                         ConnectionState::Connected | ConnectionState::Disconnected => {
-                            update_interval(keepalive_interval);
+                            update_interval(*ai.keepalive_interval.lock());
                         }
                         _ => {}
                     };
                     // Ensure we run our task loop as quickly as the minimum of our various configured timeouts
                     update_interval(disconnected_timeout);
                     update_interval(failed_timeout);
+                    update_interval(connectivity_check_timeout);
 
-                    let t = tokio::time::sleep(interval);
+                    let t = tokio::time::sleep(ai.jittered_interval(interval));
                     tokio::pin!(t);
 
                     tokio::select! {
@@ -333,18 +419,31 @@ impl AgentInternal {
             p
         );
 
+        let old = self.agent_conn.selected_pair.swap(p.clone());
+        let changed = match (&old, &p) {
+            (Some(old), Some(p)) => !Arc::ptr_eq(old, p),
+            (None, None) => false,
+            _ => true,
+        };
+
+        if changed {
+            self.selected_at.lock().replace(Instant::now());
+        }
+
         if let Some(p) = p {
             p.nominated.store(true, Ordering::SeqCst);
-            self.agent_conn.selected_pair.store(Some(p));
+            if changed {
+                self.grant_consent();
+            }
 
             self.update_connection_state(ConnectionState::Connected)
                 .await;
 
             // Notify when the selected pair changes
-            {
+            if changed {
                 let chan_candidate_pair_tx = self.chan_candidate_pair_tx.lock().await;
                 if let Some(tx) = &*chan_candidate_pair_tx {
-                    let _ = tx.send(()).await;
+                    let _ = tx.send((old, Some(p))).await;
                 }
             }
 
@@ -353,13 +452,46 @@ impl AgentInternal {
                 let mut on_connected_tx = self.on_connected_tx.lock().await;
                 on_connected_tx.take();
             }
-        } else {
-            self.agent_conn.selected_pair.store(None);
+        } else if changed {
+            let chan_candidate_pair_tx = self.chan_candidate_pair_tx.lock().await;
+            if let Some(tx) = &*chan_candidate_pair_tx {
+                let _ = tx.send((old, None)).await;
+            }
         }
     }
 
     pub(crate) async fn ping_all_candidates(&self) {
         log::trace!("[{}]: pinging all candidates", self.get_name(),);
+        self.ping_candidates_above(None).await;
+    }
+
+    /// Keeps checking the pairs of higher priority than the selected pair for
+    /// `better_pair_check_window` after the pair was selected, so that a better pair can still be
+    /// found. The other pairs are not checked while a pair is selected.
+    pub(crate) async fn check_better_pairs(&self) {
+        let selected_pair = match self.agent_conn.get_selected_pair() {
+            Some(p) => p,
+            None => return,
+        };
+        let in_window = self
+            .selected_at
+            .lock()
+            .map_or(false, |at| at.elapsed() < self.better_pair_check_window);
+        if in_window {
+            log::trace!(
+                "[{}]: pinging candidates better than the selected pair",
+                self.get_name(),
+            );
+            self.ping_candidates_above(Some(selected_pair.priority()))
+                .await;
+        }
+    }
+
+    /// Runs the connectivity checks of the pairs whose priority is above `min_priority`, or of all
+    /// pairs without it.
+    async fn ping_candidates_above(&self, min_priority: Option<u64>) {
+        let eligible =
+            |p: &CandidatePair| -> bool { min_priority.map_or(true, |min| p.priority() > min) };
 
         let mut pairs: Vec<(
             Arc<dyn Candidate + Send + Sync>,
@@ -367,37 +499,48 @@ impl AgentInternal {
         )> = vec![];
 
         {
-            let mut checklist = self.agent_conn.checklist.lock().await;
+            let checklist = self.agent_conn.checklist.lock().await;
             if checklist.is_empty() {
                 log::warn!(
                     "[{}]: pingAllCandidates called with no candidate pairs. Connection is not possible yet.",
                     self.get_name(),
                 );
             }
-            for p in &mut *checklist {
-                let p_state = p.state.load(Ordering::SeqCst);
-                if p_state == CandidatePairState::Waiting as u8 {
-                    p.state
-                        .store(CandidatePairState::InProgress as u8, Ordering::SeqCst);
-                } else if p_state != CandidatePairState::InProgress as u8 {
-                    continue;
-                }
 
-                if p.binding_request_count.load(Ordering::SeqCst) > self.max_binding_requests {
+            Self::unfreeze_pairs(&checklist);
+
+            // Pairs in progress already have a binding request out, which is retransmitted by
+            // retransmit_pending_binding_requests until it times out and fails the pair
+            for p in &*checklist {
+                if p.state.load(Ordering::SeqCst) == CandidatePairState::InProgress as u8
+                    && eligible(p)
+                    && p.binding_request_count.load(Ordering::SeqCst) > self.max_binding_requests
+                {
                     log::trace!(
                         "[{}]: max requests reached for pair {}, marking it as failed",
                         self.get_name(),
                         p
                     );
-                    p.state
-                        .store(CandidatePairState::Failed as u8, Ordering::SeqCst);
-                } else {
-                    p.binding_request_count.fetch_add(1, Ordering::SeqCst);
-                    let local = p.local.clone();
-                    let remote = p.remote.clone();
-                    pairs.push((local, remote));
+                    p.transition_state(CandidatePairState::Failed);
                 }
             }
+
+            // Every check slot the pacer grants starts the check of the highest priority waiting
+            // pair
+            let mut waiting: Vec<&Arc<CandidatePair>> = checklist
+                .iter()
+                .filter(|p| {
+                    p.state.load(Ordering::SeqCst) == CandidatePairState::Waiting as u8
+                        && eligible(p)
+                })
+                .collect();
+            waiting.sort_by_key(|p| std::cmp::Reverse(p.priority()));
+            let slots = self.take_check_slots(waiting.len());
+            for p in waiting.into_iter().take(slots) {
+                p.transition_state(CandidatePairState::InProgress);
+                p.binding_request_count.fetch_add(1, Ordering::SeqCst);
+                pairs.push((p.local.clone(), p.remote.clone()));
+            }
         }
 
         for (local, remote) in pairs {
@@ -405,18 +548,167 @@ impl AgentInternal {
         }
     }
 
+    /// Takes up to `wanted` connectivity check slots. A slot becomes available every
+    /// `check_pacing_interval` (Ta), and up to `check_pacing_burst` of them are saved up while
+    /// unused.
+    ///
+    /// reference: (IETF ref-8445)[https://tools.ietf.org/html/rfc8445#section-14.2].
+    pub(crate) fn take_check_slots(&self, wanted: usize) -> usize {
+        let now = Instant::now();
+        let ta = self.check_pacing_interval;
+        let mut next_check = self.next_check.lock();
+
+        let saved = ta * self.check_pacing_burst.saturating_sub(1);
+        let mut next = match (now.checked_sub(saved), *next_check) {
+            (Some(earliest), Some(next)) => next.max(earliest),
+            (Some(earliest), None) => earliest,
+            (None, next) => next.unwrap_or(now),
+        };
+
+        let mut slots = 0;
+        while slots < wanted && next <= now {
+            next += ta;
+            slots += 1;
+        }
+        *next_check = Some(next);
+
+        slots
+    }
+
+    /// Moves frozen pairs to Waiting (RFC 8445 Section 6.1.2.6 and 7.2.5.3.3). Every frozen pair
+    /// is unfrozen once a pair with the same foundation succeeded, otherwise the highest priority
+    /// frozen pair is unfrozen when its foundation has nothing left waiting or in progress.
+    pub(crate) fn unfreeze_pairs(checklist: &[Arc<CandidatePair>]) {
+        #[derive(Default)]
+        struct FoundationChecks<'a> {
+            succeeded: bool,
+            active: bool,
+            best_frozen: Option<&'a Arc<CandidatePair>>,
+        }
+
+        let mut foundations: HashMap<(String, String), FoundationChecks<'_>> = HashMap::new();
+        for p in checklist {
+            let checks = foundations.entry(p.foundation()).or_default();
+            match p.state.load(Ordering::SeqCst).into() {
+                CandidatePairState::Succeeded => checks.succeeded = true,
+                CandidatePairState::Waiting | CandidatePairState::InProgress => {
+                    checks.active = true
+                }
+                CandidatePairState::Frozen
+                    if checks
+                        .best_frozen
+                        .map_or(true, |best| p.priority() > best.priority()) =>
+                {
+                    checks.best_frozen = Some(p);
+                }
+                _ => {}
+            }
+        }
+
+        for (foundation, checks) in foundations {
+            if checks.succeeded {
+                for p in checklist.iter().filter(|p| p.foundation() == foundation) {
+                    Self::unfreeze_pair(p);
+                }
+            } else if !checks.active {
+                if let Some(p) = checks.best_frozen {
+                    Self::unfreeze_pair(p);
+                }
+            }
+        }
+    }
+
+    /// Moves `p` from Frozen to Waiting, pairs in any other state are left alone.
+    pub(crate) fn unfreeze_pair(p: &CandidatePair) {
+        let _ = p.state.compare_exchange(
+            CandidatePairState::Frozen as u8,
+            CandidatePairState::Waiting as u8,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        );
+    }
+
+    /// Unfreezes every pair sharing the foundation of `p`, after a check on `p` succeeded.
+    pub(crate) async fn unfreeze_foundation(&self, p: &CandidatePair) {
+        let foundation = p.foundation();
+        let checklist = self.agent_conn.checklist.lock().await;
+        for p in checklist.iter().filter(|p| p.foundation() == foundation) {
+            Self::unfreeze_pair(p);
+        }
+    }
+
+    /// Switches the agent role. The G and D terms of a pair priority swap along with it (RFC 8445
+    /// Section 6.1.2.3), so every pair takes the new role and the checklist is re-sorted.
+    pub(crate) async fn set_controlling(&self, is_controlling: bool) {
+        self.is_controlling.store(is_controlling, Ordering::SeqCst);
+
+        let mut checklist = self.agent_conn.checklist.lock().await;
+        for p in checklist.iter() {
+            p.ice_role_controlling
+                .store(is_controlling, Ordering::SeqCst);
+        }
+        checklist.sort_by_key(|p| std::cmp::Reverse(p.priority()));
+        self.agent_conn.invalidate_best_valid_candidate_pair();
+    }
+
+    /// Adds a pair to the checklist, which is kept sorted by descending pair priority. It starts
+    /// out Frozen when another pair already has the same foundation, so that only one pair per
+    /// foundation is checked at first. Beyond `max_candidate_pairs` the lowest-priority pairs are
+    /// pruned, which may be the new one. No pair is added for a local candidate the
+    /// `candidate_filter` rejects.
     pub(crate) async fn add_pair(
         &self,
         local: Arc<dyn Candidate + Send + Sync>,
         remote: Arc<dyn Candidate + Send + Sync>,
     ) {
+        if let Some(candidate_filter) = &*self.candidate_filter {
+            if !candidate_filter(&*local) {
+                log::trace!(
+                    "[{}]: local candidate {} is filtered out, not pairing it with {}",
+                    self.get_name(),
+                    local,
+                    remote
+                );
+                return;
+            }
+        }
+
         let p = Arc::new(CandidatePair::new(
             local,
             remote,
             self.is_controlling.load(Ordering::SeqCst),
         ));
+        let nominated_pair = self.nominated_pair.lock().await.clone();
+        let selected_pair = self.agent_conn.get_selected_pair();
         let mut checklist = self.agent_conn.checklist.lock().await;
-        checklist.push(p);
+        let foundation = p.foundation();
+        if checklist
+            .iter()
+            .any(|other| other.foundation() == foundation)
+        {
+            p.transition_state(CandidatePairState::Frozen);
+        }
+        let priority = p.priority();
+        let index = checklist.partition_point(|other| other.priority() >= priority);
+        checklist.insert(index, p);
+
+        if let Some(max_candidate_pairs) = self.max_candidate_pairs {
+            let is_protected = |p: &Arc<CandidatePair>| {
+                p.nominated.load(Ordering::SeqCst)
+                    || [&nominated_pair, &selected_pair]
+                        .iter()
+                        .any(|other| other.as_ref().map_or(false, |other| Arc::ptr_eq(other, p)))
+            };
+            while checklist.len() > max_candidate_pairs {
+                if let Some(index) = checklist.iter().rposition(|p| !is_protected(p)) {
+                    let pruned = checklist.remove(index);
+                    log::trace!("[{}]: pruned candidate pair {}", self.get_name(), pruned);
+                } else {
+                    break;
+                }
+            }
+        }
+        self.agent_conn.invalidate_best_valid_candidate_pair();
     }
 
     pub(crate) async fn find_pair(
@@ -441,9 +733,17 @@ impl AgentInternal {
             (*selected_pair).as_ref().map_or_else(
                 || (false, Duration::from_secs(0)),
                 |selected_pair| {
-                    let disconnected_time = SystemTime::now()
-                        .duration_since(selected_pair.remote.last_received())
-                        .unwrap_or_else(|_| Duration::from_secs(0));
+                    let disconnected_time = if !selected_pair.local.network_type().is_tcp() {
+                        system_time_now()
+                            .duration_since(selected_pair.remote.last_received())
+                            .unwrap_or_else(|_| Duration::from_secs(0))
+                    } else if Self::is_unconnected_tcp(&selected_pair.local) {
+                        // The TCP connection went away, the pair is as good as gone
+                        Duration::MAX
+                    } else {
+                        // A TCP pair is alive for as long as its connection is
+                        Duration::from_secs(0)
+                    };
                     (true, disconnected_time)
                 },
             )
@@ -474,6 +774,19 @@ impl AgentInternal {
         valid
     }
 
+    /// Randomizes `interval` by up to `check_interval_jitter` in either direction.
+    pub(crate) fn jittered_interval(&self, interval: Duration) -> Duration {
+        if self.check_interval_jitter == 0.0 {
+            return interval;
+        }
+
+        let jitter = self
+            .check_interval_rng
+            .lock()
+            .gen_range(-self.check_interval_jitter..=self.check_interval_jitter);
+        interval.mul_f64(1.0 + jitter)
+    }
+
     /// Sends STUN Binding Indications to the selected pair.
     /// if no packet has been sent on that pair in the last keepaliveInterval.
     /// Note: the caller should hold the agent lock.
@@ -491,17 +804,22 @@ impl AgentInternal {
         };
 
         if let (Some(local), Some(remote)) = (local, remote) {
-            let last_sent = SystemTime::now()
+            // TCP pairs have no datagram keepalives, the connection itself keeps them alive
+            if local.network_type().is_tcp() {
+                return;
+            }
+
+            let last_sent = system_time_now()
                 .duration_since(local.last_sent())
                 .unwrap_or_else(|_| Duration::from_secs(0));
 
-            let last_received = SystemTime::now()
+            let last_received = system_time_now()
                 .duration_since(remote.last_received())
                 .unwrap_or_else(|_| Duration::from_secs(0));
 
-            if (self.keepalive_interval != Duration::from_secs(0))
-                && ((last_sent > self.keepalive_interval)
-                    || (last_received > self.keepalive_interval))
+            let keepalive_interval = *self.keepalive_interval.lock();
+            if (keepalive_interval != Duration::from_secs(0))
+                && ((last_sent > keepalive_interval) || (last_received > keepalive_interval))
             {
                 // we use binding request instead of indication to support refresh consent schemas
                 // see https://tools.ietf.org/html/rfc7675
@@ -510,7 +828,97 @@ impl AgentInternal {
         }
     }
 
-    fn request_connectivity_check(&self) {
+    /// Records a consent check response on the selected pair and schedules the next check, the
+    /// interval is randomized to between 0.8 and 1.2 times `consent_check_interval`.
+    ///
+    /// reference: (IETF ref-7675)[https://tools.ietf.org/html/rfc7675#section-5.1].
+    pub(crate) fn grant_consent(&self) {
+        let now = Instant::now();
+        *self.consent_granted.lock() = now;
+        *self.next_consent_check.lock() = now
+            + self
+                .consent_check_interval
+                .mul_f64(0.8 + 0.4 * rand::random::<f64>());
+    }
+
+    /// Checks consent freshness of the selected pair. Sends a consent check when one is due, and
+    /// once `consent_timeout` passes without a response marks the pair as failed, drops it and
+    /// restarts connectivity checks. Returns false if consent has expired.
+    ///
+    /// reference: (IETF ref-7675)[https://tools.ietf.org/html/rfc7675#section-5.1].
+    pub(crate) async fn check_consent(&self) -> bool {
+        if self.consent_timeout == Duration::from_secs(0) {
+            return true;
+        }
+
+        let selected_pair = match self.agent_conn.get_selected_pair() {
+            Some(selected_pair) => selected_pair,
+            None => return false,
+        };
+
+        let now = Instant::now();
+        let consent_granted = *self.consent_granted.lock();
+        if now.saturating_duration_since(consent_granted) > self.consent_timeout {
+            log::warn!(
+                "[{}]: consent expired for selected pair {}",
+                self.get_name(),
+                selected_pair
+            );
+            selected_pair.transition_state(CandidatePairState::Failed);
+            self.set_selected_pair(None).await;
+            {
+                let mut nominated_pair = self.nominated_pair.lock().await;
+                *nominated_pair = None;
+            }
+            {
+                let checklist = self.agent_conn.checklist.lock().await;
+                for p in checklist.iter().filter(|p| !Arc::ptr_eq(p, &selected_pair)) {
+                    p.transition_state(CandidatePairState::Waiting);
+                    p.binding_request_count.store(0, Ordering::SeqCst);
+                }
+            }
+            self.update_connection_state(ConnectionState::Checking)
+                .await;
+            self.request_connectivity_check();
+            return false;
+        }
+
+        let consent_check_due = {
+            let mut next_consent_check = self.next_consent_check.lock();
+            if now >= *next_consent_check {
+                *next_consent_check = now
+                    + self
+                        .consent_check_interval
+                        .mul_f64(0.8 + 0.4 * rand::random::<f64>());
+                true
+            } else {
+                false
+            }
+        };
+        if consent_check_due {
+            let (local, remote) = (selected_pair.local.clone(), selected_pair.remote.clone());
+            self.ping_candidate(&local, &remote).await;
+        }
+
+        true
+    }
+
+    /// Resumes the connectivity checks and keepalives stopped by `pause`. The selected pair is
+    /// taken as alive as of now, so that the disconnected, failed and consent timeouts restart
+    /// instead of expiring over the pause, and a contact cycle runs right away.
+    pub(crate) fn resume(&self) {
+        if !self.paused.swap(false, Ordering::SeqCst) {
+            return;
+        }
+
+        if let Some(p) = &*self.agent_conn.selected_pair.load() {
+            p.remote.seen(false);
+            self.grant_consent();
+        }
+        self.request_connectivity_check();
+    }
+
+    pub(crate) fn request_connectivity_check(&self) {
         let _ = self.force_candidate_contact_tx.try_send(true);
     }
 
@@ -544,16 +952,107 @@ impl AgentInternal {
         }
 
         for cand in local_cands {
+            // Local peer-reflexive candidates only pair with the remote they were learned from
+            if cand.candidate_type() == CandidateType::PeerReflexive {
+                continue;
+            }
             self.add_pair(cand, c.clone()).await;
         }
 
         self.request_connectivity_check();
     }
 
+    /// Learns a local peer-reflexive candidate from the XOR-MAPPED-ADDRESS of a success response
+    /// to a check sent from `local`, when the mapped address matches none of the local candidates
+    /// because a NAT between the agents maps the base to an address not gathered (RFC 8445 Section
+    /// 7.2.5.3.1). The candidate takes the priority sent in the request and shares the base's
+    /// connection, as traffic is still sent from the base. It isn't paired with other remote
+    /// candidates, but forms a valid pair with `remote` (Section 7.2.5.3.2), which is returned.
+    pub(crate) async fn add_peer_reflexive_local_candidate(
+        &self,
+        m: &Message,
+        local: &Arc<dyn Candidate + Send + Sync>,
+        remote: &Arc<dyn Candidate + Send + Sync>,
+        request: &BindingRequest,
+    ) -> Option<Arc<CandidatePair>> {
+        if local.network_type().is_tcp() {
+            return None;
+        }
+
+        let mut mapped = XorMappedAddress::default();
+        mapped.get_from(m).ok()?;
+        let mapped_addr = SocketAddr::new(mapped.ip, mapped.port);
+
+        let mut priority = PriorityAttr::default();
+        if priority.get_from(&request.message).is_err() {
+            priority = PriorityAttr(local.priority());
+        }
+
+        let prflx_candidate: Arc<dyn Candidate + Send + Sync> = {
+            let mut local_candidates = self.local_candidates.lock().await;
+            let known = local.addr() == mapped_addr
+                || local_candidates
+                    .values()
+                    .flatten()
+                    .any(|c| c.addr() == mapped_addr);
+            if known {
+                return None;
+            }
+
+            let prflx_candidate_config = CandidatePeerReflexiveConfig {
+                base_config: CandidateBaseConfig {
+                    network: local.network_type().to_string(),
+                    address: mapped.ip.to_string(),
+                    port: mapped.port,
+                    component: local.component(),
+                    priority: priority.0,
+                    conn: local.get_conn().cloned(),
+                    ..CandidateBaseConfig::default()
+                },
+                rel_addr: local.address(),
+                rel_port: local.port(),
+            };
+            let prflx_candidate = match prflx_candidate_config.new_candidate_peer_reflexive() {
+                Ok(prflx_candidate) => Arc::new(prflx_candidate),
+                Err(err) => {
+                    log::error!(
+                        "[{}]: Failed to create new local prflx candidate ({})",
+                        self.get_name(),
+                        err
+                    );
+                    return None;
+                }
+            };
+            local_candidates
+                .entry(prflx_candidate.network_type())
+                .or_insert_with(Vec::new)
+                .push(prflx_candidate.clone());
+            prflx_candidate
+        };
+
+        log::debug!(
+            "[{}]: learned a local peer-reflexive candidate: {} (base {})",
+            self.get_name(),
+            prflx_candidate,
+            local
+        );
+
+        self.add_pair(Arc::clone(&prflx_candidate), Arc::clone(remote))
+            .await;
+        let p = self.find_pair(&prflx_candidate, remote).await?;
+        p.transition_state(CandidatePairState::Succeeded);
+        self.agent_conn.invalidate_best_valid_candidate_pair();
+        Some(p)
+    }
+
     pub(crate) async fn add_candidate(
         self: &Arc<Self>,
         c: &Arc<dyn Candidate + Send + Sync>,
     ) -> Result<()> {
+        if c.candidate_type() == CandidateType::Unspecified {
+            return Err(Error::ErrUnknownCandidateType);
+        }
+
         let initialized_ch = {
             let started_ch_tx = self.started_ch_tx.lock().await;
             (*started_ch_tx).as_ref().map(|tx| tx.subscribe())
@@ -614,10 +1113,13 @@ impl AgentInternal {
         {
             let mut done_tx = self.done_tx.lock().await;
             if done_tx.is_none() {
-                return Err(Error::ErrClosed);
+                return Ok(());
             }
+            // Dropping the sender ends the connectivity check loop
             done_tx.take();
         };
+        self.pending_binding_requests.lock().await.clear();
+        self.set_selected_pair(None).await;
         self.delete_all_candidates().await;
         {
             let mut started_ch_tx = self.started_ch_tx.lock().await;
@@ -655,6 +1157,10 @@ impl AgentInternal {
             let mut local_candidates = self.local_candidates.lock().await;
             for cs in local_candidates.values_mut() {
                 for c in cs {
+                    // A local peer-reflexive candidate shares the conn of its base, which closes it
+                    if c.candidate_type() == CandidateType::PeerReflexive {
+                        continue;
+                    }
                     if let Err(err) = c.close().await {
                         log::warn!(
                             "[{}]: Failed to close candidate {}: {}",
@@ -704,12 +1210,14 @@ impl AgentInternal {
         None
     }
 
+    /// Sends a binding request and tracks it as pending until it is answered. A request that
+    /// can't be written isn't tracked and the write error is returned.
     pub(crate) async fn send_binding_request(
         &self,
         m: &Message,
         local: &Arc<dyn Candidate + Send + Sync>,
         remote: &Arc<dyn Candidate + Send + Sync>,
-    ) {
+    ) -> Result<()> {
         log::trace!(
             "[{}]: ping STUN from {} to {}",
             self.get_name(),
@@ -720,16 +1228,97 @@ impl AgentInternal {
         self.invalidate_pending_binding_requests(Instant::now())
             .await;
         {
+            let now = Instant::now();
             let mut pending_binding_requests = self.pending_binding_requests.lock().await;
             pending_binding_requests.push(BindingRequest {
-                timestamp: Instant::now(),
+                timestamp: now,
                 transaction_id: m.transaction_id,
                 destination: remote.addr(),
                 is_use_candidate: m.contains(ATTR_USE_CANDIDATE),
+                message: m.clone(),
+                local: Arc::clone(local),
+                remote: Arc::clone(remote),
+                last_sent: now,
+                retransmits: 0,
             });
         }
 
-        self.send_stun(m, local, remote).await;
+        let result = self.send_stun(m, local, remote).await;
+        if result.is_err() {
+            // The request never left, there is no transaction to wait for
+            let mut pending_binding_requests = self.pending_binding_requests.lock().await;
+            pending_binding_requests.retain(|r| r.transaction_id != m.transaction_id);
+        }
+        result
+    }
+
+    /// Appends the configured SOFTWARE attribute, if any, to the setters of an outbound message.
+    /// It has to be called before MESSAGE-INTEGRITY and FINGERPRINT are pushed.
+    pub(crate) fn push_software(&self, setters: &mut Vec<Box<dyn Setter>>) {
+        if let Some(software) = &self.software {
+            setters.push(Box::new(software.clone()));
+        }
+    }
+
+    /// The USERNAME of an outbound binding request.
+    pub(crate) fn request_username(&self, ufrag_pwd: &UfragPwd) -> Username {
+        let username = match &self.credential_mode {
+            CredentialMode::ShortTerm => {
+                ufrag_pwd.remote_ufrag.clone() + ":" + ufrag_pwd.local_ufrag.as_str()
+            }
+            CredentialMode::LongTerm { username, .. } => username.clone(),
+        };
+        Username::new(ATTR_USERNAME, username)
+    }
+
+    /// Appends REALM and NONCE to the setters of an outbound binding request when long-term
+    /// credentials are used. It has to be called before MESSAGE-INTEGRITY and FINGERPRINT are
+    /// pushed.
+    pub(crate) fn push_long_term_attributes(&self, setters: &mut Vec<Box<dyn Setter>>) {
+        if let CredentialMode::LongTerm { realm, nonce, .. } = &self.credential_mode {
+            setters.push(Box::new(Realm::new(ATTR_REALM, realm.clone())));
+            setters.push(Box::new(Nonce::new(ATTR_NONCE, nonce.clone())));
+        }
+    }
+
+    /// The MESSAGE-INTEGRITY outbound binding requests, and the responses to them, are signed with.
+    pub(crate) fn request_integrity(&self, ufrag_pwd: &UfragPwd) -> MessageIntegrity {
+        match &self.credential_mode {
+            CredentialMode::ShortTerm => {
+                MessageIntegrity::new_short_term_integrity(ufrag_pwd.remote_pwd.clone())
+            }
+            CredentialMode::LongTerm {
+                username,
+                realm,
+                password,
+                ..
+            } => MessageIntegrity::new_long_term_integrity(
+                username.clone(),
+                realm.clone(),
+                password.clone(),
+            ),
+        }
+    }
+
+    /// The USERNAME an inbound binding request has to carry.
+    pub(crate) fn inbound_request_username(&self, ufrag_pwd: &UfragPwd) -> String {
+        match &self.credential_mode {
+            CredentialMode::ShortTerm => {
+                ufrag_pwd.local_ufrag.clone() + ":" + ufrag_pwd.remote_ufrag.as_str()
+            }
+            CredentialMode::LongTerm { username, .. } => username.clone(),
+        }
+    }
+
+    /// The MESSAGE-INTEGRITY inbound binding requests are verified with, and the responses to
+    /// them are signed with.
+    pub(crate) fn response_integrity(&self, ufrag_pwd: &UfragPwd) -> MessageIntegrity {
+        match &self.credential_mode {
+            CredentialMode::ShortTerm => {
+                MessageIntegrity::new_short_term_integrity(ufrag_pwd.local_pwd.clone())
+            }
+            CredentialMode::LongTerm { .. } => self.request_integrity(ufrag_pwd),
+        }
     }
 
     pub(crate) async fn send_binding_success(
@@ -740,20 +1329,23 @@ impl AgentInternal {
     ) {
         let addr = remote.addr();
         let (ip, port) = (addr.ip(), addr.port());
-        let local_pwd = {
+        let integrity = {
             let ufrag_pwd = self.ufrag_pwd.lock().await;
-            ufrag_pwd.local_pwd.clone()
+            self.response_integrity(&ufrag_pwd)
         };
 
         let (out, result) = {
-            let mut out = Message::new();
-            let result = out.build(&[
+            let mut setters: Vec<Box<dyn Setter>> = vec![
                 Box::new(m.clone()),
                 Box::new(BINDING_SUCCESS),
                 Box::new(XorMappedAddress { ip, port }),
-                Box::new(MessageIntegrity::new_short_term_integrity(local_pwd)),
-                Box::new(FINGERPRINT),
-            ]);
+            ];
+            self.push_software(&mut setters);
+            setters.push(Box::new(integrity));
+            setters.push(Box::new(FINGERPRINT));
+
+            let mut out = Message::new();
+            let result = out.build(&setters);
             (out, result)
         };
 
@@ -766,42 +1358,178 @@ impl AgentInternal {
                 err
             );
         } else {
-            self.send_stun(&out, local, remote).await;
+            let _ = self.send_stun(&out, local, remote).await;
         }
     }
 
-    /// Removes pending binding requests that are over `maxBindingRequestTimeout` old Let HTO be the
-    /// transaction timeout, which SHOULD be 2*RTT if RTT is known or 500 ms otherwise.
+    /// Rejects a binding request with a 487 (Role Conflict) error response, telling the remote
+    /// agent to switch its role.
     ///
-    /// reference: (IETF ref-8445)[https://tools.ietf.org/html/rfc8445#appendix-B.1].
-    pub(crate) async fn invalidate_pending_binding_requests(&self, filter_time: Instant) {
-        let mut pending_binding_requests = self.pending_binding_requests.lock().await;
-        let initial_size = pending_binding_requests.len();
-
-        let mut temp = vec![];
-        for binding_request in pending_binding_requests.drain(..) {
-            if filter_time
-                .checked_duration_since(binding_request.timestamp)
-                .map(|duration| duration < MAX_BINDING_REQUEST_TIMEOUT)
-                .unwrap_or(true)
-            {
-                temp.push(binding_request);
-            }
+    /// reference: (IETF ref-8445)[https://tools.ietf.org/html/rfc8445#section-7.3.1.1].
+    pub(crate) async fn send_role_conflict_error(
+        &self,
+        m: &Message,
+        local: &Arc<dyn Candidate + Send + Sync>,
+        remote: &Arc<dyn Candidate + Send + Sync>,
+    ) {
+        self.discards.role_conflict.fetch_add(1, Ordering::SeqCst);
+
+        let integrity = {
+            let ufrag_pwd = self.ufrag_pwd.lock().await;
+            self.response_integrity(&ufrag_pwd)
+        };
+
+        let (out, result) = {
+            let mut setters: Vec<Box<dyn Setter>> = vec![
+                Box::new(m.clone()),
+                Box::new(MessageType::new(METHOD_BINDING, CLASS_ERROR_RESPONSE)),
+                Box::new(CODE_ROLE_CONFLICT),
+            ];
+            self.push_software(&mut setters);
+            setters.push(Box::new(integrity));
+            setters.push(Box::new(FINGERPRINT));
+
+            let mut out = Message::new();
+            let result = out.build(&setters);
+            (out, result)
+        };
+
+        if let Err(err) = result {
+            log::warn!(
+                "[{}]: Failed to handle inbound ICE from: {} to: {} error: {}",
+                self.get_name(),
+                local,
+                remote,
+                err
+            );
+        } else {
+            let _ = self.send_stun(&out, local, remote).await;
         }
+    }
+
+    /// Retransmits the pending binding requests that are still unanswered after their
+    /// retransmission timeout. The timeout starts at `binding_request_rto` and doubles after each
+    /// retransmission, until `max_binding_request_retransmits` is reached or the request expires.
+    ///
+    /// reference: (IETF ref-8445)[https://tools.ietf.org/html/rfc8445#section-14.3].
+    pub(crate) async fn retransmit_pending_binding_requests(&self) {
+        self.invalidate_pending_binding_requests(Instant::now())
+            .await;
+
+        let to_retransmit = {
+            let now = Instant::now();
+            let mut pending_binding_requests = self.pending_binding_requests.lock().await;
+            let mut to_retransmit = vec![];
+            for binding_request in pending_binding_requests.iter_mut() {
+                if binding_request.retransmits >= self.max_binding_request_retransmits {
+                    continue;
+                }
+
+                let rto = self
+                    .binding_request_rto
+                    .saturating_mul(1 << binding_request.retransmits.min(16));
+                if now.saturating_duration_since(binding_request.last_sent) < rto {
+                    continue;
+                }
 
-        *pending_binding_requests = temp;
-        let bind_requests_removed = initial_size - pending_binding_requests.len();
-        if bind_requests_removed > 0 {
+                binding_request.last_sent = now;
+                binding_request.retransmits += 1;
+                to_retransmit.push(binding_request.clone());
+            }
+            to_retransmit
+        };
+
+        for binding_request in to_retransmit {
             log::trace!(
-                "[{}]: Discarded {} binding requests because they expired",
+                "[{}]: retransmit STUN from {} to {} ({})",
                 self.get_name(),
-                bind_requests_removed
+                binding_request.local,
+                binding_request.remote,
+                binding_request.retransmits
             );
+            let _ = self
+                .send_stun(
+                    &binding_request.message,
+                    &binding_request.local,
+                    &binding_request.remote,
+                )
+                .await;
+        }
+    }
+
+    /// The time after which an unanswered binding request is given up on: long enough for all of
+    /// its retransmissions plus one initial RTO for the answer, and at least
+    /// `MAX_BINDING_REQUEST_TIMEOUT`.
+    pub(crate) fn binding_request_timeout(&self) -> Duration {
+        let retransmits = u32::from(self.max_binding_request_retransmits.min(16));
+        MAX_BINDING_REQUEST_TIMEOUT.max(self.binding_request_rto.saturating_mul(1 << retransmits))
+    }
+
+    /// Removes pending binding requests that are over `binding_request_timeout` old Let HTO be the
+    /// transaction timeout, which SHOULD be 2*RTT if RTT is known or 500 ms otherwise. A pair
+    /// that is still being checked fails once one of its transactions times out, as the
+    /// retransmissions went unanswered (RFC 8445 Section 7.2.5.2.4).
+    ///
+    /// reference: (IETF ref-8445)[https://tools.ietf.org/html/rfc8445#appendix-B.1].
+    pub(crate) async fn invalidate_pending_binding_requests(&self, filter_time: Instant) {
+        let timeout = self.binding_request_timeout();
+        let mut expired = vec![];
+        {
+            let mut pending_binding_requests = self.pending_binding_requests.lock().await;
+
+            let mut temp = vec![];
+            for binding_request in pending_binding_requests.drain(..) {
+                if filter_time
+                    .checked_duration_since(binding_request.timestamp)
+                    .map(|duration| duration < timeout)
+                    .unwrap_or(true)
+                {
+                    temp.push(binding_request);
+                } else {
+                    expired.push(binding_request);
+                }
+            }
+
+            *pending_binding_requests = temp;
+        }
+
+        if expired.is_empty() {
+            return;
+        }
+        log::trace!(
+            "[{}]: Discarded {} binding requests because they expired",
+            self.get_name(),
+            expired.len()
+        );
+
+        for binding_request in expired {
+            if let Some(p) = self
+                .find_pair(&binding_request.local, &binding_request.remote)
+                .await
+            {
+                if p.state
+                    .compare_exchange(
+                        CandidatePairState::InProgress as u8,
+                        CandidatePairState::Failed as u8,
+                        Ordering::SeqCst,
+                        Ordering::SeqCst,
+                    )
+                    .is_ok()
+                {
+                    log::trace!(
+                        "[{}]: binding request timed out for pair {}, marking it as failed",
+                        self.get_name(),
+                        p
+                    );
+                }
+            }
         }
     }
 
     /// Assert that the passed `TransactionID` is in our `pendingBindingRequests` and returns the
-    /// destination, If the bindingRequest was valid remove it from our pending cache.
+    /// destination, If the bindingRequest was valid remove it from our pending cache. The request
+    /// is removed before the caller checks the response source, so a transaction can only ever be
+    /// matched once.
     pub(crate) async fn handle_inbound_binding_success(
         &self,
         id: TransactionId,
@@ -842,25 +1570,45 @@ impl AgentInternal {
             return;
         }
 
+        if let Err(err) = assert_inbound_fingerprint(m) {
+            log::warn!(
+                "[{}]: discard message from ({}), {}",
+                self.get_name(),
+                remote,
+                err
+            );
+            self.discards
+                .fingerprint_failure
+                .fetch_add(1, Ordering::SeqCst);
+            return;
+        }
+
         if self.is_controlling.load(Ordering::SeqCst) {
             if m.contains(ATTR_ICE_CONTROLLING) {
-                log::debug!(
-                    "[{}]: inbound isControlling && a.isControlling == true",
-                    self.get_name(),
-                );
-                return;
+                // Role conflicts in requests are resolved by the selector once the
+                // request has been authenticated.
+                if m.typ.class != CLASS_REQUEST {
+                    log::debug!(
+                        "[{}]: inbound isControlling && a.isControlling == true",
+                        self.get_name(),
+                    );
+                    self.discards.role_conflict.fetch_add(1, Ordering::SeqCst);
+                    return;
+                }
             } else if m.contains(ATTR_USE_CANDIDATE) {
                 log::debug!(
                     "[{}]: useCandidate && a.isControlling == true",
                     self.get_name(),
                 );
+                self.discards.role_conflict.fetch_add(1, Ordering::SeqCst);
                 return;
             }
-        } else if m.contains(ATTR_ICE_CONTROLLED) {
+        } else if m.contains(ATTR_ICE_CONTROLLED) && m.typ.class != CLASS_REQUEST {
             log::debug!(
                 "[{}]: inbound isControlled && a.isControlling == false",
                 self.get_name(),
             );
+            self.discards.role_conflict.fetch_add(1, Ordering::SeqCst);
             return;
         }
 
@@ -870,15 +1618,16 @@ impl AgentInternal {
         if m.typ.class == CLASS_SUCCESS_RESPONSE {
             {
                 let ufrag_pwd = self.ufrag_pwd.lock().await;
-                if let Err(err) =
-                    assert_inbound_message_integrity(m, ufrag_pwd.remote_pwd.as_bytes())
-                {
+                if let Err(err) = self.request_integrity(&ufrag_pwd).check(m) {
                     log::warn!(
                         "[{}]: discard message from ({}), {}",
                         self.get_name(),
                         remote,
                         err
                     );
+                    self.discards
+                        .integrity_failure
+                        .fetch_add(1, Ordering::SeqCst);
                     return;
                 }
             }
@@ -896,8 +1645,7 @@ impl AgentInternal {
         } else if m.typ.class == CLASS_REQUEST {
             {
                 let ufrag_pwd = self.ufrag_pwd.lock().await;
-                let username =
-                    ufrag_pwd.local_ufrag.clone() + ":" + ufrag_pwd.remote_ufrag.as_str();
+                let username = self.inbound_request_username(&ufrag_pwd);
                 if let Err(err) = assert_inbound_username(m, &username) {
                     log::warn!(
                         "[{}]: discard message from ({}), {}",
@@ -905,29 +1653,45 @@ impl AgentInternal {
                         remote,
                         err
                     );
+                    self.discards
+                        .integrity_failure
+                        .fetch_add(1, Ordering::SeqCst);
                     return;
-                } else if let Err(err) =
-                    assert_inbound_message_integrity(m, ufrag_pwd.local_pwd.as_bytes())
-                {
-                    log::warn!(
+                } else if let Err(err) = self.response_integrity(&ufrag_pwd).check(m) {
+                    // Requests are verified with our credentials before they can create a
+                    // candidate or a pair, forged ones are dropped silently.
+                    log::debug!(
                         "[{}]: discard message from ({}), {}",
                         self.get_name(),
                         remote,
                         err
                     );
+                    self.discards
+                        .integrity_failure
+                        .fetch_add(1, Ordering::SeqCst);
                     return;
                 }
             }
 
+            // A request without PRIORITY isn't a connectivity check, e.g. it comes from a plain
+            // STUN client. It is answered, but it doesn't create a candidate or a pair.
+            let is_check = m.contains(ATTR_PRIORITY);
+
             if remote_candidate.is_none() {
                 let (ip, port, network_type) = (remote.ip(), remote.port(), NetworkType::Udp4);
 
+                // The remote computed the priority it would give the candidate in the PRIORITY
+                // attribute (RFC 8445 Section 7.3.1.3), without it the type's default is used
+                let mut priority = PriorityAttr::default();
+                let _ = priority.get_from(m);
+
                 let prflx_candidate_config = CandidatePeerReflexiveConfig {
                     base_config: CandidateBaseConfig {
                         network: network_type.to_string(),
                         address: ip.to_string(),
                         port,
                         component: local.component(),
+                        priority: priority.0,
                         ..CandidateBaseConfig::default()
                     },
                     rel_addr: "".to_owned(),
@@ -946,13 +1710,15 @@ impl AgentInternal {
                     }
                 };
 
-                log::debug!(
-                    "[{}]: adding a new peer-reflexive candidate: {} ",
-                    self.get_name(),
-                    remote
-                );
-                if let Some(rc) = &remote_candidate {
-                    self.add_remote_candidate(rc).await;
+                if is_check {
+                    log::debug!(
+                        "[{}]: adding a new peer-reflexive candidate: {} ",
+                        self.get_name(),
+                        remote
+                    );
+                    if let Some(rc) = &remote_candidate {
+                        self.add_remote_candidate(rc).await;
+                    }
                 }
             }
 
@@ -964,7 +1730,16 @@ impl AgentInternal {
             );
 
             if let Some(rc) = &remote_candidate {
-                self.handle_binding_request(m, local, rc).await;
+                if is_check {
+                    self.handle_binding_request(m, local, rc).await;
+                } else {
+                    log::debug!(
+                        "[{}]: answering binding request without PRIORITY from {}",
+                        self.get_name(),
+                        remote
+                    );
+                    self.send_binding_success(m, local, rc).await;
+                }
             }
         }
 
@@ -1006,19 +1781,22 @@ impl AgentInternal {
         Ok(())
     }
 
+    /// Writes a STUN message from `local` to `remote`, logging and returning a failed write.
     pub(crate) async fn send_stun(
         &self,
         msg: &Message,
         local: &Arc<dyn Candidate + Send + Sync>,
         remote: &Arc<dyn Candidate + Send + Sync>,
-    ) {
+    ) -> Result<()> {
         if let Err(err) = local.write_to(&msg.raw, &**remote).await {
             log::trace!(
                 "[{}]: failed to send STUN message: {}",
                 self.get_name(),
                 err
             );
+            return Err(err);
         }
+        Ok(())
     }
 
     /// Runs the candidate using the provided connection.
@@ -1053,20 +1831,23 @@ impl AgentInternal {
         self: &Arc<Self>,
         mut chan_state_rx: mpsc::Receiver<ConnectionState>,
         mut chan_candidate_rx: mpsc::Receiver<Option<Arc<dyn Candidate + Send + Sync>>>,
-        mut chan_candidate_pair_rx: mpsc::Receiver<()>,
+        mut chan_candidate_pair_rx: mpsc::Receiver<SelectedPairTransition>,
     ) {
         let ai = Arc::clone(self);
         tokio::spawn(async move {
             // CandidatePair and ConnectionState are usually changed at once.
             // Blocking one by the other one causes deadlock.
-            while chan_candidate_pair_rx.recv().await.is_some() {
-                if let (Some(cb), Some(p)) = (
-                    &*ai.on_selected_candidate_pair_change_hdlr.load(),
-                    &*ai.agent_conn.selected_pair.load(),
-                ) {
+            while let Some((old, new)) = chan_candidate_pair_rx.recv().await {
+                if let (Some(cb), Some(p)) =
+                    (&*ai.on_selected_candidate_pair_change_hdlr.load(), &new)
+                {
                     let mut f = cb.lock().await;
                     f(&p.local, &p.remote).await;
                 }
+                if let Some(cb) = &*ai.on_selected_candidate_pair_transition_hdlr.load() {
+                    let mut f = cb.lock().await;
+                    f(old, new).await;
+                }
             }
         });
 
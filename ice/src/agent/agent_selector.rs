@@ -1,10 +1,12 @@
+use crate::agent::agent_config::NominationMode;
 use crate::agent::agent_internal::*;
 use crate::candidate::*;
 use crate::control::*;
+use crate::error::Result;
 use crate::priority::*;
 use crate::use_candidate::*;
 
-use stun::{agent::*, attributes::*, fingerprint::*, integrity::*, message::*, textattrs::*};
+use stun::{agent::*, attributes::*, fingerprint::*, message::*};
 
 use async_trait::async_trait;
 use std::net::SocketAddr;
@@ -61,7 +63,7 @@ trait ControlledSelector {
 }
 
 impl AgentInternal {
-    fn is_nominatable(&self, c: &Arc<dyn Candidate + Send + Sync>) -> bool {
+    pub(crate) fn is_nominatable(&self, c: &Arc<dyn Candidate + Send + Sync>) -> bool {
         let start_time = *self.start_time.lock();
         match c.candidate_type() {
             CandidateType::Host => {
@@ -92,17 +94,22 @@ impl AgentInternal {
                     .as_nanos()
                     > self.relay_acceptance_min_wait.as_nanos()
             }
-            CandidateType::Unspecified => {
-                log::error!(
-                    "is_nominatable invalid candidate type {}",
-                    c.candidate_type()
-                );
-                false
-            }
+            // Candidates without a type are rejected when they are added to the agent
+            CandidateType::Unspecified => false,
         }
     }
 
-    async fn nominate_pair(&self) {
+    /// Returns whether `c` is a TCP candidate (RFC 6544) whose connection isn't established yet.
+    /// STUN can only be sent once it is, and from then on the connection is the liveness signal.
+    pub(crate) fn is_unconnected_tcp(c: &Arc<dyn Candidate + Send + Sync>) -> bool {
+        c.network_type().is_tcp()
+            && c.get_conn()
+                .map_or(true, |conn| conn.remote_addr().is_none())
+    }
+
+    /// Sends the nomination of `nominated_pair`. When it can't be sent the nomination is taken
+    /// back, so that the next `contact_candidates` nominates the best valid pair anew.
+    async fn nominate_pair(&self) -> Result<()> {
         let result = {
             let nominated_pair = self.nominated_pair.lock().await;
             if let Some(pair) = &*nominated_pair {
@@ -113,21 +120,21 @@ impl AgentInternal {
 
                 let (msg, result) = {
                     let ufrag_pwd = self.ufrag_pwd.lock().await;
-                    let username =
-                        ufrag_pwd.remote_ufrag.clone() + ":" + ufrag_pwd.local_ufrag.as_str();
-                    let mut msg = Message::new();
-                    let result = msg.build(&[
+                    let mut setters: Vec<Box<dyn Setter>> = vec![
                         Box::new(BINDING_REQUEST),
                         Box::new(TransactionId::new()),
-                        Box::new(Username::new(ATTR_USERNAME, username)),
+                        Box::new(self.request_username(&ufrag_pwd)),
                         Box::<UseCandidateAttr>::default(),
                         Box::new(AttrControlling(self.tie_breaker.load(Ordering::SeqCst))),
                         Box::new(PriorityAttr(pair.local.priority())),
-                        Box::new(MessageIntegrity::new_short_term_integrity(
-                            ufrag_pwd.remote_pwd.clone(),
-                        )),
-                        Box::new(FINGERPRINT),
-                    ]);
+                    ];
+                    self.push_software(&mut setters);
+                    self.push_long_term_attributes(&mut setters);
+                    setters.push(Box::new(self.request_integrity(&ufrag_pwd)));
+                    setters.push(Box::new(FINGERPRINT));
+
+                    let mut msg = Message::new();
+                    let result = msg.build(&setters);
                     (msg, result)
                 };
 
@@ -150,8 +157,15 @@ impl AgentInternal {
         };
 
         if let Some((msg, local, remote)) = result {
-            self.send_binding_request(&msg, &local, &remote).await;
+            if let Err(err) = self.send_binding_request(&msg, &local, &remote).await {
+                let mut nominated_pair = self.nominated_pair.lock().await;
+                if let Some(pair) = nominated_pair.take() {
+                    pair.nominated.store(false, Ordering::SeqCst);
+                }
+                return Err(err);
+            }
         }
+        Ok(())
     }
 
     pub(crate) async fn start(&self) {
@@ -175,6 +189,16 @@ impl AgentInternal {
         local: &Arc<dyn Candidate + Send + Sync>,
         remote: &Arc<dyn Candidate + Send + Sync>,
     ) {
+        if Self::is_unconnected_tcp(local) {
+            log::trace!(
+                "[{}]: not pinging {} from {} until its TCP connection is up",
+                self.get_name(),
+                remote,
+                local
+            );
+            return;
+        }
+
         if self.is_controlling.load(Ordering::SeqCst) {
             ControllingSelector::ping_candidate(self, local, remote).await;
         } else {
@@ -233,12 +257,18 @@ impl ControllingSelector for AgentInternal {
         };
 
         if self.agent_conn.get_selected_pair().is_some() {
-            if self.validate_selected_pair().await {
+            if self.validate_selected_pair().await && self.check_consent().await {
                 log::trace!("[{}]: checking keepalive", self.get_name());
                 self.check_keepalive().await;
+                self.check_better_pairs().await;
             }
         } else if nominated_pair_is_some {
-            self.nominate_pair().await;
+            if let Err(err) = self.nominate_pair().await {
+                log::warn!("[{}]: failed to nominate pair: {}", self.get_name(), err);
+            }
+        } else if self.nomination_mode == NominationMode::Aggressive {
+            // Every check already nominates its pair, the first one to succeed is selected
+            self.ping_all_candidates().await;
         } else {
             let has_nominated_pair =
                 if let Some(p) = self.agent_conn.get_best_valid_candidate_pair().await {
@@ -261,7 +291,9 @@ impl ControllingSelector for AgentInternal {
                     }
                 }
 
-                self.nominate_pair().await;
+                if let Err(err) = self.nominate_pair().await {
+                    log::warn!("[{}]: failed to nominate pair: {}", self.get_name(), err);
+                }
             } else {
                 self.ping_all_candidates().await;
             }
@@ -275,26 +307,32 @@ impl ControllingSelector for AgentInternal {
     ) {
         let (msg, result) = {
             let ufrag_pwd = self.ufrag_pwd.lock().await;
-            let username = ufrag_pwd.remote_ufrag.clone() + ":" + ufrag_pwd.local_ufrag.as_str();
-            let mut msg = Message::new();
-            let result = msg.build(&[
+            let mut setters: Vec<Box<dyn Setter>> = vec![
                 Box::new(BINDING_REQUEST),
                 Box::new(TransactionId::new()),
-                Box::new(Username::new(ATTR_USERNAME, username)),
-                Box::new(AttrControlling(self.tie_breaker.load(Ordering::SeqCst))),
-                Box::new(PriorityAttr(local.priority())),
-                Box::new(MessageIntegrity::new_short_term_integrity(
-                    ufrag_pwd.remote_pwd.clone(),
-                )),
-                Box::new(FINGERPRINT),
-            ]);
+                Box::new(self.request_username(&ufrag_pwd)),
+            ];
+            if self.nomination_mode == NominationMode::Aggressive {
+                setters.push(Box::<UseCandidateAttr>::default());
+            }
+            setters.push(Box::new(AttrControlling(
+                self.tie_breaker.load(Ordering::SeqCst),
+            )));
+            setters.push(Box::new(PriorityAttr(local.priority())));
+            self.push_software(&mut setters);
+            self.push_long_term_attributes(&mut setters);
+            setters.push(Box::new(self.request_integrity(&ufrag_pwd)));
+            setters.push(Box::new(FINGERPRINT));
+
+            let mut msg = Message::new();
+            let result = msg.build(&setters);
             (msg, result)
         };
 
         if let Err(err) = result {
             log::error!("{}", err);
         } else {
-            self.send_binding_request(&msg, local, remote).await;
+            let _ = self.send_binding_request(&msg, local, remote).await;
         }
     }
 
@@ -312,6 +350,7 @@ impl ControllingSelector for AgentInternal {
             // https://tools.ietf.org/html/rfc8445#section-7.2.5.2.1
             if transaction_addr != remote_addr {
                 log::debug!("discard message: transaction source and destination does not match expected({}), actual({})", transaction_addr, remote);
+                self.discards.nat_mismatch.fetch_add(1, Ordering::SeqCst);
                 return;
             }
 
@@ -323,8 +362,17 @@ impl ControllingSelector for AgentInternal {
             let selected_pair_is_none = self.agent_conn.get_selected_pair().is_none();
 
             if let Some(p) = self.find_pair(local, remote).await {
-                p.state
-                    .store(CandidatePairState::Succeeded as u8, Ordering::SeqCst);
+                p.record_response(pending_request.round_trip_time());
+                p.transition_state(CandidatePairState::Succeeded);
+                self.agent_conn.invalidate_best_valid_candidate_pair();
+                self.unfreeze_foundation(&p).await;
+                self.add_peer_reflexive_local_candidate(m, local, remote, &pending_request)
+                    .await;
+                if let Some(selected_pair) = self.agent_conn.get_selected_pair() {
+                    if Arc::ptr_eq(&selected_pair, &p) {
+                        self.grant_consent();
+                    }
+                }
                 log::trace!(
                     "Found valid candidate pair: {}, p.state: {}, isUseCandidate: {}, {}",
                     p,
@@ -345,6 +393,9 @@ impl ControllingSelector for AgentInternal {
                 remote,
                 m.transaction_id
             );
+            self.discards
+                .unknown_transaction
+                .fetch_add(1, Ordering::SeqCst);
         }
     }
 
@@ -354,10 +405,44 @@ impl ControllingSelector for AgentInternal {
         local: &Arc<dyn Candidate + Send + Sync>,
         remote: &Arc<dyn Candidate + Send + Sync>,
     ) {
+        // https://tools.ietf.org/html/rfc8445#section-7.3.1.1
+        // Both agents believe they are controlling, the one with the larger
+        // tie-breaker keeps the role.
+        if m.contains(ATTR_ICE_CONTROLLING) {
+            let mut remote_controlling = AttrControlling::default();
+            if let Err(err) = remote_controlling.get_from(m) {
+                log::warn!("[{}]: invalid ICE-CONTROLLING: {}", self.get_name(), err);
+                return;
+            }
+
+            if self.tie_breaker.load(Ordering::SeqCst) >= remote_controlling.0 {
+                log::debug!(
+                    "[{}]: role conflict, keeping the controlling role",
+                    self.get_name()
+                );
+                self.send_role_conflict_error(m, local, remote).await;
+            } else {
+                log::debug!(
+                    "[{}]: role conflict, switching to the controlled role",
+                    self.get_name()
+                );
+                self.set_controlling(false).await;
+                {
+                    let mut nominated_pair = self.nominated_pair.lock().await;
+                    *nominated_pair = None;
+                }
+                ControlledSelector::handle_binding_request(self, m, local, remote).await;
+            }
+            return;
+        }
+
         self.send_binding_success(m, local, remote).await;
         log::trace!("controllingSelector: sendBindingSuccess");
 
         if let Some(p) = self.find_pair(local, remote).await {
+            // The peer is checking the pair, so it can't stay frozen (Section 7.3.1.4)
+            Self::unfreeze_pair(&p);
+
             let nominated_pair_is_none = {
                 let nominated_pair = self.nominated_pair.lock().await;
                 nominated_pair.is_none()
@@ -389,7 +474,9 @@ impl ControllingSelector for AgentInternal {
                             let mut nominated_pair = self.nominated_pair.lock().await;
                             *nominated_pair = Some(p);
                         }
-                        self.nominate_pair().await;
+                        if let Err(err) = self.nominate_pair().await {
+                            log::warn!("[{}]: failed to nominate pair: {}", self.get_name(), err);
+                        }
                     }
                 } else {
                     log::trace!("No best pair available");
@@ -411,9 +498,10 @@ impl ControlledSelector for AgentInternal {
         if self.lite.load(Ordering::SeqCst) {
             self.validate_selected_pair().await;
         } else if self.agent_conn.get_selected_pair().is_some() {
-            if self.validate_selected_pair().await {
+            if self.validate_selected_pair().await && self.check_consent().await {
                 log::trace!("[{}]: checking keepalive", self.get_name());
                 self.check_keepalive().await;
+                self.check_better_pairs().await;
             }
         } else {
             self.ping_all_candidates().await;
@@ -427,26 +515,27 @@ impl ControlledSelector for AgentInternal {
     ) {
         let (msg, result) = {
             let ufrag_pwd = self.ufrag_pwd.lock().await;
-            let username = ufrag_pwd.remote_ufrag.clone() + ":" + ufrag_pwd.local_ufrag.as_str();
-            let mut msg = Message::new();
-            let result = msg.build(&[
+            let mut setters: Vec<Box<dyn Setter>> = vec![
                 Box::new(BINDING_REQUEST),
                 Box::new(TransactionId::new()),
-                Box::new(Username::new(ATTR_USERNAME, username)),
+                Box::new(self.request_username(&ufrag_pwd)),
                 Box::new(AttrControlled(self.tie_breaker.load(Ordering::SeqCst))),
                 Box::new(PriorityAttr(local.priority())),
-                Box::new(MessageIntegrity::new_short_term_integrity(
-                    ufrag_pwd.remote_pwd.clone(),
-                )),
-                Box::new(FINGERPRINT),
-            ]);
+            ];
+            self.push_software(&mut setters);
+            self.push_long_term_attributes(&mut setters);
+            setters.push(Box::new(self.request_integrity(&ufrag_pwd)));
+            setters.push(Box::new(FINGERPRINT));
+
+            let mut msg = Message::new();
+            let result = msg.build(&setters);
             (msg, result)
         };
 
         if let Err(err) = result {
             log::error!("{}", err);
         } else {
-            self.send_binding_request(&msg, local, remote).await;
+            let _ = self.send_binding_request(&msg, local, remote).await;
         }
     }
 
@@ -470,6 +559,7 @@ impl ControlledSelector for AgentInternal {
             // https://tools.ietf.org/html/rfc8445#section-7.2.5.2.1
             if transaction_addr != remote_addr {
                 log::debug!("discard message: transaction source and destination does not match expected({}), actual({})", transaction_addr, remote);
+                self.discards.nat_mismatch.fetch_add(1, Ordering::SeqCst);
                 return;
             }
 
@@ -480,8 +570,17 @@ impl ControlledSelector for AgentInternal {
             );
 
             if let Some(p) = self.find_pair(local, remote).await {
-                p.state
-                    .store(CandidatePairState::Succeeded as u8, Ordering::SeqCst);
+                p.record_response(pending_request.round_trip_time());
+                p.transition_state(CandidatePairState::Succeeded);
+                self.agent_conn.invalidate_best_valid_candidate_pair();
+                self.unfreeze_foundation(&p).await;
+                self.add_peer_reflexive_local_candidate(m, local, remote, &pending_request)
+                    .await;
+                if let Some(selected_pair) = self.agent_conn.get_selected_pair() {
+                    if Arc::ptr_eq(&selected_pair, &p) {
+                        self.grant_consent();
+                    }
+                }
                 log::trace!("Found valid candidate pair: {}", p);
             } else {
                 // This shouldn't happen
@@ -493,6 +592,9 @@ impl ControlledSelector for AgentInternal {
                 remote,
                 m.transaction_id
             );
+            self.discards
+                .unknown_transaction
+                .fetch_add(1, Ordering::SeqCst);
         }
     }
 
@@ -502,12 +604,53 @@ impl ControlledSelector for AgentInternal {
         local: &Arc<dyn Candidate + Send + Sync>,
         remote: &Arc<dyn Candidate + Send + Sync>,
     ) {
+        // https://tools.ietf.org/html/rfc8445#section-7.3.1.1
+        // Both agents believe they are controlled, the one with the larger
+        // tie-breaker takes the controlling role.
+        if m.contains(ATTR_ICE_CONTROLLED) {
+            let mut remote_controlled = AttrControlled::default();
+            if let Err(err) = remote_controlled.get_from(m) {
+                log::warn!("[{}]: invalid ICE-CONTROLLED: {}", self.get_name(), err);
+                return;
+            }
+
+            if self.tie_breaker.load(Ordering::SeqCst) >= remote_controlled.0 {
+                log::debug!(
+                    "[{}]: role conflict, switching to the controlling role",
+                    self.get_name()
+                );
+                self.set_controlling(true).await;
+                ControllingSelector::start(self).await;
+                ControllingSelector::handle_binding_request(self, m, local, remote).await;
+            } else {
+                log::debug!(
+                    "[{}]: role conflict, keeping the controlled role",
+                    self.get_name()
+                );
+                self.send_role_conflict_error(m, local, remote).await;
+            }
+            return;
+        }
+
         if self.find_pair(local, remote).await.is_none() {
             self.add_pair(local.clone(), remote.clone()).await;
         }
 
         if let Some(p) = self.find_pair(local, remote).await {
-            let use_candidate = m.contains(ATTR_USE_CANDIDATE);
+            // A triggered check is sent on the pair, so it can't stay frozen (Section 7.3.1.4)
+            Self::unfreeze_pair(&p);
+
+            // Only the controlling agent nominates, USE-CANDIDATE in a request that
+            // doesn't carry ICE-CONTROLLING is ignored and answered as a plain check
+            let mut use_candidate = m.contains(ATTR_USE_CANDIDATE);
+            if use_candidate && !m.contains(ATTR_ICE_CONTROLLING) {
+                log::debug!(
+                    "[{}]: ignoring USE-CANDIDATE from {} without ICE-CONTROLLING",
+                    self.get_name(),
+                    remote
+                );
+                use_candidate = false;
+            }
             if use_candidate {
                 // https://tools.ietf.org/html/rfc8445#section-7.3.1.5
 
@@ -2,7 +2,7 @@ use crate::candidate::{CandidatePairState, CandidateType};
 
 use crate::agent::agent_internal::AgentInternal;
 use crate::network_type::NetworkType;
-use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicU64, Ordering};
 use tokio::time::Instant;
 
 /// Contains ICE candidate pair statistics.
@@ -23,6 +23,10 @@ pub struct CandidatePairStats {
     /// if it is the highest-priority one amongst those whose nominated flag is set.
     pub nominated: bool,
 
+    /// The priority of the pair as computed for the current role of the agent (RFC 8445 Section
+    /// 6.1.2.3).
+    pub priority: u64,
+
     /// The total number of packets sent on this candidate pair.
     pub packets_sent: u32,
 
@@ -66,6 +70,11 @@ pub struct CandidatePairStats {
     /// including those that are sent for consent verification.
     pub current_round_trip_time: f64,
 
+    /// The smoothed round trip time in seconds, an exponentially weighted moving average of the
+    /// round trip times measured. Responses to retransmitted requests aren't measured, as they
+    /// can't be matched to the transmission they answer.
+    pub smoothed_round_trip_time: f64,
+
     /// It is calculated by the underlying congestion control by combining the available bitrate for
     /// all the outgoing RTP streams using this candidate pair. The bitrate measurement does not
     /// count the size of the IP or other transport layers like TCP or UDP. It is similar to the
@@ -120,6 +129,7 @@ impl Default for CandidatePairStats {
             remote_candidate_id: String::new(),
             state: CandidatePairState::default(),
             nominated: false,
+            priority: 0,
             packets_sent: 0,
             packets_received: 0,
             bytes_sent: 0,
@@ -131,6 +141,7 @@ impl Default for CandidatePairStats {
             last_response_timestamp: Instant::now(),
             total_round_trip_time: 0.0,
             current_round_trip_time: 0.0,
+            smoothed_round_trip_time: 0.0,
             available_outgoing_bitrate: 0.0,
             available_incoming_bitrate: 0.0,
             circuit_breaker_trigger_count: 0,
@@ -211,18 +222,71 @@ impl Default for CandidateStats {
     }
 }
 
+/// Counts the inbound STUN messages the agent discarded, by reason.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DiscardStats {
+    /// Success responses that match no pending binding request.
+    pub unknown_transaction: u64,
+
+    /// Success responses that arrived from another address than their request was sent to, which
+    /// is a sign of a symmetric NAT.
+    pub nat_mismatch: u64,
+
+    /// Messages that failed the short-term credential check, i.e. whose USERNAME or
+    /// MESSAGE-INTEGRITY is wrong.
+    pub integrity_failure: u64,
+
+    /// Messages whose FINGERPRINT doesn't match their contents.
+    pub fingerprint_failure: u64,
+
+    /// Messages at odds with the role of the agent, including the requests rejected with a 487
+    /// (Role Conflict) error response.
+    pub role_conflict: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct DiscardCounters {
+    pub(crate) unknown_transaction: AtomicU64,
+    pub(crate) nat_mismatch: AtomicU64,
+    pub(crate) integrity_failure: AtomicU64,
+    pub(crate) fingerprint_failure: AtomicU64,
+    pub(crate) role_conflict: AtomicU64,
+}
+
+impl DiscardCounters {
+    pub(crate) fn snapshot(&self) -> DiscardStats {
+        DiscardStats {
+            unknown_transaction: self.unknown_transaction.load(Ordering::SeqCst),
+            nat_mismatch: self.nat_mismatch.load(Ordering::SeqCst),
+            integrity_failure: self.integrity_failure.load(Ordering::SeqCst),
+            fingerprint_failure: self.fingerprint_failure.load(Ordering::SeqCst),
+            role_conflict: self.role_conflict.load(Ordering::SeqCst),
+        }
+    }
+}
+
 impl AgentInternal {
     /// Returns a list of candidate pair stats.
     pub(crate) async fn get_candidate_pairs_stats(&self) -> Vec<CandidatePairStats> {
         let checklist = self.agent_conn.checklist.lock().await;
         let mut res = Vec::with_capacity(checklist.len());
         for cp in &*checklist {
+            let round_trip_time = *cp.round_trip_time.lock();
             let stat = CandidatePairStats {
                 timestamp: Instant::now(),
                 local_candidate_id: cp.local.id(),
                 remote_candidate_id: cp.remote.id(),
                 state: cp.state.load(Ordering::SeqCst).into(),
                 nominated: cp.nominated.load(Ordering::SeqCst),
+                priority: cp.priority(),
+                total_round_trip_time: round_trip_time.total.as_secs_f64(),
+                current_round_trip_time: round_trip_time
+                    .current
+                    .map_or(0.0, |rtt| rtt.as_secs_f64()),
+                smoothed_round_trip_time: round_trip_time
+                    .smoothed
+                    .map_or(0.0, |rtt| rtt.as_secs_f64()),
+                responses_received: round_trip_time.responses_received,
                 ..CandidatePairStats::default()
             };
             res.push(stat);
@@ -5,17 +5,21 @@ use crate::candidate::candidate_host::*;
 use crate::candidate::candidate_peer_reflexive::*;
 use crate::candidate::candidate_relay::*;
 use crate::candidate::candidate_server_reflexive::*;
-use crate::control::AttrControlling;
+use crate::control::{AttrControlled, AttrControlling};
 use crate::priority::PriorityAttr;
 use crate::use_candidate::UseCandidateAttr;
 
 use crate::agent::agent_transport_test::pipe;
 use async_trait::async_trait;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 use std::net::Ipv4Addr;
 use std::ops::Sub;
 use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+use stun::error_code::*;
 use stun::message::*;
-use stun::textattrs::Username;
+use stun::textattrs::{Nonce, Realm, TextAttribute, Username};
 use util::{vnet::*, Conn};
 use waitgroup::{WaitGroup, Worker};
 
@@ -323,6 +327,125 @@ async fn test_handle_peer_reflexive_udp_pflx_candidate() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_peer_reflexive_remote_candidate_priority() -> Result<()> {
+    let a = Agent::new(AgentConfig::default()).await?;
+    a.internal
+        .set_remote_credentials("remoteufrag".to_owned(), "remotepwd".to_owned())
+        .await?;
+
+    let (_, local) = new_recording_candidate()?;
+    let remote = SocketAddr::from_str("172.17.0.9:999")?;
+    assert!(a
+        .internal
+        .find_remote_candidate(local.network_type(), remote)
+        .await
+        .is_none());
+
+    let (username, local_pwd) = {
+        let ufrag_pwd = a.internal.ufrag_pwd.lock().await;
+        (
+            ufrag_pwd.local_ufrag.to_owned() + ":" + ufrag_pwd.remote_ufrag.as_str(),
+            ufrag_pwd.local_pwd.clone(),
+        )
+    };
+    let priority = 0x6e00_1eff;
+    let mut msg = Message::new();
+    msg.build(&[
+        Box::new(BINDING_REQUEST),
+        Box::new(TransactionId::new()),
+        Box::new(Username::new(ATTR_USERNAME, username)),
+        Box::new(AttrControlling(
+            a.internal.tie_breaker.load(Ordering::SeqCst) + 1,
+        )),
+        Box::new(PriorityAttr(priority)),
+        Box::new(MessageIntegrity::new_short_term_integrity(local_pwd)),
+        Box::new(FINGERPRINT),
+    ])?;
+    a.internal.handle_inbound(&mut msg, &local, remote).await;
+
+    let prflx = a
+        .internal
+        .find_remote_candidate(local.network_type(), remote)
+        .await
+        .expect("should create a peer-reflexive candidate");
+    assert_eq!(CandidateType::PeerReflexive, prflx.candidate_type());
+    assert_eq!(
+        priority,
+        prflx.priority(),
+        "should take the request's PRIORITY"
+    );
+    assert!(
+        a.internal.find_pair(&local, &prflx).await.is_some(),
+        "should pair the candidate with the local candidate"
+    );
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_inbound_fingerprint_validation() -> Result<()> {
+    let a = Agent::new(AgentConfig::default()).await?;
+    a.internal
+        .set_remote_credentials("remoteufrag".to_owned(), "remotepwd".to_owned())
+        .await?;
+
+    let (_, local) = new_recording_candidate()?;
+    let (username, local_pwd) = {
+        let ufrag_pwd = a.internal.ufrag_pwd.lock().await;
+        (
+            ufrag_pwd.local_ufrag.to_owned() + ":" + ufrag_pwd.remote_ufrag.as_str(),
+            ufrag_pwd.local_pwd.clone(),
+        )
+    };
+    let build_request = || -> Result<Message> {
+        let mut msg = Message::new();
+        msg.build(&[
+            Box::new(BINDING_REQUEST),
+            Box::new(TransactionId::new()),
+            Box::new(Username::new(ATTR_USERNAME, username.clone())),
+            Box::new(AttrControlling(
+                a.internal.tie_breaker.load(Ordering::SeqCst) + 1,
+            )),
+            Box::new(PriorityAttr(local.priority())),
+            Box::new(MessageIntegrity::new_short_term_integrity(
+                local_pwd.clone(),
+            )),
+            Box::new(FINGERPRINT),
+        ])?;
+        Ok(msg)
+    };
+
+    let corrupted = SocketAddr::from_str("172.17.0.10:999")?;
+    let mut msg = build_request()?;
+    let last = msg.raw.len() - 1;
+    msg.raw[last] ^= 0xff;
+    msg.decode()?;
+    a.internal.handle_inbound(&mut msg, &local, corrupted).await;
+    assert!(
+        a.internal
+            .find_remote_candidate(local.network_type(), corrupted)
+            .await
+            .is_none(),
+        "should drop a request whose fingerprint fails"
+    );
+
+    let valid = SocketAddr::from_str("172.17.0.11:999")?;
+    let mut msg = build_request()?;
+    a.internal.handle_inbound(&mut msg, &local, valid).await;
+    assert!(
+        a.internal
+            .find_remote_candidate(local.network_type(), valid)
+            .await
+            .is_some(),
+        "should process a request with a valid fingerprint"
+    );
+
+    a.close().await?;
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_handle_peer_reflexive_unknown_remote() -> Result<()> {
     let a = Agent::new(AgentConfig::default()).await?;
@@ -338,6 +461,7 @@ async fn test_handle_peer_reflexive_unknown_remote() -> Result<()> {
                 transaction_id: tid,
                 destination: SocketAddr::from_str("0.0.0.0:0")?,
                 is_use_candidate: false,
+                ..Default::default()
             }];
         }
         let ufrag_pwd = a.internal.ufrag_pwd.lock().await;
@@ -622,6 +746,7 @@ fn build_msg(c: MessageClass, username: String, key: String) -> Result<Message>
         Box::new(MessageType::new(METHOD_BINDING, c)),
         Box::new(TransactionId::new()),
         Box::new(Username::new(ATTR_USERNAME, username)),
+        Box::new(PriorityAttr(1)),
         Box::new(MessageIntegrity::new_short_term_integrity(key)),
         Box::new(FINGERPRINT),
     ])?;
@@ -814,6 +939,7 @@ async fn test_inbound_validity() -> Result<()> {
                 Box::new(BINDING_REQUEST),
                 Box::new(TransactionId::new()),
                 Box::new(Username::new(ATTR_USERNAME, username)),
+                Box::new(PriorityAttr(1)),
                 Box::new(MessageIntegrity::new_short_term_integrity(local_pwd)),
             ])?;
 
@@ -1589,6 +1715,48 @@ async fn test_binding_request_timeout() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_binding_request_timeout_covers_retransmits() -> Result<()> {
+    let a = Agent::new(AgentConfig::default()).await?;
+    assert_eq!(
+        MAX_BINDING_REQUEST_TIMEOUT,
+        a.internal.binding_request_timeout(),
+        "default retransmissions should fit in the default timeout"
+    );
+    a.close().await?;
+
+    // Retransmissions at 0.5, 1.5, 3.5, 7.5 and 15.5 seconds, the last one is answered by 16
+    let a = Agent::new(AgentConfig {
+        binding_request_rto: Some(Duration::from_millis(500)),
+        max_binding_request_retransmits: Some(5),
+        ..Default::default()
+    })
+    .await?;
+    assert_eq!(
+        Duration::from_secs(16),
+        a.internal.binding_request_timeout()
+    );
+
+    let now = Instant::now();
+    {
+        let mut pending_binding_requests = a.internal.pending_binding_requests.lock().await;
+        pending_binding_requests.push(BindingRequest {
+            timestamp: now.sub(Duration::from_secs(10)), // still retransmitting
+            ..Default::default()
+        });
+        pending_binding_requests.push(BindingRequest {
+            timestamp: now.sub(Duration::from_secs(17)), // given up on
+            ..Default::default()
+        });
+    }
+    a.internal.invalidate_pending_binding_requests(now).await;
+    assert_eq!(1, a.internal.pending_binding_requests.lock().await.len());
+
+    a.close().await?;
+
+    Ok(())
+}
+
 // test_agent_credentials checks if local username fragments and passwords (if set) meet RFC standard
 // and ensure it's backwards compatible with previous versions of the pion/ice
 #[tokio::test]
@@ -2201,3 +2369,2469 @@ async fn test_lite_lifecycle() -> Result<()> {
 
     Ok(())
 }
+
+/// Conn that keeps every datagram written to it, so tests can inspect what the agent sent.
+#[derive(Default)]
+struct RecordingConn {
+    sent: Mutex<Vec<Vec<u8>>>,
+    // Set to stand in for a connected TCP candidate
+    remote: Option<SocketAddr>,
+    // Set to make every write fail, as a broken socket does
+    fail_writes: AtomicBool,
+}
+
+impl RecordingConn {
+    async fn sent_messages(&self) -> Result<Vec<Message>> {
+        let sent = self.sent.lock().await;
+        let mut messages = vec![];
+        for raw in sent.iter() {
+            let mut m = Message::new();
+            m.raw = raw.clone();
+            m.decode()?;
+            messages.push(m);
+        }
+        Ok(messages)
+    }
+}
+
+#[async_trait]
+impl Conn for RecordingConn {
+    async fn connect(&self, _addr: SocketAddr) -> std::result::Result<(), util::Error> {
+        Ok(())
+    }
+
+    async fn recv(&self, _buf: &mut [u8]) -> std::result::Result<usize, util::Error> {
+        Ok(0)
+    }
+
+    async fn recv_from(
+        &self,
+        _buf: &mut [u8],
+    ) -> std::result::Result<(usize, SocketAddr), util::Error> {
+        Ok((0, SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), 0)))
+    }
+
+    async fn send(&self, buf: &[u8]) -> std::result::Result<usize, util::Error> {
+        if self.fail_writes.load(Ordering::SeqCst) {
+            return Err(util::Error::Other("write failed".to_owned()));
+        }
+        self.sent.lock().await.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    async fn send_to(
+        &self,
+        buf: &[u8],
+        _target: SocketAddr,
+    ) -> std::result::Result<usize, util::Error> {
+        if self.fail_writes.load(Ordering::SeqCst) {
+            return Err(util::Error::Other("write failed".to_owned()));
+        }
+        self.sent.lock().await.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn local_addr(&self) -> std::result::Result<SocketAddr, util::Error> {
+        Ok(SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), 0))
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote
+    }
+
+    async fn close(&self) -> std::result::Result<(), util::Error> {
+        Ok(())
+    }
+}
+
+fn new_recording_candidate() -> Result<(Arc<RecordingConn>, Arc<dyn Candidate + Send + Sync>)> {
+    let conn = Arc::new(RecordingConn::default());
+    let local: Arc<dyn Candidate + Send + Sync> = Arc::new(
+        CandidateHostConfig {
+            base_config: CandidateBaseConfig {
+                network: "udp".to_owned(),
+                address: "192.168.0.2".to_owned(),
+                port: 777,
+                component: 1,
+                conn: Some(Arc::clone(&conn) as Arc<dyn Conn + Send + Sync>),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+        .new_candidate_host()?,
+    );
+    Ok((conn, local))
+}
+
+fn new_remote_candidate() -> Result<Arc<dyn Candidate + Send + Sync>> {
+    Ok(Arc::new(
+        CandidateHostConfig {
+            base_config: CandidateBaseConfig {
+                network: "udp".to_owned(),
+                address: "172.17.0.3".to_owned(),
+                port: 999,
+                component: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+        .new_candidate_host()?,
+    ))
+}
+
+async fn build_binding_request(a: &Agent, role: Box<dyn Setter>) -> Result<Message> {
+    let (username, local_pwd) = {
+        let ufrag_pwd = a.internal.ufrag_pwd.lock().await;
+        (
+            ufrag_pwd.local_ufrag.to_owned() + ":" + ufrag_pwd.remote_ufrag.as_str(),
+            ufrag_pwd.local_pwd.clone(),
+        )
+    };
+
+    let mut msg = Message::new();
+    msg.build(&[
+        Box::new(BINDING_REQUEST),
+        Box::new(TransactionId::new()),
+        Box::new(Username::new(ATTR_USERNAME, username)),
+        role,
+        Box::new(PriorityAttr(1)),
+        Box::new(MessageIntegrity::new_short_term_integrity(local_pwd)),
+        Box::new(FINGERPRINT),
+    ])?;
+    Ok(msg)
+}
+
+#[tokio::test]
+async fn test_role_conflict_controlling_wins() -> Result<()> {
+    let a = Agent::new(AgentConfig::default()).await?;
+    a.internal.is_controlling.store(true, Ordering::SeqCst);
+    a.internal.tie_breaker.store(200, Ordering::SeqCst);
+
+    let (conn, local) = new_recording_candidate()?;
+    let remote = SocketAddr::from_str("172.17.0.3:999")?;
+
+    let mut msg = build_binding_request(&a, Box::new(AttrControlling(100))).await?;
+    a.internal.handle_inbound(&mut msg, &local, remote).await;
+
+    assert!(
+        a.internal.is_controlling.load(Ordering::SeqCst),
+        "agent with the larger tie-breaker should stay controlling"
+    );
+
+    let sent = conn.sent_messages().await?;
+    assert_eq!(1, sent.len(), "should only answer with an error");
+    assert_eq!(
+        MessageType::new(METHOD_BINDING, CLASS_ERROR_RESPONSE),
+        sent[0].typ
+    );
+    assert_eq!(msg.transaction_id, sent[0].transaction_id);
+
+    let mut error_code = ErrorCodeAttribute::default();
+    error_code.get_from(&sent[0])?;
+    assert!(CODE_ROLE_CONFLICT == error_code.code);
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_role_conflict_controlling_loses() -> Result<()> {
+    let a = Agent::new(AgentConfig::default()).await?;
+    a.internal.is_controlling.store(true, Ordering::SeqCst);
+    a.internal.tie_breaker.store(100, Ordering::SeqCst);
+
+    let (conn, local) = new_recording_candidate()?;
+    let remote = SocketAddr::from_str("172.17.0.3:999")?;
+
+    let mut msg = build_binding_request(&a, Box::new(AttrControlling(200))).await?;
+    a.internal.handle_inbound(&mut msg, &local, remote).await;
+
+    assert!(
+        !a.internal.is_controlling.load(Ordering::SeqCst),
+        "agent with the smaller tie-breaker should switch to controlled"
+    );
+
+    let sent = conn.sent_messages().await?;
+    assert!(!sent.is_empty(), "should answer the request");
+    assert_eq!(BINDING_SUCCESS, sent[0].typ);
+    assert!(
+        sent.iter()
+            .filter(|m| m.typ == BINDING_REQUEST)
+            .all(|m| m.contains(ATTR_ICE_CONTROLLED)),
+        "checks should be sent in the controlled role"
+    );
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_role_conflict_controlled_loses() -> Result<()> {
+    let a = Agent::new(AgentConfig::default()).await?;
+    a.internal.is_controlling.store(false, Ordering::SeqCst);
+    a.internal.tie_breaker.store(100, Ordering::SeqCst);
+
+    let (conn, local) = new_recording_candidate()?;
+    let remote = SocketAddr::from_str("172.17.0.3:999")?;
+
+    let mut msg = build_binding_request(&a, Box::new(AttrControlled(200))).await?;
+    a.internal.handle_inbound(&mut msg, &local, remote).await;
+
+    assert!(
+        !a.internal.is_controlling.load(Ordering::SeqCst),
+        "agent with the smaller tie-breaker should stay controlled"
+    );
+
+    let sent = conn.sent_messages().await?;
+    assert_eq!(1, sent.len(), "should only answer with an error");
+    assert_eq!(
+        MessageType::new(METHOD_BINDING, CLASS_ERROR_RESPONSE),
+        sent[0].typ
+    );
+    assert_eq!(msg.transaction_id, sent[0].transaction_id);
+
+    let mut error_code = ErrorCodeAttribute::default();
+    error_code.get_from(&sent[0])?;
+    assert!(CODE_ROLE_CONFLICT == error_code.code);
+
+    // the error is authenticated with our password and fingerprinted
+    let local_pwd = {
+        let ufrag_pwd = a.internal.ufrag_pwd.lock().await;
+        ufrag_pwd.local_pwd.clone()
+    };
+    MessageIntegrity::new_short_term_integrity(local_pwd).check(&mut sent[0].clone())?;
+    FINGERPRINT.check(&sent[0])?;
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_role_conflict_controlled_wins() -> Result<()> {
+    let a = Agent::new(AgentConfig::default()).await?;
+    a.internal.is_controlling.store(false, Ordering::SeqCst);
+    a.internal.tie_breaker.store(200, Ordering::SeqCst);
+
+    let (conn, local) = new_recording_candidate()?;
+    let remote = SocketAddr::from_str("172.17.0.3:999")?;
+
+    let mut msg = build_binding_request(&a, Box::new(AttrControlled(100))).await?;
+    a.internal.handle_inbound(&mut msg, &local, remote).await;
+
+    assert!(
+        a.internal.is_controlling.load(Ordering::SeqCst),
+        "agent with the larger tie-breaker should switch to controlling"
+    );
+
+    let sent = conn.sent_messages().await?;
+    assert!(!sent.is_empty(), "should answer the request");
+    assert_eq!(BINDING_SUCCESS, sent[0].typ);
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_binding_request_retransmission() -> Result<()> {
+    let a = Agent::new(AgentConfig {
+        binding_request_rto: Some(Duration::from_millis(10)),
+        max_binding_request_retransmits: Some(2),
+        ..Default::default()
+    })
+    .await?;
+
+    let (conn, local) = new_recording_candidate()?;
+    let remote = new_remote_candidate()?;
+
+    // The mock never answers, so every request is lost
+    let msg = build_binding_request(&a, Box::new(AttrControlling(100))).await?;
+    a.internal
+        .send_binding_request(&msg, &local, &remote)
+        .await?;
+    a.internal.retransmit_pending_binding_requests().await;
+    assert_eq!(
+        1,
+        conn.sent_messages().await?.len(),
+        "RTO has not elapsed yet"
+    );
+
+    tokio::time::sleep(Duration::from_millis(15)).await;
+    a.internal.retransmit_pending_binding_requests().await;
+    assert_eq!(
+        2,
+        conn.sent_messages().await?.len(),
+        "should retransmit after RTO"
+    );
+
+    // The second retransmission waits twice as long
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    a.internal.retransmit_pending_binding_requests().await;
+    assert_eq!(
+        2,
+        conn.sent_messages().await?.len(),
+        "interval should double"
+    );
+
+    tokio::time::sleep(Duration::from_millis(25)).await;
+    a.internal.retransmit_pending_binding_requests().await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    a.internal.retransmit_pending_binding_requests().await;
+
+    let sent = conn.sent_messages().await?;
+    assert_eq!(3, sent.len(), "should stop after max retransmits");
+    assert!(
+        sent.iter()
+            .all(|m| m.transaction_id == msg.transaction_id && m.typ == BINDING_REQUEST),
+        "retransmissions should reuse the transaction"
+    );
+
+    // A matching success cancels any further retransmission
+    let msg = build_binding_request(&a, Box::new(AttrControlling(100))).await?;
+    a.internal
+        .send_binding_request(&msg, &local, &remote)
+        .await?;
+    assert!(a
+        .internal
+        .handle_inbound_binding_success(msg.transaction_id)
+        .await
+        .is_some());
+    tokio::time::sleep(Duration::from_millis(15)).await;
+    a.internal.retransmit_pending_binding_requests().await;
+    assert_eq!(
+        4,
+        conn.sent_messages().await?.len(),
+        "answered request should not be retransmitted"
+    );
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_binding_request_integrity() -> Result<()> {
+    let a = Agent::new(AgentConfig::default()).await?;
+
+    let (conn, local) = new_recording_candidate()?;
+    let remote = SocketAddr::from_str("172.17.0.3:999")?;
+
+    let username = {
+        let ufrag_pwd = a.internal.ufrag_pwd.lock().await;
+        format!("{}:{}", ufrag_pwd.local_ufrag, ufrag_pwd.remote_ufrag)
+    };
+    let mut msg = build_msg(CLASS_REQUEST, username, "wrong key".to_owned())?;
+    a.internal.handle_inbound(&mut msg, &local, remote).await;
+
+    {
+        let checklist = a.internal.agent_conn.checklist.lock().await;
+        assert!(
+            checklist.is_empty(),
+            "request with a bad MESSAGE-INTEGRITY should not create a pair"
+        );
+    }
+    assert!(
+        conn.sent_messages().await?.is_empty(),
+        "request with a bad MESSAGE-INTEGRITY should not be answered"
+    );
+
+    let mut msg = build_binding_request(&a, Box::new(AttrControlling(100))).await?;
+    a.internal.handle_inbound(&mut msg, &local, remote).await;
+
+    {
+        let checklist = a.internal.agent_conn.checklist.lock().await;
+        assert_eq!(1, checklist.len(), "valid request should create a pair");
+    }
+    let sent = conn.sent_messages().await?;
+    assert_eq!(BINDING_SUCCESS, sent[0].typ);
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_regular_nomination_ping() -> Result<()> {
+    let a = Agent::new(AgentConfig {
+        nomination_mode: NominationMode::Regular,
+        ..Default::default()
+    })
+    .await?;
+    a.internal.is_controlling.store(true, Ordering::SeqCst);
+
+    let (conn, local) = new_recording_candidate()?;
+    let remote = new_remote_candidate()?;
+    a.internal.ping_candidate(&local, &remote).await;
+
+    let sent = conn.sent_messages().await?;
+    assert_eq!(1, sent.len());
+    assert!(
+        !sent[0].contains(ATTR_USE_CANDIDATE),
+        "ordinary checks should not nominate in regular mode"
+    );
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_long_term_credentials() -> Result<()> {
+    let a = Agent::new(AgentConfig {
+        credential_mode: CredentialMode::LongTerm {
+            username: "user".to_owned(),
+            realm: "example.org".to_owned(),
+            password: "pass".to_owned(),
+            nonce: "nonce".to_owned(),
+        },
+        ..Default::default()
+    })
+    .await?;
+    a.internal
+        .set_remote_credentials("remoteufrag".to_owned(), "remotepwd".to_owned())
+        .await?;
+
+    let (conn, local) = new_recording_candidate()?;
+    let remote = new_remote_candidate()?;
+    a.internal.ping_candidate(&local, &remote).await;
+
+    let mut sent = conn.sent_messages().await?;
+    assert_eq!(1, sent.len());
+    let msg = &mut sent[0];
+
+    let mut username = Username::new(ATTR_USERNAME, String::new());
+    username.get_from(msg)?;
+    assert_eq!("user", username.to_string());
+    let mut realm = Realm::new(ATTR_REALM, String::new());
+    realm.get_from(msg)?;
+    assert_eq!("example.org", realm.to_string());
+    let mut nonce = Nonce::new(ATTR_NONCE, String::new());
+    nonce.get_from(msg)?;
+    assert_eq!("nonce", nonce.to_string());
+
+    // The key is MD5(username:realm:password) rather than the remote password
+    MessageIntegrity::new_long_term_integrity(
+        "user".to_owned(),
+        "example.org".to_owned(),
+        "pass".to_owned(),
+    )
+    .check(msg)?;
+    assert!(
+        MessageIntegrity::new_short_term_integrity("remotepwd".to_owned())
+            .check(msg)
+            .is_err()
+    );
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_long_term_credentials_connect() -> Result<()> {
+    let new_agent = || {
+        Agent::new(AgentConfig {
+            network_types: supported_network_types(),
+            credential_mode: CredentialMode::LongTerm {
+                username: "user".to_owned(),
+                realm: "example.org".to_owned(),
+                password: "pass".to_owned(),
+                nonce: "nonce".to_owned(),
+            },
+            ..Default::default()
+        })
+    };
+    let a_agent = Arc::new(new_agent().await?);
+    let b_agent = Arc::new(new_agent().await?);
+
+    // Both sides verify the checks and sign the responses with the long-term key
+    connect_with_vnet(&a_agent, &b_agent).await?;
+    assert_eq!(0, a_agent.get_discard_stats().integrity_failure);
+    assert_eq!(0, b_agent.get_discard_stats().integrity_failure);
+
+    a_agent.close().await?;
+    b_agent.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_aggressive_nomination() -> Result<()> {
+    let a = Agent::new(AgentConfig {
+        nomination_mode: NominationMode::Aggressive,
+        ..Default::default()
+    })
+    .await?;
+    a.internal.is_controlling.store(true, Ordering::SeqCst);
+    a.internal
+        .set_remote_credentials("remoteufrag".to_owned(), "remotepwd".to_owned())
+        .await?;
+
+    let (conn, local) = new_recording_candidate()?;
+    let remote = new_remote_candidate()?;
+    a.internal
+        .add_pair(Arc::clone(&local), Arc::clone(&remote))
+        .await;
+    a.internal.add_remote_candidate(&remote).await;
+    a.internal.ping_candidate(&local, &remote).await;
+
+    let sent = conn.sent_messages().await?;
+    assert_eq!(1, sent.len());
+    assert!(
+        sent[0].contains(ATTR_USE_CANDIDATE),
+        "every check should nominate in aggressive mode"
+    );
+
+    let mut resp = Message::new();
+    resp.build(&[
+        Box::new(BINDING_SUCCESS),
+        Box::new(sent[0].transaction_id),
+        Box::new(MessageIntegrity::new_short_term_integrity(
+            "remotepwd".to_owned(),
+        )),
+        Box::new(FINGERPRINT),
+    ])?;
+    a.internal
+        .handle_inbound(&mut resp, &local, remote.addr())
+        .await;
+
+    let selected = a.internal.agent_conn.get_selected_pair();
+    assert!(
+        selected.is_some(),
+        "first succeeding pair should be selected"
+    );
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_consent_expiry() -> Result<()> {
+    let a = Agent::new(AgentConfig {
+        consent_check_interval: Some(Duration::from_millis(10)),
+        consent_timeout: Some(Duration::from_millis(50)),
+        ..Default::default()
+    })
+    .await?;
+    a.internal.is_controlling.store(true, Ordering::SeqCst);
+
+    // The mock stops responding once the pair has been selected
+    let (conn, local) = new_recording_candidate()?;
+    let remote = new_remote_candidate()?;
+    a.internal
+        .add_pair(Arc::clone(&local), Arc::clone(&remote))
+        .await;
+    let p = a
+        .internal
+        .find_pair(&local, &remote)
+        .await
+        .expect("pair should exist");
+    p.state
+        .store(CandidatePairState::Succeeded as u8, Ordering::SeqCst);
+    a.internal.set_selected_pair(Some(Arc::clone(&p))).await;
+
+    tokio::time::sleep(Duration::from_millis(15)).await;
+    assert!(
+        a.internal.check_consent().await,
+        "consent should still hold"
+    );
+    let sent = conn.sent_messages().await?;
+    assert_eq!(1, sent.len(), "should send a consent check");
+    assert_eq!(BINDING_REQUEST, sent[0].typ);
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!a.internal.check_consent().await, "consent should expire");
+    assert!(
+        a.internal.agent_conn.get_selected_pair().is_none(),
+        "selected pair should be cleared once consent expires"
+    );
+    assert_eq!(
+        CandidatePairState::Failed as u8,
+        p.state.load(Ordering::SeqCst)
+    );
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_use_candidate_without_controlling_is_ignored() -> Result<()> {
+    let a = Agent::new(AgentConfig::default()).await?;
+    assert!(!a.internal.is_controlling.load(Ordering::SeqCst));
+
+    let (conn, local) = new_recording_candidate()?;
+    let remote = new_remote_candidate()?;
+    a.internal.add_remote_candidate(&remote).await;
+    a.internal
+        .add_pair(Arc::clone(&local), Arc::clone(&remote))
+        .await;
+    let p = a
+        .internal
+        .find_pair(&local, &remote)
+        .await
+        .expect("pair should exist");
+    p.state
+        .store(CandidatePairState::Succeeded as u8, Ordering::SeqCst);
+
+    let mut msg = build_binding_request(&a, Box::<UseCandidateAttr>::default()).await?;
+    a.internal
+        .handle_inbound(&mut msg, &local, remote.addr())
+        .await;
+
+    assert!(
+        a.internal.agent_conn.get_selected_pair().is_none(),
+        "a nomination without ICE-CONTROLLING should not select the pair"
+    );
+    let sent = conn.sent_messages().await?;
+    assert!(
+        sent.iter()
+            .any(|m| m.typ == BINDING_SUCCESS && m.transaction_id == msg.transaction_id),
+        "the request should still be answered"
+    );
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_on_selected_candidate_pair_transition() -> Result<()> {
+    let a = Agent::new(AgentConfig::default()).await?;
+    let (transition_tx, mut transition_rx) =
+        mpsc::unbounded_channel::<(Option<(String, String)>, Option<(String, String)>)>();
+    a.on_selected_candidate_pair_transition(Box::new(move |old, new| {
+        let describe =
+            |p: Option<Arc<CandidatePair>>| p.map(|p| (p.local.to_string(), p.remote.to_string()));
+        let _ = transition_tx.send((describe(old), describe(new)));
+        Box::pin(async move {})
+    }));
+
+    let (_conn, local) = new_recording_candidate()?;
+    let remote = new_remote_candidate()?;
+    a.internal.add_remote_candidate(&remote).await;
+    a.internal
+        .add_pair(Arc::clone(&local), Arc::clone(&remote))
+        .await;
+    let p = a
+        .internal
+        .find_pair(&local, &remote)
+        .await
+        .expect("pair should exist");
+    p.state
+        .store(CandidatePairState::Succeeded as u8, Ordering::SeqCst);
+
+    // The controlling agent nominates the pair, twice
+    let (username, local_pwd) = {
+        let ufrag_pwd = a.internal.ufrag_pwd.lock().await;
+        (
+            ufrag_pwd.local_ufrag.to_owned() + ":" + ufrag_pwd.remote_ufrag.as_str(),
+            ufrag_pwd.local_pwd.clone(),
+        )
+    };
+    for _ in 0..2 {
+        let mut msg = Message::new();
+        msg.build(&[
+            Box::new(BINDING_REQUEST),
+            Box::new(TransactionId::new()),
+            Box::new(Username::new(ATTR_USERNAME, username.clone())),
+            Box::<UseCandidateAttr>::default(),
+            Box::new(AttrControlling(100)),
+            Box::new(PriorityAttr(1)),
+            Box::new(MessageIntegrity::new_short_term_integrity(
+                local_pwd.clone(),
+            )),
+            Box::new(FINGERPRINT),
+        ])?;
+        a.internal
+            .handle_inbound(&mut msg, &local, remote.addr())
+            .await;
+    }
+    a.internal.set_selected_pair(Some(Arc::clone(&p))).await;
+
+    let expected = Some((local.to_string(), remote.to_string()));
+    let (old, new) = transition_rx.recv().await.expect("should notify selection");
+    assert_eq!(None, old);
+    assert_eq!(expected, new);
+
+    a.internal.set_selected_pair(None).await;
+    let (old, new) = transition_rx
+        .recv()
+        .await
+        .expect("should notify deselection");
+    assert_eq!(expected, old);
+    assert_eq!(None, new);
+
+    a.internal.set_selected_pair(None).await;
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert!(
+        transition_rx.try_recv().is_err(),
+        "redundant sets should not notify"
+    );
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_host_acceptance_min_wait() -> Result<()> {
+    let a =
+        Agent::new(AgentConfig::default().with_host_acceptance_min_wait(Duration::from_secs(5)))
+            .await?;
+
+    let (_conn, local) = new_recording_candidate()?;
+    assert!(
+        !a.internal.is_nominatable(&local),
+        "host candidate should not be nominatable before the wait elapses"
+    );
+
+    tokio::time::sleep(Duration::from_millis(4900)).await;
+    assert!(
+        !a.internal.is_nominatable(&local),
+        "host candidate should not be nominatable before the wait elapses"
+    );
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(
+        a.internal.is_nominatable(&local),
+        "host candidate should be nominatable after the wait elapses"
+    );
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_add_unspecified_candidate() -> Result<()> {
+    let a = Agent::new(AgentConfig::default()).await?;
+
+    let c: Arc<dyn Candidate + Send + Sync> = Arc::new(CandidateBase::default());
+    assert_eq!(CandidateType::Unspecified, c.candidate_type());
+
+    assert_eq!(
+        Err(Error::ErrUnknownCandidateType),
+        a.add_remote_candidate(&c),
+        "remote candidate without a type should be rejected"
+    );
+    assert_eq!(
+        Err(Error::ErrUnknownCandidateType),
+        a.internal.add_candidate(&c).await,
+        "local candidate without a type should be rejected"
+    );
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_restart_renominates() -> Result<()> {
+    let a = Agent::new(AgentConfig::default()).await?;
+    a.internal.is_controlling.store(true, Ordering::SeqCst);
+
+    let select_pair = |a: &Agent| {
+        let ai = Arc::clone(&a.internal);
+        async move {
+            ai.set_remote_credentials("remoteufrag".to_owned(), "remotepwd".to_owned())
+                .await?;
+            let (conn, local) = new_recording_candidate()?;
+            let remote = new_remote_candidate()?;
+            ai.add_remote_candidate(&remote).await;
+            ai.add_pair(Arc::clone(&local), Arc::clone(&remote)).await;
+            let p = ai
+                .find_pair(&local, &remote)
+                .await
+                .expect("pair should exist");
+            p.state
+                .store(CandidatePairState::Succeeded as u8, Ordering::SeqCst);
+
+            // Nominates the pair with a USE-CANDIDATE check
+            ai.contact_candidates().await;
+            let sent = conn.sent_messages().await?;
+            let nomination = sent.last().expect("should send a nomination");
+            assert!(nomination.contains(ATTR_USE_CANDIDATE));
+
+            let mut resp = Message::new();
+            resp.build(&[
+                Box::new(BINDING_SUCCESS),
+                Box::new(nomination.transaction_id),
+                Box::new(MessageIntegrity::new_short_term_integrity(
+                    "remotepwd".to_owned(),
+                )),
+                Box::new(FINGERPRINT),
+            ])?;
+            Result::<_>::Ok((local, remote, p, resp))
+        }
+    };
+
+    let (local, remote, p, mut resp) = select_pair(&a).await?;
+    a.internal
+        .handle_inbound(&mut resp, &local, remote.addr())
+        .await;
+    assert!(a.internal.agent_conn.get_selected_pair().is_some());
+
+    a.restart("".to_owned(), "".to_owned()).await?;
+    assert!(a.internal.agent_conn.get_selected_pair().is_none());
+    assert!(a.internal.nominated_pair.lock().await.is_none());
+    assert!(a.internal.agent_conn.checklist.lock().await.is_empty());
+
+    // A late response from before the restart must not select the stale pair
+    let (local, remote, new_p, mut new_resp) = select_pair(&a).await?;
+    a.internal
+        .handle_inbound(&mut resp, &local, remote.addr())
+        .await;
+    assert!(a.internal.agent_conn.get_selected_pair().is_none());
+
+    a.internal
+        .handle_inbound(&mut new_resp, &local, remote.addr())
+        .await;
+    let selected = a
+        .internal
+        .agent_conn
+        .get_selected_pair()
+        .expect("should select the freshly nominated pair");
+    assert!(Arc::ptr_eq(&selected, &new_p));
+    assert!(!Arc::ptr_eq(&selected, &p));
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_success_response_from_unexpected_address() -> Result<()> {
+    let a = Agent::new(AgentConfig::default()).await?;
+    a.internal.is_controlling.store(true, Ordering::SeqCst);
+    a.internal
+        .set_remote_credentials("remoteufrag".to_owned(), "remotepwd".to_owned())
+        .await?;
+
+    let (conn, local) = new_recording_candidate()?;
+    let remote = new_remote_candidate()?;
+    let other: Arc<dyn Candidate + Send + Sync> = Arc::new(
+        CandidateHostConfig {
+            base_config: CandidateBaseConfig {
+                network: "udp".to_owned(),
+                address: "172.17.0.4".to_owned(),
+                port: 999,
+                component: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+        .new_candidate_host()?,
+    );
+    a.internal.add_remote_candidate(&remote).await;
+    a.internal.add_remote_candidate(&other).await;
+    a.internal
+        .add_pair(Arc::clone(&local), Arc::clone(&remote))
+        .await;
+    a.internal.ping_candidate(&local, &remote).await;
+
+    let sent = conn.sent_messages().await?;
+    let mut resp = Message::new();
+    resp.build(&[
+        Box::new(BINDING_SUCCESS),
+        Box::new(sent[0].transaction_id),
+        Box::new(MessageIntegrity::new_short_term_integrity(
+            "remotepwd".to_owned(),
+        )),
+        Box::new(FINGERPRINT),
+    ])?;
+
+    a.internal
+        .handle_inbound(&mut resp, &local, other.addr())
+        .await;
+    assert_eq!(1, a.get_symmetric_nat_discards());
+    assert!(
+        a.internal.pending_binding_requests.lock().await.is_empty(),
+        "transaction should be dropped"
+    );
+
+    // The same transaction can't be matched again from the expected address
+    a.internal
+        .handle_inbound(&mut resp, &local, remote.addr())
+        .await;
+    let p = a
+        .internal
+        .find_pair(&local, &remote)
+        .await
+        .expect("pair should exist");
+    assert_ne!(
+        CandidatePairState::Succeeded as u8,
+        p.state.load(Ordering::SeqCst)
+    );
+    assert_eq!(1, a.get_symmetric_nat_discards());
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_nominate_pair_send_failure() -> Result<()> {
+    let a = Agent::new(AgentConfig::default()).await?;
+    a.internal.is_controlling.store(true, Ordering::SeqCst);
+    a.internal
+        .set_remote_credentials("remoteufrag".to_owned(), "remotepwd".to_owned())
+        .await?;
+
+    let (conn, local) = new_recording_candidate()?;
+    let remote = new_remote_candidate()?;
+    a.internal.add_remote_candidate(&remote).await;
+    a.internal
+        .add_pair(Arc::clone(&local), Arc::clone(&remote))
+        .await;
+    let p = a
+        .internal
+        .find_pair(&local, &remote)
+        .await
+        .expect("pair should exist");
+    p.state
+        .store(CandidatePairState::Succeeded as u8, Ordering::SeqCst);
+
+    conn.fail_writes.store(true, Ordering::SeqCst);
+    a.internal.contact_candidates().await;
+    assert!(
+        a.internal.nominated_pair.lock().await.is_none(),
+        "a nomination that wasn't sent should be taken back"
+    );
+    assert!(!p.nominated.load(Ordering::SeqCst));
+    assert!(a.internal.pending_binding_requests.lock().await.is_empty());
+
+    // The next cycle nominates the pair again
+    conn.fail_writes.store(false, Ordering::SeqCst);
+    a.internal.contact_candidates().await;
+    let nominated_pair = a.internal.nominated_pair.lock().await.clone();
+    assert!(Arc::ptr_eq(
+        &p,
+        &nominated_pair.expect("pair should be nominated")
+    ));
+    assert!(p.nominated.load(Ordering::SeqCst));
+    let sent = conn.sent_messages().await?;
+    assert_eq!(1, sent.len());
+    assert!(sent[0].contains(ATTR_USE_CANDIDATE));
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_peer_reflexive_local_candidate_from_success_response() -> Result<()> {
+    let a = Agent::new(AgentConfig::default()).await?;
+    a.internal.is_controlling.store(true, Ordering::SeqCst);
+    a.internal
+        .set_remote_credentials("remoteufrag".to_owned(), "remotepwd".to_owned())
+        .await?;
+
+    let (conn, local) = new_recording_candidate()?;
+    let remote = new_remote_candidate()?;
+    a.internal.add_remote_candidate(&remote).await;
+    a.internal
+        .add_pair(Arc::clone(&local), Arc::clone(&remote))
+        .await;
+
+    // Answers the latest check, reporting mapped as the address it came from
+    let respond = |mapped: SocketAddr| {
+        let (a, conn, local, remote) = (&a, &conn, &local, &remote);
+        async move {
+            a.internal.ping_candidate(local, remote).await;
+            let sent = conn.sent_messages().await?;
+            let mut resp = Message::new();
+            resp.build(&[
+                Box::new(BINDING_SUCCESS),
+                Box::new(sent[sent.len() - 1].transaction_id),
+                Box::new(XorMappedAddress {
+                    ip: mapped.ip(),
+                    port: mapped.port(),
+                }),
+                Box::new(MessageIntegrity::new_short_term_integrity(
+                    "remotepwd".to_owned(),
+                )),
+                Box::new(FINGERPRINT),
+            ])?;
+            a.internal
+                .handle_inbound(&mut resp, local, remote.addr())
+                .await;
+            Result::<()>::Ok(())
+        }
+    };
+    let local_prflx_candidates = || async {
+        let local_candidates = a.internal.local_candidates.lock().await;
+        local_candidates
+            .values()
+            .flatten()
+            .filter(|c| c.candidate_type() == CandidateType::PeerReflexive)
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+
+    // The base's own address isn't peer-reflexive
+    respond(local.addr()).await?;
+    assert!(local_prflx_candidates().await.is_empty());
+
+    let mapped = SocketAddr::from_str("203.0.113.5:4000")?;
+    respond(mapped).await?;
+    let prflx = local_prflx_candidates().await;
+    assert_eq!(1, prflx.len(), "should learn a peer-reflexive candidate");
+    assert_eq!(mapped, prflx[0].addr());
+    assert_eq!(local.priority(), prflx[0].priority());
+    assert_eq!(
+        Some(CandidateRelatedAddress {
+            address: local.address(),
+            port: local.port(),
+        }),
+        prflx[0].related_address()
+    );
+
+    let p = a
+        .internal
+        .find_pair(&prflx[0], &remote)
+        .await
+        .expect("should form a pair with the remote");
+    assert_eq!(
+        CandidatePairState::Succeeded as u8,
+        p.state.load(Ordering::SeqCst)
+    );
+
+    // Traffic on the new pair goes out from the base
+    p.write(b"data").await?;
+    assert_eq!(Some(b"data".to_vec()), conn.sent.lock().await.pop());
+
+    // The same mapped address is learned only once
+    respond(mapped).await?;
+    assert_eq!(1, local_prflx_candidates().await.len());
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_round_trip_time() -> Result<()> {
+    let a = Agent::new(AgentConfig::default()).await?;
+    a.internal.is_controlling.store(true, Ordering::SeqCst);
+    a.internal
+        .set_remote_credentials("remoteufrag".to_owned(), "remotepwd".to_owned())
+        .await?;
+
+    let (conn, local) = new_recording_candidate()?;
+    let remote = new_remote_candidate()?;
+    a.internal.add_remote_candidate(&remote).await;
+    a.internal
+        .add_pair(Arc::clone(&local), Arc::clone(&remote))
+        .await;
+
+    // Answers the latest check after delay
+    let respond = |delay: Duration| {
+        let (a, conn, local, remote) = (&a, &conn, &local, &remote);
+        async move {
+            a.internal.ping_candidate(local, remote).await;
+            let sent = conn.sent_messages().await?;
+            tokio::time::sleep(delay).await;
+
+            let mut resp = Message::new();
+            resp.build(&[
+                Box::new(BINDING_SUCCESS),
+                Box::new(sent[sent.len() - 1].transaction_id),
+                Box::new(MessageIntegrity::new_short_term_integrity(
+                    "remotepwd".to_owned(),
+                )),
+                Box::new(FINGERPRINT),
+            ])?;
+            a.internal
+                .handle_inbound(&mut resp, local, remote.addr())
+                .await;
+
+            let stats = a.get_candidate_pairs_stats().await;
+            assert_eq!(1, stats.len());
+            Result::<_>::Ok(stats.into_iter().next().unwrap())
+        }
+    };
+
+    let stats = respond(Duration::from_millis(100)).await?;
+    assert_eq!(1, stats.responses_received);
+    assert!(
+        (0.1..0.2).contains(&stats.current_round_trip_time),
+        "unexpected round trip time {}",
+        stats.current_round_trip_time
+    );
+    assert_eq!(
+        stats.current_round_trip_time,
+        stats.smoothed_round_trip_time
+    );
+    let first = stats.current_round_trip_time;
+
+    let stats = respond(Duration::from_millis(20)).await?;
+    assert_eq!(2, stats.responses_received);
+    let second = stats.current_round_trip_time;
+    assert!(
+        (0.02..0.1).contains(&second),
+        "unexpected round trip time {}",
+        second
+    );
+    assert!((stats.total_round_trip_time - (first + second)).abs() < 1e-6);
+    assert!((stats.smoothed_round_trip_time - (first * 7.0 + second) / 8.0).abs() < 1e-6);
+
+    let p = a
+        .internal
+        .find_pair(&local, &remote)
+        .await
+        .expect("pair should exist");
+    assert!(
+        (p.smoothed_round_trip_time().unwrap().as_secs_f64() - stats.smoothed_round_trip_time)
+            .abs()
+            < 1e-6
+    );
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_keepalive_interval() -> Result<()> {
+    let keepalive_interval = Duration::from_secs(5);
+    let a = Agent::new(AgentConfig {
+        keepalive_interval: Some(keepalive_interval),
+        ..Default::default()
+    })
+    .await?;
+    assert_eq!(keepalive_interval, a.get_keepalive_interval());
+
+    let (conn, local) = new_recording_candidate()?;
+    let remote = new_remote_candidate()?;
+    a.internal
+        .add_pair(Arc::clone(&local), Arc::clone(&remote))
+        .await;
+    let p = a
+        .internal
+        .find_pair(&local, &remote)
+        .await
+        .expect("pair should exist");
+    a.internal.set_selected_pair(Some(p)).await;
+
+    // Nothing has been sent on the pair yet
+    a.internal.check_keepalive().await;
+    assert_eq!(1, conn.sent_messages().await?.len());
+
+    // Not due again until the interval has passed without traffic
+    remote.seen(false);
+    a.internal.check_keepalive().await;
+    assert_eq!(1, conn.sent_messages().await?.len());
+
+    tokio::time::sleep(keepalive_interval - Duration::from_millis(100)).await;
+    a.internal.check_keepalive().await;
+    assert_eq!(1, conn.sent_messages().await?.len());
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    a.internal.check_keepalive().await;
+    assert_eq!(2, conn.sent_messages().await?.len());
+
+    // A zero interval disables keepalives no matter how long the pair was idle
+    a.set_keepalive_interval(Duration::from_secs(0));
+    assert_eq!(Duration::from_secs(0), a.get_keepalive_interval());
+    tokio::time::sleep(keepalive_interval * 2).await;
+    a.internal.check_keepalive().await;
+    assert_eq!(2, conn.sent_messages().await?.len());
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pause_and_resume() -> Result<()> {
+    let a = Agent::new(AgentConfig {
+        check_interval: Duration::from_millis(10),
+        ..Default::default()
+    })
+    .await?;
+
+    let (conn, local) = new_recording_candidate()?;
+    let remote = new_remote_candidate()?;
+    a.internal
+        .add_pair(Arc::clone(&local), Arc::clone(&remote))
+        .await;
+
+    a.pause();
+    assert!(a.is_paused());
+    a.internal
+        .start_connectivity_checks(true, "remoteufrag".to_owned(), "remotepwd".to_owned())
+        .await?;
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(
+        conn.sent_messages().await?.is_empty(),
+        "a paused agent should send nothing"
+    );
+    assert_eq!(
+        ConnectionState::Checking,
+        a.internal.connection_state.load(Ordering::SeqCst).into()
+    );
+
+    a.resume();
+    assert!(!a.is_paused());
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert!(
+        !conn.sent_messages().await?.is_empty(),
+        "checks should resume right away"
+    );
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_close_stops_checks() -> Result<()> {
+    let a = Agent::new(AgentConfig {
+        check_interval: Duration::from_millis(10),
+        ..Default::default()
+    })
+    .await?;
+    let (transition_tx, mut transition_rx) = mpsc::unbounded_channel::<bool>();
+    a.on_selected_candidate_pair_transition(Box::new(move |_, new| {
+        let _ = transition_tx.send(new.is_some());
+        Box::pin(async move {})
+    }));
+
+    let (conn, local) = new_recording_candidate()?;
+    let remote = new_remote_candidate()?;
+    a.internal
+        .add_pair(Arc::clone(&local), Arc::clone(&remote))
+        .await;
+    a.internal
+        .start_connectivity_checks(true, "remoteufrag".to_owned(), "remotepwd".to_owned())
+        .await?;
+    let p = a
+        .internal
+        .find_pair(&local, &remote)
+        .await
+        .expect("pair should exist");
+    a.internal.set_selected_pair(Some(p)).await;
+    assert_eq!(Some(true), transition_rx.recv().await);
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!conn.sent_messages().await?.is_empty());
+    assert!(!a.internal.force_candidate_contact_tx.is_closed());
+
+    a.close().await?;
+    a.close().await?;
+    assert_eq!(Some(false), transition_rx.recv().await);
+    assert!(a.internal.pending_binding_requests.lock().await.is_empty());
+    assert!(a.internal.agent_conn.get_selected_pair().is_none());
+
+    // The check loop has exited and dropped its receiver
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert!(a.internal.force_candidate_contact_tx.is_closed());
+
+    let sent = conn.sent_messages().await?.len();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(
+        sent,
+        conn.sent_messages().await?.len(),
+        "a closed agent should send no more STUN"
+    );
+    assert!(
+        transition_rx.try_recv().is_err(),
+        "the selected pair should be cleared once"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_connectivity_check_timeout() -> Result<()> {
+    let a = Agent::new(AgentConfig {
+        check_interval: Duration::from_millis(10),
+        connectivity_check_timeout: Some(Duration::from_millis(200)),
+        ..Default::default()
+    })
+    .await?;
+    assert_eq!(
+        Duration::from_millis(200),
+        a.internal.connectivity_check_timeout
+    );
+
+    // The remote never answers
+    let (conn, local) = new_recording_candidate()?;
+    let remote = new_remote_candidate()?;
+    a.internal
+        .add_pair(Arc::clone(&local), Arc::clone(&remote))
+        .await;
+    a.internal
+        .start_connectivity_checks(true, "remoteufrag".to_owned(), "remotepwd".to_owned())
+        .await?;
+
+    let state = || -> ConnectionState { a.internal.connection_state.load(Ordering::SeqCst).into() };
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(ConnectionState::Checking, state());
+    assert!(!conn.sent_messages().await?.is_empty());
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(ConnectionState::Failed, state());
+
+    let sent = conn.sent_messages().await?.len();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(
+        sent,
+        conn.sent_messages().await?.len(),
+        "a failed agent should stop sending checks"
+    );
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_connectivity_check_timeout_default() -> Result<()> {
+    let a = Agent::new(AgentConfig {
+        disconnected_timeout: Some(Duration::from_secs(2)),
+        failed_timeout: Some(Duration::from_secs(3)),
+        ..Default::default()
+    })
+    .await?;
+    assert_eq!(
+        Duration::from_secs(5),
+        a.internal.connectivity_check_timeout
+    );
+    a.close().await?;
+
+    let a = Agent::new(AgentConfig {
+        failed_timeout: Some(Duration::from_secs(0)),
+        ..Default::default()
+    })
+    .await?;
+    assert_eq!(
+        Duration::from_secs(0),
+        a.internal.connectivity_check_timeout,
+        "an agent that never fails should keep checking until a pair is selected"
+    );
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_candidate_filter() -> Result<()> {
+    // 10.20.0.0/16 stands in for a metered cellular interface
+    let cellular: ipnet::Ipv4Net = "10.20.0.0/16".parse().unwrap();
+    let a = Agent::new(AgentConfig {
+        candidate_filter: Arc::new(Some(Box::new(move |c: &(dyn Candidate + Send + Sync)| {
+            !matches!(c.addr().ip(), std::net::IpAddr::V4(ip) if cellular.contains(&ip))
+        }))),
+        ..Default::default()
+    })
+    .await?;
+    a.internal.is_controlling.store(true, Ordering::SeqCst);
+
+    let (wifi_conn, wifi) = new_recording_candidate()?;
+    let cellular_conn = Arc::new(RecordingConn::default());
+    let cellular: Arc<dyn Candidate + Send + Sync> = Arc::new(
+        CandidateHostConfig {
+            base_config: CandidateBaseConfig {
+                network: "udp".to_owned(),
+                address: "10.20.0.2".to_owned(),
+                port: 777,
+                component: 1,
+                conn: Some(Arc::clone(&cellular_conn) as Arc<dyn Conn + Send + Sync>),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+        .new_candidate_host()?,
+    );
+
+    let remote = new_remote_candidate()?;
+    for local in [&wifi, &cellular] {
+        a.internal
+            .add_pair(Arc::clone(local), Arc::clone(&remote))
+            .await;
+    }
+    assert!(a.internal.find_pair(&wifi, &remote).await.is_some());
+    assert!(
+        a.internal.find_pair(&cellular, &remote).await.is_none(),
+        "a filtered candidate should not enter the checklist"
+    );
+
+    for _ in 0..3 {
+        a.internal.ping_all_candidates().await;
+    }
+    assert!(!wifi_conn.sent_messages().await?.is_empty());
+    assert!(
+        cellular_conn.sent_messages().await?.is_empty(),
+        "a filtered candidate should never be pinged"
+    );
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_discard_stats() -> Result<()> {
+    let a = Agent::new(AgentConfig::default()).await?;
+    a.internal.is_controlling.store(true, Ordering::SeqCst);
+    a.internal.tie_breaker.store(200, Ordering::SeqCst);
+    a.internal
+        .set_remote_credentials("remoteufrag".to_owned(), "remotepwd".to_owned())
+        .await?;
+    assert_eq!(DiscardStats::default(), a.get_discard_stats());
+
+    let (conn, local) = new_recording_candidate()?;
+    let remote = new_remote_candidate()?;
+    a.internal.add_remote_candidate(&remote).await;
+    a.internal
+        .add_pair(Arc::clone(&local), Arc::clone(&remote))
+        .await;
+
+    // A request whose fingerprint doesn't match its contents
+    let mut msg = build_binding_request(&a, Box::new(AttrControlled(100))).await?;
+    let last = msg.raw.len() - 1;
+    msg.raw[last] ^= 0xff;
+    msg.decode()?;
+    a.internal
+        .handle_inbound(&mut msg, &local, remote.addr())
+        .await;
+    assert_eq!(1, a.get_discard_stats().fingerprint_failure);
+
+    // A request signed with the wrong password
+    let mut msg = Message::new();
+    msg.build(&[
+        Box::new(BINDING_REQUEST),
+        Box::new(TransactionId::new()),
+        Box::new(Username::new(
+            ATTR_USERNAME,
+            a.internal.ufrag_pwd.lock().await.local_ufrag.clone() + ":remoteufrag",
+        )),
+        Box::new(AttrControlled(100)),
+        Box::new(PriorityAttr(1)),
+        Box::new(MessageIntegrity::new_short_term_integrity(
+            "wrongpwd".to_owned(),
+        )),
+        Box::new(FINGERPRINT),
+    ])?;
+    a.internal
+        .handle_inbound(&mut msg, &local, remote.addr())
+        .await;
+    assert_eq!(1, a.get_discard_stats().integrity_failure);
+
+    // A request from a controlling peer that loses the tie-breaker
+    let mut msg = build_binding_request(&a, Box::new(AttrControlling(100))).await?;
+    a.internal
+        .handle_inbound(&mut msg, &local, remote.addr())
+        .await;
+    assert_eq!(1, a.get_discard_stats().role_conflict);
+
+    // A success response that answers none of our requests
+    let build_response = |id: TransactionId| -> Result<Message> {
+        let mut resp = Message::new();
+        resp.build(&[
+            Box::new(BINDING_SUCCESS),
+            Box::new(id),
+            Box::new(MessageIntegrity::new_short_term_integrity(
+                "remotepwd".to_owned(),
+            )),
+            Box::new(FINGERPRINT),
+        ])?;
+        Ok(resp)
+    };
+    let mut resp = build_response(TransactionId::new())?;
+    a.internal
+        .handle_inbound(&mut resp, &local, remote.addr())
+        .await;
+    assert_eq!(1, a.get_discard_stats().unknown_transaction);
+
+    // A success response arriving from another address than the request went to
+    a.internal.ping_candidate(&local, &remote).await;
+    let sent = conn.sent_messages().await?;
+    let request = sent
+        .iter()
+        .rev()
+        .find(|m| m.typ == BINDING_REQUEST)
+        .expect("should have sent a binding request");
+    let other: Arc<dyn Candidate + Send + Sync> = Arc::new(
+        CandidateHostConfig {
+            base_config: CandidateBaseConfig {
+                network: "udp".to_owned(),
+                address: "172.17.0.4".to_owned(),
+                port: 999,
+                component: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+        .new_candidate_host()?,
+    );
+    a.internal.add_remote_candidate(&other).await;
+    let mut resp = build_response(request.transaction_id)?;
+    a.internal
+        .handle_inbound(&mut resp, &local, other.addr())
+        .await;
+
+    assert_eq!(
+        DiscardStats {
+            unknown_transaction: 1,
+            nat_mismatch: 1,
+            integrity_failure: 1,
+            fingerprint_failure: 1,
+            role_conflict: 1,
+        },
+        a.get_discard_stats()
+    );
+
+    a.close().await?;
+    Ok(())
+}
+
+fn new_tcp_candidate(
+    remote: Option<SocketAddr>,
+) -> Result<(Arc<RecordingConn>, Arc<dyn Candidate + Send + Sync>)> {
+    let conn = Arc::new(RecordingConn {
+        remote,
+        ..Default::default()
+    });
+    let local: Arc<dyn Candidate + Send + Sync> = Arc::new(
+        CandidateHostConfig {
+            base_config: CandidateBaseConfig {
+                network: "tcp".to_owned(),
+                address: "192.168.0.2".to_owned(),
+                port: 9,
+                component: 1,
+                conn: Some(Arc::clone(&conn) as Arc<dyn Conn + Send + Sync>),
+                ..Default::default()
+            },
+            tcp_type: TcpType::Active,
+        }
+        .new_candidate_host()?,
+    );
+    Ok((conn, local))
+}
+
+#[tokio::test]
+async fn test_tcp_candidate_pings() -> Result<()> {
+    let a = Agent::new(AgentConfig::default()).await?;
+    a.internal.is_controlling.store(true, Ordering::SeqCst);
+    let remote = new_remote_candidate()?;
+
+    // No STUN goes out on a TCP candidate before its connection is established
+    let (conn, local) = new_tcp_candidate(None)?;
+    a.internal.ping_candidate(&local, &remote).await;
+    assert!(conn.sent_messages().await?.is_empty());
+
+    let (conn, local) = new_tcp_candidate(Some(remote.addr()))?;
+    a.internal.ping_candidate(&local, &remote).await;
+    assert_eq!(1, conn.sent_messages().await?.len());
+
+    // Once selected, the connection replaces keepalive pings
+    a.internal
+        .add_pair(Arc::clone(&local), Arc::clone(&remote))
+        .await;
+    let p = a
+        .internal
+        .find_pair(&local, &remote)
+        .await
+        .expect("pair should exist");
+    a.internal.set_selected_pair(Some(p)).await;
+    a.internal.check_keepalive().await;
+    assert_eq!(1, conn.sent_messages().await?.len());
+    assert!(a.internal.validate_selected_pair().await);
+    assert_eq!(
+        ConnectionState::Connected as u8,
+        a.internal.connection_state.load(Ordering::SeqCst)
+    );
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_disconnected_and_failed_timeouts() -> Result<()> {
+    let a = Agent::new(AgentConfig {
+        disconnected_timeout: Some(Duration::from_millis(100)),
+        failed_timeout: Some(Duration::from_millis(200)),
+        ..Default::default()
+    })
+    .await?;
+    a.internal.is_controlling.store(true, Ordering::SeqCst);
+    let (states_tx, mut states_rx) = mpsc::unbounded_channel();
+    a.on_connection_state_change(Box::new(move |c: ConnectionState| {
+        let _ = states_tx.send(c);
+        Box::pin(async {})
+    }));
+
+    let (_, local) = new_recording_candidate()?;
+    let remote = new_remote_candidate()?;
+    a.internal
+        .add_pair(Arc::clone(&local), Arc::clone(&remote))
+        .await;
+    let p = a
+        .internal
+        .find_pair(&local, &remote)
+        .await
+        .expect("pair should exist");
+    remote.seen(false);
+    a.internal.set_selected_pair(Some(p)).await;
+    assert_eq!(ConnectionState::Connected, a.get_connection_state());
+    assert_eq!(Some(ConnectionState::Connected), states_rx.recv().await);
+
+    // Silence past the disconnected timeout
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    a.internal.validate_selected_pair().await;
+    assert_eq!(ConnectionState::Disconnected, a.get_connection_state());
+    assert_eq!(Some(ConnectionState::Disconnected), states_rx.recv().await);
+
+    // Traffic from the remote brings it back
+    remote.seen(false);
+    a.internal.validate_selected_pair().await;
+    assert_eq!(ConnectionState::Connected, a.get_connection_state());
+    assert_eq!(Some(ConnectionState::Connected), states_rx.recv().await);
+
+    // Still disconnected only until both timeouts passed
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    a.internal.validate_selected_pair().await;
+    assert_eq!(ConnectionState::Disconnected, a.get_connection_state());
+    assert_eq!(Some(ConnectionState::Disconnected), states_rx.recv().await);
+
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    a.internal.validate_selected_pair().await;
+    assert_eq!(ConnectionState::Failed, a.get_connection_state());
+    assert_eq!(Some(ConnectionState::Failed), states_rx.recv().await);
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_check_interval_jitter() -> Result<()> {
+    let a = Agent::new(AgentConfig {
+        check_interval_jitter: 0.2,
+        ..Default::default()
+    })
+    .await?;
+
+    let interval = Duration::from_millis(100);
+    let draw = |seed: u64| {
+        *a.internal.check_interval_rng.lock() = StdRng::seed_from_u64(seed);
+        (0..20)
+            .map(|_| a.internal.jittered_interval(interval))
+            .collect::<Vec<_>>()
+    };
+
+    let intervals = draw(1);
+    for d in &intervals {
+        assert!(
+            *d >= Duration::from_millis(80) && *d <= Duration::from_millis(120),
+            "{:?} should be within 20% of {:?}",
+            d,
+            interval
+        );
+    }
+    assert!(
+        intervals.windows(2).any(|w| w[0] != w[1]),
+        "successive intervals should vary"
+    );
+    assert_eq!(intervals, draw(1), "a seeded RNG should be deterministic");
+
+    a.close().await?;
+
+    let a = Agent::new(AgentConfig::default()).await?;
+    assert_eq!(interval, a.internal.jittered_interval(interval));
+    a.close().await?;
+
+    for jitter in [-0.1, 1.0] {
+        let result = Agent::new(AgentConfig {
+            check_interval_jitter: jitter,
+            ..Default::default()
+        })
+        .await;
+        assert!(matches!(result, Err(Error::ErrInvalidCheckIntervalJitter)));
+    }
+
+    Ok(())
+}
+
+fn new_host_candidate(address: &str, port: u16) -> Result<Arc<dyn Candidate + Send + Sync>> {
+    Ok(Arc::new(
+        CandidateHostConfig {
+            base_config: CandidateBaseConfig {
+                network: "udp".to_owned(),
+                address: address.to_owned(),
+                port,
+                component: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+        .new_candidate_host()?,
+    ))
+}
+
+#[tokio::test]
+async fn test_frozen_pairs() -> Result<()> {
+    // A single check slot per Ta
+    let ta = Duration::from_millis(50);
+    let a = Agent::new(AgentConfig {
+        check_interval: ta,
+        check_pacing_interval: Some(ta),
+        ..Default::default()
+    })
+    .await?;
+    a.internal.is_controlling.store(true, Ordering::SeqCst);
+
+    let (conn, local) = new_recording_candidate()?;
+    // remote and sibling share a foundation, other has its own
+    let remote = new_remote_candidate()?;
+    let sibling = new_host_candidate("172.17.0.3", 1000)?;
+    let other = new_host_candidate("172.17.0.4", 999)?;
+    assert_eq!(remote.foundation(), sibling.foundation());
+    assert_ne!(remote.foundation(), other.foundation());
+
+    let mut pairs = vec![];
+    for r in [&remote, &sibling, &other] {
+        a.internal.add_pair(Arc::clone(&local), Arc::clone(r)).await;
+        pairs.push(
+            a.internal
+                .find_pair(&local, r)
+                .await
+                .expect("pair should exist"),
+        );
+    }
+    let state = |i: usize| -> CandidatePairState { pairs[i].state.load(Ordering::SeqCst).into() };
+    assert_eq!(CandidatePairState::Waiting, state(0));
+    assert_eq!(CandidatePairState::Frozen, state(1));
+    assert_eq!(CandidatePairState::Waiting, state(2));
+
+    // Every check slot starts the highest priority waiting pair, the frozen pair is never pinged
+    let (first, second) = if pairs[0].priority() >= pairs[2].priority() {
+        (0, 2)
+    } else {
+        (2, 0)
+    };
+    a.internal.ping_all_candidates().await;
+    a.internal.ping_all_candidates().await;
+    assert_eq!(1, conn.sent_messages().await?.len());
+    assert_eq!(CandidatePairState::InProgress, state(first));
+    assert_eq!(CandidatePairState::Waiting, state(second));
+
+    tokio::time::sleep(ta).await;
+    a.internal.ping_all_candidates().await;
+    assert_eq!(CandidatePairState::InProgress, state(0));
+    assert_eq!(CandidatePairState::Frozen, state(1));
+    assert_eq!(CandidatePairState::InProgress, state(2));
+    assert_eq!(2, conn.sent_messages().await?.len());
+
+    // Pairs in progress are left to the retransmissions of their binding request
+    tokio::time::sleep(ta).await;
+    a.internal.ping_all_candidates().await;
+    assert_eq!(2, conn.sent_messages().await?.len());
+
+    // A success unfreezes the pairs of its own foundation only
+    pairs[0]
+        .state
+        .store(CandidatePairState::Succeeded as u8, Ordering::SeqCst);
+    a.internal.unfreeze_foundation(&pairs[0]).await;
+    assert_eq!(CandidatePairState::Waiting, state(1));
+    assert_eq!(CandidatePairState::InProgress, state(2));
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_checks_after_selection() -> Result<()> {
+    let a = Agent::new(AgentConfig {
+        check_pacing_interval: Some(Duration::from_millis(5)),
+        better_pair_check_window: Some(Duration::from_millis(100)),
+        ..Default::default()
+    })
+    .await?;
+    a.internal.is_controlling.store(true, Ordering::SeqCst);
+
+    let (_conn, local) = new_recording_candidate()?;
+    let mut pairs = vec![];
+    for (i, priority) in [3000, 2000, 1000].iter().enumerate() {
+        let remote: Arc<dyn Candidate + Send + Sync> = Arc::new(
+            CandidateHostConfig {
+                base_config: CandidateBaseConfig {
+                    network: "udp".to_owned(),
+                    address: format!("172.17.0.{}", i + 3),
+                    port: 999,
+                    component: 1,
+                    priority: *priority,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }
+            .new_candidate_host()?,
+        );
+        a.internal
+            .add_pair(Arc::clone(&local), Arc::clone(&remote))
+            .await;
+        pairs.push(a.internal.find_pair(&local, &remote).await.unwrap());
+    }
+    let (better, selected, worse) = (&pairs[0], &pairs[1], &pairs[2]);
+    assert!(better.priority() > selected.priority() && selected.priority() > worse.priority());
+    let checks = |p: &Arc<CandidatePair>| -> u16 { p.binding_request_count.load(Ordering::SeqCst) };
+
+    for _ in 0..3 {
+        a.internal.contact_candidates().await;
+    }
+    assert!(checks(better) > 0 && checks(worse) > 0);
+
+    a.internal
+        .set_selected_pair(Some(Arc::clone(selected)))
+        .await;
+    // Put the other pairs back to waiting for a new check
+    for p in [better, worse] {
+        p.state
+            .store(CandidatePairState::Waiting as u8, Ordering::SeqCst);
+    }
+    let (better_before, worse_before) = (checks(better), checks(worse));
+    for _ in 0..3 {
+        a.internal.contact_candidates().await;
+    }
+    assert!(
+        checks(better) > better_before,
+        "a better pair should still be checked within the window"
+    );
+    assert_eq!(
+        worse_before,
+        checks(worse),
+        "a worse pair should not be checked once a pair is selected"
+    );
+
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    better
+        .state
+        .store(CandidatePairState::Waiting as u8, Ordering::SeqCst);
+    let better_before = checks(better);
+    for _ in 0..3 {
+        a.internal.contact_candidates().await;
+    }
+    assert_eq!(
+        better_before,
+        checks(better),
+        "no other pair should be checked after the window"
+    );
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_frozen_pairs_unfreeze_after_failure() -> Result<()> {
+    let a = Agent::new(AgentConfig::default()).await?;
+    a.internal.is_controlling.store(true, Ordering::SeqCst);
+
+    let (conn, local) = new_recording_candidate()?;
+    let remote = new_remote_candidate()?;
+    let sibling = new_host_candidate("172.17.0.3", 1000)?;
+    a.internal
+        .add_pair(Arc::clone(&local), Arc::clone(&remote))
+        .await;
+    a.internal
+        .add_pair(Arc::clone(&local), Arc::clone(&sibling))
+        .await;
+    let p = a.internal.find_pair(&local, &remote).await.unwrap();
+    let frozen = a.internal.find_pair(&local, &sibling).await.unwrap();
+
+    a.internal.ping_all_candidates().await;
+    assert_eq!(
+        CandidatePairState::Frozen as u8,
+        frozen.state.load(Ordering::SeqCst)
+    );
+
+    // With nothing else left to check in the foundation, the frozen pair gets its turn
+    p.state
+        .store(CandidatePairState::Failed as u8, Ordering::SeqCst);
+    a.internal.ping_all_candidates().await;
+    assert_eq!(
+        CandidatePairState::InProgress as u8,
+        frozen.state.load(Ordering::SeqCst)
+    );
+    assert_eq!(2, conn.sent_messages().await?.len());
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_binding_request_timeout_fails_pair() -> Result<()> {
+    let a = Agent::new(AgentConfig::default()).await?;
+    a.internal.is_controlling.store(true, Ordering::SeqCst);
+
+    let (conn, local) = new_recording_candidate()?;
+    let remote = new_remote_candidate()?;
+    a.internal
+        .add_pair(Arc::clone(&local), Arc::clone(&remote))
+        .await;
+    let p = a.internal.find_pair(&local, &remote).await.unwrap();
+
+    // The mock never answers
+    a.internal.ping_all_candidates().await;
+    assert_eq!(1, conn.sent_messages().await?.len());
+    assert_eq!(
+        CandidatePairState::InProgress as u8,
+        p.state.load(Ordering::SeqCst)
+    );
+
+    a.internal
+        .invalidate_pending_binding_requests(Instant::now())
+        .await;
+    assert_eq!(
+        CandidatePairState::InProgress as u8,
+        p.state.load(Ordering::SeqCst),
+        "request has not timed out yet"
+    );
+
+    a.internal
+        .invalidate_pending_binding_requests(Instant::now() + MAX_BINDING_REQUEST_TIMEOUT)
+        .await;
+    assert_eq!(
+        CandidatePairState::Failed as u8,
+        p.state.load(Ordering::SeqCst),
+        "pair should fail once its request timed out"
+    );
+    assert!(
+        a.internal.pending_binding_requests.lock().await.is_empty(),
+        "timed out request should be removed"
+    );
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_configured_tie_breaker() -> Result<()> {
+    let new_agent = |tie_breaker: u64| {
+        Agent::new(AgentConfig {
+            is_controlling: true,
+            tie_breaker: Some(tie_breaker),
+            ..Default::default()
+        })
+    };
+    let a = new_agent(200).await?;
+    let b = new_agent(100).await?;
+    assert_eq!(200, a.internal.tie_breaker.load(Ordering::SeqCst));
+    assert_eq!(100, b.internal.tie_breaker.load(Ordering::SeqCst));
+
+    let remote = SocketAddr::from_str("172.17.0.3:999")?;
+
+    // Both agents think they are controlling, the larger tie-breaker keeps the role...
+    let (a_conn, a_local) = new_recording_candidate()?;
+    let mut msg = build_binding_request(&a, Box::new(AttrControlling(100))).await?;
+    a.internal.handle_inbound(&mut msg, &a_local, remote).await;
+    assert!(a.internal.is_controlling.load(Ordering::SeqCst));
+    let sent = a_conn.sent_messages().await?;
+    assert_eq!(
+        MessageType::new(METHOD_BINDING, CLASS_ERROR_RESPONSE),
+        sent[0].typ
+    );
+
+    // ...and the smaller one gives it up
+    let (_, b_local) = new_recording_candidate()?;
+    let mut msg = build_binding_request(&b, Box::new(AttrControlling(200))).await?;
+    b.internal.handle_inbound(&mut msg, &b_local, remote).await;
+    assert!(!b.internal.is_controlling.load(Ordering::SeqCst));
+
+    a.close().await?;
+    b.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_configured_rng_seed() -> Result<()> {
+    let new_agent = || {
+        Agent::new(AgentConfig {
+            check_interval_jitter: 0.5,
+            rng_seed: Some(42),
+            ..Default::default()
+        })
+    };
+    let a = new_agent().await?;
+    let b = new_agent().await?;
+
+    let interval = Duration::from_millis(100);
+    for _ in 0..20 {
+        assert_eq!(
+            a.internal.jittered_interval(interval),
+            b.internal.jittered_interval(interval),
+            "agents with the same seed should draw the same intervals"
+        );
+    }
+
+    a.close().await?;
+    b.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_best_valid_candidate_pair_cache() -> Result<()> {
+    const PAIRS: u16 = 300;
+
+    let a = Agent::new(AgentConfig::default()).await?;
+    a.internal.is_controlling.store(true, Ordering::SeqCst);
+
+    let (_, local) = new_recording_candidate()?;
+    let mut pairs = vec![];
+    for port in 0..PAIRS {
+        let remote = new_host_candidate("172.17.0.3", 1000 + port)?;
+        a.internal
+            .add_pair(Arc::clone(&local), Arc::clone(&remote))
+            .await;
+        pairs.push(a.internal.find_pair(&local, &remote).await.unwrap());
+    }
+    for p in &pairs {
+        p.state
+            .store(CandidatePairState::Succeeded as u8, Ordering::SeqCst);
+    }
+    a.internal.agent_conn.invalidate_best_valid_candidate_pair();
+
+    let scans = || {
+        a.internal
+            .agent_conn
+            .best_valid_pair_scans
+            .load(Ordering::SeqCst)
+    };
+    let agent_conn = &a.internal.agent_conn;
+
+    let first = agent_conn.get_best_valid_candidate_pair().await.unwrap();
+    for _ in 0..100 {
+        let p = agent_conn.get_best_valid_candidate_pair().await.unwrap();
+        assert!(Arc::ptr_eq(&first, &p));
+    }
+    assert_eq!(
+        1,
+        scans(),
+        "repeated lookups should be served from the cache"
+    );
+
+    // A cached pair that failed is replaced without an explicit invalidation
+    first
+        .state
+        .store(CandidatePairState::Failed as u8, Ordering::SeqCst);
+    let second = agent_conn.get_best_valid_candidate_pair().await.unwrap();
+    assert!(!Arc::ptr_eq(&first, &second));
+    assert_eq!(2, scans());
+    assert!(pairs
+        .iter()
+        .filter(|p| p.state.load(Ordering::SeqCst) == CandidatePairState::Succeeded as u8)
+        .all(|p| p.priority() <= second.priority()));
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_candidate_pair_stats_states() -> Result<()> {
+    let a = Agent::new(AgentConfig::default()).await?;
+    a.internal.is_controlling.store(true, Ordering::SeqCst);
+
+    let local = new_host_candidate("192.168.0.2", 777)?;
+    let states = [
+        CandidatePairState::Waiting,
+        CandidatePairState::InProgress,
+        CandidatePairState::Succeeded,
+        CandidatePairState::Failed,
+        CandidatePairState::Frozen,
+    ];
+    let mut pairs = vec![];
+    for (i, state) in states.iter().enumerate() {
+        let remote = new_host_candidate(&format!("172.17.0.{}", i + 1), 999)?;
+        a.internal
+            .add_pair(Arc::clone(&local), Arc::clone(&remote))
+            .await;
+        let p = a.internal.find_pair(&local, &remote).await.unwrap();
+        p.state.store(*state as u8, Ordering::SeqCst);
+        pairs.push(p);
+    }
+    pairs[2].nominated.store(true, Ordering::SeqCst);
+
+    let stats = a.get_candidate_pairs_stats().await;
+    assert_eq!(states.len(), stats.len());
+    for (p, stat) in pairs.iter().zip(stats.iter()) {
+        assert_eq!(p.local.id(), stat.local_candidate_id);
+        assert_eq!(p.remote.id(), stat.remote_candidate_id);
+        assert_eq!(
+            CandidatePairState::from(p.state.load(Ordering::SeqCst)),
+            stat.state
+        );
+        assert_eq!(p.nominated.load(Ordering::SeqCst), stat.nominated);
+        assert_eq!(p.priority(), stat.priority);
+    }
+    assert_eq!(
+        states.to_vec(),
+        stats.iter().map(|s| s.state).collect::<Vec<_>>()
+    );
+    assert_eq!(
+        vec![false, false, true, false, false],
+        stats.iter().map(|s| s.nominated).collect::<Vec<_>>()
+    );
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_check_pacing() -> Result<()> {
+    const PAIRS: u16 = 10;
+    let ta = Duration::from_millis(20);
+
+    // check_interval / Ta slots are saved up between runs of the task loop
+    let new_agent_with_pairs = |check_interval: Duration| async move {
+        let a = Agent::new(AgentConfig {
+            check_interval,
+            check_pacing_interval: Some(ta),
+            ..Default::default()
+        })
+        .await?;
+        a.internal.is_controlling.store(true, Ordering::SeqCst);
+
+        let (conn, local) = new_recording_candidate()?;
+        let mut pairs = vec![];
+        for port in 0..PAIRS {
+            let remote = new_host_candidate("172.17.0.3", 1000 + port)?;
+            a.internal
+                .add_pair(Arc::clone(&local), Arc::clone(&remote))
+                .await;
+            let p = a.internal.find_pair(&local, &remote).await.unwrap();
+            p.state
+                .store(CandidatePairState::Waiting as u8, Ordering::SeqCst);
+            pairs.push(p);
+        }
+        Result::<_>::Ok((a, conn, pairs))
+    };
+
+    let (a, conn, _) = new_agent_with_pairs(ta * 5).await?;
+    a.internal.ping_all_candidates().await;
+    assert_eq!(
+        5,
+        conn.sent_messages().await?.len(),
+        "should send a burst of saved up checks only"
+    );
+    a.internal.ping_all_candidates().await;
+    assert_eq!(5, conn.sent_messages().await?.len(), "no slot left");
+    a.close().await?;
+
+    let (a, conn, pairs) = new_agent_with_pairs(ta).await?;
+    for i in 1..=PAIRS as usize {
+        a.internal.ping_all_candidates().await;
+        a.internal.ping_all_candidates().await;
+        assert_eq!(
+            i,
+            conn.sent_messages().await?.len(),
+            "should send a single check per Ta"
+        );
+        tokio::time::sleep(ta).await;
+    }
+    assert!(
+        pairs
+            .iter()
+            .all(|p| p.binding_request_count.load(Ordering::SeqCst) == 1),
+        "every pair should get its turn"
+    );
+    a.close().await?;
+
+    // Ta has a floor
+    let a = Agent::new(AgentConfig {
+        check_pacing_interval: Some(Duration::from_millis(1)),
+        ..Default::default()
+    })
+    .await?;
+    assert_eq!(Duration::from_millis(5), a.internal.check_pacing_interval);
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_binding_request_without_priority_or_role() -> Result<()> {
+    for is_controlling in [true, false] {
+        let a = Agent::new(AgentConfig::default()).await?;
+        a.internal
+            .is_controlling
+            .store(is_controlling, Ordering::SeqCst);
+
+        let (username, local_pwd) = {
+            let ufrag_pwd = a.internal.ufrag_pwd.lock().await;
+            (
+                ufrag_pwd.local_ufrag.to_owned() + ":" + ufrag_pwd.remote_ufrag.as_str(),
+                ufrag_pwd.local_pwd.clone(),
+            )
+        };
+        let (conn, local) = new_recording_candidate()?;
+        let remote = SocketAddr::from_str("172.17.0.3:999")?;
+
+        // Neither PRIORITY nor a role attribute, as a plain STUN client would send it
+        let mut msg = Message::new();
+        msg.build(&[
+            Box::new(BINDING_REQUEST),
+            Box::new(TransactionId::new()),
+            Box::new(Username::new(ATTR_USERNAME, username.clone())),
+            Box::new(MessageIntegrity::new_short_term_integrity(
+                local_pwd.clone(),
+            )),
+            Box::new(FINGERPRINT),
+        ])?;
+        a.internal.handle_inbound(&mut msg, &local, remote).await;
+
+        let sent = conn.sent_messages().await?;
+        assert_eq!(1, sent.len(), "should answer the request");
+        assert_eq!(BINDING_SUCCESS, sent[0].typ);
+        assert_eq!(msg.transaction_id, sent[0].transaction_id);
+        let mut addr = XorMappedAddress::default();
+        addr.get_from(&sent[0])?;
+        assert_eq!(remote, SocketAddr::new(addr.ip, addr.port));
+
+        assert!(a.internal.remote_candidates.lock().await.is_empty());
+        assert!(a.internal.agent_conn.checklist.lock().await.is_empty());
+        assert_eq!(
+            is_controlling,
+            a.internal.is_controlling.load(Ordering::SeqCst)
+        );
+
+        // A check without a role attribute skips conflict resolution
+        let mut msg = Message::new();
+        msg.build(&[
+            Box::new(BINDING_REQUEST),
+            Box::new(TransactionId::new()),
+            Box::new(Username::new(ATTR_USERNAME, username)),
+            Box::new(PriorityAttr(1)),
+            Box::new(MessageIntegrity::new_short_term_integrity(local_pwd)),
+            Box::new(FINGERPRINT),
+        ])?;
+        a.internal.handle_inbound(&mut msg, &local, remote).await;
+
+        let sent = conn.sent_messages().await?;
+        assert!(sent
+            .iter()
+            .any(|m| m.typ == BINDING_SUCCESS && m.transaction_id == msg.transaction_id));
+        assert_eq!(1, a.internal.remote_candidates.lock().await.len());
+        assert_eq!(
+            is_controlling,
+            a.internal.is_controlling.load(Ordering::SeqCst)
+        );
+
+        a.close().await?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_software_attribute() -> Result<()> {
+    for software in [None, Some("webrtc-rs ice".to_owned())] {
+        let a = Agent::new(AgentConfig {
+            software: software.clone(),
+            ..Default::default()
+        })
+        .await?;
+        a.internal.is_controlling.store(true, Ordering::SeqCst);
+
+        let (conn, local) = new_recording_candidate()?;
+        a.internal
+            .ping_candidate(&local, &new_remote_candidate()?)
+            .await;
+        let mut msg = build_binding_request(&a, Box::new(AttrControlled(100))).await?;
+        a.internal
+            .handle_inbound(&mut msg, &local, SocketAddr::from_str("172.17.0.3:999")?)
+            .await;
+
+        let sent = conn.sent_messages().await?;
+        assert!(sent.iter().any(|m| m.typ == BINDING_REQUEST));
+        assert!(sent.iter().any(|m| m.typ == BINDING_SUCCESS));
+        for m in &sent {
+            let position = |typ| m.attributes.0.iter().position(|attr| attr.typ == typ);
+            match &software {
+                Some(software) => {
+                    let got = TextAttribute::get_from_as(m, ATTR_SOFTWARE)?;
+                    assert_eq!(software, &got.text);
+                    assert!(
+                        position(ATTR_SOFTWARE) < position(ATTR_MESSAGE_INTEGRITY),
+                        "SOFTWARE must precede MESSAGE-INTEGRITY"
+                    );
+                }
+                None => assert!(!m.contains(ATTR_SOFTWARE)),
+            }
+        }
+
+        a.close().await?;
+    }
+
+    Ok(())
+}
+
+fn new_prioritized_candidate(
+    address: &str,
+    port: u16,
+    priority: u32,
+) -> Result<Arc<dyn Candidate + Send + Sync>> {
+    Ok(Arc::new(
+        CandidateHostConfig {
+            base_config: CandidateBaseConfig {
+                network: "udp".to_owned(),
+                address: address.to_owned(),
+                port,
+                component: 1,
+                priority,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+        .new_candidate_host()?,
+    ))
+}
+
+#[tokio::test]
+async fn test_role_switch_recomputes_pair_priorities() -> Result<()> {
+    let a = Agent::new(AgentConfig::default()).await?;
+    a.internal.is_controlling.store(true, Ordering::SeqCst);
+    a.internal.tie_breaker.store(100, Ordering::SeqCst);
+
+    let candidate = |address, priority| new_prioritized_candidate(address, 1000, priority);
+    // Both pairs have the same MIN and MAX, only the G>D term tells them apart
+    let (local_high, local_low) = (
+        candidate("192.168.0.2", 200)?,
+        candidate("192.168.0.3", 100)?,
+    );
+    let (remote_high, remote_low) = (candidate("172.17.0.4", 200)?, candidate("172.17.0.5", 100)?);
+    a.internal
+        .add_pair(Arc::clone(&local_high), Arc::clone(&remote_low))
+        .await;
+    a.internal
+        .add_pair(Arc::clone(&local_low), Arc::clone(&remote_high))
+        .await;
+    let local_wins = a
+        .internal
+        .find_pair(&local_high, &remote_low)
+        .await
+        .unwrap();
+    let remote_wins = a
+        .internal
+        .find_pair(&local_low, &remote_high)
+        .await
+        .unwrap();
+    assert!(local_wins.priority() > remote_wins.priority());
+    {
+        let checklist = a.internal.agent_conn.checklist.lock().await;
+        assert!(Arc::ptr_eq(&local_wins, &checklist[0]));
+    }
+    for p in [&local_wins, &remote_wins] {
+        p.state
+            .store(CandidatePairState::Succeeded as u8, Ordering::SeqCst);
+    }
+    a.internal.agent_conn.invalidate_best_valid_candidate_pair();
+    let best = a
+        .internal
+        .agent_conn
+        .get_best_valid_candidate_pair()
+        .await
+        .unwrap();
+    assert!(Arc::ptr_eq(&local_wins, &best));
+
+    // Lose a role conflict, which makes the agent controlled
+    let (_, local) = new_recording_candidate()?;
+    let mut msg = build_binding_request(&a, Box::new(AttrControlling(200))).await?;
+    a.internal
+        .handle_inbound(&mut msg, &local, SocketAddr::from_str("172.17.0.3:999")?)
+        .await;
+    assert!(!a.internal.is_controlling.load(Ordering::SeqCst));
+
+    assert!(remote_wins.priority() > local_wins.priority());
+    {
+        let checklist = a.internal.agent_conn.checklist.lock().await;
+        assert!(checklist
+            .iter()
+            .all(|p| !p.ice_role_controlling.load(Ordering::SeqCst)));
+        assert!(
+            checklist
+                .windows(2)
+                .all(|w| w[0].priority() >= w[1].priority()),
+            "checklist should be sorted by the recomputed priorities"
+        );
+        let position = |pair: &Arc<CandidatePair>| {
+            checklist.iter().position(|p| Arc::ptr_eq(p, pair)).unwrap()
+        };
+        assert!(position(&remote_wins) < position(&local_wins));
+    }
+    let best = a
+        .internal
+        .agent_conn
+        .get_best_valid_candidate_pair()
+        .await
+        .unwrap();
+    assert!(Arc::ptr_eq(&remote_wins, &best));
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_max_candidate_pairs() -> Result<()> {
+    let a = Agent::new(AgentConfig {
+        max_candidate_pairs: Some(4),
+        ..Default::default()
+    })
+    .await?;
+    a.internal.is_controlling.store(true, Ordering::SeqCst);
+
+    let local = new_prioritized_candidate("192.168.0.2", 777, 100)?;
+    for priority in 1..=8 {
+        let remote = new_prioritized_candidate("172.17.0.3", 1000 + priority as u16, priority)?;
+        a.internal
+            .add_pair(Arc::clone(&local), Arc::clone(&remote))
+            .await;
+
+        // The two lowest-priority pairs are selected and nominated before the rest is added
+        let p = a.internal.find_pair(&local, &remote).await;
+        match priority {
+            1 => a.internal.set_selected_pair(p.clone()).await,
+            2 => *a.internal.nominated_pair.lock().await = p.clone(),
+            _ => {}
+        }
+    }
+
+    let checklist = a.internal.agent_conn.checklist.lock().await;
+    assert_eq!(4, checklist.len(), "checklist should be held at the limit");
+    let remote_priorities: Vec<u32> = checklist.iter().map(|p| p.remote.priority()).collect();
+    assert_eq!(
+        vec![8, 7, 2, 1],
+        remote_priorities,
+        "should keep the highest-priority pairs and the selected and nominated one"
+    );
+    drop(checklist);
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_is_controlling() -> Result<()> {
+    for (is_controlling, tie_breaker, role, expected) in [
+        (
+            true,
+            100,
+            Box::new(AttrControlling(200)) as Box<dyn Setter>,
+            false,
+        ),
+        (true, 200, Box::new(AttrControlling(100)), true),
+        (
+            false,
+            200,
+            Box::new(AttrControlled(100)) as Box<dyn Setter>,
+            true,
+        ),
+        (false, 100, Box::new(AttrControlled(200)), false),
+    ] {
+        let a = Agent::new(AgentConfig {
+            tie_breaker: Some(tie_breaker),
+            ..Default::default()
+        })
+        .await?;
+        a.internal.set_controlling(is_controlling).await;
+        assert_eq!(is_controlling, a.is_controlling());
+
+        let (_, local) = new_recording_candidate()?;
+        let mut msg = build_binding_request(&a, role).await?;
+        a.internal
+            .handle_inbound(&mut msg, &local, SocketAddr::from_str("172.17.0.3:999")?)
+            .await;
+        assert_eq!(
+            expected,
+            a.is_controlling(),
+            "role after a conflict as controlling={} with tie-breaker {}",
+            is_controlling,
+            tie_breaker
+        );
+
+        a.close().await?;
+    }
+
+    Ok(())
+}
@@ -83,6 +83,11 @@ impl Agent {
 pub(crate) struct AgentConn {
     pub(crate) selected_pair: ArcSwapOption<CandidatePair>,
     pub(crate) checklist: Mutex<Vec<Arc<CandidatePair>>>,
+    // Cache of get_best_valid_candidate_pair, the checklist is only scanned again once the cache
+    // has been invalidated or the cached pair is no longer valid.
+    best_valid_pair: ArcSwapOption<CandidatePair>,
+    best_valid_pair_stale: AtomicBool,
+    pub(crate) best_valid_pair_scans: AtomicUsize,
 
     pub(crate) buffer: Buffer,
     pub(crate) bytes_received: AtomicUsize,
@@ -95,6 +100,9 @@ impl AgentConn {
         Self {
             selected_pair: ArcSwapOption::empty(),
             checklist: Mutex::new(vec![]),
+            best_valid_pair: ArcSwapOption::empty(),
+            best_valid_pair_stale: AtomicBool::new(true),
+            best_valid_pair_scans: AtomicUsize::new(0),
             // Make sure the buffer doesn't grow indefinitely.
             // NOTE: We actually won't get anywhere close to this limit.
             // SRTP will constantly read from the endpoint and drop packets if it's full.
@@ -118,7 +126,7 @@ impl AgentConn {
             }
 
             if let Some(b) = &mut best {
-                if b.priority() < p.priority() {
+                if b.cmp_preference(p) == std::cmp::Ordering::Less {
                     *b = p;
                 }
             } else {
@@ -129,7 +137,27 @@ impl AgentConn {
         best.cloned()
     }
 
+    /// Returns the most preferred succeeded pair, see `CandidatePair::cmp_preference`. The result
+    /// is cached, so callers that add pairs, mark a pair as succeeded or change the pair
+    /// priorities must call `invalidate_best_valid_candidate_pair`. A cached pair that stopped
+    /// being valid is noticed without it.
     pub(crate) async fn get_best_valid_candidate_pair(&self) -> Option<Arc<CandidatePair>> {
+        if !self.best_valid_pair_stale.load(Ordering::SeqCst) {
+            match self.best_valid_pair.load_full() {
+                Some(p)
+                    if p.state.load(Ordering::SeqCst) == CandidatePairState::Succeeded as u8 =>
+                {
+                    return Some(p)
+                }
+                None => return None,
+                Some(_) => {}
+            }
+        }
+
+        // Clear the flag before scanning, so an invalidation racing with the scan isn't lost
+        self.best_valid_pair_stale.store(false, Ordering::SeqCst);
+        self.best_valid_pair_scans.fetch_add(1, Ordering::SeqCst);
+
         let mut best: Option<&Arc<CandidatePair>> = None;
 
         let checklist = self.checklist.lock().await;
@@ -139,7 +167,7 @@ impl AgentConn {
             }
 
             if let Some(b) = &mut best {
-                if b.priority() < p.priority() {
+                if b.cmp_preference(p) == std::cmp::Ordering::Less {
                     *b = p;
                 }
             } else {
@@ -147,7 +175,14 @@ impl AgentConn {
             }
         }
 
-        best.cloned()
+        let best = best.cloned();
+        self.best_valid_pair.store(best.clone());
+        best
+    }
+
+    /// Forces the next `get_best_valid_candidate_pair` to scan the checklist again.
+    pub(crate) fn invalidate_best_valid_candidate_pair(&self) {
+        self.best_valid_pair_stale.store(true, Ordering::SeqCst);
     }
 
     /// Returns the number of bytes sent.
@@ -2,6 +2,7 @@ use super::agent_vnet_test::*;
 use super::*;
 
 use crate::agent::agent_transport::AgentConn;
+use crate::candidate::{candidate_base::*, candidate_host::*, candidate_relay::*};
 use util::{vnet::*, Conn};
 use waitgroup::WaitGroup;
 
@@ -130,3 +131,89 @@ async fn test_conn_stats() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_best_valid_candidate_pair_tie_break() -> Result<()> {
+    let candidate = |relay: bool,
+                     address: &str,
+                     foundation: &str|
+     -> Result<Arc<dyn Candidate + Send + Sync>> {
+        let base_config = CandidateBaseConfig {
+            network: "udp".to_owned(),
+            address: address.to_owned(),
+            port: 999,
+            component: 1,
+            // Every candidate has the same priority
+            priority: 1000,
+            foundation: foundation.to_owned(),
+            ..Default::default()
+        };
+        Ok(if relay {
+            Arc::new(
+                CandidateRelayConfig {
+                    base_config,
+                    rel_addr: "0.0.0.0".to_owned(),
+                    rel_port: 0,
+                    ..Default::default()
+                }
+                .new_candidate_relay()?,
+            )
+        } else {
+            Arc::new(
+                CandidateHostConfig {
+                    base_config,
+                    ..Default::default()
+                }
+                .new_candidate_host()?,
+            )
+        })
+    };
+    let remote = candidate(false, "172.17.0.3", "remote")?;
+    let pair = |local: Arc<dyn Candidate + Send + Sync>| {
+        let p = Arc::new(CandidatePair::new(local, Arc::clone(&remote), true));
+        p.state
+            .store(CandidatePairState::Succeeded as u8, Ordering::SeqCst);
+        p
+    };
+
+    let tests = vec![
+        // A host pair beats a relayed one of the same priority
+        (
+            pair(candidate(false, "192.168.0.2", "b")?),
+            pair(candidate(true, "10.0.0.1", "a")?),
+        ),
+        // Between candidates of the same type the lower foundation wins
+        (
+            pair(candidate(false, "192.168.0.3", "a")?),
+            pair(candidate(false, "192.168.0.2", "b")?),
+        ),
+        // and then the lower address
+        (
+            pair(candidate(false, "192.168.0.2", "a")?),
+            pair(candidate(false, "192.168.0.3", "a")?),
+        ),
+    ];
+
+    for (winner, loser) in tests {
+        assert_eq!(winner.priority(), loser.priority());
+        for checklist in [
+            vec![Arc::clone(&winner), Arc::clone(&loser)],
+            vec![Arc::clone(&loser), Arc::clone(&winner)],
+        ] {
+            let conn = AgentConn::new();
+            *conn.checklist.lock().await = checklist;
+            let best = conn
+                .get_best_valid_candidate_pair()
+                .await
+                .expect("should have a valid pair");
+            assert!(
+                Arc::ptr_eq(&winner, &best),
+                "{} should win over {}",
+                winner,
+                loser
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -40,16 +40,21 @@ use std::future::Future;
 use std::pin::Pin;
 use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::SystemTime;
 use tokio::sync::{broadcast, mpsc, Mutex};
 use tokio::time::{Duration, Instant};
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub(crate) struct BindingRequest {
     pub(crate) timestamp: Instant,
     pub(crate) transaction_id: TransactionId,
     pub(crate) destination: SocketAddr,
     pub(crate) is_use_candidate: bool,
+    // The request itself and the pair it was sent over, kept so that it can be retransmitted
+    pub(crate) message: Message,
+    pub(crate) local: Arc<dyn Candidate + Send + Sync>,
+    pub(crate) remote: Arc<dyn Candidate + Send + Sync>,
+    pub(crate) last_sent: Instant,
+    pub(crate) retransmits: u16,
 }
 
 impl Default for BindingRequest {
@@ -59,6 +64,24 @@ impl Default for BindingRequest {
             transaction_id: TransactionId::default(),
             destination: SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), 0),
             is_use_candidate: false,
+            message: Message::default(),
+            local: Arc::new(candidate_base::CandidateBase::default()),
+            remote: Arc::new(candidate_base::CandidateBase::default()),
+            last_sent: Instant::now(),
+            retransmits: 0,
+        }
+    }
+}
+
+impl BindingRequest {
+    /// The round trip time of the request, given its response arrived just now. A response to a
+    /// retransmitted request can't be told apart from one to an earlier transmission, so it gives
+    /// no measurement (Karn's algorithm).
+    pub(crate) fn round_trip_time(&self) -> Option<Duration> {
+        if self.retransmits > 0 {
+            None
+        } else {
+            Some(Instant::now().saturating_duration_since(self.timestamp))
         }
     }
 }
@@ -76,6 +99,14 @@ pub type OnSelectedCandidatePairChangeHdlrFn = Box<
         + Send
         + Sync,
 >;
+pub type OnSelectedCandidatePairTransitionHdlrFn = Box<
+    dyn (FnMut(
+            Option<Arc<CandidatePair>>,
+            Option<Arc<CandidatePair>>,
+        ) -> Pin<Box<dyn Future<Output = ()> + Send + 'static>>)
+        + Send
+        + Sync,
+>;
 pub type OnCandidateHdlrFn = Box<
     dyn (FnMut(
             Option<Arc<dyn Candidate + Send + Sync>>,
@@ -88,7 +119,7 @@ pub type GatherCandidateCancelFn = Box<dyn Fn() + Send + Sync>;
 struct ChanReceivers {
     chan_state_rx: mpsc::Receiver<ConnectionState>,
     chan_candidate_rx: mpsc::Receiver<Option<Arc<dyn Candidate + Send + Sync>>>,
-    chan_candidate_pair_rx: mpsc::Receiver<()>,
+    chan_candidate_pair_rx: mpsc::Receiver<SelectedPairTransition>,
 }
 
 /// Represents the ICE agent.
@@ -125,6 +156,10 @@ impl Agent {
             return Err(Error::ErrInvalidMulticastDnshostName);
         }
 
+        if !(0.0..1.0).contains(&config.check_interval_jitter) {
+            return Err(Error::ErrInvalidCheckIntervalJitter);
+        }
+
         let mut mdns_mode = config.multicast_dns_mode;
         if mdns_mode == MulticastDnsMode::Unspecified {
             mdns_mode = MulticastDnsMode::QueryOnly;
@@ -234,6 +269,41 @@ impl Agent {
         self.internal.agent_conn.bytes_sent()
     }
 
+    /// Returns how many success responses were discarded because they arrived from another
+    /// address than their request was sent to, which is a sign of a symmetric NAT.
+    pub fn get_symmetric_nat_discards(&self) -> u64 {
+        self.internal.discards.nat_mismatch.load(Ordering::SeqCst)
+    }
+
+    /// Returns how often keepalives are sent on the selected pair, zero means they are disabled.
+    pub fn get_keepalive_interval(&self) -> Duration {
+        *self.internal.keepalive_interval.lock()
+    }
+
+    /// Sets how often keepalives are sent on the selected pair. `Duration::ZERO` disables
+    /// them entirely, for applications that manage keepalives themselves.
+    pub fn set_keepalive_interval(&self, keepalive_interval: Duration) {
+        *self.internal.keepalive_interval.lock() = keepalive_interval;
+        // Wake the check loop so the new interval takes effect right away
+        self.internal.request_connectivity_check();
+    }
+
+    /// Pauses connectivity checks and keepalives, for when the network is known to be unavailable
+    /// for a while. A paused agent sends no checks but keeps its candidates, pairs and state.
+    pub fn pause(&self) {
+        self.internal.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Resumes connectivity checks and keepalives after `pause`, running a check cycle right away.
+    pub fn resume(&self) {
+        self.internal.resume();
+    }
+
+    /// Returns whether connectivity checks are paused.
+    pub fn is_paused(&self) -> bool {
+        self.internal.paused.load(Ordering::SeqCst)
+    }
+
     /// Sets a handler that is fired when the connection state changes.
     pub fn on_connection_state_change(&self, f: OnConnectionStateChangeHdlrFn) {
         self.internal
@@ -248,6 +318,17 @@ impl Agent {
             .store(Some(Arc::new(Mutex::new(f))))
     }
 
+    /// Sets a handler that is fired whenever the selected candidate pair changes, with the old and
+    /// the new pair. Either is `None` when no pair was or is selected anymore.
+    pub fn on_selected_candidate_pair_transition(
+        &self,
+        f: OnSelectedCandidatePairTransitionHdlrFn,
+    ) {
+        self.internal
+            .on_selected_candidate_pair_transition_hdlr
+            .store(Some(Arc::new(Mutex::new(f))))
+    }
+
     /// Sets a handler that is fired when new candidates gathered. When the gathering process
     /// complete the last candidate is nil.
     pub fn on_candidate(&self, f: OnCandidateHdlrFn) {
@@ -258,6 +339,10 @@ impl Agent {
 
     /// Adds a new remote candidate.
     pub fn add_remote_candidate(&self, c: &Arc<dyn Candidate + Send + Sync>) -> Result<()> {
+        if c.candidate_type() == CandidateType::Unspecified {
+            return Err(Error::ErrUnknownCandidateType);
+        }
+
         // cannot check for network yet because it might not be applied
         // when mDNS hostame is used.
         if c.tcp_type() == TcpType::Active {
@@ -332,7 +417,9 @@ impl Agent {
         (ufrag_pwd.remote_ufrag.clone(), ufrag_pwd.remote_pwd.clone())
     }
 
-    /// Cleans up the Agent.
+    /// Cleans up the Agent. The connectivity check loop stops, the checks in flight are
+    /// abandoned, the selected pair is cleared and the candidates' conns are closed. Closing
+    /// an agent that is already closed does nothing.
     pub async fn close(&self) -> Result<()> {
         if let Some(gather_candidate_cancel) = &self.gather_candidate_cancel {
             gather_candidate_cancel();
@@ -347,6 +434,20 @@ impl Agent {
         self.internal.close().await
     }
 
+    /// Returns whether the agent is currently in the controlling role. The role is set when the
+    /// connectivity checks start and may flip afterwards when a role conflict is resolved.
+    pub fn is_controlling(&self) -> bool {
+        self.internal.is_controlling.load(Ordering::SeqCst)
+    }
+
+    /// Returns the current connection state. Once a pair is selected, the agent is disconnected
+    /// when nothing was received on it for `disconnected_timeout` and fails when the silence
+    /// lasts another `failed_timeout`. Every change is also reported to the handler set with
+    /// `on_connection_state_change`.
+    pub fn get_connection_state(&self) -> ConnectionState {
+        self.internal.connection_state.load(Ordering::SeqCst).into()
+    }
+
     /// Returns the selected pair or nil if there is none
     pub fn get_selected_candidate_pair(&self) -> Option<Arc<CandidatePair>> {
         self.internal.agent_conn.get_selected_pair()
@@ -414,8 +515,19 @@ impl Agent {
         {
             let mut checklist = self.internal.agent_conn.checklist.lock().await;
             *checklist = vec![];
+            self.internal
+                .agent_conn
+                .invalidate_best_valid_candidate_pair();
         }
 
+        // Forget the previous nomination whatever our role is, the controlled selector keeps
+        // no state of its own on start
+        {
+            let mut nominated_pair = self.internal.nominated_pair.lock().await;
+            *nominated_pair = None;
+        }
+        *self.internal.start_time.lock() = Instant::now();
+
         self.internal.set_selected_pair(None).await;
         self.internal.delete_all_candidates().await;
         self.internal.start().await;
@@ -474,6 +586,11 @@ impl Agent {
         self.internal.get_candidate_pairs_stats().await
     }
 
+    /// Returns how many inbound STUN messages were discarded, by reason.
+    pub fn get_discard_stats(&self) -> DiscardStats {
+        self.internal.discards.snapshot()
+    }
+
     /// Returns a list of local candidates stats.
     pub async fn get_local_candidates_stats(&self) -> Vec<CandidateStats> {
         self.internal.get_local_candidates_stats().await
@@ -259,7 +259,7 @@ impl Candidate for CandidateBase {
     }
 
     fn seen(&self, outbound: bool) {
-        let d = SystemTime::now()
+        let d = system_time_now()
             .duration_since(UNIX_EPOCH)
             .unwrap_or_else(|_| Duration::from_secs(0));
 
@@ -155,3 +155,32 @@ fn test_candidate_pair_equality() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_candidate_pair_transition_state() -> Result<()> {
+    let pair = CandidatePair::new(
+        Arc::new(host_candidate()?),
+        Arc::new(srflx_candidate()?),
+        true,
+    );
+    let state = || -> CandidatePairState { pair.state.load(Ordering::SeqCst).into() };
+
+    assert!(pair.transition_state(CandidatePairState::InProgress));
+    assert!(pair.transition_state(CandidatePairState::Succeeded));
+    assert_eq!(CandidatePairState::Succeeded, state());
+
+    assert!(
+        !pair.transition_state(CandidatePairState::Frozen),
+        "a succeeded pair can't be frozen"
+    );
+    assert!(!pair.transition_state(CandidatePairState::InProgress));
+    assert!(!pair.transition_state(CandidatePairState::Unspecified));
+    assert_eq!(CandidatePairState::Succeeded, state());
+
+    // Checks restart from Waiting
+    assert!(pair.transition_state(CandidatePairState::Waiting));
+    assert!(pair.transition_state(CandidatePairState::InProgress));
+    assert_eq!(CandidatePairState::InProgress, state());
+
+    Ok(())
+}
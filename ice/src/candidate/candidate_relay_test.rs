@@ -61,6 +61,11 @@ async fn test_relay_only_connection() -> Result<(), Error> {
             }),
         }],
         channel_bind_timeout: Duration::from_secs(0),
+        allocation: turn::server::config::AllocationConfig {
+            // the agents are on loopback
+            peer_filter: turn::allocation::peer_filter::PeerFilter::allow_all(),
+            ..Default::default()
+        },
     })
     .await?;
 
@@ -24,8 +24,9 @@ use std::fmt;
 use std::net::{IpAddr, SocketAddr};
 use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU8, Ordering};
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use tokio::sync::{broadcast, Mutex};
+use util::sync::Mutex as SyncMutex;
 
 pub(crate) const RECEIVE_MTU: usize = 8192;
 pub(crate) const DEFAULT_LOCAL_PREFERENCE: u16 = 65535;
@@ -190,6 +191,11 @@ pub enum CandidatePairState {
     /// Means a check for this pair was already done and produced a successful result.
     #[serde(rename = "succeeded")]
     Succeeded = 4,
+
+    /// Means a check for this pair hasn't been performed, and it can't yet be performed until
+    /// a pair with the same foundation is checked or its foundation has nothing else to check.
+    #[serde(rename = "frozen")]
+    Frozen = 5,
 }
 
 impl From<u8> for CandidatePairState {
@@ -199,6 +205,7 @@ impl From<u8> for CandidatePairState {
             2 => Self::InProgress,
             3 => Self::Failed,
             4 => Self::Succeeded,
+            5 => Self::Frozen,
             _ => Self::Unspecified,
         }
     }
@@ -210,6 +217,20 @@ impl Default for CandidatePairState {
     }
 }
 
+impl CandidatePairState {
+    /// Whether a pair may go from `self` to `to`. Checks only start from Waiting and only a new
+    /// pair is frozen, while a check may succeed or fail at any time and checks may restart.
+    fn can_transition_to(self, to: Self) -> bool {
+        match (self, to) {
+            (_, Self::Unspecified) => false,
+            (from, to) if from == to => true,
+            (Self::Waiting, Self::InProgress | Self::Frozen) => true,
+            (_, Self::Waiting | Self::Succeeded | Self::Failed) => true,
+            _ => false,
+        }
+    }
+}
+
 impl fmt::Display for CandidatePairState {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let s = match *self {
@@ -217,6 +238,7 @@ impl fmt::Display for CandidatePairState {
             Self::InProgress => "in-progress",
             Self::Failed => "failed",
             Self::Succeeded => "succeeded",
+            Self::Frozen => "frozen",
             Self::Unspecified => "unspecified",
         };
 
@@ -232,6 +254,16 @@ pub struct CandidatePair {
     pub(crate) binding_request_count: AtomicU16,
     pub(crate) state: AtomicU8, // convert it to CandidatePairState,
     pub(crate) nominated: AtomicBool,
+    pub(crate) round_trip_time: SyncMutex<RoundTripTime>,
+}
+
+/// The round trip times measured from the connectivity check responses received on a pair.
+#[derive(Default, Debug, Clone, Copy)]
+pub(crate) struct RoundTripTime {
+    pub(crate) current: Option<Duration>,
+    pub(crate) smoothed: Option<Duration>,
+    pub(crate) total: Duration,
+    pub(crate) responses_received: u64,
 }
 
 impl Default for CandidatePair {
@@ -243,6 +275,7 @@ impl Default for CandidatePair {
             state: AtomicU8::new(CandidatePairState::Waiting as u8),
             binding_request_count: AtomicU16::new(0),
             nominated: AtomicBool::new(false),
+            round_trip_time: SyncMutex::new(RoundTripTime::default()),
         }
     }
 }
@@ -295,9 +328,59 @@ impl CandidatePair {
             state: AtomicU8::new(CandidatePairState::Waiting as u8),
             binding_request_count: AtomicU16::new(0),
             nominated: AtomicBool::new(false),
+            round_trip_time: SyncMutex::new(RoundTripTime::default()),
         }
     }
 
+    /// Moves the pair to the `new` state. An illegal transition, e.g. from Succeeded back to
+    /// Frozen, is logged and ignored, in which case false is returned.
+    pub(crate) fn transition_state(&self, new: CandidatePairState) -> bool {
+        let old: CandidatePairState = self.state.load(Ordering::SeqCst).into();
+        if !old.can_transition_to(new) {
+            log::warn!(
+                "ignoring illegal state transition of candidate pair {}: {} -> {}",
+                self,
+                old,
+                new
+            );
+            return false;
+        }
+
+        if old != new {
+            log::trace!("candidate pair {} state: {} -> {}", self, old, new);
+        }
+        self.state.store(new as u8, Ordering::SeqCst);
+        true
+    }
+
+    /// Records a connectivity check response received on the pair, along with the round trip time
+    /// measured for it if there is one. The smoothed round trip time is an exponentially weighted
+    /// moving average, updated like TCP's SRTT with a gain of 1/8 (RFC 6298 Section 2).
+    pub(crate) fn record_response(&self, rtt: Option<Duration>) {
+        let mut round_trip_time = self.round_trip_time.lock();
+        round_trip_time.responses_received += 1;
+        if let Some(rtt) = rtt {
+            round_trip_time.current = Some(rtt);
+            round_trip_time.total += rtt;
+            round_trip_time.smoothed = Some(match round_trip_time.smoothed {
+                Some(smoothed) => (smoothed * 7 + rtt) / 8,
+                None => rtt,
+            });
+        }
+    }
+
+    /// The smoothed round trip time of the pair's connectivity checks, or `None` if no response
+    /// was measured yet.
+    pub fn smoothed_round_trip_time(&self) -> Option<Duration> {
+        self.round_trip_time.lock().smoothed
+    }
+
+    /// The pair foundation, the foundations of the local and remote candidates combined. Pairs
+    /// sharing one are likely to have the same outcome, so only one of them is checked at first.
+    pub(crate) fn foundation(&self) -> (String, String) {
+        (self.local.foundation(), self.remote.foundation())
+    }
+
     /// RFC 5245 - 5.7.2.  Computing Pair Priority and Ordering Pairs
     /// Let G be the priority for the candidate provided by the controlling
     /// agent.  Let D be the priority for the candidate provided by the
@@ -317,6 +400,36 @@ impl CandidatePair {
             + u64::from(g > d)
     }
 
+    /// Orders the pairs by preference, the more preferred pair being the greater. Pairs of equal
+    /// priority are ordered by the type preference of their local, then remote candidate, so a
+    /// host pair beats a relayed one, and then by foundation and address, the lower one winning.
+    /// Distinct pairs never compare equal, so the best of a set of pairs doesn't depend on the
+    /// order they are looked at.
+    pub(crate) fn cmp_preference(&self, other: &Self) -> std::cmp::Ordering {
+        self.priority()
+            .cmp(&other.priority())
+            .then_with(|| {
+                let preference = |p: &Self| {
+                    (
+                        p.local.candidate_type().preference(),
+                        p.remote.candidate_type().preference(),
+                    )
+                };
+                preference(self).cmp(&preference(other))
+            })
+            .then_with(|| {
+                let key = |p: &Self| {
+                    (
+                        p.local.foundation(),
+                        p.remote.foundation(),
+                        p.local.addr(),
+                        p.remote.addr(),
+                    )
+                };
+                key(other).cmp(&key(self))
+            })
+    }
+
     pub async fn write(&self, b: &[u8]) -> Result<usize> {
         self.local.write_to(b, &*self.remote).await
     }
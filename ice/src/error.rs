@@ -100,6 +100,10 @@ pub enum Error {
     #[error("agent does not need URL with selected candidate types")]
     ErrUselessUrlsProvided,
 
+    /// Indicates that the check interval jitter is outside of [0, 1).
+    #[error("check interval jitter must be in [0, 1)")]
+    ErrInvalidCheckIntervalJitter,
+
     /// Indicates that the specified NAT1To1IPCandidateType is unsupported.
     #[error("unsupported 1:1 NAT IP candidate type")]
     ErrUnsupportedNat1to1IpCandidateType,
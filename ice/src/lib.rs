@@ -1,6 +1,9 @@
 #![warn(rust_2018_idioms)]
 #![allow(dead_code)]
 
+#[macro_use]
+extern crate lazy_static;
+
 pub mod agent;
 pub mod candidate;
 pub mod control;
@@ -8,10 +8,24 @@ use crate::network_type::*;
 use std::collections::HashSet;
 use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use stun::{agent::*, attributes::*, integrity::*, message::*, textattrs::*, xoraddr::*};
-use tokio::time::Duration;
+use std::time::SystemTime;
+use stun::{
+    agent::*, attributes::*, fingerprint::*, integrity::*, message::*, textattrs::*, xoraddr::*,
+};
+use tokio::time::{Duration, Instant};
 use util::{vnet::net::*, Conn};
 
+lazy_static! {
+    static ref CLOCK_ANCHOR: (SystemTime, Instant) = (SystemTime::now(), Instant::now());
+}
+
+/// Returns the wall-clock time advanced by tokio's clock rather than the system's, so candidate
+/// activity timestamps follow `tokio::time::pause` and `advance` in tests.
+pub(crate) fn system_time_now() -> SystemTime {
+    let (system_time, instant) = *CLOCK_ANCHOR;
+    system_time + instant.elapsed()
+}
+
 pub fn create_addr(_network: NetworkType, ip: IpAddr, port: u16) -> SocketAddr {
     /*if network.is_tcp(){
         return &net.TCPAddr{IP: ip, Port: port}
@@ -42,6 +56,14 @@ pub fn assert_inbound_message_integrity(m: &mut Message, key: &[u8]) -> Result<(
     Ok(message_integrity_attr.check(m)?)
 }
 
+/// Checks the FINGERPRINT of `m` if it carries one, a message without FINGERPRINT passes.
+pub fn assert_inbound_fingerprint(m: &Message) -> Result<()> {
+    if !m.contains(ATTR_FINGERPRINT) {
+        return Ok(());
+    }
+    Ok(FINGERPRINT.check(m)?)
+}
+
 /// Initiates a stun requests to `server_addr` using conn, reads the response and returns the
 /// `XORMappedAddress` returned by the stun server.
 /// Adapted from stun v0.2.
@@ -4,76 +4,328 @@ mod allocation_manager_test;
 use super::*;
 use crate::error::*;
 use crate::relay::*;
+use crate::server::request::{build_nonce, NONCE_LIFETIME};
 
 use futures::future;
 use std::collections::HashMap;
+use std::sync::atomic::AtomicBool;
+use std::sync::Weak;
 use stun::textattrs::Username;
 use util::Conn;
 
+// RelayConn is a bound relay socket along with its relayed transport address
+pub type RelayConn = (Arc<dyn Conn + Send + Sync>, SocketAddr);
+
+// AllocationParams describes the allocation a client asked for
+pub struct AllocationParams {
+    pub five_tuple: FiveTuple,
+    pub lifetime: Duration,
+    pub username: Username,
+    // requested_transport is the transport the relay speaks, whatever transport the
+    // client reached the server over
+    pub requested_transport: Protocol,
+    // dont_fragment makes a UDP relay set the DF bit
+    pub dont_fragment: bool,
+}
+
+// EVEN_PORT_MAX_RETRIES is how many relay sockets are bound looking for an even port,
+// along with the port above it when that is reserved too
+const EVEN_PORT_MAX_RETRIES: usize = 16;
+
 // ManagerConfig a bag of config params for Manager.
 pub struct ManagerConfig {
     pub relay_addr_generator: Box<dyn RelayAddressGenerator + Send + Sync>,
+    // expiry_notifier receives the FiveTuple of every allocation that expires or is closed
+    pub expiry_notifier: Option<mpsc::Sender<FiveTuple>>,
+    // max_allocations caps how many allocations the manager holds across all clients,
+    // None means no limit
+    pub max_allocations: Option<usize>,
+    // max_allocations_per_source caps how many allocations a single client source
+    // address may hold at once, None means no limit
+    pub max_allocations_per_source: Option<usize>,
+    // relay_rate_limit caps the bytes every allocation relays to its peers,
+    // None means no limit
+    pub relay_rate_limit: Option<RateLimit>,
+    // peer_filter decides which peers allocations may relay to
+    pub peer_filter: PeerFilter,
+    // nonce_lifetime is how long an issued nonce stays valid, None means the hour
+    // RFC 5766 Section 4 suggests
+    pub nonce_lifetime: Option<Duration>,
+    // idle_timeout closes allocations that have relayed nothing and weren't refreshed
+    // for longer than this, whatever lifetime they were granted. None, the default,
+    // leaves them to their lifetime. The manager must be created within a tokio
+    // runtime when it is set.
+    pub idle_timeout: Option<Duration>,
+    // min_lifetime and max_lifetime bound the lifetime granted to allocations on
+    // Allocate and Refresh. None leaves no lower bound and the hour RFC 5766 Section
+    // 6.2 recommends as the upper bound.
+    pub min_lifetime: Option<Duration>,
+    pub max_lifetime: Option<Duration>,
+    // max_permissions and max_channel_bindings cap how many permissions and channel
+    // bindings a single allocation may hold, None means no limit
+    pub max_permissions: Option<usize>,
+    pub max_channel_bindings: Option<usize>,
+    // permission_timeout is how long a permission lasts without being refreshed, None
+    // means the 5 minutes of RFC 5766 Section 8
+    pub permission_timeout: Option<Duration>,
+    // data_listener receives a copy of every payload an allocation relays to its client
+    // in a Data indication, along with the peer it came from
+    pub data_listener: Option<mpsc::Sender<(SocketAddr, Vec<u8>)>>,
+    // permission_refresh_on_traffic, see Allocation::with_permission_refresh_on_traffic
+    pub permission_refresh_on_traffic: bool,
+    // outbound_queue_size bounds the frames each allocation queues for its client, the
+    // oldest UDP frame being dropped once it is full. None writes every frame to the
+    // client before reading the next one.
+    pub outbound_queue_size: Option<usize>,
+    // too_large_listener receives the peer and size of every datagram a relay socket
+    // refused as larger than the path MTU
+    pub too_large_listener: Option<mpsc::Sender<(SocketAddr, usize)>>,
+    // recv_buffer_size is how many bytes of a datagram relay sockets read, longer UDP
+    // datagrams are truncated. None means 1500 bytes.
+    pub recv_buffer_size: Option<usize>,
 }
 
 // Manager is used to hold active allocations
 pub struct Manager {
     allocations: AllocationMap,
-    reservations: Arc<Mutex<HashMap<String, u16>>>,
+    reservations: Arc<Mutex<HashMap<String, RelayConn>>>,
     relay_addr_generator: Box<dyn RelayAddressGenerator + Send + Sync>,
+    expiry_notifier: Option<mpsc::Sender<FiveTuple>>,
+    max_allocations: Option<usize>,
+    max_allocations_per_source: Option<usize>,
+    relay_rate_limit: Option<RateLimit>,
+    peer_filter: Arc<PeerFilter>,
+    nonces: Mutex<HashMap<String, Instant>>,
+    nonce_lifetime: Duration,
+    min_lifetime: Option<Duration>,
+    max_lifetime: Option<Duration>,
+    max_permissions: Option<usize>,
+    max_channel_bindings: Option<usize>,
+    permission_timeout: Option<Duration>,
+    data_listener: Option<mpsc::Sender<(SocketAddr, Vec<u8>)>>,
+    permission_refresh_on_traffic: bool,
+    outbound_queue_size: Option<usize>,
+    too_large_listener: Option<mpsc::Sender<(SocketAddr, usize)>>,
+    recv_buffer_size: Option<usize>,
+    draining: AtomicBool,
 }
 
 impl Manager {
     // creates a new instance of Manager.
     pub fn new(config: ManagerConfig) -> Self {
+        let allocations: AllocationMap = Arc::new(Mutex::new(HashMap::new()));
+        if let Some(idle_timeout) = config.idle_timeout {
+            tokio::spawn(Manager::reap_idle_loop(
+                Arc::downgrade(&allocations),
+                idle_timeout,
+            ));
+        }
+
         Manager {
-            allocations: Arc::new(Mutex::new(HashMap::new())),
+            allocations,
             reservations: Arc::new(Mutex::new(HashMap::new())),
             relay_addr_generator: config.relay_addr_generator,
+            expiry_notifier: config.expiry_notifier,
+            max_allocations: config.max_allocations,
+            max_allocations_per_source: config.max_allocations_per_source,
+            relay_rate_limit: config.relay_rate_limit,
+            peer_filter: Arc::new(config.peer_filter),
+            nonces: Mutex::new(HashMap::new()),
+            nonce_lifetime: config.nonce_lifetime.unwrap_or(NONCE_LIFETIME),
+            min_lifetime: config.min_lifetime,
+            max_lifetime: config.max_lifetime,
+            max_permissions: config.max_permissions,
+            max_channel_bindings: config.max_channel_bindings,
+            permission_timeout: config.permission_timeout,
+            data_listener: config.data_listener,
+            permission_refresh_on_traffic: config.permission_refresh_on_traffic,
+            outbound_queue_size: config.outbound_queue_size,
+            too_large_listener: config.too_large_listener,
+            recv_buffer_size: config.recv_buffer_size,
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    // reap_idle_loop sweeps the allocations for idle ones every half idle_timeout,
+    // until the manager is dropped
+    async fn reap_idle_loop(
+        allocations: Weak<Mutex<HashMap<FiveTuple, Arc<Allocation>>>>,
+        idle_timeout: Duration,
+    ) {
+        let period = (idle_timeout / 2).max(Duration::from_millis(1));
+        loop {
+            tokio::time::sleep(period).await;
+            let allocations = match allocations.upgrade() {
+                Some(allocations) => allocations,
+                None => break,
+            };
+            Manager::reap_idle(&allocations, idle_timeout).await;
+        }
+    }
+
+    // reap_idle removes and closes the allocations idle for longer than idle_timeout
+    async fn reap_idle(allocations: &AllocationMap, idle_timeout: Duration) {
+        let idle: Vec<Arc<Allocation>> = {
+            let mut allocations = allocations.lock().await;
+            let mut idle = vec![];
+            allocations.retain(|_, a| {
+                let is_idle = a.idle_time() > idle_timeout;
+                if is_idle {
+                    idle.push(Arc::clone(a));
+                }
+                !is_idle
+            });
+            idle
+        };
+
+        for a in idle {
+            log::debug!(
+                "allocation {} idle for {:?}, closing it",
+                a.five_tuple,
+                a.idle_time()
+            );
+            if let Err(err) = a.close().await {
+                log::error!("Failed to close allocation: {}", err);
+            }
         }
     }
 
     // Close closes the manager and closes all allocations it manages
     pub async fn close(&self) -> Result<()> {
-        let allocations = self.allocations.lock().await;
-        for a in allocations.values() {
+        // Closing notifies the expiry notifier, which must not happen with the map
+        // locked as its consumer may call back into the manager
+        let allocations: Vec<Arc<Allocation>> =
+            self.allocations.lock().await.values().cloned().collect();
+        for a in allocations {
             a.close().await?;
         }
         Ok(())
     }
 
+    // close_all closes every allocation and removes it from the manager, which stops
+    // their lifetime timers and packet handlers, so none of them outlive the manager
+    pub async fn close_all(&self) {
+        let allocations: Vec<Arc<Allocation>> = {
+            let mut allocations = self.allocations.lock().await;
+            allocations.drain().map(|(_, a)| a).collect()
+        };
+
+        future::join_all(allocations.iter().map(|a| async move {
+            if let Err(err) = a.close().await {
+                log::error!("Failed to close allocation: {}", err);
+            }
+        }))
+        .await;
+    }
+
     // Returns the information about the all [`Allocation`]s associated with
     // the specified [`FiveTuple`]s.
     pub async fn get_allocations_info(
         &self,
         five_tuples: Option<Vec<FiveTuple>>,
     ) -> HashMap<FiveTuple, AllocationInfo> {
+        let allocations: Vec<Arc<Allocation>> = {
+            let guarded = self.allocations.lock().await;
+            guarded
+                .iter()
+                .filter(|(five_tuple, _)| {
+                    five_tuples
+                        .as_ref()
+                        .map_or(true, |five_tuples| five_tuples.contains(five_tuple))
+                })
+                .map(|(_, a)| Arc::clone(a))
+                .collect()
+        };
+
         let mut infos = HashMap::new();
+        for a in allocations {
+            let info = a.info().await;
+            infos.insert(info.five_tuple, info);
+        }
 
-        let guarded = self.allocations.lock().await;
-
-        guarded.iter().for_each(|(five_tuple, alloc)| {
-            if five_tuples.is_none() || five_tuples.as_ref().unwrap().contains(five_tuple) {
-                infos.insert(
-                    *five_tuple,
-                    AllocationInfo::new(
-                        *five_tuple,
-                        alloc.username.text.clone(),
-                        #[cfg(feature = "metrics")]
-                        alloc.relayed_bytes.load(Ordering::Acquire),
-                    ),
-                );
-            }
-        });
+        infos
+    }
+
+    // list returns a summary of every active allocation. The allocation map is only
+    // locked to snapshot it, each allocation is summarized after it is released.
+    pub async fn list(&self) -> Vec<AllocationInfo> {
+        let allocations: Vec<Arc<Allocation>> = {
+            let allocations = self.allocations.lock().await;
+            allocations.values().cloned().collect()
+        };
 
+        let mut infos = Vec::with_capacity(allocations.len());
+        for a in allocations {
+            infos.push(a.info().await);
+        }
         infos
     }
 
+    // drain stops the manager from creating new allocations, existing ones keep
+    // relaying and can be refreshed until they expire or are deleted
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    // is_draining reports whether drain has been called
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    // active_count returns the number of allocations currently held, so an operator
+    // can poll it after drain until it reaches zero
+    pub async fn active_count(&self) -> usize {
+        let allocations = self.allocations.lock().await;
+        allocations.len()
+    }
+
     // get_allocation fetches the allocation matching the passed FiveTuple
     pub async fn get_allocation(&self, five_tuple: &FiveTuple) -> Option<Arc<Allocation>> {
         let allocations = self.allocations.lock().await;
         allocations.get(five_tuple).map(Arc::clone)
     }
 
+    // has_allocation reports whether the passed FiveTuple currently holds an allocation,
+    // without handing out the allocation itself
+    pub async fn has_allocation(&self, five_tuple: &FiveTuple) -> bool {
+        let allocations = self.allocations.lock().await;
+        allocations.contains_key(five_tuple)
+    }
+
+    // allocations_for_src returns the FiveTuples of all allocations held by the
+    // client at src_addr, regardless of the server address or protocol
+    pub async fn allocations_for_src(&self, src_addr: &SocketAddr) -> Vec<FiveTuple> {
+        let allocations = self.allocations.lock().await;
+        allocations
+            .keys()
+            .filter(|five_tuple| five_tuple.matches_src(src_addr))
+            .copied()
+            .collect()
+    }
+
+    // refresh_allocations_for_src refreshes every allocation held by the client at
+    // src_addr and returns how many were refreshed. Each allocation clamps lifetime
+    // to its own bounds as on any refresh, and a zero lifetime deallocates them.
+    pub async fn refresh_allocations_for_src(
+        &self,
+        src_addr: &SocketAddr,
+        lifetime: Duration,
+    ) -> usize {
+        let to_refresh: Vec<Arc<Allocation>> = {
+            let allocations = self.allocations.lock().await;
+            allocations
+                .iter()
+                .filter(|(five_tuple, _)| five_tuple.matches_src(src_addr))
+                .map(|(_, a)| Arc::clone(a))
+                .collect()
+        };
+
+        for a in &to_refresh {
+            a.refresh(lifetime).await;
+        }
+        to_refresh.len()
+    }
+
     // create_allocation creates a new allocation and starts relaying
     pub async fn create_allocation(
         &self,
@@ -83,42 +335,200 @@ impl Manager {
         lifetime: Duration,
         username: Username,
     ) -> Result<Arc<Allocation>> {
+        self.create_allocation_with_dont_fragment(
+            AllocationParams {
+                five_tuple,
+                lifetime,
+                username,
+                requested_transport: PROTO_UDP,
+                dont_fragment: false,
+            },
+            turn_socket,
+            requested_port,
+            true,
+        )
+        .await
+    }
+
+    // create_allocation_with_dont_fragment creates a new allocation for params on a
+    // relay conn it binds on requested_port, or any port when it is 0, and starts
+    // relaying. The relay address is IPv4 when use_ipv4 is set and IPv6 otherwise.
+    pub async fn create_allocation_with_dont_fragment(
+        &self,
+        params: AllocationParams,
+        turn_socket: Arc<dyn Conn + Send + Sync>,
+        requested_port: u16,
+        use_ipv4: bool,
+    ) -> Result<Arc<Allocation>> {
+        self.check_new_allocation(&params.five_tuple, params.lifetime)
+            .await?;
+
+        if !self.relay_addr_generator.supports_family(use_ipv4) {
+            return Err(Error::ErrUnsupportedAddressFamily);
+        }
+
+        let relay_conn = self
+            .allocate_relay_conn(
+                use_ipv4,
+                requested_port,
+                params.requested_transport,
+                params.dont_fragment,
+            )
+            .await?;
+        self.start_allocation(params, turn_socket, relay_conn).await
+    }
+
+    // create_allocation_with_relay_conn creates a new allocation for params on a relay
+    // conn that is already bound, such as one from allocate_even_port or
+    // take_reservation, and starts relaying
+    pub async fn create_allocation_with_relay_conn(
+        &self,
+        params: AllocationParams,
+        turn_socket: Arc<dyn Conn + Send + Sync>,
+        relay_conn: RelayConn,
+    ) -> Result<Arc<Allocation>> {
+        self.check_new_allocation(&params.five_tuple, params.lifetime)
+            .await?;
+
+        self.start_allocation(params, turn_socket, relay_conn).await
+    }
+
+    // check_new_allocation refuses allocations the manager can't create before a relay
+    // socket is bound for them
+    async fn check_new_allocation(&self, five_tuple: &FiveTuple, lifetime: Duration) -> Result<()> {
+        if self.is_draining() {
+            return Err(Error::ErrServerDraining);
+        }
+
         if lifetime == Duration::from_secs(0) {
             return Err(Error::ErrLifetimeZero);
         }
 
-        if self.get_allocation(&five_tuple).await.is_some() {
+        if self.get_allocation(five_tuple).await.is_some() {
             return Err(Error::ErrDupeFiveTuple);
         }
 
-        let (relay_socket, relay_addr) = self
-            .relay_addr_generator
-            .allocate_conn(true, requested_port)
-            .await?;
-        let mut a = Allocation::new(turn_socket, relay_socket, relay_addr, five_tuple, username);
+        Ok(())
+    }
+
+    // start_allocation sets up an allocation on relay_conn, starts its lifetime timer
+    // and relay loop and adds it to the manager
+    async fn start_allocation(
+        &self,
+        params: AllocationParams,
+        turn_socket: Arc<dyn Conn + Send + Sync>,
+        (relay_socket, relay_addr): RelayConn,
+    ) -> Result<Arc<Allocation>> {
+        let AllocationParams {
+            five_tuple,
+            lifetime,
+            username,
+            requested_transport,
+            dont_fragment,
+        } = params;
+        let mut a = Allocation::new(turn_socket, relay_socket, relay_addr, five_tuple, username)
+            .with_protocol(requested_transport)
+            .with_peer_filter(Arc::clone(&self.peer_filter));
+        if let Some(rate_limit) = self.relay_rate_limit {
+            a = a.with_rate_limit(rate_limit);
+        }
+        if let Some(min_lifetime) = self.min_lifetime {
+            a = a.with_min_lifetime(min_lifetime);
+        }
+        if let Some(max_lifetime) = self.max_lifetime {
+            a = a.with_max_lifetime(max_lifetime);
+        }
+        if let Some(max_permissions) = self.max_permissions {
+            a = a.with_max_permissions(max_permissions);
+        }
+        if let Some(max_channel_bindings) = self.max_channel_bindings {
+            a = a.with_max_channel_bindings(max_channel_bindings);
+        }
+        if let Some(permission_timeout) = self.permission_timeout {
+            a = a.with_permission_timeout(permission_timeout);
+        }
+        if let Some(data_listener) = &self.data_listener {
+            a = a.with_data_listener(data_listener.clone());
+        }
+        if self.permission_refresh_on_traffic {
+            a = a.with_permission_refresh_on_traffic();
+        }
+        if let Some(outbound_queue_size) = self.outbound_queue_size {
+            a = a.with_outbound_queue(outbound_queue_size);
+        }
+        if let Some(too_large_listener) = &self.too_large_listener {
+            a = a.with_too_large_listener(too_large_listener.clone());
+        }
+        if let Some(recv_buffer_size) = self.recv_buffer_size {
+            a = a.with_recv_buffer_size(recv_buffer_size);
+        }
         a.allocations = Some(Arc::clone(&self.allocations));
+        a.expiry_notifier = self.expiry_notifier.clone();
+        a.dont_fragment = dont_fragment;
 
-        log::debug!("listening on relay addr: {:?}", a.relay_addr);
+        log::debug!("listening on relay addr: {:?}", a.relay_addr());
+
+        // Hold the map lock while the background tasks are spawned, so that a relay
+        // socket failing straight away can't try to delete the allocation before it
+        // has been inserted.
+        let mut allocations = self.allocations.lock().await;
+        // Another Allocate for the five-tuple may have won the race since it was checked
+        // above, it must not be overwritten.
+        if allocations.contains_key(&five_tuple) {
+            return Err(Error::ErrDupeFiveTuple);
+        }
+        // Two allocations sharing a relay address would make relayed traffic
+        // ambiguous, which can only come from a broken relay address generator.
+        if let Some(existing) = allocations
+            .values()
+            .find(|existing| existing.relay_addr() == relay_addr)
+        {
+            log::error!(
+                "relay addr {} of allocation {} is already used by allocation {}",
+                relay_addr,
+                five_tuple,
+                existing.five_tuple
+            );
+            return Err(Error::ErrDupeRelayAddr);
+        }
+        // Expired and closed allocations remove themselves from the map, so its size
+        // is the number of live allocations
+        if let Some(max) = self.max_allocations {
+            if allocations.len() >= max {
+                return Err(Error::ErrAllocationCapacityReached);
+            }
+        }
+        if let Some(max) = self.max_allocations_per_source {
+            let count = allocations
+                .keys()
+                .filter(|t| t.matches_src(&five_tuple.src_addr))
+                .count();
+            if count >= max {
+                return Err(Error::ErrAllocationQuotaReached);
+            }
+        }
         a.start(lifetime).await;
         a.packet_handler().await;
 
         let a = Arc::new(a);
-        {
-            let mut allocations = self.allocations.lock().await;
-            allocations.insert(five_tuple, Arc::clone(&a));
-        }
+        allocations.insert(five_tuple, Arc::clone(&a));
 
         Ok(a)
     }
 
-    // delete_allocation removes an allocation
-    pub async fn delete_allocation(&self, five_tuple: &FiveTuple) {
+    // delete_allocation removes an allocation and closes it, which stops its timers,
+    // permissions and channel bindings and releases its relay socket. It returns
+    // whether there was an allocation to delete.
+    pub async fn delete_allocation(&self, five_tuple: &FiveTuple) -> bool {
         let allocation = self.allocations.lock().await.remove(five_tuple);
 
         if let Some(a) = allocation {
             if let Err(err) = a.close().await {
                 log::error!("Failed to close allocation: {}", err);
             }
+            true
+        } else {
+            false
         }
     }
 
@@ -151,35 +561,145 @@ impl Manager {
         .await;
     }
 
-    // create_reservation stores the reservation for the token+port
-    pub async fn create_reservation(&self, reservation_token: String, port: u16) {
+    // create_reservation holds relay_conn for the token, so that nothing else can take
+    // its port before the token is redeemed. Unredeemed reservations are closed after
+    // 30 seconds.
+    pub async fn create_reservation(&self, reservation_token: String, relay_conn: RelayConn) {
         let reservations = Arc::clone(&self.reservations);
         let reservation_token2 = reservation_token.clone();
 
         tokio::spawn(async move {
-            let sleep = tokio::time::sleep(Duration::from_secs(30));
-            tokio::pin!(sleep);
-            tokio::select! {
-                _ = &mut sleep => {
-                    let mut reservations = reservations.lock().await;
-                    reservations.remove(&reservation_token2);
-                },
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            let expired = reservations.lock().await.remove(&reservation_token2);
+            if let Some((relay_socket, _)) = expired {
+                let _ = relay_socket.close().await;
             }
         });
 
         let mut reservations = self.reservations.lock().await;
-        reservations.insert(reservation_token, port);
+        reservations.insert(reservation_token, relay_conn);
     }
 
     // get_reservation returns the port for a given reservation if it exists
     pub async fn get_reservation(&self, reservation_token: &str) -> Option<u16> {
         let reservations = self.reservations.lock().await;
-        reservations.get(reservation_token).copied()
+        reservations
+            .get(reservation_token)
+            .map(|(_, relay_addr)| relay_addr.port())
+    }
+
+    // take_reservation returns the relay conn held for a given reservation and removes
+    // it, so that a token can only be redeemed once
+    pub async fn take_reservation(&self, reservation_token: &str) -> Option<RelayConn> {
+        let mut reservations = self.reservations.lock().await;
+        reservations.remove(reservation_token)
     }
 
-    // get_random_even_port returns a random un-allocated udp4 port
-    pub async fn get_random_even_port(&self) -> Result<u16> {
-        let (_, addr) = self.relay_addr_generator.allocate_conn(true, 0).await?;
-        Ok(addr.port())
+    // generate_nonce issues a new nonce for a client to authenticate its requests with
+    pub async fn generate_nonce(&self) -> Result<String> {
+        let nonce = build_nonce()?;
+
+        let mut nonces = self.nonces.lock().await;
+        if nonces.contains_key(&nonce) {
+            return Err(Error::ErrDuplicatedNonce);
+        }
+        nonces.insert(nonce.clone(), Instant::now());
+        Ok(nonce)
+    }
+
+    // validate_nonce checks the nonce of a request. It fails with ErrUnknownNonce for a
+    // nonce that was never issued, and with ErrStaleNonce for one issued longer than
+    // nonce_lifetime ago, which is forgotten so the client has to get a new one.
+    pub async fn validate_nonce(&self, nonce: &str) -> Result<()> {
+        let mut nonces = self.nonces.lock().await;
+        match nonces.get(nonce) {
+            None => Err(Error::ErrUnknownNonce),
+            Some(issued_at) if issued_at.elapsed() >= self.nonce_lifetime => {
+                nonces.remove(nonce);
+                Err(Error::ErrStaleNonce)
+            }
+            Some(_) => Ok(()),
+        }
+    }
+
+    // insert_nonce makes nonce valid as if it had been issued at issued_at
+    #[cfg(test)]
+    pub(crate) async fn insert_nonce(&self, nonce: &str, issued_at: Instant) {
+        let mut nonces = self.nonces.lock().await;
+        nonces.insert(nonce.to_owned(), issued_at);
+    }
+
+    // allocate_relay_conn binds a relay socket speaking requested_transport on
+    // requested_port, or any port when it is 0. A UDP relay socket sets the DF bit when
+    // dont_fragment is true, TCP relays are refused when the relay address generator
+    // can't bind them.
+    async fn allocate_relay_conn(
+        &self,
+        use_ipv4: bool,
+        requested_port: u16,
+        requested_transport: Protocol,
+        dont_fragment: bool,
+    ) -> Result<RelayConn> {
+        if requested_transport == PROTO_TCP {
+            if dont_fragment {
+                return Err(Error::ErrNoDontFragmentSupport);
+            }
+            self.relay_addr_generator
+                .allocate_tcp_conn(use_ipv4, requested_port)
+                .await
+        } else if dont_fragment {
+            self.relay_addr_generator
+                .allocate_conn_dont_fragment(use_ipv4, requested_port)
+                .await
+        } else {
+            self.relay_addr_generator
+                .allocate_conn(use_ipv4, requested_port)
+                .await
+        }
+    }
+
+    // allocate_even_port binds a relay socket on an even port (RFC 5766 Section 14.6).
+    // When reserve_next is set the port above it is bound as well, for the caller to
+    // hold with create_reservation. Sockets on odd ports, or whose next port is taken,
+    // are released and other ports tried.
+    pub async fn allocate_even_port(
+        &self,
+        use_ipv4: bool,
+        dont_fragment: bool,
+        reserve_next: bool,
+    ) -> Result<(RelayConn, Option<RelayConn>)> {
+        if !self.relay_addr_generator.supports_family(use_ipv4) {
+            return Err(Error::ErrUnsupportedAddressFamily);
+        }
+
+        for _ in 0..EVEN_PORT_MAX_RETRIES {
+            let (relay_socket, relay_addr) = self
+                .allocate_relay_conn(use_ipv4, 0, PROTO_UDP, dont_fragment)
+                .await?;
+            if relay_addr.port() % 2 != 0 {
+                let _ = relay_socket.close().await;
+                continue;
+            }
+            if !reserve_next {
+                return Ok(((relay_socket, relay_addr), None));
+            }
+
+            match self
+                .allocate_relay_conn(use_ipv4, relay_addr.port() + 1, PROTO_UDP, dont_fragment)
+                .await
+            {
+                Ok(next) => return Ok(((relay_socket, relay_addr), Some(next))),
+                Err(err) => {
+                    log::debug!(
+                        "port above even relay port {} is not free: {}",
+                        relay_addr.port(),
+                        err
+                    );
+                    let _ = relay_socket.close().await;
+                }
+            }
+        }
+
+        Err(Error::ErrMaxRetriesExceeded)
     }
 }
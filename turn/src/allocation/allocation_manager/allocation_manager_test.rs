@@ -7,27 +7,50 @@ use crate::{
     proto::lifetime::DEFAULT_LIFETIME,
     relay::{relay_none::*, relay_static::RelayAddressGeneratorStatic},
     server::{
-        config::{ConnConfig, ServerConfig},
+        config::{AllocationConfig, ConnConfig, ServerConfig},
         Server,
     },
+    test_util::MockConn,
 };
 
 use std::{
     net::{IpAddr, Ipv4Addr},
     str::FromStr,
 };
-use stun::{attributes::ATTR_USERNAME, textattrs::TextAttribute};
+use stun::{attributes::ATTR_USERNAME, message::*, textattrs::TextAttribute};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::UdpSocket;
 use util::vnet::net::*;
 
-fn new_test_manager() -> Manager {
-    let config = ManagerConfig {
+fn new_test_manager_config() -> ManagerConfig {
+    ManagerConfig {
         relay_addr_generator: Box::new(RelayAddressGeneratorNone {
             address: "0.0.0.0".to_owned(),
             net: Arc::new(Net::new(None)),
         }),
-    };
-    Manager::new(config)
+        expiry_notifier: None,
+        max_allocations: None,
+        max_allocations_per_source: None,
+        relay_rate_limit: None,
+        // the tests relay to peers on loopback
+        peer_filter: PeerFilter::allow_all(),
+        nonce_lifetime: None,
+        idle_timeout: None,
+        min_lifetime: None,
+        max_lifetime: None,
+        max_permissions: None,
+        max_channel_bindings: None,
+        permission_timeout: None,
+        data_listener: None,
+        permission_refresh_on_traffic: false,
+        outbound_queue_size: None,
+        too_large_listener: None,
+        recv_buffer_size: None,
+    }
+}
+
+fn new_test_manager() -> Manager {
+    Manager::new(new_test_manager_config())
 }
 
 fn random_five_tuple() -> FiveTuple {
@@ -89,12 +112,12 @@ async fn test_packet_handler() -> Result<()> {
     let port = {
         // add permission with peer1 address
         a.add_permission(Permission::new(peer_listener1.local_addr()?))
-            .await;
+            .await?;
         // add channel with min channel number and peer2 address
         a.add_channel_bind(channel_bind.clone(), DEFAULT_LIFETIME)
             .await?;
 
-        a.relay_socket.local_addr()?.port()
+        a.relay_socket().local_addr()?.port()
     };
 
     let relay_addr_with_host_str = format!("127.0.0.1:{}", port);
@@ -163,234 +186,1265 @@ async fn test_packet_handler() -> Result<()> {
 }
 
 #[tokio::test]
-async fn test_create_allocation_duplicate_five_tuple() -> Result<()> {
-    //env_logger::init();
-
-    // turn server initialization
-    let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+async fn test_rebind_relay() -> Result<()> {
+    let turn_socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let client_listener = UdpSocket::bind("127.0.0.1:0").await?;
+    let src_addr = client_listener.local_addr()?;
+    let (data_ch_tx, mut data_ch_rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        let mut buffer = vec![0u8; RTP_MTU];
+        while let Ok((n, _)) = client_listener.recv_from(&mut buffer).await {
+            let _ = data_ch_tx.send(buffer[..n].to_vec()).await;
+        }
+    });
 
     let m = new_test_manager();
-
-    let five_tuple = random_five_tuple();
-
-    let _ = m
+    let five_tuple = FiveTuple {
+        src_addr,
+        dst_addr: turn_socket.local_addr()?,
+        ..Default::default()
+    };
+    let a = m
         .create_allocation(
             five_tuple,
-            Arc::clone(&turn_socket),
+            Arc::new(turn_socket),
             0,
             DEFAULT_LIFETIME,
             TextAttribute::new(ATTR_USERNAME, "user".into()),
         )
         .await?;
 
-    let result = m
-        .create_allocation(
-            five_tuple,
-            Arc::clone(&turn_socket),
-            0,
-            DEFAULT_LIFETIME,
-            TextAttribute::new(ATTR_USERNAME, "user".into()),
-        )
-        .await;
-    assert!(result.is_err(), "expected error, but got ok");
-
-    Ok(())
-}
-
-#[tokio::test]
-async fn test_delete_allocation() -> Result<()> {
-    //env_logger::init();
-
-    // turn server initialization
-    let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
-
-    let m = new_test_manager();
-
-    let five_tuple = random_five_tuple();
-
-    let _ = m
-        .create_allocation(
-            five_tuple,
-            Arc::clone(&turn_socket),
-            0,
-            DEFAULT_LIFETIME,
-            TextAttribute::new(ATTR_USERNAME, "user".into()),
-        )
+    let permitted = UdpSocket::bind("127.0.0.1:0").await?;
+    // Permissions are per IP, the denied peer needs an address of its own
+    let denied = UdpSocket::bind("127.0.0.2:0").await?;
+    a.add_permission(Permission::new(permitted.local_addr()?))
         .await?;
 
+    let new_socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let new_addr = new_socket.local_addr()?;
+    a.rebind_relay(Arc::new(new_socket)).await?;
+    assert_eq!(new_addr, a.relay_addr());
+    assert!(a.has_permission(&permitted.local_addr()?).await);
     assert!(
         m.get_allocation(&five_tuple).await.is_some(),
-        "Failed to get allocation right after creation"
+        "closing the old socket should not delete the allocation"
     );
 
-    m.delete_allocation(&five_tuple).await;
+    // The peer without a permission is still dropped on the new socket
+    denied.send_to(b"denied", new_addr).await?;
+    permitted.send_to(b"permitted", new_addr).await?;
+    let data = data_ch_rx
+        .recv()
+        .await
+        .ok_or(Error::Other("data ch closed".to_owned()))?;
+    let mut msg = Message::new();
+    msg.raw = data;
+    msg.decode()?;
+    let mut msg_data = Data::default();
+    msg_data.get_from(&msg)?;
+    assert_eq!(b"permitted", &msg_data.0[..]);
 
-    assert!(
-        m.get_allocation(&five_tuple).await.is_none(),
-        "Get allocation with {} should be nil after delete",
-        five_tuple
-    );
+    // Traffic to the peer leaves from the new relayed address
+    a.relay_to_peer(&permitted.local_addr()?, b"to peer")
+        .await?;
+    let mut buffer = vec![0u8; RTP_MTU];
+    let (n, from) = permitted.recv_from(&mut buffer).await?;
+    assert_eq!(b"to peer", &buffer[..n]);
+    assert_eq!(new_addr, from);
+
+    m.close().await?;
+    assert!(matches!(
+        a.rebind_relay(Arc::new(UdpSocket::bind("127.0.0.1:0").await?))
+            .await,
+        Err(Error::ErrClosed)
+    ));
 
     Ok(())
 }
 
-#[tokio::test]
-async fn test_allocation_timeout() -> Result<()> {
-    //env_logger::init();
-
-    // turn server initialization
-    let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
-
-    let m = new_test_manager();
+struct FailingRelayConn;
 
-    let mut allocations = vec![];
-    let lifetime = Duration::from_millis(100);
+#[async_trait::async_trait]
+impl Conn for FailingRelayConn {
+    async fn connect(&self, _addr: SocketAddr) -> std::result::Result<(), util::Error> {
+        Err(util::Error::Other("not supported".to_owned()))
+    }
 
-    for _ in 0..5 {
-        let five_tuple = random_five_tuple();
+    async fn recv(&self, _buf: &mut [u8]) -> std::result::Result<usize, util::Error> {
+        Err(util::Error::Other("relay socket failed".to_owned()))
+    }
 
-        let a = m
-            .create_allocation(
-                five_tuple,
-                Arc::clone(&turn_socket),
-                0,
-                lifetime,
-                TextAttribute::new(ATTR_USERNAME, "user".into()),
-            )
-            .await?;
+    async fn recv_from(
+        &self,
+        _buf: &mut [u8],
+    ) -> std::result::Result<(usize, SocketAddr), util::Error> {
+        Err(util::Error::Other("relay socket failed".to_owned()))
+    }
 
-        allocations.push(a);
+    async fn send(&self, _buf: &[u8]) -> std::result::Result<usize, util::Error> {
+        Err(util::Error::Other("relay socket failed".to_owned()))
     }
 
-    let mut count = 0;
+    async fn send_to(
+        &self,
+        _buf: &[u8],
+        _target: SocketAddr,
+    ) -> std::result::Result<usize, util::Error> {
+        Err(util::Error::Other("relay socket failed".to_owned()))
+    }
 
-    'outer: loop {
-        count += 1;
+    fn local_addr(&self) -> std::result::Result<SocketAddr, util::Error> {
+        Ok(SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 5000))
+    }
 
-        if count >= 10 {
-            panic!("Allocations didn't timeout");
-        }
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        None
+    }
 
-        tokio::time::sleep(lifetime + Duration::from_millis(100)).await;
+    async fn close(&self) -> std::result::Result<(), util::Error> {
+        Ok(())
+    }
+}
 
-        let any_outstanding = false;
+struct FailingRelayAddressGenerator;
 
-        for a in &allocations {
-            if a.close().await.is_ok() {
-                continue 'outer;
-            }
-        }
+#[async_trait::async_trait]
+impl RelayAddressGenerator for FailingRelayAddressGenerator {
+    fn validate(&self) -> Result<()> {
+        Ok(())
+    }
 
-        if !any_outstanding {
-            return Ok(());
-        }
+    async fn allocate_conn(
+        &self,
+        _use_ipv4: bool,
+        _requested_port: u16,
+    ) -> Result<(Arc<dyn Conn + Send + Sync>, SocketAddr)> {
+        let conn = Arc::new(FailingRelayConn);
+        let addr = conn.local_addr()?;
+        Ok((conn, addr))
     }
 }
 
 #[tokio::test]
-async fn test_manager_close() -> Result<()> {
-    // env_logger::init();
-
-    // turn server initialization
+async fn test_packet_handler_read_error_deletes_allocation() -> Result<()> {
     let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
 
-    let m = new_test_manager();
-
-    let mut allocations = vec![];
+    let m = Manager::new(ManagerConfig {
+        relay_addr_generator: Box::new(FailingRelayAddressGenerator),
+        expiry_notifier: None,
+        max_allocations: None,
+        max_allocations_per_source: None,
+        relay_rate_limit: None,
+        peer_filter: PeerFilter::default(),
+        nonce_lifetime: None,
+        idle_timeout: None,
+        min_lifetime: None,
+        max_lifetime: None,
+        max_permissions: None,
+        max_channel_bindings: None,
+        permission_timeout: None,
+        data_listener: None,
+        permission_refresh_on_traffic: false,
+        outbound_queue_size: None,
+        too_large_listener: None,
+        recv_buffer_size: None,
+    });
 
-    let a1 = m
+    let five_tuple = random_five_tuple();
+    let a = m
         .create_allocation(
-            random_five_tuple(),
-            Arc::clone(&turn_socket),
+            five_tuple,
+            turn_socket,
             0,
-            Duration::from_millis(100),
+            DEFAULT_LIFETIME,
             TextAttribute::new(ATTR_USERNAME, "user".into()),
         )
         .await?;
-    allocations.push(a1);
 
-    let a2 = m
-        .create_allocation(
-            random_five_tuple(),
-            Arc::clone(&turn_socket),
-            0,
-            Duration::from_millis(200),
-            TextAttribute::new(ATTR_USERNAME, "user".into()),
-        )
-        .await?;
-    allocations.push(a2);
+    tokio::time::sleep(Duration::from_millis(50)).await;
 
-    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert!(
+        m.get_allocation(&five_tuple).await.is_none(),
+        "allocation should be deleted once its relay socket fails"
+    );
+    assert!(
+        a.close().await.is_err(),
+        "allocation should already be closed by the packet handler"
+    );
 
-    log::trace!("Mgr is going to be closed...");
+    Ok(())
+}
 
-    m.close().await?;
+// FixedRelayAddressGenerator binds a fresh socket every time but always reports
+// the same relay address, as a broken port selection would.
+struct FixedRelayAddressGenerator;
 
-    for a in allocations {
-        assert!(
-            a.close().await.is_err(),
-            "Allocation should be closed if lifetime timeout"
-        );
+#[async_trait::async_trait]
+impl RelayAddressGenerator for FixedRelayAddressGenerator {
+    fn validate(&self) -> Result<()> {
+        Ok(())
     }
 
-    Ok(())
+    async fn allocate_conn(
+        &self,
+        _use_ipv4: bool,
+        _requested_port: u16,
+    ) -> Result<(Arc<dyn Conn + Send + Sync>, SocketAddr)> {
+        let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+        Ok((
+            conn,
+            SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 5000),
+        ))
+    }
 }
 
 #[tokio::test]
-async fn test_delete_allocation_by_username() -> Result<()> {
-    let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
-
-    let m = new_test_manager();
+async fn test_create_allocation_duplicate_relay_addr() -> Result<()> {
+    let m = Manager::new(ManagerConfig {
+        relay_addr_generator: Box::new(FixedRelayAddressGenerator),
+        expiry_notifier: None,
+        max_allocations: None,
+        max_allocations_per_source: None,
+        relay_rate_limit: None,
+        peer_filter: PeerFilter::default(),
+        nonce_lifetime: None,
+        idle_timeout: None,
+        min_lifetime: None,
+        max_lifetime: None,
+        max_permissions: None,
+        max_channel_bindings: None,
+        permission_timeout: None,
+        data_listener: None,
+        permission_refresh_on_traffic: false,
+        outbound_queue_size: None,
+        too_large_listener: None,
+        recv_buffer_size: None,
+    });
 
-    let five_tuple1 = random_five_tuple();
-    let five_tuple2 = random_five_tuple();
-    let five_tuple3 = random_five_tuple();
+    let five_tuple = random_five_tuple();
+    let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    m.create_allocation(
+        five_tuple,
+        turn_socket,
+        0,
+        DEFAULT_LIFETIME,
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    )
+    .await?;
 
-    let _ = m
+    let other = random_five_tuple();
+    let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    let result = m
         .create_allocation(
-            five_tuple1,
-            Arc::clone(&turn_socket),
+            other,
+            turn_socket,
             0,
             DEFAULT_LIFETIME,
             TextAttribute::new(ATTR_USERNAME, "user".into()),
         )
-        .await?;
-    let _ = m
-        .create_allocation(
-            five_tuple2,
-            Arc::clone(&turn_socket),
+        .await;
+    assert!(matches!(result, Err(Error::ErrDupeRelayAddr)));
+
+    assert!(m.get_allocation(&five_tuple).await.is_some());
+    assert!(m.get_allocation(&other).await.is_none());
+
+    m.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_create_allocation_udp_and_tcp() -> Result<()> {
+    let m = new_test_manager();
+
+    let udp_five_tuple = random_five_tuple();
+    let tcp_five_tuple = FiveTuple {
+        protocol: PROTO_TCP,
+        ..udp_five_tuple
+    };
+
+    // A client over TCP usually still asks for a UDP relay
+    let tcp_client_five_tuple = FiveTuple {
+        protocol: PROTO_TCP,
+        ..random_five_tuple()
+    };
+
+    for (five_tuple, requested_transport) in [
+        (udp_five_tuple, PROTO_UDP),
+        (tcp_five_tuple, PROTO_TCP),
+        (tcp_client_five_tuple, PROTO_UDP),
+    ] {
+        let turn_socket: Arc<dyn Conn + Send + Sync> =
+            Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+        m.create_allocation_with_dont_fragment(
+            AllocationParams {
+                five_tuple,
+                lifetime: DEFAULT_LIFETIME,
+                username: TextAttribute::new(ATTR_USERNAME, "user".into()),
+                requested_transport,
+                dont_fragment: false,
+            },
+            turn_socket,
             0,
-            DEFAULT_LIFETIME,
-            TextAttribute::new(ATTR_USERNAME, "user".into()),
+            true,
         )
         .await?;
-    let _ = m
-        .create_allocation(
-            five_tuple3,
-            Arc::clone(&turn_socket),
+    }
+
+    let udp_allocation = m.get_allocation(&udp_five_tuple).await.unwrap();
+    let tcp_allocation = m.get_allocation(&tcp_five_tuple).await.unwrap();
+    let tcp_client_allocation = m.get_allocation(&tcp_client_five_tuple).await.unwrap();
+    assert_eq!(PROTO_UDP, udp_allocation.protocol);
+    assert_eq!(PROTO_TCP, tcp_allocation.protocol);
+    assert_eq!(
+        PROTO_UDP, tcp_client_allocation.protocol,
+        "the relay should speak the requested transport"
+    );
+    assert_eq!(3, m.get_allocations_info(None).await.len());
+
+    m.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tcp_relay() -> Result<()> {
+    let turn_socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    let peer_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let peer_addr = peer_listener.local_addr()?;
+
+    let mut config = new_test_manager_config();
+    config.relay_addr_generator = Box::new(RelayAddressGeneratorNone {
+        address: "127.0.0.1".to_owned(),
+        net: Arc::new(Net::new(None)),
+    });
+    let m = Manager::new(config);
+
+    let five_tuple = FiveTuple {
+        protocol: PROTO_TCP,
+        src_addr: client.local_addr()?,
+        dst_addr: turn_socket.local_addr()?,
+    };
+    let a = m
+        .create_allocation_with_dont_fragment(
+            AllocationParams {
+                five_tuple,
+                lifetime: DEFAULT_LIFETIME,
+                username: TextAttribute::new(ATTR_USERNAME, "user".into()),
+                requested_transport: PROTO_TCP,
+                dont_fragment: false,
+            },
+            Arc::new(turn_socket),
             0,
-            DEFAULT_LIFETIME,
-            TextAttribute::new(ATTR_USERNAME, "user2".into()),
+            true,
         )
         .await?;
+    a.add_permission(Permission::new(peer_addr)).await?;
 
-    assert_eq!(m.allocations.lock().await.len(), 3);
+    // The first write opens the relay connection to the peer and is framed on it
+    assert_eq!(5, a.write_to_relay(b"hello", peer_addr).await?);
+    let (mut peer, src_addr) = peer_listener.accept().await?;
+    assert_eq!(a.relay_socket().local_addr()?, src_addr);
+    let mut frame = [0u8; 7];
+    peer.read_exact(&mut frame).await?;
+    assert_eq!(b"\x00\x05hello", &frame);
 
-    m.delete_allocations_by_username("user").await;
+    assert_eq!(
+        Err(Error::ErrTcpRelayPeerMismatch),
+        a.write_to_relay(b"hello", SocketAddr::from_str("127.0.0.1:1")?)
+            .await,
+        "a TCP relay carries data for a single peer"
+    );
 
-    assert_eq!(m.allocations.lock().await.len(), 1);
+    // A frame from the peer reaches the client as a Data indication
+    peer.write_all(b"\x00\x03abc").await?;
+    let mut buffer = vec![0u8; RTP_MTU];
+    let (n, _) = client.recv_from(&mut buffer).await?;
+    let mut msg = Message::new();
+    msg.raw = buffer[..n].to_vec();
+    msg.decode()?;
+    let mut data = Data::default();
+    data.get_from(&msg)?;
+    assert_eq!(b"abc", &data.0[..]);
+
+    // The peer half-closing its connection reads as 0 bytes, which ends the allocation
+    peer.shutdown().await?;
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while m.get_allocation(&five_tuple).await.is_some() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("allocation should be removed once the peer closed its side");
 
-    assert!(
-        m.get_allocation(&five_tuple1).await.is_none()
-            && m.get_allocation(&five_tuple2).await.is_none()
-            && m.get_allocation(&five_tuple3).await.is_some()
-    );
+    m.close().await?;
 
     Ok(())
 }
 
-struct TestAuthHandler;
-impl AuthHandler for TestAuthHandler {
+#[tokio::test]
+async fn test_tcp_relay_unsupported() -> Result<()> {
+    let mut config = new_test_manager_config();
+    config.relay_addr_generator = Box::new(RelayAddressGeneratorNone {
+        address: "0.0.0.0".to_owned(),
+        net: Arc::new(Net::new(Some(NetConfig::default()))),
+    });
+    let m = Manager::new(config);
+
+    let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    let result = m
+        .create_allocation_with_dont_fragment(
+            AllocationParams {
+                five_tuple: FiveTuple {
+                    protocol: PROTO_TCP,
+                    ..random_five_tuple()
+                },
+                lifetime: DEFAULT_LIFETIME,
+                username: TextAttribute::new(ATTR_USERNAME, "user".into()),
+                requested_transport: PROTO_TCP,
+                dont_fragment: false,
+            },
+            turn_socket,
+            0,
+            true,
+        )
+        .await;
+    assert_eq!(Some(Error::ErrNoTcpRelaySupport), result.err());
+    assert!(m.get_allocations_info(None).await.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_list() -> Result<()> {
+    let m = new_test_manager();
+    assert!(m.list().await.is_empty());
+
+    let udp_five_tuple = random_five_tuple();
+    let tcp_five_tuple = FiveTuple {
+        protocol: PROTO_TCP,
+        ..random_five_tuple()
+    };
+
+    let mut relay_addrs = HashMap::new();
+    for (five_tuple, lifetime) in [
+        (udp_five_tuple, DEFAULT_LIFETIME),
+        (tcp_five_tuple, Duration::from_secs(60)),
+    ] {
+        let turn_socket: Arc<dyn Conn + Send + Sync> =
+            Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+        let a = m
+            .create_allocation_with_dont_fragment(
+                AllocationParams {
+                    five_tuple,
+                    lifetime,
+                    username: TextAttribute::new(ATTR_USERNAME, "user".into()),
+                    requested_transport: five_tuple.protocol,
+                    dont_fragment: false,
+                },
+                turn_socket,
+                0,
+                true,
+            )
+            .await?;
+        relay_addrs.insert(five_tuple, a.relay_addr());
+    }
+
+    let udp_allocation = m.get_allocation(&udp_five_tuple).await.unwrap();
+    let peer = SocketAddr::from_str("127.0.0.1:3478")?;
+    udp_allocation.add_permission(Permission::new(peer)).await?;
+    udp_allocation
+        .add_permission(Permission::new(SocketAddr::from_str("127.0.0.2:3478")?))
+        .await?;
+    udp_allocation
+        .add_channel_bind(
+            ChannelBind::new(ChannelNumber(MIN_CHANNEL_NUMBER), peer),
+            DEFAULT_LIFETIME,
+        )
+        .await?;
+
+    tokio::time::sleep(Duration::from_secs(10)).await;
+
+    let infos = m.list().await;
+    assert_eq!(2, infos.len());
+    for info in infos {
+        assert_eq!(relay_addrs[&info.five_tuple], info.relay_addr);
+        assert_eq!("user", info.username);
+        if info.five_tuple == udp_five_tuple {
+            assert_eq!(PROTO_UDP, info.protocol);
+            assert_eq!(2, info.permission_count);
+            assert_eq!(1, info.channel_count);
+            assert_eq!(
+                DEFAULT_LIFETIME - Duration::from_secs(10),
+                info.remaining_lifetime
+            );
+        } else {
+            assert_eq!(tcp_five_tuple, info.five_tuple);
+            assert_eq!(PROTO_TCP, info.protocol);
+            assert_eq!(0, info.permission_count);
+            assert_eq!(0, info.channel_count);
+            assert_eq!(Duration::from_secs(50), info.remaining_lifetime);
+        }
+    }
+
+    m.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_allocations_for_src() -> Result<()> {
+    let m = new_test_manager();
+
+    let five_tuple = random_five_tuple();
+    let other_dst = FiveTuple {
+        dst_addr: SocketAddr::new(five_tuple.dst_addr.ip(), five_tuple.dst_addr.port() ^ 1),
+        ..five_tuple
+    };
+    let other_protocol = FiveTuple {
+        protocol: PROTO_TCP,
+        ..five_tuple
+    };
+    let other_src = FiveTuple {
+        src_addr: SocketAddr::new(five_tuple.src_addr.ip(), five_tuple.src_addr.port() ^ 1),
+        ..five_tuple
+    };
+
+    for five_tuple in [five_tuple, other_dst, other_protocol, other_src] {
+        let turn_socket: Arc<dyn Conn + Send + Sync> =
+            Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+        m.create_allocation(
+            five_tuple,
+            turn_socket,
+            0,
+            DEFAULT_LIFETIME,
+            TextAttribute::new(ATTR_USERNAME, "user".into()),
+        )
+        .await?;
+    }
+
+    let mut found = m.allocations_for_src(&five_tuple.src_addr).await;
+    found.sort_by_key(|five_tuple| five_tuple.to_string());
+    let mut expected = vec![five_tuple, other_dst, other_protocol];
+    expected.sort_by_key(|five_tuple| five_tuple.to_string());
+    assert_eq!(expected, found);
+
+    assert_eq!(
+        vec![other_src],
+        m.allocations_for_src(&other_src.src_addr).await
+    );
+
+    m.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_idle_timeout() -> Result<()> {
+    let idle_timeout = Duration::from_secs(30);
+    let m = Manager::new(ManagerConfig {
+        idle_timeout: Some(idle_timeout),
+        ..new_test_manager_config()
+    });
+
+    let busy_five_tuple = random_five_tuple();
+    let idle_five_tuple = random_five_tuple();
+    for five_tuple in [busy_five_tuple, idle_five_tuple] {
+        m.create_allocation(
+            five_tuple,
+            Arc::new(UdpSocket::bind("0.0.0.0:0").await?),
+            0,
+            DEFAULT_LIFETIME,
+            TextAttribute::new(ATTR_USERNAME, "user".into()),
+        )
+        .await?;
+    }
+
+    let peer = UdpSocket::bind("127.0.0.1:0").await?;
+    let busy = m.get_allocation(&busy_five_tuple).await.unwrap();
+    busy.add_permission(Permission::new(peer.local_addr()?))
+        .await?;
+
+    // Both outlive twice the idle timeout, only one of them relays meanwhile
+    for _ in 0..6 {
+        tokio::time::sleep(idle_timeout / 3).await;
+        busy.relay_to_peer(&peer.local_addr()?, b"keepalive")
+            .await?;
+    }
+    assert!(busy.idle_time() < idle_timeout);
+
+    assert!(m.get_allocation(&busy_five_tuple).await.is_some());
+    assert!(
+        m.get_allocation(&idle_five_tuple).await.is_none(),
+        "the idle allocation should be reaped"
+    );
+
+    m.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_refresh_allocations_for_src() -> Result<()> {
+    let m = new_test_manager();
+
+    let five_tuple = random_five_tuple();
+    let mut same_src = vec![];
+    for i in 1..=3 {
+        same_src.push(FiveTuple {
+            dst_addr: SocketAddr::new(five_tuple.dst_addr.ip(), five_tuple.dst_addr.port() ^ i),
+            ..five_tuple
+        });
+    }
+    let other_src = FiveTuple {
+        src_addr: SocketAddr::new(five_tuple.src_addr.ip(), five_tuple.src_addr.port() ^ 1),
+        ..five_tuple
+    };
+
+    for five_tuple in same_src.iter().chain([&other_src]) {
+        let turn_socket: Arc<dyn Conn + Send + Sync> =
+            Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+        m.create_allocation(
+            *five_tuple,
+            turn_socket,
+            0,
+            Duration::from_secs(10),
+            TextAttribute::new(ATTR_USERNAME, "user".into()),
+        )
+        .await?;
+    }
+
+    // Far beyond the maximum lifetime, every allocation clamps it
+    let refreshed = m
+        .refresh_allocations_for_src(&five_tuple.src_addr, Duration::from_secs(24 * 60 * 60))
+        .await;
+    assert_eq!(3, refreshed);
+
+    tokio::time::sleep(Duration::from_secs(20)).await;
+    for five_tuple in &same_src {
+        assert!(m.has_allocation(five_tuple).await, "should be refreshed");
+    }
+    assert!(
+        !m.has_allocation(&other_src).await,
+        "allocation of another source should expire"
+    );
+
+    tokio::time::sleep(MAXIMUM_ALLOCATION_LIFETIME).await;
+    assert_eq!(
+        0,
+        m.active_count().await,
+        "refreshed allocations should expire at the maximum lifetime"
+    );
+
+    m.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_drain() -> Result<()> {
+    let m = new_test_manager();
+
+    let five_tuple = random_five_tuple();
+    let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    let a = m
+        .create_allocation(
+            five_tuple,
+            turn_socket,
+            0,
+            DEFAULT_LIFETIME,
+            TextAttribute::new(ATTR_USERNAME, "user".into()),
+        )
+        .await?;
+    assert_eq!(1, m.active_count().await);
+
+    m.drain();
+    assert!(m.is_draining());
+
+    let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    let result = m
+        .create_allocation(
+            random_five_tuple(),
+            turn_socket,
+            0,
+            DEFAULT_LIFETIME,
+            TextAttribute::new(ATTR_USERNAME, "user".into()),
+        )
+        .await;
+    assert!(matches!(result, Err(Error::ErrServerDraining)));
+    assert_eq!(1, m.active_count().await);
+
+    // The existing allocation is still served and can be refreshed
+    assert_eq!(DEFAULT_LIFETIME, a.refresh(DEFAULT_LIFETIME).await);
+    assert!(m.get_allocation(&five_tuple).await.is_some());
+
+    m.delete_allocation(&five_tuple).await;
+    assert_eq!(0, m.active_count().await);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_create_allocation_duplicate_five_tuple() -> Result<()> {
+    //env_logger::init();
+
+    // turn server initialization
+    let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+
+    let m = new_test_manager();
+
+    let five_tuple = random_five_tuple();
+
+    let _ = m
+        .create_allocation(
+            five_tuple,
+            Arc::clone(&turn_socket),
+            0,
+            DEFAULT_LIFETIME,
+            TextAttribute::new(ATTR_USERNAME, "user".into()),
+        )
+        .await?;
+
+    let result = m
+        .create_allocation(
+            five_tuple,
+            Arc::clone(&turn_socket),
+            0,
+            DEFAULT_LIFETIME,
+            TextAttribute::new(ATTR_USERNAME, "user".into()),
+        )
+        .await;
+    let err = result.err().expect("expected error, but got ok");
+    assert_eq!(Error::ErrDupeFiveTuple, err);
+    assert_eq!(Some(437), err.error_code().map(|c| c.0));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_create_allocation_concurrent_five_tuple() -> Result<()> {
+    let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+
+    let m = new_test_manager();
+    let five_tuple = random_five_tuple();
+    let create = || {
+        m.create_allocation(
+            five_tuple,
+            Arc::clone(&turn_socket),
+            0,
+            DEFAULT_LIFETIME,
+            TextAttribute::new(ATTR_USERNAME, "user".into()),
+        )
+    };
+
+    let (first, second) = tokio::join!(create(), create());
+    assert!(
+        first.is_ok() != second.is_ok(),
+        "exactly one Allocate should win"
+    );
+    assert_eq!(
+        Some(Error::ErrDupeFiveTuple),
+        first.err().or_else(|| second.err())
+    );
+    assert_eq!(1, m.active_count().await);
+
+    m.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_delete_allocation() -> Result<()> {
+    //env_logger::init();
+
+    // turn server initialization
+    let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+
+    let m = new_test_manager();
+
+    let five_tuple = random_five_tuple();
+
+    let _ = m
+        .create_allocation(
+            five_tuple,
+            Arc::clone(&turn_socket),
+            0,
+            DEFAULT_LIFETIME,
+            TextAttribute::new(ATTR_USERNAME, "user".into()),
+        )
+        .await?;
+
+    assert!(
+        m.get_allocation(&five_tuple).await.is_some(),
+        "Failed to get allocation right after creation"
+    );
+
+    assert!(m.delete_allocation(&five_tuple).await);
+
+    assert!(
+        m.get_allocation(&five_tuple).await.is_none(),
+        "Get allocation with {} should be nil after delete",
+        five_tuple
+    );
+    assert!(
+        !m.delete_allocation(&five_tuple).await,
+        "nothing is left to delete"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_delete_allocation_releases_relay() -> Result<()> {
+    let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+
+    let m = new_test_manager();
+
+    let five_tuple = random_five_tuple();
+    let a = m
+        .create_allocation(
+            five_tuple,
+            Arc::clone(&turn_socket),
+            0,
+            DEFAULT_LIFETIME,
+            TextAttribute::new(ATTR_USERNAME, "user".into()),
+        )
+        .await?;
+    let relay_port = a.relay_addr().port();
+    let peer = SocketAddr::from_str("127.0.0.1:5000")?;
+    a.add_permission(Permission::new(peer)).await?;
+    a.add_channel_bind(
+        ChannelBind::new(ChannelNumber(MIN_CHANNEL_NUMBER), peer),
+        DEFAULT_LIFETIME,
+    )
+    .await?;
+
+    assert!(m.delete_allocation(&five_tuple).await);
+    assert!(!m.has_allocation(&five_tuple).await);
+    assert_eq!(
+        Err(Error::ErrClosed),
+        a.close().await,
+        "allocation should be closed"
+    );
+
+    drop(a);
+    for _ in 0..10 {
+        if UdpSocket::bind(("0.0.0.0", relay_port)).await.is_ok() {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("relay port {} wasn't released", relay_port);
+}
+
+#[tokio::test]
+async fn test_has_allocation() -> Result<()> {
+    let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+
+    let m = new_test_manager();
+
+    let lifetime = Duration::from_millis(100);
+    let expiring = random_five_tuple();
+    let deleted = random_five_tuple();
+
+    assert!(!m.has_allocation(&expiring).await);
+
+    for five_tuple in [expiring, deleted] {
+        let _ = m
+            .create_allocation(
+                five_tuple,
+                Arc::clone(&turn_socket),
+                0,
+                lifetime,
+                TextAttribute::new(ATTR_USERNAME, "user".into()),
+            )
+            .await?;
+        assert!(
+            m.has_allocation(&five_tuple).await,
+            "should have an allocation right after creation"
+        );
+    }
+
+    m.delete_allocation(&deleted).await;
+    assert!(
+        !m.has_allocation(&deleted).await,
+        "should not have an allocation after delete"
+    );
+
+    for _ in 0..10 {
+        tokio::time::sleep(lifetime).await;
+        if !m.has_allocation(&expiring).await {
+            return Ok(());
+        }
+    }
+    panic!("Allocation didn't expire");
+}
+
+#[tokio::test]
+async fn test_refresh_zero_deallocates() -> Result<()> {
+    let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+
+    let m = new_test_manager();
+
+    let five_tuple = random_five_tuple();
+    let a = m
+        .create_allocation(
+            five_tuple,
+            Arc::clone(&turn_socket),
+            0,
+            DEFAULT_LIFETIME,
+            TextAttribute::new(ATTR_USERNAME, "user".into()),
+        )
+        .await?;
+    let relay_port = a.relay_addr().port();
+
+    assert_eq!(
+        Duration::from_secs(0),
+        a.refresh(Duration::from_secs(0)).await,
+        "should confirm the deallocation"
+    );
+    assert!(
+        !m.has_allocation(&five_tuple).await,
+        "allocation should be gone right after the refresh"
+    );
+    assert_eq!(Err(Error::ErrClosed), a.close().await);
+
+    drop(a);
+    for _ in 0..10 {
+        if UdpSocket::bind(("0.0.0.0", relay_port)).await.is_ok() {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("relay port {} wasn't released", relay_port);
+}
+
+#[tokio::test]
+async fn test_allocation_timeout() -> Result<()> {
+    //env_logger::init();
+
+    // turn server initialization
+    let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+
+    let m = new_test_manager();
+
+    let mut allocations = vec![];
+    let lifetime = Duration::from_millis(100);
+
+    for _ in 0..5 {
+        let five_tuple = random_five_tuple();
+
+        let a = m
+            .create_allocation(
+                five_tuple,
+                Arc::clone(&turn_socket),
+                0,
+                lifetime,
+                TextAttribute::new(ATTR_USERNAME, "user".into()),
+            )
+            .await?;
+
+        allocations.push(a);
+    }
+
+    let mut count = 0;
+
+    'outer: loop {
+        count += 1;
+
+        if count >= 10 {
+            panic!("Allocations didn't timeout");
+        }
+
+        tokio::time::sleep(lifetime + Duration::from_millis(100)).await;
+
+        let any_outstanding = false;
+
+        for a in &allocations {
+            if a.close().await.is_ok() {
+                continue 'outer;
+            }
+        }
+
+        if !any_outstanding {
+            return Ok(());
+        }
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_allocation_lifetime_expiry() -> Result<()> {
+    let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+
+    let m = new_test_manager();
+
+    let five_tuple = random_five_tuple();
+    m.create_allocation(
+        five_tuple,
+        Arc::clone(&turn_socket),
+        0,
+        DEFAULT_LIFETIME,
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    )
+    .await?;
+
+    tokio::time::sleep(DEFAULT_LIFETIME - Duration::from_secs(1)).await;
+    assert!(
+        m.has_allocation(&five_tuple).await,
+        "should not expire early"
+    );
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert!(
+        !m.has_allocation(&five_tuple).await,
+        "should expire once its lifetime has passed"
+    );
+
+    m.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_allocation_expiry_notifier() -> Result<()> {
+    let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+
+    let (expiry_tx, mut expiry_rx) = mpsc::channel(1);
+    let m = Manager::new(ManagerConfig {
+        relay_addr_generator: Box::new(RelayAddressGeneratorNone {
+            address: "0.0.0.0".to_owned(),
+            net: Arc::new(Net::new(None)),
+        }),
+        expiry_notifier: Some(expiry_tx),
+        max_allocations: None,
+        max_allocations_per_source: None,
+        relay_rate_limit: None,
+        peer_filter: PeerFilter::default(),
+        nonce_lifetime: None,
+        idle_timeout: None,
+        min_lifetime: None,
+        max_lifetime: None,
+        max_permissions: None,
+        max_channel_bindings: None,
+        permission_timeout: None,
+        data_listener: None,
+        permission_refresh_on_traffic: false,
+        outbound_queue_size: None,
+        too_large_listener: None,
+        recv_buffer_size: None,
+    });
+
+    // expired by its lifetime
+    let expired = random_five_tuple();
+    m.create_allocation(
+        expired,
+        Arc::clone(&turn_socket),
+        0,
+        Duration::from_millis(100),
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    )
+    .await?;
+
+    let notified = tokio::time::timeout(Duration::from_secs(1), expiry_rx.recv())
+        .await
+        .expect("allocation should expire");
+    assert_eq!(Some(expired), notified);
+
+    // closed explicitly
+    let deleted = random_five_tuple();
+    m.create_allocation(
+        deleted,
+        Arc::clone(&turn_socket),
+        0,
+        DEFAULT_LIFETIME,
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    )
+    .await?;
+    m.delete_allocation(&deleted).await;
+
+    let notified = tokio::time::timeout(Duration::from_secs(1), expiry_rx.recv())
+        .await
+        .expect("allocation should be closed");
+    assert_eq!(Some(deleted), notified);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_full_expiry_notifier_does_not_block_manager() -> Result<()> {
+    let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+
+    let (expiry_tx, mut expiry_rx) = mpsc::channel(1);
+    assert!(expiry_tx.try_send(random_five_tuple()).is_ok());
+    let mut config = new_test_manager_config();
+    config.expiry_notifier = Some(expiry_tx);
+    let m = Manager::new(config);
+
+    let expired = random_five_tuple();
+    m.create_allocation(
+        expired,
+        Arc::clone(&turn_socket),
+        0,
+        Duration::from_millis(100),
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    )
+    .await?;
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    // The expired allocation waits for room in the notifier, without holding up the
+    // manager meanwhile
+    let found = tokio::time::timeout(Duration::from_secs(1), m.get_allocation(&expired))
+        .await
+        .expect("manager should not be blocked by the expiry notifier");
+    assert!(
+        found.is_none(),
+        "should have removed the expired allocation"
+    );
+
+    expiry_rx.recv().await;
+    assert_eq!(Some(expired), expiry_rx.recv().await);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_manager_close() -> Result<()> {
+    // env_logger::init();
+
+    // turn server initialization
+    let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+
+    let m = new_test_manager();
+
+    let mut allocations = vec![];
+
+    let a1 = m
+        .create_allocation(
+            random_five_tuple(),
+            Arc::clone(&turn_socket),
+            0,
+            Duration::from_millis(100),
+            TextAttribute::new(ATTR_USERNAME, "user".into()),
+        )
+        .await?;
+    allocations.push(a1);
+
+    let a2 = m
+        .create_allocation(
+            random_five_tuple(),
+            Arc::clone(&turn_socket),
+            0,
+            Duration::from_millis(200),
+            TextAttribute::new(ATTR_USERNAME, "user".into()),
+        )
+        .await?;
+    allocations.push(a2);
+
+    tokio::time::sleep(Duration::from_millis(150)).await;
+
+    log::trace!("Mgr is going to be closed...");
+
+    m.close().await?;
+
+    for a in allocations {
+        assert!(
+            a.close().await.is_err(),
+            "Allocation should be closed if lifetime timeout"
+        );
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_manager_close_all() -> Result<()> {
+    let m = new_test_manager();
+
+    for _ in 0..3 {
+        let turn_socket: Arc<dyn Conn + Send + Sync> =
+            Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+        m.create_allocation(
+            random_five_tuple(),
+            turn_socket,
+            0,
+            DEFAULT_LIFETIME,
+            TextAttribute::new(ATTR_USERNAME, "user".into()),
+        )
+        .await?;
+    }
+    assert_eq!(3, m.active_count().await);
+    assert!(Arc::strong_count(&m.allocations) > 1);
+
+    m.close_all().await;
+    assert_eq!(0, m.active_count().await);
+
+    // Every lifetime timer and packet handler holds on to the map until it stops
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(
+        1,
+        Arc::strong_count(&m.allocations),
+        "background tasks should have stopped"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_delete_allocation_by_username() -> Result<()> {
+    let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+
+    let m = new_test_manager();
+
+    let five_tuple1 = random_five_tuple();
+    let five_tuple2 = random_five_tuple();
+    let five_tuple3 = random_five_tuple();
+
+    let _ = m
+        .create_allocation(
+            five_tuple1,
+            Arc::clone(&turn_socket),
+            0,
+            DEFAULT_LIFETIME,
+            TextAttribute::new(ATTR_USERNAME, "user".into()),
+        )
+        .await?;
+    let _ = m
+        .create_allocation(
+            five_tuple2,
+            Arc::clone(&turn_socket),
+            0,
+            DEFAULT_LIFETIME,
+            TextAttribute::new(ATTR_USERNAME, "user".into()),
+        )
+        .await?;
+    let _ = m
+        .create_allocation(
+            five_tuple3,
+            Arc::clone(&turn_socket),
+            0,
+            DEFAULT_LIFETIME,
+            TextAttribute::new(ATTR_USERNAME, "user2".into()),
+        )
+        .await?;
+
+    assert_eq!(m.allocations.lock().await.len(), 3);
+
+    m.delete_allocations_by_username("user").await;
+
+    assert_eq!(m.allocations.lock().await.len(), 1);
+
+    assert!(
+        m.get_allocation(&five_tuple1).await.is_none()
+            && m.get_allocation(&five_tuple2).await.is_none()
+            && m.get_allocation(&five_tuple3).await.is_some()
+    );
+
+    Ok(())
+}
+
+struct TestAuthHandler;
+impl AuthHandler for TestAuthHandler {
     fn auth_handle(&self, username: &str, realm: &str, _src_addr: SocketAddr) -> Result<Vec<u8>> {
         Ok(generate_auth_key(username, realm, "pass"))
     }
@@ -412,150 +1466,658 @@ async fn create_server() -> Result<(Server, u16)> {
         realm: "webrtc.rs".to_owned(),
         auth_handler: Arc::new(TestAuthHandler {}),
         channel_bind_timeout: Duration::from_secs(0),
+        allocation: AllocationConfig {
+            peer_filter: PeerFilter::allow_all(),
+            ..Default::default()
+        },
+    })
+    .await?;
+
+    Ok((server, server_port))
+}
+
+async fn create_client(username: String, server_port: u16) -> Result<Client> {
+    let conn = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+
+    Client::new(ClientConfig {
+        stun_serv_addr: format!("127.0.0.1:{}", server_port),
+        turn_serv_addr: format!("127.0.0.1:{}", server_port),
+        username,
+        password: "pass".to_owned(),
+        realm: String::new(),
+        software: String::new(),
+        rto_in_ms: 0,
+        conn,
+        vnet: None,
     })
+    .await
+}
+
+#[cfg(feature = "metrics")]
+#[tokio::test]
+async fn test_get_allocations_info() -> Result<()> {
+    let (server, server_port) = create_server().await?;
+
+    let client1 = create_client("user1".to_owned(), server_port).await?;
+    client1.listen().await?;
+
+    let client2 = create_client("user2".to_owned(), server_port).await?;
+    client2.listen().await?;
+
+    let client3 = create_client("user3".to_owned(), server_port).await?;
+    client3.listen().await?;
+
+    assert!(server.get_allocations_info(None).await?.is_empty());
+
+    let user1 = client1.allocate().await?;
+    let user2 = client2.allocate().await?;
+    let user3 = client3.allocate().await?;
+
+    assert_eq!(server.get_allocations_info(None).await?.len(), 3);
+
+    let addr1 = client1
+        .send_binding_request_to(format!("127.0.0.1:{}", server_port).as_str())
+        .await?;
+    let addr2 = client2
+        .send_binding_request_to(format!("127.0.0.1:{}", server_port).as_str())
+        .await?;
+    let addr3 = client3
+        .send_binding_request_to(format!("127.0.0.1:{}", server_port).as_str())
+        .await?;
+
+    user1.send_to(b"1", addr1).await?;
+    user2.send_to(b"12", addr2).await?;
+    user3.send_to(b"123", addr3).await?;
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    server
+        .get_allocations_info(None)
+        .await?
+        .iter()
+        .for_each(|(_, ai)| match ai.username.as_str() {
+            "user1" => assert_eq!(ai.relayed_bytes, 1),
+            "user2" => assert_eq!(ai.relayed_bytes, 2),
+            "user3" => assert_eq!(ai.relayed_bytes, 3),
+            _ => unreachable!(),
+        });
+
+    Ok(())
+}
+
+#[cfg(feature = "metrics")]
+#[tokio::test]
+async fn test_get_allocations_info_bytes_count() -> Result<()> {
+    let (server, server_port) = create_server().await?;
+
+    let client = create_client("foo".to_owned(), server_port).await?;
+
+    client.listen().await?;
+
+    assert!(server.get_allocations_info(None).await?.is_empty());
+
+    let conn = client.allocate().await?;
+    let addr = client
+        .send_binding_request_to(format!("127.0.0.1:{}", server_port).as_str())
+        .await?;
+
+    assert!(!server.get_allocations_info(None).await?.is_empty());
+
+    assert_eq!(
+        server
+            .get_allocations_info(None)
+            .await?
+            .values()
+            .last()
+            .unwrap()
+            .relayed_bytes,
+        0
+    );
+
+    for _ in 0..10 {
+        conn.send_to(b"Hello", addr).await?;
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    tokio::time::sleep(Duration::from_millis(1000)).await;
+
+    assert_eq!(
+        server
+            .get_allocations_info(None)
+            .await?
+            .values()
+            .last()
+            .unwrap()
+            .relayed_bytes,
+        50
+    );
+
+    for _ in 0..10 {
+        conn.send_to(b"Hello", addr).await?;
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    tokio::time::sleep(Duration::from_millis(1000)).await;
+
+    assert_eq!(
+        server
+            .get_allocations_info(None)
+            .await?
+            .values()
+            .last()
+            .unwrap()
+            .relayed_bytes,
+        100
+    );
+
+    client.close().await?;
+    server.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_max_allocations() -> Result<()> {
+    const MAX_ALLOCATIONS: usize = 2;
+
+    let m = Manager::new(ManagerConfig {
+        relay_addr_generator: Box::new(RelayAddressGeneratorNone {
+            address: "0.0.0.0".to_owned(),
+            net: Arc::new(Net::new(None)),
+        }),
+        expiry_notifier: None,
+        max_allocations: Some(MAX_ALLOCATIONS),
+        max_allocations_per_source: None,
+        relay_rate_limit: None,
+        peer_filter: PeerFilter::default(),
+        nonce_lifetime: None,
+        idle_timeout: None,
+        min_lifetime: None,
+        max_lifetime: None,
+        max_permissions: None,
+        max_channel_bindings: None,
+        permission_timeout: None,
+        data_listener: None,
+        permission_refresh_on_traffic: false,
+        outbound_queue_size: None,
+        too_large_listener: None,
+        recv_buffer_size: None,
+    });
+
+    let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+
+    // The first allocation expires quickly, the second one lives on
+    for lifetime in [Duration::from_millis(100), DEFAULT_LIFETIME] {
+        m.create_allocation(
+            random_five_tuple(),
+            Arc::clone(&turn_socket),
+            0,
+            lifetime,
+            TextAttribute::new(ATTR_USERNAME, "user".into()),
+        )
+        .await?;
+    }
+
+    let err = m
+        .create_allocation(
+            random_five_tuple(),
+            Arc::clone(&turn_socket),
+            0,
+            DEFAULT_LIFETIME,
+            TextAttribute::new(ATTR_USERNAME, "user".into()),
+        )
+        .await
+        .err()
+        .expect("should refuse allocations beyond the global limit");
+    assert_eq!(Error::ErrAllocationCapacityReached, err);
+    assert_eq!(Some(508), err.error_code().map(|c| c.0));
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    m.create_allocation(
+        random_five_tuple(),
+        turn_socket,
+        0,
+        DEFAULT_LIFETIME,
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    )
+    .await?;
+    assert_eq!(MAX_ALLOCATIONS, m.active_count().await);
+
+    m.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_max_allocations_per_source() -> Result<()> {
+    const MAX_ALLOCATIONS: usize = 2;
+
+    let m = Manager::new(ManagerConfig {
+        relay_addr_generator: Box::new(RelayAddressGeneratorNone {
+            address: "0.0.0.0".to_owned(),
+            net: Arc::new(Net::new(None)),
+        }),
+        expiry_notifier: None,
+        max_allocations: None,
+        max_allocations_per_source: Some(MAX_ALLOCATIONS),
+        relay_rate_limit: None,
+        peer_filter: PeerFilter::default(),
+        nonce_lifetime: None,
+        idle_timeout: None,
+        min_lifetime: None,
+        max_lifetime: None,
+        max_permissions: None,
+        max_channel_bindings: None,
+        permission_timeout: None,
+        data_listener: None,
+        permission_refresh_on_traffic: false,
+        outbound_queue_size: None,
+        too_large_listener: None,
+        recv_buffer_size: None,
+    });
+
+    let src_five_tuple = random_five_tuple();
+    let five_tuple_with_dst = |port: u16| FiveTuple {
+        dst_addr: SocketAddr::new(src_five_tuple.dst_addr.ip(), port),
+        ..src_five_tuple
+    };
+
+    // The first allocation expires quickly, the second one lives on
+    for (port, lifetime) in [(1000, Duration::from_millis(100)), (1001, DEFAULT_LIFETIME)] {
+        let turn_socket: Arc<dyn Conn + Send + Sync> =
+            Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+        m.create_allocation(
+            five_tuple_with_dst(port),
+            turn_socket,
+            0,
+            lifetime,
+            TextAttribute::new(ATTR_USERNAME, "user".into()),
+        )
+        .await?;
+    }
+
+    let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    let result = m
+        .create_allocation(
+            five_tuple_with_dst(1002),
+            Arc::clone(&turn_socket),
+            0,
+            DEFAULT_LIFETIME,
+            TextAttribute::new(ATTR_USERNAME, "user".into()),
+        )
+        .await;
+    assert_eq!(Some(Error::ErrAllocationQuotaReached), result.err());
+
+    // Other clients are not affected
+    m.create_allocation(
+        random_five_tuple(),
+        Arc::clone(&turn_socket),
+        0,
+        DEFAULT_LIFETIME,
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    )
     .await?;
 
-    Ok((server, server_port))
-}
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    m.create_allocation(
+        five_tuple_with_dst(1002),
+        Arc::clone(&turn_socket),
+        0,
+        DEFAULT_LIFETIME,
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    )
+    .await?;
 
-async fn create_client(username: String, server_port: u16) -> Result<Client> {
-    let conn = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    // Deleting an allocation frees its slot as well
+    m.delete_allocation(&five_tuple_with_dst(1001)).await;
+    m.create_allocation(
+        five_tuple_with_dst(1003),
+        turn_socket,
+        0,
+        DEFAULT_LIFETIME,
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    )
+    .await?;
+    assert_eq!(
+        MAX_ALLOCATIONS,
+        m.allocations_for_src(&src_five_tuple.src_addr).await.len()
+    );
 
-    Client::new(ClientConfig {
-        stun_serv_addr: format!("127.0.0.1:{}", server_port),
-        turn_serv_addr: format!("127.0.0.1:{}", server_port),
-        username,
-        password: "pass".to_owned(),
-        realm: String::new(),
-        software: String::new(),
-        rto_in_ms: 0,
-        conn,
-        vnet: None,
-    })
-    .await
+    m.close().await?;
+
+    Ok(())
 }
 
-#[cfg(feature = "metrics")]
-#[tokio::test]
-async fn test_get_allocations_info() -> Result<()> {
-    let (server, server_port) = create_server().await?;
+#[tokio::test(start_paused = true)]
+async fn test_nonces() -> Result<()> {
+    let m = Manager::new(ManagerConfig {
+        relay_addr_generator: Box::new(RelayAddressGeneratorNone {
+            address: "0.0.0.0".to_owned(),
+            net: Arc::new(Net::new(None)),
+        }),
+        expiry_notifier: None,
+        max_allocations: None,
+        max_allocations_per_source: None,
+        relay_rate_limit: None,
+        peer_filter: PeerFilter::default(),
+        nonce_lifetime: Some(Duration::from_secs(60)),
+        idle_timeout: None,
+        min_lifetime: None,
+        max_lifetime: None,
+        max_permissions: None,
+        max_channel_bindings: None,
+        permission_timeout: None,
+        data_listener: None,
+        permission_refresh_on_traffic: false,
+        outbound_queue_size: None,
+        too_large_listener: None,
+        recv_buffer_size: None,
+    });
 
-    let client1 = create_client("user1".to_owned(), server_port).await?;
-    client1.listen().await?;
+    let nonce = m.generate_nonce().await?;
+    m.validate_nonce(&nonce).await?;
 
-    let client2 = create_client("user2".to_owned(), server_port).await?;
-    client2.listen().await?;
+    tokio::time::sleep(Duration::from_secs(59)).await;
+    m.validate_nonce(&nonce).await?;
 
-    let client3 = create_client("user3".to_owned(), server_port).await?;
-    client3.listen().await?;
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let err = m.validate_nonce(&nonce).await.unwrap_err();
+    assert_eq!(Error::ErrStaleNonce, err);
+    assert_eq!(Some(438), err.error_code().map(|c| c.0));
 
-    assert!(server.get_allocations_info(None).await?.is_empty());
+    let err = m.validate_nonce("unknown").await.unwrap_err();
+    assert_eq!(Error::ErrUnknownNonce, err);
+    assert_eq!(Some(401), err.error_code().map(|c| c.0));
 
-    let user1 = client1.allocate().await?;
-    let user2 = client2.allocate().await?;
-    let user3 = client3.allocate().await?;
+    Ok(())
+}
 
-    assert_eq!(server.get_allocations_info(None).await?.len(), 3);
+#[tokio::test]
+async fn test_allocation_quotas() -> Result<()> {
+    let m = Manager::new(ManagerConfig {
+        max_permissions: Some(1),
+        max_channel_bindings: Some(1),
+        ..new_test_manager_config()
+    });
 
-    let addr1 = client1
-        .send_binding_request_to(format!("127.0.0.1:{}", server_port).as_str())
-        .await?;
-    let addr2 = client2
-        .send_binding_request_to(format!("127.0.0.1:{}", server_port).as_str())
-        .await?;
-    let addr3 = client3
-        .send_binding_request_to(format!("127.0.0.1:{}", server_port).as_str())
+    let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    let a = m
+        .create_allocation(
+            random_five_tuple(),
+            turn_socket,
+            0,
+            DEFAULT_LIFETIME,
+            TextAttribute::new(ATTR_USERNAME, "user".into()),
+        )
         .await?;
 
-    user1.send_to(b"1", addr1).await?;
-    user2.send_to(b"12", addr2).await?;
-    user3.send_to(b"123", addr3).await?;
+    let peer = SocketAddr::from_str("127.0.0.1:5000")?;
+    let other_peer = SocketAddr::from_str("127.0.0.2:5000")?;
+    a.add_permission(Permission::new(peer)).await?;
+    assert_eq!(
+        Err(Error::ErrTooManyPermissions),
+        a.add_permission(Permission::new(other_peer)).await
+    );
 
-    tokio::time::sleep(Duration::from_millis(100)).await;
+    a.add_channel_bind(
+        ChannelBind::new(ChannelNumber(MIN_CHANNEL_NUMBER), peer),
+        DEFAULT_LIFETIME,
+    )
+    .await?;
+    assert_eq!(
+        Err(Error::ErrTooManyChannelBindings),
+        a.add_channel_bind(
+            ChannelBind::new(ChannelNumber(MIN_CHANNEL_NUMBER + 1), other_peer),
+            DEFAULT_LIFETIME,
+        )
+        .await
+    );
 
-    server
-        .get_allocations_info(None)
-        .await?
-        .iter()
-        .for_each(|(_, ai)| match ai.username.as_str() {
-            "user1" => assert_eq!(ai.relayed_bytes, 1),
-            "user2" => assert_eq!(ai.relayed_bytes, 2),
-            "user3" => assert_eq!(ai.relayed_bytes, 3),
-            _ => unreachable!(),
-        });
+    m.close().await?;
 
     Ok(())
 }
 
-#[cfg(feature = "metrics")]
 #[tokio::test]
-async fn test_get_allocations_info_bytes_count() -> Result<()> {
-    let (server, server_port) = create_server().await?;
+async fn test_allocate_even_port() -> Result<()> {
+    let m = new_test_manager();
 
-    let client = create_client("foo".to_owned(), server_port).await?;
+    for _ in 0..4 {
+        let ((_, even_addr), next) = m.allocate_even_port(true, false, false).await?;
+        assert_eq!(0, even_addr.port() % 2, "should bind an even port");
+        assert!(
+            next.is_none(),
+            "should not bind the next port unless asked to"
+        );
+    }
 
-    client.listen().await?;
+    let ((_, even_addr), next) = m.allocate_even_port(true, false, true).await?;
+    assert_eq!(0, even_addr.port() % 2);
+    let (_next_socket, next_addr) = next.expect("should bind the next port");
+    assert_eq!(even_addr.port() + 1, next_addr.port());
+    assert!(
+        UdpSocket::bind(("0.0.0.0", next_addr.port()))
+            .await
+            .is_err(),
+        "should hold the next port while the conn is alive"
+    );
 
-    assert!(server.get_allocations_info(None).await?.is_empty());
+    Ok(())
+}
 
-    let conn = client.allocate().await?;
-    let addr = client
-        .send_binding_request_to(format!("127.0.0.1:{}", server_port).as_str())
+#[tokio::test(start_paused = true)]
+async fn test_permission_timeout() -> Result<()> {
+    let m = Manager::new(ManagerConfig {
+        permission_timeout: Some(Duration::from_secs(1)),
+        ..new_test_manager_config()
+    });
+
+    let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    let a = m
+        .create_allocation(
+            random_five_tuple(),
+            turn_socket,
+            0,
+            DEFAULT_LIFETIME,
+            TextAttribute::new(ATTR_USERNAME, "user".into()),
+        )
         .await?;
 
-    assert!(!server.get_allocations_info(None).await?.is_empty());
+    let peer = SocketAddr::from_str("127.0.0.1:5000")?;
+    a.add_permission(Permission::new(peer)).await?;
 
-    assert_eq!(
-        server
-            .get_allocations_info(None)
-            .await?
-            .values()
-            .last()
-            .unwrap()
-            .relayed_bytes,
-        0
+    tokio::time::sleep(Duration::from_millis(900)).await;
+    assert!(a.has_permission(&peer).await, "should not expire early");
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(
+        !a.has_permission(&peer).await,
+        "should expire after the configured timeout"
     );
 
-    for _ in 0..10 {
-        conn.send_to(b"Hello", addr).await?;
+    m.close().await?;
 
-        tokio::time::sleep(Duration::from_millis(100)).await;
-    }
+    Ok(())
+}
 
-    tokio::time::sleep(Duration::from_millis(1000)).await;
+#[tokio::test]
+async fn test_data_listener() -> Result<()> {
+    let (listener_tx, mut listener_rx) = mpsc::channel(1);
+    let m = Manager::new(ManagerConfig {
+        data_listener: Some(listener_tx),
+        ..new_test_manager_config()
+    });
+
+    let turn_socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    let a = m
+        .create_allocation(
+            FiveTuple {
+                src_addr: client.local_addr()?,
+                dst_addr: turn_socket.local_addr()?,
+                ..Default::default()
+            },
+            Arc::new(turn_socket),
+            0,
+            DEFAULT_LIFETIME,
+            TextAttribute::new(ATTR_USERNAME, "user".into()),
+        )
+        .await?;
 
+    let peer = UdpSocket::bind("127.0.0.1:0").await?;
+    let peer_addr = peer.local_addr()?;
+    a.add_permission(Permission::new(peer_addr)).await?;
+
+    let relay_port = a.relay_socket().local_addr()?.port();
+    peer.send_to(b"payload", ("127.0.0.1", relay_port)).await?;
     assert_eq!(
-        server
-            .get_allocations_info(None)
-            .await?
-            .values()
-            .last()
-            .unwrap()
-            .relayed_bytes,
-        50
+        Some((peer_addr, b"payload".to_vec())),
+        listener_rx.recv().await
     );
 
-    for _ in 0..10 {
-        conn.send_to(b"Hello", addr).await?;
+    m.close().await?;
 
-        tokio::time::sleep(Duration::from_millis(100)).await;
-    }
+    Ok(())
+}
 
-    tokio::time::sleep(Duration::from_millis(1000)).await;
+#[tokio::test(start_paused = true)]
+async fn test_permission_refresh_on_traffic() -> Result<()> {
+    let m = Manager::new(ManagerConfig {
+        permission_timeout: Some(Duration::from_secs(1)),
+        permission_refresh_on_traffic: true,
+        ..new_test_manager_config()
+    });
+
+    let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    let a = m
+        .create_allocation(
+            random_five_tuple(),
+            turn_socket,
+            0,
+            DEFAULT_LIFETIME,
+            TextAttribute::new(ATTR_USERNAME, "user".into()),
+        )
+        .await?;
+
+    let peer = SocketAddr::from_str("127.0.0.1:5000")?;
+    a.add_permission(Permission::new(peer)).await?;
+
+    tokio::time::sleep(Duration::from_millis(900)).await;
+    a.write_to_relay(b"payload", peer).await?;
+    tokio::time::sleep(Duration::from_millis(900)).await;
+    assert!(
+        a.has_permission(&peer).await,
+        "should be refreshed by the relayed traffic"
+    );
+
+    m.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_outbound_queue_size() -> Result<()> {
+    let m = Manager::new(ManagerConfig {
+        outbound_queue_size: Some(4),
+        ..new_test_manager_config()
+    });
 
+    let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    let a = m
+        .create_allocation(
+            random_five_tuple(),
+            turn_socket,
+            0,
+            DEFAULT_LIFETIME,
+            TextAttribute::new(ATTR_USERNAME, "user".into()),
+        )
+        .await?;
+    assert_eq!(Some(4), a.outbound_high_water_mark);
+
+    m.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_too_large_listener() -> Result<()> {
+    let (listener_tx, mut listener_rx) = mpsc::channel(1);
+    let m = Manager::new(ManagerConfig {
+        too_large_listener: Some(listener_tx),
+        ..new_test_manager_config()
+    });
+
+    let relay_addr = SocketAddr::from_str("192.0.2.1:50000")?;
+    let relay_socket = Arc::new(MockConn::new(relay_addr).with_max_datagram_size(4));
+    let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    let a = m
+        .create_allocation_with_relay_conn(
+            AllocationParams {
+                five_tuple: random_five_tuple(),
+                lifetime: DEFAULT_LIFETIME,
+                username: TextAttribute::new(ATTR_USERNAME, "user".into()),
+                requested_transport: PROTO_UDP,
+                dont_fragment: true,
+            },
+            turn_socket,
+            (relay_socket, relay_addr),
+        )
+        .await?;
+
+    let peer = SocketAddr::from_str("127.0.0.1:5000")?;
     assert_eq!(
-        server
-            .get_allocations_info(None)
-            .await?
-            .values()
-            .last()
-            .unwrap()
-            .relayed_bytes,
-        100
+        Err(Error::ErrDatagramTooLarge),
+        a.write_to_relay(b"too large", peer).await
     );
+    assert_eq!(Some((peer, 9)), listener_rx.recv().await);
 
-    client.close().await?;
-    server.close().await?;
+    m.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_recv_buffer_size() -> Result<()> {
+    let m = Manager::new(ManagerConfig {
+        recv_buffer_size: Some(4),
+        ..new_test_manager_config()
+    });
+
+    let turn_socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    let a = m
+        .create_allocation(
+            FiveTuple {
+                src_addr: client.local_addr()?,
+                dst_addr: turn_socket.local_addr()?,
+                ..Default::default()
+            },
+            Arc::new(turn_socket),
+            0,
+            DEFAULT_LIFETIME,
+            TextAttribute::new(ATTR_USERNAME, "user".into()),
+        )
+        .await?;
+
+    let peer = UdpSocket::bind("127.0.0.1:0").await?;
+    a.add_permission(Permission::new(peer.local_addr()?))
+        .await?;
+
+    let relay_port = a.relay_socket().local_addr()?.port();
+    peer.send_to(b"too large", ("127.0.0.1", relay_port))
+        .await?;
+
+    let mut buffer = vec![0u8; RTP_MTU];
+    let n = client.recv(&mut buffer).await?;
+    let mut msg = Message::new();
+    msg.raw = buffer[..n].to_vec();
+    msg.decode()?;
+    let mut data = Data::default();
+    data.get_from(&msg)?;
+    assert_eq!(b"too ", &data.0[..]);
+
+    m.close().await?;
 
     Ok(())
 }
@@ -1,8 +1,9 @@
 use super::*;
 
-use crate::proto::lifetime::DEFAULT_LIFETIME;
+use crate::proto::{lifetime::DEFAULT_LIFETIME, peeraddr::PeerAddress};
+use crate::test_util::MockConn;
 use std::str::FromStr;
-use stun::{attributes::ATTR_USERNAME, textattrs::TextAttribute};
+use stun::{attributes::ATTR_USERNAME, message::*, textattrs::TextAttribute};
 use tokio::net::UdpSocket;
 
 #[tokio::test]
@@ -26,9 +27,9 @@ async fn test_has_permission() -> Result<()> {
     let p2 = Permission::new(addr2);
     let p3 = Permission::new(addr3);
 
-    a.add_permission(p1).await;
-    a.add_permission(p2).await;
-    a.add_permission(p3).await;
+    a.add_permission(p1).await?;
+    a.add_permission(p2).await?;
+    a.add_permission(p3).await?;
 
     let found_p1 = a.has_permission(&addr1).await;
     assert!(found_p1, "Should keep the first one.");
@@ -42,6 +43,36 @@ async fn test_has_permission() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_try_has_permission() -> Result<()> {
+    let turn_socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    let relay_socket = Arc::clone(&turn_socket);
+    let relay_addr = relay_socket.local_addr()?;
+    let a = Allocation::new(
+        turn_socket,
+        relay_socket,
+        relay_addr,
+        FiveTuple::default(),
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    );
+
+    let addr = SocketAddr::from_str("127.0.0.1:3478")?;
+    let other = SocketAddr::from_str("127.0.0.2:3478")?;
+    a.add_permission(Permission::new(addr)).await?;
+
+    assert_eq!(Ok(true), a.try_has_permission(&addr));
+    assert_eq!(Ok(false), a.try_has_permission(&other));
+
+    // a task holding the lock is reported instead of looking like a missing permission
+    {
+        let _permissions = a.permissions.lock().await;
+        assert_eq!(Err(Error::ErrPermissionsBusy), a.try_has_permission(&addr));
+    }
+    assert_eq!(Ok(true), a.try_has_permission(&addr));
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_add_permission() -> Result<()> {
     let turn_socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
@@ -57,7 +88,7 @@ async fn test_add_permission() -> Result<()> {
 
     let addr = SocketAddr::from_str("127.0.0.1:3478")?;
     let p = Permission::new(addr);
-    a.add_permission(p).await;
+    a.add_permission(p).await?;
 
     let found_p = a.has_permission(&addr).await;
     assert!(found_p, "Should keep the first one.");
@@ -65,6 +96,97 @@ async fn test_add_permission() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test(start_paused = true)]
+async fn test_permission_timeout() -> Result<()> {
+    let turn_socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    let relay_socket = Arc::clone(&turn_socket);
+    let relay_addr = relay_socket.local_addr()?;
+    let a = Allocation::new(
+        turn_socket,
+        relay_socket,
+        relay_addr,
+        FiveTuple::default(),
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    )
+    .with_permission_timeout(Duration::from_secs(1));
+
+    let addr = SocketAddr::from_str("127.0.0.1:3478")?;
+    a.add_permission(Permission::new(addr)).await?;
+
+    tokio::time::sleep(Duration::from_millis(900)).await;
+    assert!(a.has_permission(&addr).await, "should not expire early");
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(
+        !a.has_permission(&addr).await,
+        "should expire after the configured timeout"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_add_permission_same_ip() -> Result<()> {
+    let turn_socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    let relay_socket = Arc::clone(&turn_socket);
+    let relay_addr = relay_socket.local_addr()?;
+    let a = Allocation::new(
+        turn_socket,
+        relay_socket,
+        relay_addr,
+        FiveTuple::default(),
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    );
+
+    let addr1 = SocketAddr::from_str("127.0.0.1:3478")?;
+    let addr2 = SocketAddr::from_str("127.0.0.1:3479")?;
+    a.add_permission(Permission::new(addr1)).await?;
+    a.add_permission(Permission::new(addr2)).await?;
+
+    assert_eq!(1, a.permission_count().await, "one permission per IP");
+    assert!(a.has_permission(&addr1).await);
+    assert!(a.has_permission(&addr2).await);
+    assert!(
+        a.has_permission(&SocketAddr::from_str("127.0.0.1:1")?)
+            .await
+    );
+    assert_eq!(
+        vec![addr2],
+        a.permissions().await,
+        "should keep the most recent peer address"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_add_permission_ipv4_mapped() -> Result<()> {
+    let turn_socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    let relay_socket = Arc::clone(&turn_socket);
+    let relay_addr = relay_socket.local_addr()?;
+    let a = Allocation::new(
+        turn_socket,
+        relay_socket,
+        relay_addr,
+        FiveTuple::default(),
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    );
+
+    let v4 = SocketAddr::from_str("1.2.3.4:3478")?;
+    let mapped = SocketAddr::from_str("[::ffff:1.2.3.4]:3478")?;
+    a.add_permission(Permission::new(v4)).await?;
+    a.add_permission(Permission::new(mapped)).await?;
+
+    assert_eq!(1, a.permission_count().await, "one permission per host");
+    assert!(a.has_permission(&v4).await);
+    assert!(a.has_permission(&mapped).await);
+
+    a.remove_permission(&mapped).await;
+    assert!(!a.has_permission(&v4).await);
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_remove_permission() -> Result<()> {
     let turn_socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
@@ -81,7 +203,7 @@ async fn test_remove_permission() -> Result<()> {
     let addr = SocketAddr::from_str("127.0.0.1:3478")?;
 
     let p = Permission::new(addr);
-    a.add_permission(p).await;
+    a.add_permission(p).await?;
 
     let found_p = a.has_permission(&addr).await;
     assert!(found_p, "Should keep the first one.");
@@ -165,6 +287,46 @@ async fn test_get_channel_by_number() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test(start_paused = true)]
+async fn test_channel_expiry() -> Result<()> {
+    let turn_socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    let relay_socket = Arc::clone(&turn_socket);
+    let relay_addr = relay_socket.local_addr()?;
+    let a = Allocation::new(
+        turn_socket,
+        relay_socket,
+        relay_addr,
+        FiveTuple::default(),
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    );
+
+    let number = ChannelNumber(MIN_CHANNEL_NUMBER);
+    let addr = SocketAddr::from_str("127.0.0.1:3478")?;
+    let bound_at = Instant::now();
+    a.add_channel_bind(ChannelBind::new(number, addr), DEFAULT_LIFETIME)
+        .await?;
+    assert_eq!(
+        Some(bound_at + DEFAULT_LIFETIME),
+        a.channel_expiry(&number).await
+    );
+    assert!(a
+        .channel_expiry(&ChannelNumber(MIN_CHANNEL_NUMBER + 1))
+        .await
+        .is_none());
+
+    tokio::time::sleep(Duration::from_secs(60)).await;
+    let refreshed_at = Instant::now();
+    a.add_channel_bind(ChannelBind::new(number, addr), DEFAULT_LIFETIME)
+        .await?;
+    assert_eq!(
+        Some(refreshed_at + DEFAULT_LIFETIME),
+        a.channel_expiry(&number).await,
+        "a refresh should move the expiry forward"
+    );
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_get_channel_by_addr() -> Result<()> {
     let turn_socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
@@ -246,10 +408,21 @@ async fn test_allocation_refresh() -> Result<()> {
     );
 
     a.start(DEFAULT_LIFETIME).await;
-    a.refresh(Duration::from_secs(0)).await;
-
+    a.refresh(DEFAULT_LIFETIME).await;
     assert!(!a.stop(), "lifetimeTimer has expired");
 
+    a.start(DEFAULT_LIFETIME).await;
+    a.refresh(Duration::from_secs(0)).await;
+    assert!(
+        a.stop(),
+        "refreshing with a zero lifetime should end the lifetime"
+    );
+    assert_eq!(
+        Err(Error::ErrClosed),
+        a.close().await,
+        "refreshing with a zero lifetime should close the allocation"
+    );
+
     Ok(())
 }
 
@@ -277,7 +450,1434 @@ async fn test_allocation_close() -> Result<()> {
     a.add_channel_bind(c, DEFAULT_LIFETIME).await?;
 
     // add permission
-    a.add_permission(Permission::new(addr)).await;
+    a.add_permission(Permission::new(addr)).await?;
+
+    a.close().await?;
+
+    Ok(())
+}
+
+struct MockTcpRelayConn {
+    rx: Mutex<mpsc::Receiver<Vec<u8>>>,
+    written: Mutex<Vec<Vec<u8>>>,
+    peer: SocketAddr,
+}
+
+#[async_trait::async_trait]
+impl Conn for MockTcpRelayConn {
+    async fn connect(&self, _addr: SocketAddr) -> std::result::Result<(), util::Error> {
+        Err(util::Error::Other("not supported".to_owned()))
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> std::result::Result<usize, util::Error> {
+        // a closed channel stands in for the peer closing its side of the connection
+        match self.rx.lock().await.recv().await {
+            Some(chunk) => {
+                buf[..chunk.len()].copy_from_slice(&chunk);
+                Ok(chunk.len())
+            }
+            None => Ok(0),
+        }
+    }
+
+    async fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> std::result::Result<(usize, SocketAddr), util::Error> {
+        let n = self.recv(buf).await?;
+        Ok((n, self.peer))
+    }
+
+    async fn send(&self, buf: &[u8]) -> std::result::Result<usize, util::Error> {
+        self.written.lock().await.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    async fn send_to(
+        &self,
+        _buf: &[u8],
+        _target: SocketAddr,
+    ) -> std::result::Result<usize, util::Error> {
+        Err(util::Error::Other("not supported".to_owned()))
+    }
+
+    fn local_addr(&self) -> std::result::Result<SocketAddr, util::Error> {
+        Ok(SocketAddr::from_str("127.0.0.1:5000")?)
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        Some(self.peer)
+    }
+
+    async fn close(&self) -> std::result::Result<(), util::Error> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_tcp_relay_framing() -> Result<()> {
+    let turn_socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    let peer = SocketAddr::from_str("127.0.0.1:6000")?;
+
+    let (chunk_tx, chunk_rx) = mpsc::channel(4);
+    let relay_socket = Arc::new(MockTcpRelayConn {
+        rx: Mutex::new(chunk_rx),
+        written: Mutex::new(vec![]),
+        peer,
+    });
+
+    let five_tuple = FiveTuple {
+        protocol: PROTO_TCP,
+        src_addr: client.local_addr()?,
+        dst_addr: turn_socket.local_addr()?,
+    };
+    let mut a = Allocation::new(
+        Arc::new(turn_socket),
+        Arc::clone(&relay_socket) as Arc<dyn Conn + Send + Sync>,
+        relay_socket.local_addr()?,
+        five_tuple,
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    )
+    .with_protocol(PROTO_TCP);
+
+    let allocations: AllocationMap = Arc::new(Mutex::new(HashMap::new()));
+    a.allocations = Some(Arc::clone(&allocations));
+    a.add_permission(Permission::new(peer)).await?;
+    a.packet_handler().await;
+
+    let a = Arc::new(a);
+    allocations.lock().await.insert(five_tuple, Arc::clone(&a));
+
+    // one frame split across two reads
+    chunk_tx.send(vec![0, 5, b'h', b'e']).await.unwrap();
+    chunk_tx.send(vec![b'l', b'l', b'o']).await.unwrap();
+
+    let mut buf = vec![0u8; RTP_MTU];
+    let (n, _) = client.recv_from(&mut buf).await?;
+    let mut msg = Message::new();
+    msg.raw = buf[..n].to_vec();
+    msg.decode()?;
+    let mut data = Data::default();
+    data.get_from(&msg)?;
+    assert_eq!(b"hello", &data.0[..], "should relay the whole frame");
+
+    let n = a.write_to_relay(b"world", peer).await?;
+    assert_eq!(5, n, "should report the payload length");
+    assert_eq!(
+        vec![vec![0, 5, b'w', b'o', b'r', b'l', b'd']],
+        *relay_socket.written.lock().await,
+        "should prefix the payload with its length"
+    );
+
+    assert_eq!(
+        Err(Error::ErrTcpFrameTooLarge),
+        a.write_to_relay(&vec![0u8; 65536], peer).await,
+        "should not write a payload the length prefix can't describe"
+    );
+    assert_eq!(1, relay_socket.written.lock().await.len());
+
+    // half-close from the peer tears the allocation down
+    drop(chunk_tx);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    assert!(
+        allocations.lock().await.is_empty(),
+        "allocation should be removed once the relay connection closes"
+    );
+    assert!(
+        a.close().await.is_err(),
+        "allocation should already be closed"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_allocation_stats() -> Result<()> {
+    const FRAMES: usize = 3;
+    const FRAME_SIZE: usize = 100;
+
+    let turn_socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    let peer = SocketAddr::from_str("127.0.0.1:6000")?;
+
+    let (chunk_tx, chunk_rx) = mpsc::channel(FRAMES);
+    let relay_socket = Arc::new(MockTcpRelayConn {
+        rx: Mutex::new(chunk_rx),
+        written: Mutex::new(vec![]),
+        peer,
+    });
+
+    let a = Allocation::new(
+        Arc::new(turn_socket),
+        Arc::clone(&relay_socket) as Arc<dyn Conn + Send + Sync>,
+        relay_socket.local_addr()?,
+        FiveTuple {
+            protocol: PROTO_TCP,
+            src_addr: client.local_addr()?,
+            ..Default::default()
+        },
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    )
+    .with_protocol(PROTO_TCP);
+
+    a.add_permission(Permission::new(peer)).await?;
+    a.packet_handler().await;
+
+    assert_eq!(AllocationStats::default(), a.stats().await);
+
+    let payload = vec![0xAA; FRAME_SIZE];
+    let mut frame = (FRAME_SIZE as u16).to_be_bytes().to_vec();
+    frame.extend_from_slice(&payload);
+
+    let mut buf = vec![0u8; RTP_MTU];
+    for _ in 0..FRAMES {
+        chunk_tx.send(frame.clone()).await.unwrap();
+        client.recv_from(&mut buf).await?;
+        a.write_to_relay(&payload, peer).await?;
+    }
+
+    // the handler bumps its counters right after the send the client just observed
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    assert_eq!(
+        AllocationStats {
+            bytes_sent: (FRAMES * FRAME_SIZE) as u64,
+            packets_sent: FRAMES as u64,
+            bytes_received: (FRAMES * FRAME_SIZE) as u64,
+            packets_received: FRAMES as u64,
+            frames_dropped: 0,
+        },
+        a.stats().await
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_allocation_relay_addr() -> Result<()> {
+    let turn_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let relay_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let bound_addr = relay_socket.local_addr()?;
+
+    let a = Allocation::new(
+        turn_socket,
+        relay_socket,
+        SocketAddr::from_str("0.0.0.0:0")?,
+        FiveTuple::default(),
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    );
+
+    let relay_addr = a.relay_addr();
+    assert_ne!(0, relay_addr.port(), "relay port should be filled in");
+    assert_eq!(
+        bound_addr, relay_addr,
+        "should match the bound relay socket"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_allocation_max_lifetime() -> Result<()> {
+    let max_lifetime = Duration::from_secs(600);
+
+    let turn_socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    let relay_socket = Arc::clone(&turn_socket);
+    let relay_addr = relay_socket.local_addr()?;
+    let a = Allocation::new(
+        turn_socket,
+        relay_socket,
+        relay_addr,
+        FiveTuple::default(),
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    )
+    .with_max_lifetime(max_lifetime);
+
+    assert_eq!(
+        max_lifetime,
+        a.start(Duration::from_secs(3600)).await,
+        "start should clamp to max lifetime"
+    );
+
+    let tests = vec![
+        ("below", Duration::from_secs(300), Duration::from_secs(300)),
+        ("at", max_lifetime, max_lifetime),
+        ("above", Duration::from_secs(7200), max_lifetime),
+    ];
+
+    for (name, requested, expected) in tests {
+        assert_eq!(
+            expected,
+            a.refresh(requested).await,
+            "testCase: {} granted wrong lifetime",
+            name
+        );
+    }
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_allocation_min_lifetime() -> Result<()> {
+    let min_lifetime = Duration::from_secs(60);
+    let max_lifetime = Duration::from_secs(600);
+
+    let turn_socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    let relay_socket = Arc::clone(&turn_socket);
+    let relay_addr = relay_socket.local_addr()?;
+    let a = Allocation::new(
+        turn_socket,
+        relay_socket,
+        relay_addr,
+        FiveTuple::default(),
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    )
+    .with_min_lifetime(min_lifetime)
+    .with_max_lifetime(max_lifetime);
+
+    assert_eq!(
+        min_lifetime,
+        a.start(Duration::from_secs(1)).await,
+        "start should raise to min lifetime"
+    );
+
+    let tests = vec![
+        ("below min", Duration::from_secs(1), min_lifetime),
+        (
+            "in range",
+            Duration::from_secs(300),
+            Duration::from_secs(300),
+        ),
+        ("above max", Duration::from_secs(7200), max_lifetime),
+        ("deallocate", Duration::from_secs(0), Duration::from_secs(0)),
+    ];
+
+    for (name, requested, expected) in tests {
+        assert_eq!(
+            expected,
+            a.refresh(requested).await,
+            "testCase: {} granted wrong lifetime",
+            name
+        );
+    }
+
+    assert!(
+        a.timer_expired.load(Ordering::SeqCst),
+        "refreshing with a zero lifetime should expire the allocation"
+    );
+    assert_eq!(Err(Error::ErrClosed), a.close().await);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_permissions() -> Result<()> {
+    let turn_socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    let relay_socket = Arc::clone(&turn_socket);
+    let relay_addr = relay_socket.local_addr()?;
+    let a = Allocation::new(
+        turn_socket,
+        relay_socket,
+        relay_addr,
+        FiveTuple::default(),
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    );
+
+    assert!(a.permissions().await.is_empty());
+    assert_eq!(0, a.permission_count().await);
+
+    let addr1 = SocketAddr::from_str("127.0.0.1:3478")?;
+    let addr2 = SocketAddr::from_str("127.0.0.1:3479")?;
+    let addr3 = SocketAddr::from_str("127.0.0.2:3478")?;
+
+    a.add_permission(Permission::new(addr1)).await?;
+    a.add_permission(Permission::new(addr2)).await?;
+    a.add_permission(Permission::new(addr3)).await?;
+
+    let mut permissions = a.permissions().await;
+    permissions.sort();
+    assert_eq!(
+        vec![addr2, addr3],
+        permissions,
+        "should return the latest address of each permitted IP"
+    );
+    assert_eq!(2, a.permission_count().await);
+
+    a.remove_permission(&addr3).await;
+    assert_eq!(vec![addr2], a.permissions().await);
+    assert_eq!(1, a.permission_count().await);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_permission_quota() -> Result<()> {
+    let turn_socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    let relay_socket = Arc::clone(&turn_socket);
+    let relay_addr = relay_socket.local_addr()?;
+    let a = Allocation::new(
+        turn_socket,
+        relay_socket,
+        relay_addr,
+        FiveTuple::default(),
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    )
+    .with_max_permissions(2);
+
+    let addr1 = SocketAddr::from_str("127.0.0.1:3478")?;
+    let addr2 = SocketAddr::from_str("127.0.0.2:3478")?;
+    let addr3 = SocketAddr::from_str("127.0.0.3:3478")?;
+
+    a.add_permission(Permission::new(addr1)).await?;
+    a.add_permission(Permission::new(addr2)).await?;
+
+    let result = a.add_permission(Permission::new(addr3)).await;
+    assert_eq!(
+        Err(Error::ErrTooManyPermissions),
+        result,
+        "should reject a permission over the quota"
+    );
+    assert!(!a.has_permission(&addr3).await);
+
+    a.add_permission(Permission::new(addr1)).await?;
+    assert_eq!(2, a.permission_count().await);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_add_permissions() -> Result<()> {
+    let turn_socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    let relay_socket = Arc::clone(&turn_socket);
+    let relay_addr = relay_socket.local_addr()?;
+    let a = Allocation::new(
+        turn_socket,
+        relay_socket,
+        relay_addr,
+        FiveTuple::default(),
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    )
+    .with_max_permissions(3);
+
+    let addr1 = SocketAddr::from_str("127.0.0.1:3478")?;
+    let addr2 = SocketAddr::from_str("127.0.0.2:3478")?;
+    let addr3 = SocketAddr::from_str("127.0.0.3:3478")?;
+
+    a.add_permission(Permission::new(addr1)).await?;
+    a.add_permissions(&[addr1, addr2, addr3]).await?;
+    assert_eq!(3, a.permission_count().await);
+    for addr in [addr1, addr2, addr3] {
+        assert!(a.has_permission(&addr).await);
+    }
+
+    // Refreshing what is already there doesn't count against the quota
+    a.add_permissions(&[addr2, addr3]).await?;
+    assert_eq!(3, a.permission_count().await);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_add_permissions_quota() -> Result<()> {
+    let turn_socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    let relay_socket = Arc::clone(&turn_socket);
+    let relay_addr = relay_socket.local_addr()?;
+    let a = Allocation::new(
+        turn_socket,
+        relay_socket,
+        relay_addr,
+        FiveTuple::default(),
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    )
+    .with_max_permissions(2);
+
+    let addr1 = SocketAddr::from_str("127.0.0.1:3478")?;
+    let addr2 = SocketAddr::from_str("127.0.0.2:3478")?;
+    let addr3 = SocketAddr::from_str("127.0.0.3:3478")?;
+
+    a.add_permission(Permission::new(addr1)).await?;
+
+    let result = a.add_permissions(&[addr1, addr2, addr3]).await;
+    assert_eq!(
+        Err(Error::ErrTooManyPermissions),
+        result,
+        "should reject a batch over the quota"
+    );
+    assert_eq!(
+        1,
+        a.permission_count().await,
+        "should install none of the batch"
+    );
+    assert!(!a.has_permission(&addr2).await);
+    assert!(!a.has_permission(&addr3).await);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_channel_bind_quota() -> Result<()> {
+    let turn_socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    let relay_socket = Arc::clone(&turn_socket);
+    let relay_addr = relay_socket.local_addr()?;
+    let a = Allocation::new(
+        turn_socket,
+        relay_socket,
+        relay_addr,
+        FiveTuple::default(),
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    )
+    .with_max_channel_bindings(1);
+
+    let addr1 = SocketAddr::from_str("127.0.0.1:3478")?;
+    let addr2 = SocketAddr::from_str("127.0.0.2:3478")?;
+
+    let c1 = ChannelBind::new(ChannelNumber(MIN_CHANNEL_NUMBER), addr1);
+    let c2 = ChannelBind::new(ChannelNumber(MIN_CHANNEL_NUMBER + 1), addr2);
+
+    a.add_channel_bind(c1.clone(), DEFAULT_LIFETIME).await?;
+
+    let result = a.add_channel_bind(c2, DEFAULT_LIFETIME).await;
+    assert_eq!(
+        Err(Error::ErrTooManyChannelBindings),
+        result,
+        "should reject a channel bind over the quota"
+    );
+    assert!(
+        !a.has_permission(&addr2).await,
+        "rejected channel bind should not install a permission"
+    );
+
+    a.add_channel_bind(c1, DEFAULT_LIFETIME).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_allocation_stop_aborts_timer() -> Result<()> {
+    let turn_socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    let relay_socket = Arc::clone(&turn_socket);
+    let relay_addr = relay_socket.local_addr()?;
+    let mut a = Allocation::new(
+        turn_socket,
+        relay_socket,
+        relay_addr,
+        FiveTuple::default(),
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    );
+
+    // the timer task holds a reference to the allocation map while it runs
+    let allocations: AllocationMap = Arc::new(Mutex::new(HashMap::new()));
+    a.allocations = Some(Arc::clone(&allocations));
+    a.start(Duration::from_secs(3600)).await;
+    assert_eq!(3, Arc::strong_count(&allocations));
+
+    assert!(!a.stop(), "lifetime timer should not have expired");
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    assert_eq!(
+        2,
+        Arc::strong_count(&allocations),
+        "timer task should have terminated"
+    );
+
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_channel_bind_keeps_permission() -> Result<()> {
+    let turn_socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    let relay_socket = Arc::clone(&turn_socket);
+    let relay_addr = relay_socket.local_addr()?;
+    let a = Allocation::new(
+        turn_socket,
+        relay_socket,
+        relay_addr,
+        FiveTuple::default(),
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    );
+
+    let addr = SocketAddr::from_str("127.0.0.1:3478")?;
+    let c = ChannelBind::new(ChannelNumber(MIN_CHANNEL_NUMBER), addr);
+    a.add_channel_bind(c, Duration::from_secs(10 * 60)).await?;
+
+    // A CreatePermission refresh must not cut the channel's permission short
+    a.add_permission(Permission::new(addr)).await?;
+
+    tokio::time::sleep(PERMISSION_TIMEOUT + Duration::from_secs(1)).await;
+    assert!(
+        a.has_permission(&addr).await,
+        "permission should last as long as the channel"
+    );
+
+    tokio::time::sleep(Duration::from_secs(5 * 60)).await;
+    assert!(
+        !a.has_permission(&addr).await,
+        "permission should expire with the channel"
+    );
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_concurrent_permissions_and_channel_binds() -> Result<()> {
+    let turn_socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    let relay_socket = Arc::clone(&turn_socket);
+    let relay_addr = relay_socket.local_addr()?;
+    let a = Arc::new(Allocation::new(
+        turn_socket,
+        relay_socket,
+        relay_addr,
+        FiveTuple::default(),
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    ));
+
+    let mut handles = vec![];
+    for i in 0..8u16 {
+        let a = Arc::clone(&a);
+        handles.push(tokio::spawn(async move {
+            let addr = SocketAddr::from_str(&format!("127.0.0.{}:3478", i % 4 + 1)).unwrap();
+            let number = ChannelNumber(MIN_CHANNEL_NUMBER + i % 4);
+            for j in 0..200 {
+                match j % 4 {
+                    // Short lifetimes make the channel timers expire and take the
+                    // channel_bindings lock while the other tasks are running.
+                    0 => {
+                        let c = ChannelBind::new(number, addr);
+                        let _ = a.add_channel_bind(c, Duration::from_millis(1)).await;
+                    }
+                    1 => {
+                        let _ = a.add_permission(Permission::new(addr)).await;
+                    }
+                    2 => {
+                        a.remove_channel_bind(number).await;
+                    }
+                    _ => {
+                        a.remove_permission(&addr).await;
+                    }
+                }
+            }
+        }));
+    }
+
+    let all = futures::future::join_all(handles);
+    let results = tokio::time::timeout(Duration::from_secs(10), all)
+        .await
+        .expect("permission and channel bind locks deadlocked");
+    for result in results {
+        assert!(result.is_ok());
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_add_channel_bind_number_range() -> Result<()> {
+    let turn_socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    let relay_socket = Arc::clone(&turn_socket);
+    let relay_addr = relay_socket.local_addr()?;
+    let a = Allocation::new(
+        turn_socket,
+        relay_socket,
+        relay_addr,
+        FiveTuple::default(),
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    );
+
+    let low = SocketAddr::from_str("127.0.0.1:3478")?;
+    a.add_channel_bind(
+        ChannelBind::new(ChannelNumber(MIN_CHANNEL_NUMBER), low),
+        DEFAULT_LIFETIME,
+    )
+    .await?;
+    assert_eq!(
+        Some(low),
+        a.get_channel_addr(&ChannelNumber(MIN_CHANNEL_NUMBER)).await
+    );
+
+    let high = SocketAddr::from_str("127.0.0.1:3479")?;
+    a.add_channel_bind(
+        ChannelBind::new(ChannelNumber(MAX_CHANNEL_NUMBER), high),
+        DEFAULT_LIFETIME,
+    )
+    .await?;
+    assert_eq!(
+        Some(ChannelNumber(MAX_CHANNEL_NUMBER)),
+        a.get_channel_number(&high).await
+    );
+
+    let peer = SocketAddr::from_str("127.0.0.2:3480")?;
+    for number in [MIN_CHANNEL_NUMBER - 1, MAX_CHANNEL_NUMBER + 1] {
+        let result = a
+            .add_channel_bind(
+                ChannelBind::new(ChannelNumber(number), peer),
+                DEFAULT_LIFETIME,
+            )
+            .await;
+        assert_eq!(Err(Error::ErrInvalidChannelNumber), result);
+        assert!(a.get_channel_addr(&ChannelNumber(number)).await.is_none());
+    }
+    assert!(a.get_channel_number(&peer).await.is_none());
+    assert!(
+        !a.has_permission(&peer).await,
+        "a rejected channel must not install a permission"
+    );
+
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_permissions_expiring_within() -> Result<()> {
+    let turn_socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    let relay_socket = Arc::clone(&turn_socket);
+    let relay_addr = relay_socket.local_addr()?;
+    let a = Allocation::new(
+        turn_socket,
+        relay_socket,
+        relay_addr,
+        FiveTuple::default(),
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    );
+
+    let addr = SocketAddr::from_str("127.0.0.1:3478")?;
+    a.add_permission(Permission::new(addr)).await?;
+
+    let window = Duration::from_secs(30);
+    assert!(a.permissions_expiring_within(window).await.is_empty());
+
+    tokio::time::sleep(PERMISSION_TIMEOUT - window - Duration::from_secs(1)).await;
+    assert!(a.permissions_expiring_within(window).await.is_empty());
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(vec![addr], a.permissions_expiring_within(window).await);
+
+    // Looking at the expiring set must not have extended the permission
+    tokio::time::sleep(window).await;
+    assert!(!a.has_permission(&addr).await);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relay_to_peer() -> Result<()> {
+    let turn_socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let permitted = SocketAddr::from_str("127.0.0.1:6000")?;
+    let unpermitted = SocketAddr::from_str("127.0.0.2:6000")?;
+
+    let (_chunk_tx, chunk_rx) = mpsc::channel(1);
+    let relay_socket = Arc::new(MockTcpRelayConn {
+        rx: Mutex::new(chunk_rx),
+        written: Mutex::new(vec![]),
+        peer: permitted,
+    });
+
+    let a = Allocation::new(
+        Arc::new(turn_socket),
+        Arc::clone(&relay_socket) as Arc<dyn Conn + Send + Sync>,
+        relay_socket.local_addr()?,
+        FiveTuple::default(),
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    )
+    .with_protocol(PROTO_TCP);
+    a.add_permission(Permission::new(permitted)).await?;
+
+    let result = a.relay_to_peer(&unpermitted, b"nope").await;
+    assert_eq!(Err(Error::ErrNoPermission), result.map(|_| ()));
+    assert!(
+        relay_socket.written.lock().await.is_empty(),
+        "nothing should be written without a permission"
+    );
+
+    let n = a.relay_to_peer(&permitted, b"data").await?;
+    assert_eq!(4, n, "should report the payload length");
+    assert_eq!(1, relay_socket.written.lock().await.len());
+
+    let stats = a.stats().await;
+    assert_eq!(1, stats.packets_sent);
+    assert_eq!(4, stats.bytes_sent);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_drop_aborts_timer() -> Result<()> {
+    let turn_socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    let relay_socket = Arc::clone(&turn_socket);
+    let relay_addr = relay_socket.local_addr()?;
+    let mut a = Allocation::new(
+        turn_socket,
+        relay_socket,
+        relay_addr,
+        FiveTuple::default(),
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    );
+
+    // The lifetime timer holds on to the allocation map while it runs
+    let allocations: AllocationMap = Arc::new(Mutex::new(HashMap::new()));
+    a.allocations = Some(Arc::clone(&allocations));
+    a.start(Duration::from_secs(3600)).await;
+    assert_eq!(3, Arc::strong_count(&allocations));
+
+    drop(a);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(
+        1,
+        Arc::strong_count(&allocations),
+        "timer task should have been aborted"
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "packet-trace")]
+#[tokio::test]
+async fn test_relay_trace() -> Result<()> {
+    let turn_socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    let relay_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let relay_addr = relay_socket.local_addr()?;
+
+    let five_tuple = FiveTuple {
+        protocol: PROTO_UDP,
+        src_addr: client.local_addr()?,
+        dst_addr: turn_socket.local_addr()?,
+    };
+    let a = Allocation::new(
+        Arc::new(turn_socket),
+        relay_socket,
+        relay_addr,
+        five_tuple,
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    );
+
+    let channel_peer = UdpSocket::bind("127.0.0.1:0").await?;
+    let permitted_peer = UdpSocket::bind("127.0.0.2:0").await?;
+    let other_peer = UdpSocket::bind("127.0.0.3:0").await?;
+    a.add_channel_bind(
+        ChannelBind::new(
+            ChannelNumber(MIN_CHANNEL_NUMBER),
+            channel_peer.local_addr()?,
+        ),
+        DEFAULT_LIFETIME,
+    )
+    .await?;
+    a.add_permission(Permission::new(permitted_peer.local_addr()?))
+        .await?;
+    a.packet_handler().await;
+
+    let mut expected = vec![];
+    for (peer, decision) in [
+        (
+            &channel_peer,
+            format!("matched channel {}", MIN_CHANNEL_NUMBER),
+        ),
+        (&permitted_peer, "matched permission".to_owned()),
+        (&other_peer, "dropped, no permission or channel".to_owned()),
+    ] {
+        peer.send_to(b"data", relay_addr).await?;
+        expected.push(format!(
+            "allocation {}: 4 bytes from {} {}",
+            five_tuple,
+            peer.local_addr()?,
+            decision
+        ));
+    }
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let records = RELAY_TRACE.with(|trace| trace.borrow().clone());
+    for e in expected {
+        assert!(records.contains(&e), "missing relay decision: {}", e);
+    }
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_rate_limit_udp() -> Result<()> {
+    let turn_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let relay_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let relay_addr = relay_socket.local_addr()?;
+    let peer = UdpSocket::bind("127.0.0.1:0").await?;
+    let rate_limit = RateLimit {
+        bytes_per_second: 10_000,
+        burst: 1_000,
+    };
+    let a = Allocation::new(
+        turn_socket,
+        relay_socket,
+        relay_addr,
+        FiveTuple::default(),
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    )
+    .with_rate_limit(rate_limit);
+
+    // Offer 100 kB/s for a second, ten times the limit
+    let start = Instant::now();
+    let (mut sent, mut dropped) = (0, 0);
+    for _ in 0..1000 {
+        match a.write_to_relay(&[0; 100], peer.local_addr()?).await {
+            Ok(n) => sent += n as u64,
+            Err(Error::ErrRateLimited) => dropped += 1,
+            Err(err) => return Err(err),
+        }
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let allowed = rate_limit.burst + (elapsed * rate_limit.bytes_per_second as f64) as u64;
+    assert!(sent <= allowed, "sent {} bytes, allowed {}", sent, allowed);
+    assert!(
+        sent >= allowed - 200,
+        "sent {} bytes, should use up the allowed {}",
+        sent,
+        allowed
+    );
+    assert!(dropped > 0, "should drop what is over the limit");
+    assert_eq!(sent, a.stats().await.bytes_sent);
+
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_rate_limit_tcp() -> Result<()> {
+    let turn_socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let peer = SocketAddr::from_str("127.0.0.1:6000")?;
+    let (_chunk_tx, chunk_rx) = mpsc::channel(1);
+    let relay_socket = Arc::new(MockTcpRelayConn {
+        rx: Mutex::new(chunk_rx),
+        written: Mutex::new(vec![]),
+        peer,
+    });
+    let a = Allocation::new(
+        Arc::new(turn_socket),
+        Arc::clone(&relay_socket) as Arc<dyn Conn + Send + Sync>,
+        relay_socket.local_addr()?,
+        FiveTuple::default(),
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    )
+    .with_protocol(PROTO_TCP)
+    .with_rate_limit(RateLimit {
+        bytes_per_second: 10_000,
+        burst: 1_000,
+    });
+
+    // 11 kB at 10 kB/s on top of a 1 kB burst, nothing is dropped but it takes a second
+    let start = Instant::now();
+    for _ in 0..11 {
+        assert_eq!(1_000, a.write_to_relay(&[0; 1_000], peer).await?);
+    }
+    let elapsed = start.elapsed();
+
+    assert_eq!(11, relay_socket.written.lock().await.len());
+    assert!(
+        elapsed >= Duration::from_millis(999),
+        "should wait for the bucket, took {:?}",
+        elapsed
+    );
+    assert!(elapsed < Duration::from_millis(1_100), "took {:?}", elapsed);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_peer_filter() -> Result<()> {
+    let turn_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let relay_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let relay_addr = relay_socket.local_addr()?;
+    let a = Allocation::new(
+        turn_socket,
+        relay_socket,
+        relay_addr,
+        FiveTuple::default(),
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    )
+    .with_peer_filter(Arc::new(PeerFilter::default()));
+
+    let private = SocketAddr::from_str("192.168.1.10:3478")?;
+    let public = SocketAddr::from_str("203.0.113.10:3478")?;
+
+    assert_eq!(
+        Err(Error::ErrPeerAddressDenied),
+        a.add_permission(Permission::new(private)).await,
+        "should refuse a permission for a private address"
+    );
+    assert_eq!(
+        Err(Error::ErrPeerAddressDenied),
+        a.add_permissions(&[public, private]).await,
+        "should refuse a batch with a private address"
+    );
+    assert_eq!(
+        Err(Error::ErrPeerAddressDenied),
+        a.add_channel_bind(
+            ChannelBind::new(ChannelNumber(MIN_CHANNEL_NUMBER), private),
+            DEFAULT_LIFETIME
+        )
+        .await
+    );
+    assert_eq!(
+        Err(Error::ErrPeerAddressDenied),
+        a.write_to_relay(b"data", private).await.map(|_| ()),
+        "should not send to a private address"
+    );
+    assert_eq!(0, a.permission_count().await);
+    assert_eq!(0, a.stats().await.packets_sent);
+
+    a.add_permission(Permission::new(public)).await?;
+    a.add_channel_bind(
+        ChannelBind::new(ChannelNumber(MIN_CHANNEL_NUMBER), public),
+        DEFAULT_LIFETIME,
+    )
+    .await?;
+    assert!(a.has_permission(&public).await);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_packet_handler_with_mock_conn() -> Result<()> {
+    let client_addr = SocketAddr::from_str("198.51.100.1:5000")?;
+    let turn_addr = SocketAddr::from_str("192.0.2.1:3478")?;
+    let relay_addr = SocketAddr::from_str("192.0.2.1:50000")?;
+    let channel_peer = SocketAddr::from_str("203.0.113.1:6000")?;
+    let permitted_peer = SocketAddr::from_str("203.0.113.2:6000")?;
+    let other_peer = SocketAddr::from_str("203.0.113.3:6000")?;
+
+    let turn_socket = Arc::new(MockConn::new(turn_addr));
+    let relay_socket = Arc::new(MockConn::new(relay_addr));
+    let five_tuple = FiveTuple {
+        protocol: PROTO_UDP,
+        src_addr: client_addr,
+        dst_addr: turn_addr,
+    };
+    let a = Allocation::new(
+        Arc::clone(&turn_socket) as Arc<dyn Conn + Send + Sync>,
+        Arc::clone(&relay_socket) as Arc<dyn Conn + Send + Sync>,
+        relay_addr,
+        five_tuple,
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    );
+    a.add_channel_bind(
+        ChannelBind::new(ChannelNumber(MIN_CHANNEL_NUMBER), channel_peer),
+        DEFAULT_LIFETIME,
+    )
+    .await?;
+    a.add_permission(Permission::new(permitted_peer)).await?;
+    a.packet_handler().await;
+
+    // Nothing is relayed for a peer without a permission
+    relay_socket.push_datagram(b"dropped", other_peer);
+
+    relay_socket.push_datagram(b"channel", channel_peer);
+    let (raw, to) = turn_socket.next_write().await;
+    assert_eq!(client_addr, to);
+    let mut channel_data = ChannelData {
+        raw,
+        ..Default::default()
+    };
+    channel_data.decode()?;
+    assert_eq!(ChannelNumber(MIN_CHANNEL_NUMBER), channel_data.number);
+    assert_eq!(b"channel", &channel_data.data[..]);
+
+    relay_socket.push_datagram(b"permission", permitted_peer);
+    let (raw, to) = turn_socket.next_write().await;
+    assert_eq!(client_addr, to);
+    let mut msg = Message::new();
+    msg.raw = raw;
+    msg.decode()?;
+    assert_eq!(MessageType::new(METHOD_DATA, CLASS_INDICATION), msg.typ);
+    let mut data = Data::default();
+    data.get_from(&msg)?;
+    assert_eq!(b"permission", &data.0[..]);
+    let mut peer = PeerAddress::default();
+    peer.get_from(&msg)?;
+    assert_eq!(permitted_peer, SocketAddr::new(peer.ip, peer.port));
+
+    assert_eq!(0, turn_socket.write_count());
+    assert_eq!(2, a.stats().await.packets_received);
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_data_listener() -> Result<()> {
+    let client_addr = SocketAddr::from_str("198.51.100.1:5000")?;
+    let turn_addr = SocketAddr::from_str("192.0.2.1:3478")?;
+    let relay_addr = SocketAddr::from_str("192.0.2.1:50000")?;
+    let peer = SocketAddr::from_str("203.0.113.2:6000")?;
+
+    let turn_socket = Arc::new(MockConn::new(turn_addr));
+    let relay_socket = Arc::new(MockConn::new(relay_addr));
+    let (listener_tx, mut listener_rx) = mpsc::channel(1);
+    let a = Allocation::new(
+        Arc::clone(&turn_socket) as Arc<dyn Conn + Send + Sync>,
+        Arc::clone(&relay_socket) as Arc<dyn Conn + Send + Sync>,
+        relay_addr,
+        FiveTuple {
+            protocol: PROTO_UDP,
+            src_addr: client_addr,
+            dst_addr: turn_addr,
+        },
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    )
+    .with_data_listener(listener_tx);
+    a.add_permission(Permission::new(peer)).await?;
+    a.packet_handler().await;
+
+    relay_socket.push_datagram(b"payload", peer);
+    assert_eq!(Some((peer, b"payload".to_vec())), listener_rx.recv().await);
+
+    // The payload still reaches the client
+    let (_, to) = turn_socket.next_write().await;
+    assert_eq!(client_addr, to);
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_datagram_too_large() -> Result<()> {
+    let turn_addr = SocketAddr::from_str("192.0.2.1:3478")?;
+    let relay_addr = SocketAddr::from_str("192.0.2.1:50000")?;
+    let peer = SocketAddr::from_str("203.0.113.2:6000")?;
+
+    let turn_socket = Arc::new(MockConn::new(turn_addr));
+    let relay_socket = Arc::new(MockConn::new(relay_addr).with_max_datagram_size(4));
+    let (listener_tx, mut listener_rx) = mpsc::channel(1);
+    let mut a = Allocation::new(
+        turn_socket,
+        Arc::clone(&relay_socket) as Arc<dyn Conn + Send + Sync>,
+        relay_addr,
+        FiveTuple::default(),
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    )
+    .with_too_large_listener(listener_tx);
+    a.dont_fragment = true;
+
+    assert_eq!(4, a.write_to_relay(b"fits", peer).await?);
+    assert!(
+        listener_rx.try_recv().is_err(),
+        "should not report a sent datagram"
+    );
+
+    assert_eq!(
+        Err(Error::ErrDatagramTooLarge),
+        a.write_to_relay(b"too large", peer).await
+    );
+    assert_eq!(Some((peer, 9)), listener_rx.recv().await);
+    assert_eq!(
+        1,
+        relay_socket.write_count(),
+        "should not relay the datagram"
+    );
+    assert_eq!(1, a.stats().await.packets_sent);
+
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_permission_refresh_on_traffic() -> Result<()> {
+    let turn_addr = SocketAddr::from_str("192.0.2.1:3478")?;
+    let relay_addr = SocketAddr::from_str("192.0.2.1:50000")?;
+    let peer = SocketAddr::from_str("203.0.113.2:6000")?;
+
+    for refresh_on_traffic in [false, true] {
+        let mut a = Allocation::new(
+            Arc::new(MockConn::new(turn_addr)),
+            Arc::new(MockConn::new(relay_addr)),
+            relay_addr,
+            FiveTuple::default(),
+            TextAttribute::new(ATTR_USERNAME, "user".into()),
+        )
+        .with_permission_timeout(Duration::from_secs(1));
+        if refresh_on_traffic {
+            a = a.with_permission_refresh_on_traffic();
+        }
+        a.add_permission(Permission::new(peer)).await?;
+
+        tokio::time::sleep(Duration::from_millis(900)).await;
+        a.write_to_relay(b"payload", peer).await?;
+
+        tokio::time::sleep(Duration::from_millis(900)).await;
+        assert_eq!(
+            refresh_on_traffic,
+            a.has_permission(&peer).await,
+            "refresh_on_traffic: {}",
+            refresh_on_traffic
+        );
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_outbound_queue_stalled_client() -> Result<()> {
+    const FRAMES: u64 = 10;
+    const HIGH_WATER_MARK: usize = 4;
+
+    let client_addr = SocketAddr::from_str("198.51.100.1:5000")?;
+    let turn_addr = SocketAddr::from_str("192.0.2.1:3478")?;
+    let relay_addr = SocketAddr::from_str("192.0.2.1:50000")?;
+    let peer = SocketAddr::from_str("203.0.113.2:6000")?;
+
+    let relay_socket = Arc::new(MockConn::new(relay_addr));
+    let a = Allocation::new(
+        Arc::new(MockConn::new(turn_addr).with_stalled_writes()),
+        Arc::clone(&relay_socket) as Arc<dyn Conn + Send + Sync>,
+        relay_addr,
+        FiveTuple {
+            protocol: PROTO_UDP,
+            src_addr: client_addr,
+            dst_addr: turn_addr,
+        },
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    )
+    .with_outbound_queue(HIGH_WATER_MARK);
+    a.add_permission(Permission::new(peer)).await?;
+    a.packet_handler().await;
+
+    for _ in 0..FRAMES {
+        relay_socket.push_datagram(b"payload", peer);
+    }
+
+    // The writer holds one frame the client never takes and the queue the newest
+    // HIGH_WATER_MARK, every other frame is dropped
+    let max_dropped = FRAMES - HIGH_WATER_MARK as u64;
+    for _ in 0..100 {
+        if a.stats().await.frames_dropped >= max_dropped - 1 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    let stats = a.stats().await;
+    assert!(
+        (max_dropped - 1..=max_dropped).contains(&stats.frames_dropped),
+        "dropped {} frames",
+        stats.frames_dropped
+    );
+    assert_eq!(0, stats.packets_received);
+
+    a.close().await?;
+
+    Ok(())
+}
+
+fn new_tcp_allocation(client_addr: SocketAddr) -> Result<Allocation> {
+    let turn_addr = SocketAddr::from_str("192.0.2.1:3478")?;
+    let relay_addr = SocketAddr::from_str("192.0.2.1:50000")?;
+    Ok(Allocation::new(
+        Arc::new(MockConn::new(turn_addr)),
+        Arc::new(MockConn::new(relay_addr)),
+        relay_addr,
+        FiveTuple {
+            protocol: PROTO_TCP,
+            src_addr: client_addr,
+            dst_addr: turn_addr,
+        },
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    )
+    .with_protocol(PROTO_TCP))
+}
+
+#[tokio::test]
+async fn test_connect() -> Result<()> {
+    let client_addr = SocketAddr::from_str("198.51.100.1:5000")?;
+    let peer = SocketAddr::from_str("203.0.113.1:6000")?;
+    let new_peer_conn = || -> Arc<dyn Conn + Send + Sync> {
+        Arc::new(
+            MockConn::new(SocketAddr::from_str("192.0.2.1:50001").unwrap()).with_remote_addr(peer),
+        )
+    };
+
+    let udp_allocation = Allocation::new(
+        Arc::new(MockConn::new(client_addr)),
+        Arc::new(MockConn::new(client_addr)),
+        client_addr,
+        FiveTuple::default(),
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    );
+    udp_allocation.add_permission(Permission::new(peer)).await?;
+    assert!(matches!(
+        udp_allocation.connect(peer, new_peer_conn()).await,
+        Err(Error::ErrNotTcpAllocation)
+    ));
+
+    let a = new_tcp_allocation(client_addr)?;
+    assert!(matches!(
+        a.connect(peer, new_peer_conn()).await,
+        Err(Error::ErrNoPermission)
+    ));
+
+    a.add_permission(Permission::new(peer)).await?;
+    let id = a.connect(peer, new_peer_conn()).await?;
+    assert_eq!(Some(id), a.peer_connection_id(&peer).await);
+    assert!(matches!(
+        a.connect(peer, new_peer_conn()).await,
+        Err(Error::ErrConnectionAlreadyExists)
+    ));
+
+    a.close().await?;
+    assert_eq!(None, a.peer_connection_id(&peer).await);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_connection_bind() -> Result<()> {
+    let client_addr = SocketAddr::from_str("198.51.100.1:5000")?;
+    let peer = SocketAddr::from_str("203.0.113.1:6000")?;
+    let a = new_tcp_allocation(client_addr)?;
+    a.add_permission(Permission::new(peer)).await?;
+
+    let peer_conn =
+        Arc::new(MockConn::new(SocketAddr::from_str("192.0.2.1:50001")?).with_remote_addr(peer));
+    let id = a
+        .connect(peer, Arc::clone(&peer_conn) as Arc<dyn Conn + Send + Sync>)
+        .await?;
+
+    let data_conn = Arc::new(
+        MockConn::new(SocketAddr::from_str("192.0.2.1:3478")?).with_remote_addr(client_addr),
+    );
+    assert!(matches!(
+        a.connection_bind(
+            ConnectionId(id.0.wrapping_add(1)),
+            Arc::clone(&data_conn) as Arc<dyn Conn + Send + Sync>
+        )
+        .await,
+        Err(Error::ErrUnknownConnectionId)
+    ));
+    assert_eq!(
+        peer,
+        a.connection_bind(id, Arc::clone(&data_conn) as Arc<dyn Conn + Send + Sync>)
+            .await?
+    );
+    assert!(
+        matches!(
+            a.connection_bind(id, Arc::clone(&data_conn) as Arc<dyn Conn + Send + Sync>)
+                .await,
+            Err(Error::ErrUnknownConnectionId)
+        ),
+        "a peer connection is bound once"
+    );
+
+    // Bytes are forwarded as they are, without framing, in both directions
+    peer_conn.push_datagram(b"from peer", peer);
+    assert_eq!(
+        (b"from peer".to_vec(), client_addr),
+        data_conn.next_write().await
+    );
+    data_conn.push_datagram(b"from client", client_addr);
+    assert_eq!(
+        (b"from client".to_vec(), peer),
+        peer_conn.next_write().await
+    );
+
+    let stats = a.stats().await;
+    assert_eq!(b"from peer".len() as u64, stats.bytes_received);
+    assert_eq!(b"from client".len() as u64, stats.bytes_sent);
+
+    // The peer hanging up tears the data connection down too
+    peer_conn.close().await?;
+    for _ in 0..100 {
+        if a.peer_connection_id(&peer).await.is_none() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    assert_eq!(None, a.peer_connection_id(&peer).await);
+    assert!(data_conn.recv(&mut [0u8; 16]).await.is_err());
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_recv_buffer_size() -> Result<()> {
+    let turn_addr = SocketAddr::from_str("192.0.2.1:3478")?;
+    let relay_addr = SocketAddr::from_str("192.0.2.1:50000")?;
+    let client_addr = SocketAddr::from_str("198.51.100.1:5000")?;
+    let peer = SocketAddr::from_str("203.0.113.2:6000")?;
+
+    let turn_socket = Arc::new(MockConn::new(turn_addr));
+    let relay_socket = Arc::new(MockConn::new(relay_addr));
+    let five_tuple = FiveTuple {
+        protocol: PROTO_UDP,
+        src_addr: client_addr,
+        dst_addr: turn_addr,
+    };
+    let a = Allocation::new(
+        Arc::clone(&turn_socket) as Arc<dyn Conn + Send + Sync>,
+        Arc::clone(&relay_socket) as Arc<dyn Conn + Send + Sync>,
+        relay_addr,
+        five_tuple,
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    )
+    .with_recv_buffer_size(4);
+    a.add_permission(Permission::new(peer)).await?;
+    a.packet_handler().await;
+
+    // A datagram that fits exactly is relayed whole, a longer one is truncated
+    for (datagram, relayed) in [(&b"fits"[..], &b"fits"[..]), (b"too large", b"too ")] {
+        relay_socket.push_datagram(datagram, peer);
+        let (raw, _) = turn_socket.next_write().await;
+        let mut msg = Message::new();
+        msg.raw = raw;
+        msg.decode()?;
+        let mut data = Data::default();
+        data.get_from(&msg)?;
+        assert_eq!(relayed, &data.0[..]);
+    }
+    assert_eq!(8, a.stats().await.bytes_received);
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_recv_failing_long_read() -> Result<()> {
+    let turn_addr = SocketAddr::from_str("192.0.2.1:3478")?;
+    let relay_addr = SocketAddr::from_str("192.0.2.1:50000")?;
+    let client_addr = SocketAddr::from_str("198.51.100.1:5000")?;
+    let peer = SocketAddr::from_str("203.0.113.2:6000")?;
+
+    let turn_socket = Arc::new(MockConn::new(turn_addr));
+    let relay_socket = Arc::new(MockConn::new(relay_addr).with_failing_long_reads());
+    let a = Allocation::new(
+        Arc::clone(&turn_socket) as Arc<dyn Conn + Send + Sync>,
+        Arc::clone(&relay_socket) as Arc<dyn Conn + Send + Sync>,
+        relay_addr,
+        FiveTuple {
+            protocol: PROTO_UDP,
+            src_addr: client_addr,
+            dst_addr: turn_addr,
+        },
+        TextAttribute::new(ATTR_USERNAME, "user".into()),
+    )
+    .with_recv_buffer_size(4);
+    a.add_permission(Permission::new(peer)).await?;
+    a.packet_handler().await;
+
+    // The datagram the read failed on is dropped and the relay keeps reading
+    relay_socket.push_datagram(b"much too large", peer);
+    relay_socket.push_datagram(b"fits", peer);
+    let (raw, _) = turn_socket.next_write().await;
+    let mut msg = Message::new();
+    msg.raw = raw;
+    msg.decode()?;
+    let mut data = Data::default();
+    data.get_from(&msg)?;
+    assert_eq!(b"fits", &data.0[..]);
+    assert_eq!(4, a.stats().await.bytes_received);
 
     a.close().await?;
 
@@ -17,6 +17,7 @@ pub struct ChannelBind {
     pub(crate) channel_bindings: Option<Arc<Mutex<HashMap<ChannelNumber, ChannelBind>>>>,
     reset_tx: Option<mpsc::Sender<Duration>>,
     timer_expired: Arc<AtomicBool>,
+    expires_at: Arc<SyncMutex<Option<Instant>>>,
 }
 
 impl ChannelBind {
@@ -28,6 +29,7 @@ impl ChannelBind {
             channel_bindings: None,
             reset_tx: None,
             timer_expired: Arc::new(AtomicBool::new(false)),
+            expires_at: Arc::new(SyncMutex::new(None)),
         }
     }
 
@@ -38,6 +40,7 @@ impl ChannelBind {
         let channel_bindings = self.channel_bindings.clone();
         let number = self.number;
         let timer_expired = Arc::clone(&self.timer_expired);
+        self.expires_at.lock().replace(Instant::now() + lifetime);
 
         tokio::spawn(async move {
             let timer = tokio::time::sleep(lifetime);
@@ -75,8 +78,15 @@ impl ChannelBind {
         expired
     }
 
+    // expires_at returns when the channel expires unless it is refreshed, or None if
+    // it has not been started
+    pub fn expires_at(&self) -> Option<Instant> {
+        *self.expires_at.lock()
+    }
+
     pub(crate) async fn refresh(&self, lifetime: Duration) {
         if let Some(tx) = &self.reset_tx {
+            self.expires_at.lock().replace(Instant::now() + lifetime);
             let _ = tx.send(lifetime).await;
         }
     }
@@ -29,6 +29,30 @@ impl Default for FiveTuple {
     }
 }
 
+impl FiveTuple {
+    // new creates the 5-tuple of the client at src_addr talking to the server at
+    // dst_addr over protocol
+    pub fn new(protocol: Protocol, src_addr: SocketAddr, dst_addr: SocketAddr) -> Self {
+        FiveTuple {
+            protocol,
+            src_addr,
+            dst_addr,
+        }
+    }
+
+    // fingerprint is a string key for the 5-tuple, two 5-tuples share it exactly
+    // when they are equal, and so when they hash the same
+    pub fn fingerprint(&self) -> String {
+        self.to_string()
+    }
+
+    // matches_src reports whether the 5-tuple belongs to the client at addr,
+    // whatever the server address and transport protocol are
+    pub fn matches_src(&self, addr: &SocketAddr) -> bool {
+        self.src_addr == *addr
+    }
+}
+
 impl fmt::Display for FiveTuple {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}_{}_{}", self.protocol, self.src_addr, self.dst_addr)
@@ -1,6 +1,9 @@
 use super::*;
 use crate::error::Result;
 
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
 #[test]
 fn test_five_tuple_protocol() -> Result<()> {
     let udp_expect = PROTO_UDP;
@@ -101,3 +104,77 @@ fn test_five_tuple_equal() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_five_tuple_matches_src() -> Result<()> {
+    let src_addr: SocketAddr = "10.0.0.1:3478".parse::<SocketAddr>()?;
+    let other_src_addr: SocketAddr = "10.0.0.1:3479".parse::<SocketAddr>()?;
+
+    let udp = FiveTuple {
+        protocol: PROTO_UDP,
+        src_addr,
+        dst_addr: "0.0.0.0:3480".parse::<SocketAddr>()?,
+    };
+    let tcp = FiveTuple {
+        protocol: PROTO_TCP,
+        src_addr,
+        dst_addr: "0.0.0.0:3481".parse::<SocketAddr>()?,
+    };
+
+    assert!(udp.matches_src(&src_addr));
+    assert!(tcp.matches_src(&src_addr));
+    assert!(!udp.matches_src(&other_src_addr));
+    assert!(!tcp.matches_src(&udp.dst_addr));
+
+    Ok(())
+}
+
+#[test]
+fn test_five_tuple_fingerprint() -> Result<()> {
+    let src_addr: SocketAddr = "10.0.0.1:3478".parse::<SocketAddr>()?;
+    let dst_addr: SocketAddr = "10.0.0.2:3478".parse::<SocketAddr>()?;
+    let v6_addr: SocketAddr = "[::1]:3478".parse::<SocketAddr>()?;
+
+    let hash = |five_tuple: &FiveTuple| {
+        let mut hasher = DefaultHasher::new();
+        five_tuple.hash(&mut hasher);
+        hasher.finish()
+    };
+
+    let five_tuple = FiveTuple::new(PROTO_UDP, src_addr, dst_addr);
+    assert_eq!(PROTO_UDP, five_tuple.protocol);
+    assert_eq!(src_addr, five_tuple.src_addr);
+    assert_eq!(dst_addr, five_tuple.dst_addr);
+
+    let same = FiveTuple::new(PROTO_UDP, src_addr, dst_addr);
+    assert_eq!(five_tuple, same);
+    assert_eq!(five_tuple.fingerprint(), same.fingerprint());
+    assert_eq!(hash(&five_tuple), hash(&same));
+
+    let tests = vec![
+        (
+            "DifferentProtocol",
+            FiveTuple::new(PROTO_TCP, src_addr, dst_addr),
+        ),
+        (
+            "DifferentSrcAddr",
+            FiveTuple::new(PROTO_UDP, "10.0.0.1:3479".parse()?, dst_addr),
+        ),
+        (
+            "DifferentDstAddr",
+            FiveTuple::new(PROTO_UDP, src_addr, "10.0.0.3:3478".parse()?),
+        ),
+        (
+            "SwappedAddrs",
+            FiveTuple::new(PROTO_UDP, dst_addr, src_addr),
+        ),
+        ("IPv6", FiveTuple::new(PROTO_UDP, v6_addr, dst_addr)),
+    ];
+
+    for (name, other) in tests {
+        assert_ne!(five_tuple, other, "{}", name);
+        assert_ne!(five_tuple.fingerprint(), other.fingerprint(), "{}", name);
+    }
+
+    Ok(())
+}
@@ -4,21 +4,31 @@ mod allocation_test;
 pub mod allocation_manager;
 pub mod channel_bind;
 pub mod five_tuple;
+mod outbound_queue;
+mod peer_connection;
+pub mod peer_filter;
 pub mod permission;
+pub mod rate_limit;
 
 use crate::error::*;
-use crate::proto::{chandata::*, channum::*, data::*, peeraddr::*, *};
+use crate::proto::{chandata::*, channum::*, connid::ConnectionId, data::*, *};
+use crate::relay::is_datagram_too_large;
+use crate::server::request::MAXIMUM_ALLOCATION_LIFETIME;
 use channel_bind::*;
 use five_tuple::*;
+use outbound_queue::*;
+use peer_connection::*;
+use peer_filter::*;
 use permission::*;
-use stun::{agent::*, message::*, textattrs::Username};
+use rate_limit::*;
+use stun::textattrs::Username;
 use util::sync::Mutex as SyncMutex;
 
 use util::Conn;
 
-use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::{AtomicU64, AtomicUsize};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     marker::{Send, Sync},
     net::SocketAddr,
     sync::{atomic::AtomicBool, atomic::Ordering, Arc},
@@ -29,10 +39,34 @@ use tokio::{
         oneshot::{self, Sender},
         Mutex,
     },
+    task::JoinHandle,
     time::{Duration, Instant},
 };
 
+// relay_trace logs what happened to a datagram read from a relay socket. It runs for
+// every datagram, so it is compiled out unless the packet-trace feature is enabled.
+// Tests read the decisions back from RELAY_TRACE rather than installing a logger,
+// which would be global to the whole test binary.
+macro_rules! relay_trace {
+    ($($arg:tt)+) => {
+        #[cfg(feature = "packet-trace")]
+        {
+            log::trace!(target: "turn::relay", $($arg)+);
+            #[cfg(test)]
+            RELAY_TRACE.with(|trace| trace.borrow_mut().push(format!($($arg)+)));
+        }
+    };
+}
+
+// RELAY_TRACE keeps the relay decisions made on the current thread, which is the test's
+// own thread as the relay loop runs on the test's single-threaded runtime
+#[cfg(all(test, feature = "packet-trace"))]
+thread_local! {
+    static RELAY_TRACE: std::cell::RefCell<Vec<String>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
 const RTP_MTU: usize = 1500;
+const TCP_FRAME_HEADER_SIZE: usize = 2;
 
 pub type AllocationMap = Arc<Mutex<HashMap<FiveTuple, Arc<Allocation>>>>;
 
@@ -45,6 +79,21 @@ pub struct AllocationInfo {
     /// Username of this [`Allocation`].
     pub username: String,
 
+    /// Relayed transport address of this [`Allocation`].
+    pub relay_addr: SocketAddr,
+
+    /// Transport protocol of the relayed transport address.
+    pub protocol: Protocol,
+
+    /// Number of permissions installed on this [`Allocation`].
+    pub permission_count: usize,
+
+    /// Number of channels bound on this [`Allocation`].
+    pub channel_count: usize,
+
+    /// Time left until this [`Allocation`] expires unless it is refreshed.
+    pub remaining_lifetime: Duration,
+
     /// Relayed bytes with this [`Allocation`].
     #[cfg(feature = "metrics")]
     pub relayed_bytes: usize,
@@ -52,68 +101,345 @@ pub struct AllocationInfo {
 
 impl AllocationInfo {
     // Creates a new `AllocationInfo`
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         five_tuple: FiveTuple,
         username: String,
+        relay_addr: SocketAddr,
+        protocol: Protocol,
+        permission_count: usize,
+        channel_count: usize,
+        remaining_lifetime: Duration,
         #[cfg(feature = "metrics")] relayed_bytes: usize,
     ) -> Self {
         Self {
             five_tuple,
             username,
+            relay_addr,
+            protocol,
+            permission_count,
+            channel_count,
+            remaining_lifetime,
             #[cfg(feature = "metrics")]
             relayed_bytes,
         }
     }
 }
 
+/// Snapshot of the traffic relayed by an [`Allocation`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AllocationStats {
+    /// Payload bytes relayed from the client to peers.
+    pub bytes_sent: u64,
+
+    /// Packets relayed from the client to peers.
+    pub packets_sent: u64,
+
+    /// Payload bytes relayed from peers to the client.
+    pub bytes_received: u64,
+
+    /// Packets relayed from peers to the client.
+    pub packets_received: u64,
+
+    /// Frames for the client dropped because its outbound queue was full.
+    pub frames_dropped: u64,
+}
+
+struct AllocationCounters {
+    bytes_sent: AtomicU64,
+    packets_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    packets_received: AtomicU64,
+    frames_dropped: AtomicU64,
+    created_at: Instant,
+    // last_activity is when the allocation last relayed a datagram or was refreshed,
+    // in milliseconds since created_at
+    last_activity: AtomicU64,
+}
+
+impl AllocationCounters {
+    fn new() -> Self {
+        AllocationCounters {
+            bytes_sent: AtomicU64::new(0),
+            packets_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            packets_received: AtomicU64::new(0),
+            frames_dropped: AtomicU64::new(0),
+            created_at: Instant::now(),
+            last_activity: AtomicU64::new(0),
+        }
+    }
+
+    fn add_sent(&self, n: usize) {
+        self.bytes_sent.fetch_add(n as u64, Ordering::Relaxed);
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+        self.touch();
+    }
+
+    fn add_received(&self, n: usize) {
+        self.bytes_received.fetch_add(n as u64, Ordering::Relaxed);
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+        self.touch();
+    }
+
+    fn touch(&self) {
+        let now = self.created_at.elapsed().as_millis() as u64;
+        self.last_activity.fetch_max(now, Ordering::Relaxed);
+    }
+
+    fn idle_time(&self) -> Duration {
+        let last_activity = Duration::from_millis(self.last_activity.load(Ordering::Relaxed));
+        self.created_at.elapsed().saturating_sub(last_activity)
+    }
+}
+
 // Allocation is tied to a FiveTuple and relays traffic
 // use create_allocation and get_allocation to operate
 pub struct Allocation {
     protocol: Protocol,
     turn_socket: Arc<dyn Conn + Send + Sync>,
-    pub(crate) relay_addr: SocketAddr,
-    pub(crate) relay_socket: Arc<dyn Conn + Send + Sync>,
+    // relay_addr and relay_socket change together when the relay is rebound
+    relay_addr: SyncMutex<SocketAddr>,
+    relay_socket: SyncMutex<Arc<dyn Conn + Send + Sync>>,
     five_tuple: FiveTuple,
     username: Username,
+    // Lock order: permissions is always taken before channel_bindings, and neither
+    // is held while awaiting a permission or channel timer, since those timers take
+    // the same locks when they expire.
     permissions: Arc<Mutex<HashMap<String, Permission>>>,
     channel_bindings: Arc<Mutex<HashMap<ChannelNumber, ChannelBind>>>,
+    // Connections to peers of a TCP allocation, by CONNECTION-ID (RFC 6062)
+    peer_connections: Arc<Mutex<PeerConnections>>,
     pub(crate) allocations: Option<AllocationMap>,
+    pub(crate) expiry_notifier: Option<mpsc::Sender<FiveTuple>>,
+    // dont_fragment is set when the client asked for DONT-FRAGMENT and the relay
+    // conn sets the DF bit on the datagrams it sends
+    pub(crate) dont_fragment: bool,
     reset_tx: SyncMutex<Option<mpsc::Sender<Duration>>>,
+    timer_handle: SyncMutex<Option<JoinHandle<()>>>,
     timer_expired: Arc<AtomicBool>,
+    expires_at: SyncMutex<Option<Instant>>,
+    min_lifetime: Duration,
+    max_lifetime: Duration,
+    permission_timeout: Duration,
+    refresh_permissions_on_traffic: bool,
+    max_permissions: Option<usize>,
+    max_channel_bindings: Option<usize>,
     closed: AtomicBool, // Option<mpsc::Receiver<()>>,
     pub(crate) relayed_bytes: AtomicUsize,
-    drop_tx: Option<Sender<u32>>,
+    counters: Arc<AllocationCounters>,
+    rate_limiter: Option<TokenBucket>,
+    peer_filter: Option<Arc<PeerFilter>>,
+    data_listener: Option<mpsc::Sender<(SocketAddr, Vec<u8>)>>,
+    outbound_high_water_mark: Option<usize>,
+    too_large_listener: Option<mpsc::Sender<(SocketAddr, usize)>>,
+    recv_buffer_size: usize,
+    drop_tx: SyncMutex<Option<Sender<u32>>>,
 }
 
+// addr2ipfingerprint keys permissions by IP, an IPv4-mapped IPv6 address shares the
+// key of its IPv4 address as both reach the same host
 fn addr2ipfingerprint(addr: &SocketAddr) -> String {
-    addr.ip().to_string()
+    unmap_ipv4(&addr.ip()).to_string()
 }
 
 impl Allocation {
-    // creates a new instance of NewAllocation.
+    // creates a new instance of NewAllocation. A relay_addr without a port is
+    // taken as a placeholder and replaced by the relay socket's bound address.
     pub fn new(
         turn_socket: Arc<dyn Conn + Send + Sync>,
         relay_socket: Arc<dyn Conn + Send + Sync>,
-        relay_addr: SocketAddr,
+        mut relay_addr: SocketAddr,
         five_tuple: FiveTuple,
         username: Username,
     ) -> Self {
+        if relay_addr.port() == 0 {
+            if let Ok(addr) = relay_socket.local_addr() {
+                relay_addr = addr;
+            }
+        }
+
         Allocation {
             protocol: PROTO_UDP,
             turn_socket,
-            relay_addr,
-            relay_socket,
+            relay_addr: SyncMutex::new(relay_addr),
+            relay_socket: SyncMutex::new(relay_socket),
             five_tuple,
             username,
             permissions: Arc::new(Mutex::new(HashMap::new())),
             channel_bindings: Arc::new(Mutex::new(HashMap::new())),
+            peer_connections: Arc::new(Mutex::new(PeerConnections::default())),
             allocations: None,
+            expiry_notifier: None,
+            dont_fragment: false,
             reset_tx: SyncMutex::new(None),
+            timer_handle: SyncMutex::new(None),
             timer_expired: Arc::new(AtomicBool::new(false)),
+            expires_at: SyncMutex::new(None),
+            min_lifetime: Duration::from_secs(0),
+            max_lifetime: MAXIMUM_ALLOCATION_LIFETIME,
+            permission_timeout: PERMISSION_TIMEOUT,
+            refresh_permissions_on_traffic: false,
+            max_permissions: None,
+            max_channel_bindings: None,
             closed: AtomicBool::new(false),
             relayed_bytes: Default::default(),
-            drop_tx: None,
+            counters: Arc::new(AllocationCounters::new()),
+            rate_limiter: None,
+            peer_filter: None,
+            data_listener: None,
+            outbound_high_water_mark: None,
+            too_large_listener: None,
+            recv_buffer_size: RTP_MTU,
+            drop_tx: SyncMutex::new(None),
+        }
+    }
+
+    // with_protocol sets the transport protocol spoken on the relay socket
+    pub fn with_protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    // with_min_lifetime sets the lower bound applied to non-zero requested lifetimes
+    pub fn with_min_lifetime(mut self, min_lifetime: Duration) -> Self {
+        self.min_lifetime = min_lifetime;
+        self
+    }
+
+    // with_max_lifetime sets the upper bound applied to requested lifetimes
+    pub fn with_max_lifetime(mut self, max_lifetime: Duration) -> Self {
+        self.max_lifetime = max_lifetime;
+        self
+    }
+
+    // with_permission_timeout sets how long a permission lasts without being
+    // refreshed, by default the 5 minutes of RFC 5766 Section 8
+    pub fn with_permission_timeout(mut self, permission_timeout: Duration) -> Self {
+        self.permission_timeout = permission_timeout;
+        self
+    }
+
+    // with_permission_refresh_on_traffic makes every datagram relayed to a peer refresh
+    // the peer's permission, as a CreatePermission would. Off by default, RFC 8656
+    // only refreshes permissions with CreatePermission and ChannelBind.
+    pub fn with_permission_refresh_on_traffic(mut self) -> Self {
+        self.refresh_permissions_on_traffic = true;
+        self
+    }
+
+    // with_max_permissions limits the number of permissions the allocation can hold
+    pub fn with_max_permissions(mut self, max_permissions: usize) -> Self {
+        self.max_permissions = Some(max_permissions);
+        self
+    }
+
+    // with_max_channel_bindings limits the number of channels the allocation can bind
+    pub fn with_max_channel_bindings(mut self, max_channel_bindings: usize) -> Self {
+        self.max_channel_bindings = Some(max_channel_bindings);
+        self
+    }
+
+    // with_rate_limit caps the bytes relayed to peers, by default there is no limit
+    pub fn with_rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.rate_limiter = Some(TokenBucket::new(rate_limit));
+        self
+    }
+
+    // with_peer_filter refuses permissions, channels and traffic for the peers the
+    // filter denies, by default every peer is accepted
+    pub fn with_peer_filter(mut self, peer_filter: Arc<PeerFilter>) -> Self {
+        self.peer_filter = Some(peer_filter);
+        self
+    }
+
+    // with_data_listener hands a copy of every payload relayed to the client in a Data
+    // indication to listener, along with the peer it came from. Payloads are dropped
+    // rather than holding up the relay while the listener is behind.
+    pub fn with_data_listener(mut self, listener: mpsc::Sender<(SocketAddr, Vec<u8>)>) -> Self {
+        self.data_listener = Some(listener);
+        self
+    }
+
+    // with_outbound_queue relays to the client through a queue of at most
+    // high_water_mark frames, so that a client slower than its peers can't hold up
+    // reading from the relay socket. Once the queue is full a UDP relay drops the
+    // oldest frame, counted in frames_dropped, while a TCP relay stops reading from
+    // its peer until there is room, which backpressures the peer. Without a queue
+    // every frame is written to the client before the next one is read.
+    pub fn with_outbound_queue(mut self, high_water_mark: usize) -> Self {
+        self.outbound_high_water_mark = Some(high_water_mark);
+        self
+    }
+
+    // with_too_large_listener reports every datagram the relay socket refused as larger
+    // than the path MTU to listener, along with the peer and the datagram size. Reports
+    // are dropped while the listener is behind.
+    pub fn with_too_large_listener(mut self, listener: mpsc::Sender<(SocketAddr, usize)>) -> Self {
+        self.too_large_listener = Some(listener);
+        self
+    }
+
+    // with_recv_buffer_size sets how many bytes of a datagram the relay socket reads,
+    // 1500 by default. A larger UDP datagram is truncated to this size before it is
+    // relayed to the client.
+    pub fn with_recv_buffer_size(mut self, recv_buffer_size: usize) -> Self {
+        self.recv_buffer_size = recv_buffer_size.max(1);
+        self
+    }
+
+    // username returns the USERNAME of the Allocate request that created the allocation
+    pub(crate) fn username(&self) -> &str {
+        &self.username.text
+    }
+
+    // relay_addr returns the relayed transport address of the allocation
+    pub fn relay_addr(&self) -> SocketAddr {
+        *self.relay_addr.lock()
+    }
+
+    // relay_socket returns the socket currently relaying traffic to and from peers
+    pub(crate) fn relay_socket(&self) -> Arc<dyn Conn + Send + Sync> {
+        Arc::clone(&self.relay_socket.lock())
+    }
+
+    // rebind_relay moves the allocation onto new_socket, e.g. after a network change.
+    // The relayed transport address becomes the address new_socket is bound to, the
+    // read loop is restarted on it and the old socket is closed. Permissions and
+    // channel bindings are kept.
+    pub async fn rebind_relay(&self, new_socket: Arc<dyn Conn + Send + Sync>) -> Result<()> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(Error::ErrClosed);
         }
+
+        let relay_addr = new_socket.local_addr()?;
+        let old_socket = {
+            let mut relay_socket = self.relay_socket.lock();
+            *self.relay_addr.lock() = relay_addr;
+            std::mem::replace(&mut *relay_socket, new_socket)
+        };
+
+        // Stop the read loop on the old socket before closing it, so that the loop
+        // doesn't take the read error for a failed allocation
+        if let Some(drop_tx) = self.drop_tx.lock().take() {
+            let _ = drop_tx.send(0);
+        }
+        self.packet_handler().await;
+
+        log::debug!(
+            "allocation {} rebound its relay from {:?} to {}",
+            self.five_tuple,
+            old_socket.local_addr(),
+            relay_addr
+        );
+        let _ = old_socket.close().await;
+
+        Ok(())
+    }
+
+    // dont_fragment reports whether the relay conn sets the DF bit on what it sends
+    pub fn dont_fragment(&self) -> bool {
+        self.dont_fragment
     }
 
     // has_permission gets the Permission from the allocation
@@ -122,25 +448,114 @@ impl Allocation {
         permissions.get(&addr2ipfingerprint(addr)).is_some()
     }
 
-    // add_permission adds a new permission to the allocation
-    pub async fn add_permission(&self, mut p: Permission) {
-        let fingerprint = addr2ipfingerprint(&p.addr);
+    // try_has_permission is has_permission without waiting for the permissions lock,
+    // it fails with ErrPermissionsBusy while another task holds the lock
+    pub fn try_has_permission(&self, addr: &SocketAddr) -> Result<bool> {
+        let permissions = self
+            .permissions
+            .try_lock()
+            .map_err(|_| Error::ErrPermissionsBusy)?;
+        Ok(permissions.get(&addr2ipfingerprint(addr)).is_some())
+    }
 
-        {
+    // check_peer fails with ErrPeerAddressDenied for a peer the peer filter denies
+    fn check_peer(&self, addr: &SocketAddr) -> Result<()> {
+        match &self.peer_filter {
+            Some(peer_filter) if peer_filter.is_denied(&addr.ip()) => {
+                Err(Error::ErrPeerAddressDenied)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // add_permission adds a new permission to the allocation, refreshing an existing
+    // permission never counts against max_permissions
+    pub async fn add_permission(&self, p: Permission) -> Result<()> {
+        self.add_permission_with_lifetime(p, self.permission_timeout)
+            .await
+    }
+
+    // add_permissions installs or refreshes a permission for every address in addrs,
+    // as a CreatePermission with several XOR-PEER-ADDRESS attributes needs. The batch
+    // is checked against max_permissions up front and the permissions it created are
+    // removed again if it still fails, so either all of addrs are permitted or none
+    // of the new ones is.
+    pub async fn add_permissions(&self, addrs: &[SocketAddr]) -> Result<()> {
+        for addr in addrs {
+            self.check_peer(addr)?;
+        }
+
+        let created: HashSet<String> = {
             let permissions = self.permissions.lock().await;
-            if let Some(existed_permission) = permissions.get(&fingerprint) {
-                existed_permission.refresh(PERMISSION_TIMEOUT).await;
-                return;
+            let created: HashSet<String> = addrs
+                .iter()
+                .map(addr2ipfingerprint)
+                .filter(|fingerprint| !permissions.contains_key(fingerprint))
+                .collect();
+
+            if let Some(max_permissions) = self.max_permissions {
+                if permissions.len() + created.len() > max_permissions {
+                    return Err(Error::ErrTooManyPermissions);
+                }
+            }
+
+            created
+        };
+
+        for addr in addrs {
+            if let Err(err) = self.add_permission(Permission::new(*addr)).await {
+                let mut permissions = self.permissions.lock().await;
+                permissions.retain(|fingerprint, _| !created.contains(fingerprint));
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    // add_permission_with_lifetime adds or refreshes a permission so that it lasts at
+    // least lifetime, a refresh never shortens a permission's remaining time
+    async fn add_permission_with_lifetime(
+        &self,
+        mut p: Permission,
+        lifetime: Duration,
+    ) -> Result<()> {
+        self.check_peer(&p.addr)?;
+        let fingerprint = addr2ipfingerprint(&p.addr);
+
+        let existed_permission = {
+            let mut permissions = self.permissions.lock().await;
+            // Permissions are per IP, but keep the peer address that asked last
+            let existed_permission = permissions.get_mut(&fingerprint).map(|existed| {
+                existed.addr = p.addr;
+                existed.clone()
+            });
+
+            if existed_permission.is_none() {
+                if let Some(max_permissions) = self.max_permissions {
+                    if permissions.len() >= max_permissions {
+                        return Err(Error::ErrTooManyPermissions);
+                    }
+                }
             }
+
+            existed_permission
+        };
+
+        if let Some(existed_permission) = existed_permission {
+            existed_permission.refresh(lifetime).await;
+            return Ok(());
         }
 
         p.permissions = Some(Arc::clone(&self.permissions));
-        p.start(PERMISSION_TIMEOUT).await;
+        p.start(lifetime).await;
 
         {
             let mut permissions = self.permissions.lock().await;
             permissions.insert(fingerprint, p);
         }
+
+        Ok(())
     }
 
     // remove_permission removes the net.Addr's fingerprint from the allocation's permissions
@@ -149,9 +564,58 @@ impl Allocation {
         permissions.remove(&addr2ipfingerprint(addr)).is_some()
     }
 
+    // permissions returns the peer addresses currently permitted on the allocation.
+    // Permissions are keyed by IP, so each address is the one the permission was
+    // most recently installed or refreshed with.
+    pub async fn permissions(&self) -> Vec<SocketAddr> {
+        let permissions = self.permissions.lock().await;
+        permissions.values().map(|p| p.addr).collect()
+    }
+
+    // permissions_expiring_within returns the peer addresses whose permissions lapse
+    // within d unless refreshed, it doesn't reset any permission timers
+    pub async fn permissions_expiring_within(&self, d: Duration) -> Vec<SocketAddr> {
+        let permissions = self.permissions.lock().await;
+        permissions
+            .values()
+            .filter(|p| p.remaining().map_or(false, |remaining| remaining <= d))
+            .map(|p| p.addr)
+            .collect()
+    }
+
+    // refresh_permission restarts the timer of the permission for addr if there is one
+    async fn refresh_permission(&self, addr: &SocketAddr) {
+        let permission = {
+            let permissions = self.permissions.lock().await;
+            permissions.get(&addr2ipfingerprint(addr)).cloned()
+        };
+
+        if let Some(permission) = permission {
+            permission.refresh(self.permission_timeout).await;
+        }
+    }
+
+    // permission_count returns the number of permissions on the allocation
+    pub async fn permission_count(&self) -> usize {
+        let permissions = self.permissions.lock().await;
+        permissions.len()
+    }
+
+    // channel_count returns the number of channels bound on the allocation
+    pub async fn channel_count(&self) -> usize {
+        let channel_bindings = self.channel_bindings.lock().await;
+        channel_bindings.len()
+    }
+
     // add_channel_bind adds a new ChannelBind to the allocation, it also updates the
     // permissions needed for this ChannelBind
     pub async fn add_channel_bind(&self, mut c: ChannelBind, lifetime: Duration) -> Result<()> {
+        // Only [0x4000, 0x7FFF] may be bound, the rest is reserved
+        if !c.number.valid() {
+            return Err(Error::ErrInvalidChannelNumber);
+        }
+        self.check_peer(&c.peer)?;
+
         {
             if let Some(addr) = self.get_channel_addr(&c.number).await {
                 if addr != c.peer {
@@ -166,19 +630,39 @@ impl Allocation {
             }
         }
 
-        {
+        let existed_bind = {
             let channel_bindings = self.channel_bindings.lock().await;
-            if let Some(cb) = channel_bindings.get(&c.number) {
-                cb.refresh(lifetime).await;
-
-                // Channel binds also refresh permissions.
-                self.add_permission(Permission::new(cb.peer)).await;
+            let existed_bind = channel_bindings.get(&c.number).cloned();
 
-                return Ok(());
+            if existed_bind.is_none() {
+                if let Some(max_channel_bindings) = self.max_channel_bindings {
+                    if channel_bindings.len() >= max_channel_bindings {
+                        return Err(Error::ErrTooManyChannelBindings);
+                    }
+                }
             }
+
+            existed_bind
+        };
+
+        if let Some(cb) = existed_bind {
+            cb.refresh(lifetime).await;
+
+            // Channel binds also refresh permissions, for as long as the channel lasts.
+            return self
+                .add_permission_with_lifetime(
+                    Permission::new(cb.peer),
+                    lifetime.max(self.permission_timeout),
+                )
+                .await;
         }
 
-        let peer = c.peer;
+        // Channel binds also refresh permissions, for as long as the channel lasts.
+        self.add_permission_with_lifetime(
+            Permission::new(c.peer),
+            lifetime.max(self.permission_timeout),
+        )
+        .await?;
 
         // Add or refresh this channel.
         c.channel_bindings = Some(Arc::clone(&self.channel_bindings));
@@ -189,9 +673,6 @@ impl Allocation {
             channel_bindings.insert(c.number, c);
         }
 
-        // Channel binds also refresh permissions.
-        self.add_permission(Permission::new(peer)).await;
-
         Ok(())
     }
 
@@ -203,21 +684,136 @@ impl Allocation {
 
     // get_channel_addr gets the ChannelBind's addr
     pub async fn get_channel_addr(&self, number: &ChannelNumber) -> Option<SocketAddr> {
+        if !number.valid() {
+            return None;
+        }
+
         let channel_bindings = self.channel_bindings.lock().await;
         channel_bindings.get(number).map(|cb| cb.peer)
     }
 
+    // channel_expiry returns when the channel bound to number expires unless it is
+    // refreshed, or None if there is no such channel
+    pub async fn channel_expiry(&self, number: &ChannelNumber) -> Option<Instant> {
+        let channel_bindings = self.channel_bindings.lock().await;
+        channel_bindings.get(number).and_then(|cb| cb.expires_at())
+    }
+
     // GetChannelByAddr gets the ChannelBind's number from this allocation by net.Addr
     pub async fn get_channel_number(&self, addr: &SocketAddr) -> Option<ChannelNumber> {
         let channel_bindings = self.channel_bindings.lock().await;
         for cb in channel_bindings.values() {
-            if cb.peer == *addr {
+            if cb.peer == *addr && cb.number.valid() {
                 return Some(cb.number);
             }
         }
         None
     }
 
+    // connect adds conn, a TCP connection the server opened to peer for the client of
+    // this TCP allocation, and returns the CONNECTION-ID the client binds a data
+    // connection to it with. The allocation needs a permission for peer and may hold
+    // one connection per peer.
+    //
+    // RFC 6062 Section 5.2
+    pub async fn connect(
+        &self,
+        peer: SocketAddr,
+        conn: Arc<dyn Conn + Send + Sync>,
+    ) -> Result<ConnectionId> {
+        if self.protocol != PROTO_TCP {
+            return Err(Error::ErrNotTcpAllocation);
+        }
+        self.check_peer(&peer)?;
+        if !self.has_permission(&peer).await {
+            return Err(Error::ErrNoPermission);
+        }
+
+        let mut peer_connections = self.peer_connections.lock().await;
+        if peer_connections.find(&peer).is_some() {
+            return Err(Error::ErrConnectionAlreadyExists);
+        }
+        let id = peer_connections.insert(peer, conn);
+        log::debug!(
+            "allocation {} connected to {} with CONNECTION-ID {}",
+            self.five_tuple,
+            peer,
+            id
+        );
+        Ok(id)
+    }
+
+    // connection_bind binds data_conn, a data connection from the client, to the peer
+    // connection id identifies and starts forwarding between the two. It returns the
+    // address of the peer. Each peer connection is bound once.
+    //
+    // RFC 6062 Section 5.4
+    pub async fn connection_bind(
+        &self,
+        id: ConnectionId,
+        data_conn: Arc<dyn Conn + Send + Sync>,
+    ) -> Result<SocketAddr> {
+        let mut peer_connections = self.peer_connections.lock().await;
+        let c = match peer_connections.get_mut(&id) {
+            Some(c) if c.data_conn.is_none() => c,
+            _ => return Err(Error::ErrUnknownConnectionId),
+        };
+
+        c.data_conn = Some(Arc::clone(&data_conn));
+        c.forwarder = Some(tokio::spawn(Allocation::forward_peer_connection(
+            id,
+            Arc::clone(&c.conn),
+            data_conn,
+            Arc::clone(&self.counters),
+            Arc::clone(&self.peer_connections),
+        )));
+        Ok(c.peer)
+    }
+
+    // peer_connection_id returns the CONNECTION-ID of the connection to peer, if any
+    pub async fn peer_connection_id(&self, peer: &SocketAddr) -> Option<ConnectionId> {
+        self.peer_connections.lock().await.find(peer)
+    }
+
+    // forward_peer_connection copies the bytes read from each of the peer and data
+    // connections to the other one. Once either is closed or fails, both are closed and
+    // the peer connection is removed.
+    async fn forward_peer_connection(
+        id: ConnectionId,
+        conn: Arc<dyn Conn + Send + Sync>,
+        data_conn: Arc<dyn Conn + Send + Sync>,
+        counters: Arc<AllocationCounters>,
+        peer_connections: Arc<Mutex<PeerConnections>>,
+    ) {
+        let mut from_peer = vec![0u8; RTP_MTU];
+        let mut from_client = vec![0u8; RTP_MTU];
+        loop {
+            let result = tokio::select! {
+                result = conn.recv(&mut from_peer) => match result {
+                    Ok(0) => break,
+                    Ok(n) => data_conn.send(&from_peer[..n]).await.map(|_| counters.add_received(n)),
+                    Err(err) => Err(err),
+                },
+                result = data_conn.recv(&mut from_client) => match result {
+                    Ok(0) => break,
+                    Ok(n) => conn.send(&from_client[..n]).await.map(|_| counters.add_sent(n)),
+                    Err(err) => Err(err),
+                },
+            };
+            if let Err(err) = result {
+                log::debug!("peer connection {} failed: {}", id, err);
+                break;
+            }
+        }
+
+        // The forwarder is this very task, it isn't aborted
+        let c = peer_connections.lock().await.remove(&id);
+        if let Some(mut c) = c {
+            c.forwarder.take();
+            c.close().await;
+        }
+    }
+
     // Close closes the allocation
     pub async fn close(&self) -> Result<()> {
         if self.closed.load(Ordering::Acquire) {
@@ -241,23 +837,46 @@ impl Allocation {
             }
         }
 
+        let peer_connections = self.peer_connections.lock().await.drain();
+        for c in peer_connections {
+            c.close().await;
+        }
+
         log::trace!("allocation with {} closed!", self.five_tuple);
 
+        // This waits for room in the notifier, callers must not hold the allocations
+        // lock meanwhile
+        if let Some(expiry_notifier) = &self.expiry_notifier {
+            let _ = expiry_notifier.send(self.five_tuple).await;
+        }
+
         let _ = self.turn_socket.close().await;
-        let _ = self.relay_socket.close().await;
+        let _ = self.relay_socket().close().await;
 
         Ok(())
     }
 
-    pub async fn start(&self, lifetime: Duration) {
+    // granted_lifetime clamps a requested lifetime to [min_lifetime, max_lifetime].
+    // A zero lifetime is a deallocation and is passed through untouched.
+    pub(crate) fn granted_lifetime(&self, lifetime: Duration) -> Duration {
+        if lifetime == Duration::from_secs(0) {
+            return lifetime;
+        }
+        lifetime.max(self.min_lifetime).min(self.max_lifetime)
+    }
+
+    // start arms the lifetime timer and returns the granted lifetime
+    pub async fn start(&self, lifetime: Duration) -> Duration {
+        let lifetime = self.granted_lifetime(lifetime);
         let (reset_tx, mut reset_rx) = mpsc::channel(1);
         self.reset_tx.lock().replace(reset_tx);
 
         let allocations = self.allocations.clone();
         let five_tuple = self.five_tuple;
         let timer_expired = Arc::clone(&self.timer_expired);
+        self.expires_at.lock().replace(Instant::now() + lifetime);
 
-        tokio::spawn(async move {
+        let timer_handle = tokio::spawn(async move {
             let timer = tokio::time::sleep(lifetime);
             tokio::pin!(timer);
             let mut done = false;
@@ -265,9 +884,12 @@ impl Allocation {
             while !done {
                 tokio::select! {
                     _ = &mut timer => {
-                        if let Some(allocs) = &allocations{
-                            let mut alls = allocs.lock().await;
-                            if let Some(a) = alls.remove(&five_tuple) {
+                        // Mark the timer as expired first, so that closing the
+                        // allocation from here doesn't abort this very task.
+                        timer_expired.store(true, Ordering::SeqCst);
+                        if let Some(allocs) = &allocations {
+                            let a = allocs.lock().await.remove(&five_tuple);
+                            if let Some(a) = a {
                                 let _ = a.close().await;
                             }
                         }
@@ -285,19 +907,234 @@ impl Allocation {
 
             timer_expired.store(true, Ordering::SeqCst);
         });
+        self.timer_handle.lock().replace(timer_handle);
+
+        lifetime
     }
 
+    // stop aborts the lifetime timer and reports whether it had already expired
     fn stop(&self) -> bool {
         let reset_tx = self.reset_tx.lock().take();
-        reset_tx.is_none() || self.timer_expired.load(Ordering::SeqCst)
+        let expired = reset_tx.is_none() || self.timer_expired.load(Ordering::SeqCst);
+
+        if let Some(timer_handle) = self.timer_handle.lock().take() {
+            if !expired {
+                timer_handle.abort();
+            }
+        }
+
+        expired
     }
 
-    // Refresh updates the allocations lifetime
-    pub async fn refresh(&self, lifetime: Duration) {
+    // Refresh updates the allocations lifetime and returns the granted lifetime.
+    // Refreshing with a zero lifetime deallocates: the allocation is removed from
+    // its manager and closed before this returns, and the zero lifetime confirms it.
+    // The relay socket is released once the last reference to the allocation goes.
+    pub async fn refresh(&self, lifetime: Duration) -> Duration {
+        let lifetime = self.granted_lifetime(lifetime);
+        if lifetime == Duration::from_secs(0) {
+            // The lifetime is over, there is no timer left to abort
+            self.timer_expired.store(true, Ordering::SeqCst);
+            if let Some(allocs) = &self.allocations {
+                allocs.lock().await.remove(&self.five_tuple);
+            }
+            let _ = self.close().await;
+            return lifetime;
+        }
+
+        self.counters.touch();
         let reset_tx = self.reset_tx.lock().clone();
         if let Some(tx) = reset_tx {
+            self.expires_at.lock().replace(Instant::now() + lifetime);
             let _ = tx.send(lifetime).await;
         }
+        lifetime
+    }
+
+    // idle_time returns how long the allocation has gone without relaying a datagram
+    // in either direction or being refreshed
+    pub fn idle_time(&self) -> Duration {
+        self.counters.idle_time()
+    }
+
+    // remaining_lifetime returns the time left until the allocation expires unless it
+    // is refreshed, zero once it has expired or if it was never started
+    pub fn remaining_lifetime(&self) -> Duration {
+        self.expires_at
+            .lock()
+            .map_or(Duration::from_secs(0), |expires_at| {
+                expires_at.saturating_duration_since(Instant::now())
+            })
+    }
+
+    // info returns a summary of the allocation, it takes the permission and channel
+    // locks one at a time and holds neither across other awaits
+    pub(crate) async fn info(&self) -> AllocationInfo {
+        AllocationInfo::new(
+            self.five_tuple,
+            self.username.text.clone(),
+            self.relay_addr(),
+            self.protocol,
+            self.permission_count().await,
+            self.channel_count().await,
+            self.remaining_lifetime(),
+            #[cfg(feature = "metrics")]
+            self.relayed_bytes.load(Ordering::Acquire),
+        )
+    }
+
+    // stats returns a snapshot of the traffic relayed by this allocation
+    pub async fn stats(&self) -> AllocationStats {
+        AllocationStats {
+            bytes_sent: self.counters.bytes_sent.load(Ordering::Relaxed),
+            packets_sent: self.counters.packets_sent.load(Ordering::Relaxed),
+            bytes_received: self.counters.bytes_received.load(Ordering::Relaxed),
+            packets_received: self.counters.packets_received.load(Ordering::Relaxed),
+            frames_dropped: self.counters.frames_dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    // write_to_relay sends data to peer through the relay socket and returns the
+    // number of payload bytes written. A TCP relay has no datagram boundaries,
+    // so every packet is framed with a 2-byte length prefix, which can't describe
+    // more than 65535 bytes. Nothing is sent to a
+    // peer the peer filter denies. Under a rate limit a
+    // UDP datagram is dropped when the allocation is over it, while a TCP relay
+    // waits for the bucket to refill, which backpressures the client.
+    pub(crate) async fn write_to_relay(&self, data: &[u8], peer: SocketAddr) -> Result<usize> {
+        self.check_peer(&peer)?;
+        if self.protocol == PROTO_TCP && data.len() > u16::MAX as usize {
+            return Err(Error::ErrTcpFrameTooLarge);
+        }
+        if let Some(rate_limiter) = &self.rate_limiter {
+            if self.protocol == PROTO_TCP {
+                rate_limiter.take(data.len()).await;
+            } else if !rate_limiter.try_take(data.len()) {
+                return Err(Error::ErrRateLimited);
+            }
+        }
+
+        let n = if self.protocol == PROTO_TCP {
+            let mut frame = Vec::with_capacity(TCP_FRAME_HEADER_SIZE + data.len());
+            frame.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            frame.extend_from_slice(data);
+
+            // The relay connection is opened to the first peer written to and carries
+            // nothing for any other peer
+            let relay_socket = self.relay_socket();
+            match relay_socket.remote_addr() {
+                Some(remote_addr) if remote_addr != peer => {
+                    return Err(Error::ErrTcpRelayPeerMismatch)
+                }
+                Some(_) => {}
+                None => relay_socket.connect(peer).await?,
+            }
+            let n = relay_socket.send(&frame).await?;
+            n.saturating_sub(TCP_FRAME_HEADER_SIZE)
+        } else {
+            match self.relay_socket().send_to(data, peer).await {
+                Ok(n) => n,
+                Err(err) if is_datagram_too_large(&err) => {
+                    log::warn!(
+                        "allocation {}: {} byte datagram to {} exceeds the path MTU (dont_fragment: {})",
+                        self.five_tuple,
+                        data.len(),
+                        peer,
+                        self.dont_fragment
+                    );
+                    if let Some(listener) = &self.too_large_listener {
+                        let _ = listener.try_send((peer, data.len()));
+                    }
+                    return Err(Error::ErrDatagramTooLarge);
+                }
+                Err(err) => return Err(err.into()),
+            }
+        };
+
+        self.counters.add_sent(n);
+
+        if self.refresh_permissions_on_traffic {
+            self.refresh_permission(&peer).await;
+        }
+
+        Ok(n)
+    }
+
+    // relay_to_peer sends application-injected data to peer through the relay
+    // socket. The same permission check as for client Send indications applies,
+    // so nothing is written to a peer without an installed permission.
+    pub async fn relay_to_peer(&self, peer: &SocketAddr, data: &[u8]) -> Result<usize> {
+        let has_permission = match self.try_has_permission(peer) {
+            Ok(has_permission) => has_permission,
+            Err(err) => {
+                log::debug!("relay to {} waits for permissions: {}", peer, err);
+                self.has_permission(peer).await
+            }
+        };
+        if !has_permission {
+            return Err(Error::ErrNoPermission);
+        }
+
+        self.write_to_relay(data, *peer).await
+    }
+
+    // recv_from_relay reads the next packet from the relay socket into buffer.
+    // It returns None once a TCP relay connection has been closed by the peer.
+    // A UDP datagram longer than recv_buffer_size is truncated, buffer must have room
+    // for one more byte so that the truncation can be told apart from a datagram that
+    // fits exactly.
+    async fn recv_from_relay(
+        protocol: Protocol,
+        relay_socket: &Arc<dyn Conn + Send + Sync>,
+        pending: &mut Vec<u8>,
+        buffer: &mut Vec<u8>,
+        recv_buffer_size: usize,
+    ) -> Result<Option<(usize, SocketAddr)>> {
+        if protocol != PROTO_TCP {
+            let (n, src_addr) = loop {
+                match relay_socket.recv_from(buffer).await {
+                    Ok(read) => break read,
+                    // Windows fails the read of a datagram longer than buffer with
+                    // WSAEMSGSIZE instead of truncating it. The error doesn't carry the
+                    // peer the datagram came from, so it can't be relayed and is dropped.
+                    Err(err) if is_datagram_too_large(&err) => {
+                        log::debug!("dropping datagram longer than {} bytes", buffer.len());
+                    }
+                    Err(err) => return Err(err.into()),
+                }
+            };
+            if n > recv_buffer_size {
+                log::debug!(
+                    "truncating datagram from {} to {} bytes",
+                    src_addr,
+                    recv_buffer_size
+                );
+                return Ok(Some((recv_buffer_size, src_addr)));
+            }
+            return Ok(Some((n, src_addr)));
+        }
+
+        loop {
+            if pending.len() >= TCP_FRAME_HEADER_SIZE {
+                let n = u16::from_be_bytes([pending[0], pending[1]]) as usize;
+                if pending.len() >= TCP_FRAME_HEADER_SIZE + n {
+                    buffer.clear();
+                    buffer.extend_from_slice(
+                        &pending[TCP_FRAME_HEADER_SIZE..TCP_FRAME_HEADER_SIZE + n],
+                    );
+                    pending.drain(..TCP_FRAME_HEADER_SIZE + n);
+                    let src_addr = relay_socket.remote_addr().ok_or(Error::ErrNoRemoteAddr)?;
+                    return Ok(Some((n, src_addr)));
+                }
+            }
+
+            let mut chunk = [0u8; RTP_MTU];
+            let n = relay_socket.recv(&mut chunk).await?;
+            if n == 0 {
+                return Ok(None);
+            }
+            pending.extend_from_slice(&chunk[..n]);
+        }
     }
 
     //  https://tools.ietf.org/html/rfc5766#section-10.3
@@ -319,40 +1156,66 @@ impl Allocation {
     //  datagram, and the XOR-PEER-ADDRESS attribute is set to the source
     //  transport address of the received UDP datagram.  The Data indication
     //  is then sent on the 5-tuple associated with the allocation.
-    async fn packet_handler(&mut self) {
+    async fn packet_handler(&self) {
+        let protocol = self.protocol;
         let five_tuple = self.five_tuple;
-        let relay_addr = self.relay_addr;
-        let relay_socket = Arc::clone(&self.relay_socket);
+        let relay_addr = self.relay_addr();
+        let relay_socket = self.relay_socket();
         let turn_socket = Arc::clone(&self.turn_socket);
         let allocations = self.allocations.clone();
         let channel_bindings = Arc::clone(&self.channel_bindings);
         let permissions = Arc::clone(&self.permissions);
+        let counters = Arc::clone(&self.counters);
+        let data_listener = self.data_listener.clone();
+        let recv_buffer_size = self.recv_buffer_size;
+        let outbound = self
+            .outbound_high_water_mark
+            .map(|high_water_mark| Arc::new(OutboundQueue::new(high_water_mark)));
         let (drop_tx, drop_rx) = oneshot::channel::<u32>();
-        self.drop_tx = Some(drop_tx);
+        self.drop_tx.lock().replace(drop_tx);
 
         tokio::spawn(async move {
-            let mut buffer = vec![0u8; RTP_MTU];
+            let writer = outbound.as_ref().map(|outbound| {
+                tokio::spawn(Allocation::write_to_client(
+                    Arc::clone(outbound),
+                    Arc::clone(&turn_socket),
+                    Arc::clone(&counters),
+                    five_tuple,
+                ))
+            });
+
+            let mut buffer = vec![0u8; recv_buffer_size + 1];
+            let mut pending = vec![];
 
             tokio::pin!(drop_rx);
 
             loop {
+                // A stopped loop must not act on a read error from its closed socket
                 let (n, src_addr) = tokio::select! {
-                    result = relay_socket.recv_from(&mut buffer) => {
+                    biased;
+
+                    _ = drop_rx.as_mut() => {
+                        log::trace!("allocation has stopped, stop packet_handler. five_tuple: {:?}", five_tuple);
+                        break;
+                    }
+                    result = Allocation::recv_from_relay(protocol, &relay_socket, &mut pending, &mut buffer, recv_buffer_size) => {
                         match result {
-                            Ok((n, src_addr)) => (n, src_addr),
-                            Err(_) => {
+                            Ok(Some((n, src_addr))) => (n, src_addr),
+                            result => {
+                                match result {
+                                    Err(err) => log::debug!("relay socket for allocation {} failed to read: {}", five_tuple, err),
+                                    _ => log::debug!("relay connection for allocation {} closed by peer", five_tuple),
+                                }
                                 if let Some(allocs) = &allocations {
-                                    let mut alls = allocs.lock().await;
-                                    alls.remove(&five_tuple);
+                                    let a = allocs.lock().await.remove(&five_tuple);
+                                    if let Some(a) = a {
+                                        let _ = a.close().await;
+                                    }
                                 }
                                 break;
                             }
                         }
                     }
-                    _ = drop_rx.as_mut() => {
-                        log::trace!("allocation has stopped, stop packet_handler. five_tuple: {:?}", five_tuple);
-                        break;
-                    }
                 };
 
                 log::debug!(
@@ -375,22 +1238,43 @@ impl Allocation {
                 };
 
                 if let Some(number) = cb_number {
+                    relay_trace!(
+                        "allocation {}: {} bytes from {} matched channel {}",
+                        five_tuple,
+                        n,
+                        src_addr,
+                        number
+                    );
                     let mut channel_data = ChannelData {
                         data: buffer[..n].to_vec(),
                         number,
                         raw: vec![],
                     };
-                    channel_data.encode();
+                    channel_data.encode_for(five_tuple.protocol);
 
-                    if let Err(err) = turn_socket
+                    if let Some(outbound) = &outbound {
+                        Allocation::queue_for_client(
+                            outbound,
+                            &counters,
+                            protocol,
+                            (channel_data.raw, n),
+                        )
+                        .await;
+                        continue;
+                    }
+
+                    match turn_socket
                         .send_to(&channel_data.raw, five_tuple.src_addr)
                         .await
                     {
-                        log::error!(
-                            "Failed to send ChannelData from allocation {} {}",
-                            src_addr,
-                            err
-                        );
+                        Ok(_) => counters.add_received(n),
+                        Err(err) => {
+                            log::error!(
+                                "Failed to send ChannelData from allocation {} {}",
+                                src_addr,
+                                err
+                            );
+                        }
                     }
                 } else {
                     let exist = {
@@ -399,28 +1283,24 @@ impl Allocation {
                     };
 
                     if exist {
-                        let msg = {
-                            let peer_address_attr = PeerAddress {
-                                ip: src_addr.ip(),
-                                port: src_addr.port(),
-                            };
-                            let data_attr = Data(buffer[..n].to_vec());
-
-                            let mut msg = Message::new();
-                            if let Err(err) = msg.build(&[
-                                Box::new(TransactionId::new()),
-                                Box::new(MessageType::new(METHOD_DATA, CLASS_INDICATION)),
-                                Box::new(peer_address_attr),
-                                Box::new(data_attr),
-                            ]) {
+                        relay_trace!(
+                            "allocation {}: {} bytes from {} matched permission",
+                            five_tuple,
+                            n,
+                            src_addr
+                        );
+                        if let Some(listener) = &data_listener {
+                            let _ = listener.try_send((src_addr, buffer[..n].to_vec()));
+                        }
+                        let msg = match build_data_indication(&src_addr, &buffer[..n]) {
+                            Ok(msg) => Some(msg),
+                            Err(err) => {
                                 log::error!(
                                     "Failed to send DataIndication from allocation {} {}",
                                     src_addr,
                                     err
                                 );
                                 None
-                            } else {
-                                Some(msg)
                             }
                         };
 
@@ -430,17 +1310,34 @@ impl Allocation {
                                 src_addr,
                                 five_tuple.src_addr
                             );
-                            if let Err(err) =
-                                turn_socket.send_to(&msg.raw, five_tuple.src_addr).await
-                            {
-                                log::error!(
-                                    "Failed to send DataIndication from allocation {} {}",
-                                    src_addr,
-                                    err
-                                );
+                            if let Some(outbound) = &outbound {
+                                Allocation::queue_for_client(
+                                    outbound,
+                                    &counters,
+                                    protocol,
+                                    (msg.raw, n),
+                                )
+                                .await;
+                                continue;
+                            }
+                            match turn_socket.send_to(&msg.raw, five_tuple.src_addr).await {
+                                Ok(_) => counters.add_received(n),
+                                Err(err) => {
+                                    log::error!(
+                                        "Failed to send DataIndication from allocation {} {}",
+                                        src_addr,
+                                        err
+                                    );
+                                }
                             }
                         }
                     } else {
+                        relay_trace!(
+                            "allocation {}: {} bytes from {} dropped, no permission or channel",
+                            five_tuple,
+                            n,
+                            src_addr
+                        );
                         log::info!(
                             "No Permission or Channel exists for {} on allocation {}",
                             src_addr,
@@ -449,6 +1346,58 @@ impl Allocation {
                     }
                 }
             }
+
+            if let Some(writer) = writer {
+                writer.abort();
+            }
         });
     }
+
+    // queue_for_client hands frame to the task writing to the client. A UDP relay
+    // drops the oldest queued frame when the queue is full, a TCP relay waits for room.
+    async fn queue_for_client(
+        outbound: &OutboundQueue,
+        counters: &AllocationCounters,
+        protocol: Protocol,
+        frame: OutboundFrame,
+    ) {
+        if protocol == PROTO_TCP {
+            outbound.push(frame).await;
+        } else if outbound.push_dropping_oldest(frame) {
+            counters.frames_dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // write_to_client writes the queued frames to the client until it is aborted
+    async fn write_to_client(
+        outbound: Arc<OutboundQueue>,
+        turn_socket: Arc<dyn Conn + Send + Sync>,
+        counters: Arc<AllocationCounters>,
+        five_tuple: FiveTuple,
+    ) {
+        loop {
+            let (frame, n) = outbound.pop().await;
+            match turn_socket.send_to(&frame, five_tuple.src_addr).await {
+                Ok(_) => counters.add_received(n),
+                Err(err) => {
+                    log::error!(
+                        "Failed to relay {} bytes to the client of allocation {}: {}",
+                        n,
+                        five_tuple,
+                        err
+                    );
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Allocation {
+    // An allocation dropped without being closed must not leave its lifetime timer running.
+    // Drop can't wait, so the task is only aborted.
+    fn drop(&mut self) {
+        if let Some(timer_handle) = self.timer_handle.lock().take() {
+            timer_handle.abort();
+        }
+    }
 }
@@ -0,0 +1,74 @@
+use util::sync::Mutex as SyncMutex;
+
+use std::collections::VecDeque;
+use tokio::sync::Notify;
+
+// A frame on its way to the client, along with the payload bytes it carries
+pub(crate) type OutboundFrame = (Vec<u8>, usize);
+
+// OutboundQueue holds the frames relayed to the client until the turn socket takes
+// them, at most high_water_mark of them, so that a slow client can't make the relay
+// buffer without bounds. It has a single producer, the relay loop, and a single
+// consumer writing to the turn socket.
+pub(crate) struct OutboundQueue {
+    frames: SyncMutex<VecDeque<OutboundFrame>>,
+    high_water_mark: usize,
+    pushed: Notify,
+    popped: Notify,
+}
+
+impl OutboundQueue {
+    pub(crate) fn new(high_water_mark: usize) -> Self {
+        OutboundQueue {
+            frames: SyncMutex::new(VecDeque::new()),
+            high_water_mark: high_water_mark.max(1),
+            pushed: Notify::new(),
+            popped: Notify::new(),
+        }
+    }
+
+    // push_dropping_oldest queues frame, a full queue makes room by dropping its
+    // oldest frame. It reports whether a frame was dropped.
+    pub(crate) fn push_dropping_oldest(&self, frame: OutboundFrame) -> bool {
+        let dropped = {
+            let mut frames = self.frames.lock();
+            let dropped = if frames.len() >= self.high_water_mark {
+                frames.pop_front().is_some()
+            } else {
+                false
+            };
+            frames.push_back(frame);
+            dropped
+        };
+        self.pushed.notify_one();
+        dropped
+    }
+
+    // push queues frame, waiting for the consumer to make room while the queue is full
+    pub(crate) async fn push(&self, frame: OutboundFrame) {
+        loop {
+            let popped = self.popped.notified();
+            {
+                let mut frames = self.frames.lock();
+                if frames.len() < self.high_water_mark {
+                    frames.push_back(frame);
+                    break;
+                }
+            }
+            popped.await;
+        }
+        self.pushed.notify_one();
+    }
+
+    // pop waits for a frame and takes the oldest one
+    pub(crate) async fn pop(&self) -> OutboundFrame {
+        loop {
+            let pushed = self.pushed.notified();
+            if let Some(frame) = self.frames.lock().pop_front() {
+                self.popped.notify_one();
+                return frame;
+            }
+            pushed.await;
+        }
+    }
+}
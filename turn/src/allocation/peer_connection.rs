@@ -0,0 +1,81 @@
+use crate::proto::connid::ConnectionId;
+
+use util::Conn;
+
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use tokio::task::JoinHandle;
+
+// PeerConnection is a TCP connection opened to a peer on behalf of the client of a
+// TCP allocation, along with the client's data connection once it is bound to it
+//
+// RFC 6062 Section 5.2
+pub(crate) struct PeerConnection {
+    pub(crate) peer: SocketAddr,
+    pub(crate) conn: Arc<dyn Conn + Send + Sync>,
+    pub(crate) data_conn: Option<Arc<dyn Conn + Send + Sync>>,
+    pub(crate) forwarder: Option<JoinHandle<()>>,
+}
+
+impl PeerConnection {
+    // close stops forwarding and closes both connections
+    pub(crate) async fn close(self) {
+        if let Some(forwarder) = self.forwarder {
+            forwarder.abort();
+        }
+        let _ = self.conn.close().await;
+        if let Some(data_conn) = self.data_conn {
+            let _ = data_conn.close().await;
+        }
+    }
+}
+
+// PeerConnections maps the CONNECTION-IDs handed to the client to the peer
+// connections they identify
+#[derive(Default)]
+pub(crate) struct PeerConnections {
+    conns: HashMap<ConnectionId, PeerConnection>,
+}
+
+impl PeerConnections {
+    // find returns the CONNECTION-ID of the connection to peer, if any
+    pub(crate) fn find(&self, peer: &SocketAddr) -> Option<ConnectionId> {
+        self.conns
+            .iter()
+            .find(|(_, c)| c.peer == *peer)
+            .map(|(id, _)| *id)
+    }
+
+    // insert adds the connection to peer under a new random CONNECTION-ID
+    pub(crate) fn insert(
+        &mut self,
+        peer: SocketAddr,
+        conn: Arc<dyn Conn + Send + Sync>,
+    ) -> ConnectionId {
+        let mut id = ConnectionId(rand::random());
+        while self.conns.contains_key(&id) {
+            id = ConnectionId(rand::random());
+        }
+        self.conns.insert(
+            id,
+            PeerConnection {
+                peer,
+                conn,
+                data_conn: None,
+                forwarder: None,
+            },
+        );
+        id
+    }
+
+    pub(crate) fn get_mut(&mut self, id: &ConnectionId) -> Option<&mut PeerConnection> {
+        self.conns.get_mut(id)
+    }
+
+    pub(crate) fn remove(&mut self, id: &ConnectionId) -> Option<PeerConnection> {
+        self.conns.remove(id)
+    }
+
+    pub(crate) fn drain(&mut self) -> Vec<PeerConnection> {
+        self.conns.drain().map(|(_, c)| c).collect()
+    }
+}
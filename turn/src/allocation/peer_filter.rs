@@ -0,0 +1,136 @@
+#[cfg(test)]
+mod peer_filter_test;
+
+use crate::error::*;
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+// IpPrefix is an IP network in CIDR notation, e.g. 10.0.0.0/8
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpPrefix {
+    addr: IpAddr,
+    len: u8,
+}
+
+impl IpPrefix {
+    // new creates a prefix of the first len bits of addr
+    pub fn new(addr: IpAddr, len: u8) -> Result<Self> {
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        if len > max_len {
+            return Err(Error::ErrInvalidIpPrefix);
+        }
+        Ok(IpPrefix { addr, len })
+    }
+
+    // contains reports whether ip is in the prefix. IPv4-mapped IPv6 addresses are
+    // matched as the IPv4 address they carry, so they can't sneak past an IPv4 prefix.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, unmap_ipv4(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.len as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.len as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// unmap_ipv4 turns an IPv4-mapped IPv6 address into the IPv4 address it maps
+pub(crate) fn unmap_ipv4(ip: &IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, high, low] => IpAddr::V4(Ipv4Addr::new(
+                (high >> 8) as u8,
+                high as u8,
+                (low >> 8) as u8,
+                low as u8,
+            )),
+            _ => *ip,
+        },
+        IpAddr::V4(_) => *ip,
+    }
+}
+
+impl FromStr for IpPrefix {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, len) = s.split_once('/').ok_or(Error::ErrInvalidIpPrefix)?;
+        let addr = addr.parse().map_err(|_| Error::ErrInvalidIpPrefix)?;
+        let len = len.parse().map_err(|_| Error::ErrInvalidIpPrefix)?;
+        IpPrefix::new(addr, len)
+    }
+}
+
+impl fmt::Display for IpPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+// PeerFilter decides which peers allocations may relay to, it keeps a TURN server
+// from being used to reach networks behind it. An address in a denied prefix is
+// refused unless it is also in an allowed prefix.
+//
+// The default denies the unspecified ("this network", 0.0.0.0/8 and ::), private
+// (RFC 1918 and IPv6 unique local), loopback and link-local ranges.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerFilter {
+    denied: Vec<IpPrefix>,
+    allowed: Vec<IpPrefix>,
+}
+
+impl Default for PeerFilter {
+    fn default() -> Self {
+        let prefix = |addr: IpAddr, len| IpPrefix { addr, len };
+        PeerFilter {
+            denied: vec![
+                prefix(Ipv4Addr::UNSPECIFIED.into(), 8),
+                prefix(Ipv4Addr::new(10, 0, 0, 0).into(), 8),
+                prefix(Ipv4Addr::new(172, 16, 0, 0).into(), 12),
+                prefix(Ipv4Addr::new(192, 168, 0, 0).into(), 16),
+                prefix(Ipv4Addr::new(127, 0, 0, 0).into(), 8),
+                prefix(Ipv4Addr::new(169, 254, 0, 0).into(), 16),
+                prefix(Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0).into(), 7),
+                prefix(Ipv6Addr::UNSPECIFIED.into(), 128),
+                prefix(Ipv6Addr::LOCALHOST.into(), 128),
+                prefix(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0).into(), 10),
+            ],
+            allowed: vec![],
+        }
+    }
+}
+
+impl PeerFilter {
+    // allow_all creates a filter that denies nothing
+    pub fn allow_all() -> Self {
+        PeerFilter {
+            denied: vec![],
+            allowed: vec![],
+        }
+    }
+
+    // with_denied adds a prefix whose addresses are refused
+    pub fn with_denied(mut self, prefix: IpPrefix) -> Self {
+        self.denied.push(prefix);
+        self
+    }
+
+    // with_allowed adds a prefix whose addresses are accepted even if denied
+    pub fn with_allowed(mut self, prefix: IpPrefix) -> Self {
+        self.allowed.push(prefix);
+        self
+    }
+
+    // is_denied reports whether allocations must not relay to ip
+    pub fn is_denied(&self, ip: &IpAddr) -> bool {
+        self.denied.iter().any(|prefix| prefix.contains(ip))
+            && !self.allowed.iter().any(|prefix| prefix.contains(ip))
+    }
+}
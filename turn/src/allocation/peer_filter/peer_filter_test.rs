@@ -0,0 +1,86 @@
+use super::*;
+
+#[test]
+fn test_ip_prefix() -> Result<()> {
+    let tests = vec![
+        ("10.0.0.0/8", "10.1.2.3", true),
+        ("10.0.0.0/8", "11.0.0.1", false),
+        ("172.16.0.0/12", "172.31.255.255", true),
+        ("172.16.0.0/12", "172.32.0.1", false),
+        ("0.0.0.0/0", "8.8.8.8", true),
+        ("192.0.2.1/32", "192.0.2.1", true),
+        ("192.0.2.1/32", "192.0.2.2", false),
+        ("fe80::/10", "fe80::1", true),
+        ("fe80::/10", "fec0::1", false),
+        ("10.0.0.0/8", "::ffff:10.0.0.1", true),
+        ("10.0.0.0/8", "::1", false),
+    ];
+
+    for (prefix, ip, expected) in tests {
+        let prefix: IpPrefix = prefix.parse()?;
+        let ip: IpAddr = ip.parse().unwrap();
+        assert_eq!(
+            expected,
+            prefix.contains(&ip),
+            "{} contains {} should be {}",
+            prefix,
+            ip,
+            expected
+        );
+    }
+
+    for invalid in ["10.0.0.0", "10.0.0.0/33", "::/129", "nope/8", "10.0.0.0/x"] {
+        assert_eq!(
+            Err(Error::ErrInvalidIpPrefix),
+            invalid.parse::<IpPrefix>(),
+            "{} should not parse",
+            invalid
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_peer_filter() -> Result<()> {
+    let filter = PeerFilter::default();
+    for denied in [
+        "0.0.0.0",
+        "0.1.2.3",
+        "10.0.0.1",
+        "172.16.5.4",
+        "192.168.1.1",
+        "127.0.0.1",
+        "169.254.169.254",
+        "::",
+        "::1",
+        "fe80::1",
+        "fd00::1",
+        "::ffff:192.168.1.1",
+        "::ffff:0.0.0.0",
+    ] {
+        assert!(
+            filter.is_denied(&denied.parse().unwrap()),
+            "{} should be denied by default",
+            denied
+        );
+    }
+    for allowed in ["1.0.0.1", "8.8.8.8", "203.0.113.7", "::2", "2001:db8::1"] {
+        assert!(
+            !filter.is_denied(&allowed.parse().unwrap()),
+            "{} should be allowed by default",
+            allowed
+        );
+    }
+
+    let filter = PeerFilter::default()
+        .with_allowed("192.168.1.0/24".parse()?)
+        .with_denied("203.0.113.0/24".parse()?);
+    assert!(!filter.is_denied(&"192.168.1.1".parse().unwrap()));
+    assert!(filter.is_denied(&"192.168.2.1".parse().unwrap()));
+    assert!(filter.is_denied(&"203.0.113.7".parse().unwrap()));
+
+    assert!(!PeerFilter::allow_all().is_denied(&"127.0.0.1".parse().unwrap()));
+
+    Ok(())
+}
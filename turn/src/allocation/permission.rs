@@ -9,11 +9,13 @@ pub(crate) const PERMISSION_TIMEOUT: Duration = Duration::from_secs(5 * 60);
 // Permission represents a TURN permission. TURN permissions mimic the address-restricted
 // filtering mechanism of NATs that comply with [RFC4787].
 // https://tools.ietf.org/html/rfc5766#section-2.3
+#[derive(Clone)]
 pub struct Permission {
     pub(crate) addr: SocketAddr,
     pub(crate) permissions: Option<Arc<Mutex<HashMap<String, Permission>>>>,
     reset_tx: Option<mpsc::Sender<Duration>>,
     timer_expired: Arc<AtomicBool>,
+    expires_at: Arc<SyncMutex<Option<Instant>>>,
 }
 
 impl Permission {
@@ -24,6 +26,7 @@ impl Permission {
             permissions: None,
             reset_tx: None,
             timer_expired: Arc::new(AtomicBool::new(false)),
+            expires_at: Arc::new(SyncMutex::new(None)),
         }
     }
 
@@ -34,6 +37,8 @@ impl Permission {
         let permissions = self.permissions.clone();
         let addr = self.addr;
         let timer_expired = Arc::clone(&self.timer_expired);
+        let expires_at = Arc::clone(&self.expires_at);
+        expires_at.lock().replace(Instant::now() + lifetime);
 
         tokio::spawn(async move {
             let timer = tokio::time::sleep(lifetime);
@@ -51,7 +56,13 @@ impl Permission {
                     },
                     result = reset_rx.recv() => {
                         if let Some(d) = result {
-                            timer.as_mut().reset(Instant::now() + d);
+                            // a channel bind may have extended the permission beyond
+                            // what a plain refresh asks for, so never move it earlier
+                            let deadline = Instant::now() + d;
+                            if deadline > timer.deadline() {
+                                timer.as_mut().reset(deadline);
+                                expires_at.lock().replace(deadline);
+                            }
                         } else {
                             done = true;
                         }
@@ -69,6 +80,14 @@ impl Permission {
         expired
     }
 
+    // remaining returns how long until the permission expires, or None if it has
+    // not been started. Reading it doesn't touch the timer.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.expires_at
+            .lock()
+            .map(|expires_at| expires_at.saturating_duration_since(Instant::now()))
+    }
+
     pub(crate) async fn refresh(&self, lifetime: Duration) {
         if let Some(tx) = &self.reset_tx {
             let _ = tx.send(lifetime).await;
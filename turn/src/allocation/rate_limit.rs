@@ -0,0 +1,79 @@
+use util::sync::Mutex as SyncMutex;
+
+use tokio::time::{Duration, Instant};
+
+// RateLimit caps the bytes an allocation relays to its peers. Up to burst bytes
+// can be sent at once, after that the allocation is held to bytes_per_second.
+// burst should be at least as large as the largest datagram relayed, a UDP
+// datagram that doesn't fit into it is never sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub bytes_per_second: u64,
+    pub burst: u64,
+}
+
+struct BucketState {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+// TokenBucket enforces a RateLimit, one token is one byte
+pub(crate) struct TokenBucket {
+    limit: RateLimit,
+    state: SyncMutex<BucketState>,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(mut limit: RateLimit) -> Self {
+        // A bucket that never refills would hold back a TCP relay forever
+        limit.bytes_per_second = limit.bytes_per_second.max(1);
+        TokenBucket {
+            limit,
+            state: SyncMutex::new(BucketState {
+                tokens: limit.burst as f64,
+                updated_at: Instant::now(),
+            }),
+        }
+    }
+
+    fn refill(&self, state: &mut BucketState) {
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(state.updated_at);
+        state.tokens = (state.tokens + elapsed.as_secs_f64() * self.limit.bytes_per_second as f64)
+            .min(self.limit.burst as f64);
+        state.updated_at = now;
+    }
+
+    // try_take takes n tokens if the bucket holds them, and leaves it untouched otherwise
+    pub(crate) fn try_take(&self, n: usize) -> bool {
+        let mut state = self.state.lock();
+        self.refill(&mut state);
+        if state.tokens < n as f64 {
+            return false;
+        }
+        state.tokens -= n as f64;
+        true
+    }
+
+    // take takes n tokens, waiting for the bucket to refill when it runs short. The
+    // tokens are reserved up front, so concurrent callers queue up behind each other
+    // instead of racing for the refill.
+    pub(crate) async fn take(&self, n: usize) {
+        let wait = {
+            let mut state = self.state.lock();
+            self.refill(&mut state);
+            state.tokens -= n as f64;
+            if state.tokens >= 0.0 {
+                None
+            } else {
+                Some(Duration::from_secs_f64(
+                    -state.tokens / self.limit.bytes_per_second as f64,
+                ))
+            }
+        };
+
+        if let Some(wait) = wait {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
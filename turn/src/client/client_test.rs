@@ -1,4 +1,5 @@
 use super::*;
+use crate::allocation::peer_filter::PeerFilter;
 use crate::auth::*;
 use crate::relay::relay_static::*;
 use crate::server::{config::*, *};
@@ -125,7 +126,7 @@ impl AuthHandler for TestAuthHandler {
     }
 }
 
-// Create an allocation, and then delete all nonces
+// Create an allocation, and then let its nonce expire
 // The subsequent Write on the allocation will cause a CreatePermission
 // which will be forced to handle a stale nonce response
 #[tokio::test]
@@ -150,6 +151,12 @@ async fn test_client_nonce_expiration() -> Result<()> {
         realm: "webrtc.rs".to_owned(),
         auth_handler: Arc::new(TestAuthHandler {}),
         channel_bind_timeout: Duration::from_secs(0),
+        allocation: AllocationConfig {
+            nonce_lifetime: Some(Duration::from_millis(500)),
+            // the client's peer is on loopback
+            peer_filter: PeerFilter::allow_all(),
+            ..Default::default()
+        },
     })
     .await?;
 
@@ -172,10 +179,7 @@ async fn test_client_nonce_expiration() -> Result<()> {
 
     let allocation = client.allocate().await?;
 
-    {
-        let mut nonces = server.nonces.lock().await;
-        nonces.clear();
-    }
+    tokio::time::sleep(Duration::from_millis(600)).await;
 
     allocation
         .send_to(&[0x00], SocketAddr::from_str("127.0.0.1:8080")?)
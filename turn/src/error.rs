@@ -1,3 +1,7 @@
+#[cfg(test)]
+mod error_test;
+
+use stun::error_code::*;
 use thiserror::Error;
 
 use std::io;
@@ -54,6 +58,8 @@ pub enum Error {
     ErrNonStunmessage,
     #[error("failed to decode STUN message")]
     ErrFailedToDecodeStun,
+    #[error("packet is neither a STUN message nor ChannelData")]
+    ErrUnknownPacketType,
     #[error("unexpected STUN request message")]
     ErrUnexpectedStunrequestMessage,
     #[error("channel number not in [0x4000, 0x7FFF]")]
@@ -110,6 +116,14 @@ pub enum Error {
     ErrLifetimeZero,
     #[error("allocation attempt created with duplicate FiveTuple")]
     ErrDupeFiveTuple,
+    #[error("relay address is already used by another allocation")]
+    ErrDupeRelayAddr,
+    #[error("allocation quota reached for client")]
+    ErrAllocationQuotaReached,
+    #[error("server has reached its allocation capacity")]
+    ErrAllocationCapacityReached,
+    #[error("server is shutting down")]
+    ErrServerDraining,
     #[error("failed to cast net.Addr to *net.UDPAddr")]
     ErrFailedToCastUdpaddr,
     #[error("failed to generate nonce")]
@@ -118,6 +132,10 @@ pub enum Error {
     ErrFailedToSendError,
     #[error("duplicated Nonce generated, discarding request")]
     ErrDuplicatedNonce,
+    #[error("nonce was not issued by this server")]
+    ErrUnknownNonce,
+    #[error("nonce has expired")]
+    ErrStaleNonce,
     #[error("no such user exists")]
     ErrNoSuchUser,
     #[error("unexpected class")]
@@ -140,18 +158,52 @@ pub enum Error {
     ErrRequestedTransportMustBeUdp,
     #[error("no support for DONT-FRAGMENT")]
     ErrNoDontFragmentSupport,
+    #[error("no support for TCP relays")]
+    ErrNoTcpRelaySupport,
+    #[error("requested address family is not supported")]
+    ErrUnsupportedAddressFamily,
     #[error("Request must not contain RESERVATION-TOKEN and EVEN-PORT")]
     ErrRequestWithReservationTokenAndEvenPort,
+    #[error("invalid or expired RESERVATION-TOKEN")]
+    ErrInvalidReservationToken,
     #[error("no allocation found")]
     ErrNoAllocationFound,
+    #[error("credentials don't match the allocation's")]
+    ErrWrongCredentials,
     #[error("unable to handle send-indication, no permission added")]
     ErrNoPermission,
+    #[error("permissions are locked by another task")]
+    ErrPermissionsBusy,
+    #[error("peer address is in a denied range")]
+    ErrPeerAddressDenied,
+    #[error("invalid IP prefix")]
+    ErrInvalidIpPrefix,
+    #[error("allocation is over its relay rate limit")]
+    ErrRateLimited,
+    #[error("relayed datagram is larger than the path MTU")]
+    ErrDatagramTooLarge,
     #[error("packet write smaller than packet")]
     ErrShortWrite,
     #[error("no such channel bind")]
     ErrNoSuchChannelBind,
     #[error("failed writing to socket")]
     ErrFailedWriteSocket,
+    #[error("allocation has too many permissions")]
+    ErrTooManyPermissions,
+    #[error("allocation has too many channel bindings")]
+    ErrTooManyChannelBindings,
+    #[error("relay connection has no remote address")]
+    ErrNoRemoteAddr,
+    #[error("data is too large for a TCP relay frame")]
+    ErrTcpFrameTooLarge,
+    #[error("TCP relay is connected to another peer")]
+    ErrTcpRelayPeerMismatch,
+    #[error("peer connections need a TCP allocation")]
+    ErrNotTcpAllocation,
+    #[error("a connection to the peer already exists")]
+    ErrConnectionAlreadyExists,
+    #[error("no peer connection waits for the CONNECTION-ID")]
+    ErrUnknownConnectionId,
     #[error("parse int: {0}")]
     ParseInt(#[from] ParseIntError),
     #[error("parse addr: {0}")]
@@ -166,6 +218,41 @@ pub enum Error {
     Other(String),
 }
 
+impl Error {
+    // error_code maps the error to the STUN ERROR-CODE a TURN server answers the
+    // request that caused it with, or None if the error has no specific code
+    pub fn error_code(&self) -> Option<ErrorCode> {
+        match self {
+            Error::ErrUnknownNonce => Some(CODE_UNAUTHORIZED),
+            Error::ErrPeerAddressDenied => Some(CODE_FORBIDDEN),
+            Error::ErrRelayAlreadyAllocatedForFiveTuple | Error::ErrDupeFiveTuple => {
+                Some(CODE_ALLOC_MISMATCH)
+            }
+            Error::ErrNotTcpAllocation | Error::ErrUnknownConnectionId => Some(CODE_BAD_REQUEST),
+            Error::ErrWrongCredentials => Some(CODE_WRONG_CREDENTIALS),
+            Error::ErrStaleNonce => Some(CODE_STALE_NONCE),
+            Error::ErrAllocationQuotaReached => Some(CODE_ALLOC_QUOTA_REACHED),
+            Error::ErrInvalidReservationToken
+            | Error::ErrAllocationCapacityReached
+            | Error::ErrDupeRelayAddr
+            | Error::ErrMaxRetriesExceeded
+            | Error::ErrTooManyPermissions
+            | Error::ErrTooManyChannelBindings => Some(CODE_INSUFFICIENT_CAPACITY),
+            Error::ErrConnectionAlreadyExists => Some(CODE_CONN_ALREADY_EXISTS),
+            _ => None,
+        }
+    }
+
+    // error_code_attribute is the ERROR-CODE attribute for the error, carrying the
+    // reason phrase of its code
+    pub fn error_code_attribute(&self) -> Option<ErrorCodeAttribute> {
+        self.error_code().map(|code| ErrorCodeAttribute {
+            code,
+            reason: ERROR_REASONS.get(&code).cloned().unwrap_or_default(),
+        })
+    }
+}
+
 #[derive(Debug, Error)]
 #[error("io error: {0}")]
 pub struct IoError(#[from] pub io::Error);
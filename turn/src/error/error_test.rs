@@ -0,0 +1,59 @@
+use super::*;
+
+#[test]
+fn test_error_code() {
+    let tests = vec![
+        (Error::ErrUnknownNonce, 401, "Unauthorized"),
+        (Error::ErrPeerAddressDenied, 403, "Forbidden"),
+        (
+            Error::ErrRelayAlreadyAllocatedForFiveTuple,
+            437,
+            "Allocation Mismatch",
+        ),
+        (Error::ErrDupeFiveTuple, 437, "Allocation Mismatch"),
+        (Error::ErrNotTcpAllocation, 400, "Bad Request"),
+        (Error::ErrUnknownConnectionId, 400, "Bad Request"),
+        (Error::ErrWrongCredentials, 441, "Wrong Credentials"),
+        (Error::ErrStaleNonce, 438, "Stale Nonce"),
+        (
+            Error::ErrAllocationQuotaReached,
+            486,
+            "Allocation Quota Reached",
+        ),
+        (
+            Error::ErrInvalidReservationToken,
+            508,
+            "Insufficient Capacity",
+        ),
+        (
+            Error::ErrAllocationCapacityReached,
+            508,
+            "Insufficient Capacity",
+        ),
+        (Error::ErrDupeRelayAddr, 508, "Insufficient Capacity"),
+        (Error::ErrMaxRetriesExceeded, 508, "Insufficient Capacity"),
+        (Error::ErrTooManyPermissions, 508, "Insufficient Capacity"),
+        (
+            Error::ErrTooManyChannelBindings,
+            508,
+            "Insufficient Capacity",
+        ),
+        (
+            Error::ErrConnectionAlreadyExists,
+            446,
+            "Connection Already Exists",
+        ),
+    ];
+
+    for (err, code, reason) in tests {
+        assert_eq!(Some(code), err.error_code().map(|c| c.0), "{}", err);
+        let attr = err
+            .error_code_attribute()
+            .expect("should have an ERROR-CODE attribute");
+        assert_eq!(code, attr.code.0);
+        assert_eq!(reason.as_bytes(), &attr.reason[..], "{}", err);
+    }
+
+    assert!(Error::ErrNoAllocationFound.error_code().is_none());
+    assert!(Error::ErrNoAllocationFound.error_code_attribute().is_none());
+}
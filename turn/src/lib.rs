@@ -9,5 +9,7 @@ mod error;
 pub mod proto;
 pub mod relay;
 pub mod server;
+#[cfg(test)]
+pub(crate) mod test_util;
 
 pub use error::Error;
@@ -2,6 +2,7 @@
 mod chandata_test;
 
 use super::channum::*;
+use super::*;
 use crate::error::*;
 
 const PADDING: usize = 4;
@@ -42,7 +43,8 @@ impl ChannelData {
         self.data.clear();
     }
 
-    // Encode encodes ChannelData Message to Raw.
+    // Encode encodes ChannelData Message to Raw, padded to a multiple of 4 bytes
+    // so that it is valid over any transport.
     pub fn encode(&mut self) {
         self.raw.clear();
         self.write_header();
@@ -54,6 +56,19 @@ impl ChannelData {
         }
     }
 
+    // encode_for encodes ChannelData Message to Raw for the given transport. Over
+    // TCP the message MUST be padded to a multiple of 4 bytes, over UDP the padding
+    // is not required and is left out (RFC 5766 Section 11.5).
+    pub fn encode_for(&mut self, protocol: Protocol) {
+        if protocol == PROTO_UDP {
+            self.raw.clear();
+            self.write_header();
+            self.raw.extend_from_slice(&self.data);
+        } else {
+            self.encode();
+        }
+    }
+
     // Decode decodes The ChannelData Message from Raw.
     pub fn decode(&mut self) -> Result<()> {
         let buf = &self.raw;
@@ -77,6 +92,25 @@ impl ChannelData {
         Ok(())
     }
 
+    // decode_for decodes The ChannelData Message from Raw as received over the given
+    // transport and returns how many bytes of Raw the message takes up. Over TCP the
+    // message is padded to a multiple of 4 bytes, the padding must be in Raw and is
+    // left out of Data. Over UDP the padding is optional and whatever follows the
+    // declared length is ignored (RFC 5766 Section 11.5).
+    pub fn decode_for(&mut self, protocol: Protocol) -> Result<usize> {
+        self.decode()?;
+
+        let l = CHANNEL_DATA_HEADER_SIZE + self.data.len();
+        if protocol == PROTO_UDP {
+            return Ok(l);
+        }
+        let padded = nearest_padded_value_length(l);
+        if padded > self.raw.len() {
+            return Err(Error::ErrBadChannelDataLength);
+        }
+        Ok(padded)
+    }
+
     // WriteHeader writes channel number and length.
     pub fn write_header(&mut self) {
         if self.raw.len() < CHANNEL_DATA_HEADER_SIZE {
@@ -213,3 +213,138 @@ fn test_chrome_channel_data() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_channel_data_encode_wire_format() -> Result<()> {
+    let mut d = ChannelData {
+        data: vec![0xAA, 0xBB, 0xCC, 0xDD, 0xEE],
+        number: ChannelNumber(0x4001),
+        ..Default::default()
+    };
+
+    // Over TCP the message is padded up to the next 4 byte boundary, while the
+    // length field still carries the unpadded payload length.
+    d.encode_for(PROTO_TCP);
+    assert_eq!(
+        vec![0x40, 0x01, 0x00, 0x05, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0x00, 0x00, 0x00],
+        d.raw
+    );
+
+    d.encode();
+    assert_eq!(12, d.raw.len(), "encode pads like TCP");
+
+    // Over UDP no padding is added
+    d.encode_for(PROTO_UDP);
+    assert_eq!(
+        vec![0x40, 0x01, 0x00, 0x05, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE],
+        d.raw
+    );
+
+    // Payloads already on a 4 byte boundary are never padded
+    let mut aligned = ChannelData {
+        data: vec![1, 2, 3, 4],
+        number: ChannelNumber(MAX_CHANNEL_NUMBER),
+        ..Default::default()
+    };
+    aligned.encode_for(PROTO_TCP);
+    assert_eq!(vec![0x7F, 0xFF, 0x00, 0x04, 1, 2, 3, 4], aligned.raw);
+
+    Ok(())
+}
+
+#[test]
+fn test_channel_data_encode_round_trip() -> Result<()> {
+    for protocol in [PROTO_UDP, PROTO_TCP] {
+        for n in 0..=8 {
+            let mut d = ChannelData {
+                data: (0..n as u8).collect(),
+                number: ChannelNumber(MIN_CHANNEL_NUMBER),
+                ..Default::default()
+            };
+            d.encode_for(protocol);
+            if protocol == PROTO_TCP {
+                assert_eq!(0, d.raw.len() % 4, "{} bytes over TCP", n);
+            } else {
+                assert_eq!(4 + n, d.raw.len(), "{} bytes over UDP", n);
+            }
+
+            let mut b = ChannelData {
+                raw: d.raw.clone(),
+                ..Default::default()
+            };
+            b.decode()?;
+            assert_eq!(d, b, "{} bytes over {}", n, protocol);
+            assert!(ChannelData::is_channel_data(&d.raw));
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_channel_data_decode_for() -> Result<()> {
+    let number = ChannelNumber(0x4001);
+
+    // A valid frame, unpadded over UDP
+    let mut m = ChannelData {
+        raw: vec![0x40, 0x01, 0x00, 0x03, 1, 2, 3],
+        ..Default::default()
+    };
+    assert_eq!(7, m.decode_for(PROTO_UDP)?);
+    assert_eq!(number, m.number);
+    assert_eq!(vec![1, 2, 3], m.data);
+
+    // A length running past the end of the buffer
+    for protocol in [PROTO_UDP, PROTO_TCP] {
+        let mut m = ChannelData {
+            raw: vec![0x40, 0x01, 0x00, 0x08, 1, 2, 3, 4],
+            ..Default::default()
+        };
+        assert_eq!(
+            Err(Error::ErrBadChannelDataLength),
+            m.decode_for(protocol),
+            "over {}",
+            protocol
+        );
+    }
+
+    // A padded TCP frame followed by the next one, the padding isn't part of the data
+    let raw = vec![
+        0x40, 0x01, 0x00, 0x05, 1, 2, 3, 4, 5, 0, 0, 0, 0x40, 0x02, 0x00, 0x00,
+    ];
+    let mut m = ChannelData {
+        raw: raw.clone(),
+        ..Default::default()
+    };
+    assert_eq!(12, m.decode_for(PROTO_TCP)?);
+    assert_eq!(vec![1, 2, 3, 4, 5], m.data);
+    let mut next = ChannelData {
+        raw: raw[12..].to_vec(),
+        ..Default::default()
+    };
+    assert_eq!(4, next.decode_for(PROTO_TCP)?);
+    assert_eq!(ChannelNumber(0x4002), next.number);
+    assert!(next.data.is_empty());
+
+    // Over TCP the padding must be there, over UDP it may be left out
+    let unpadded = vec![0x40, 0x01, 0x00, 0x05, 1, 2, 3, 4, 5];
+    let mut m = ChannelData {
+        raw: unpadded.clone(),
+        ..Default::default()
+    };
+    assert_eq!(Err(Error::ErrBadChannelDataLength), m.decode_for(PROTO_TCP));
+    let mut m = ChannelData {
+        raw: unpadded,
+        ..Default::default()
+    };
+    assert_eq!(9, m.decode_for(PROTO_UDP)?);
+
+    // Channel numbers outside 0x4000-0x7FFF are rejected
+    let mut m = ChannelData {
+        raw: vec![0x80, 0x00, 0x00, 0x00],
+        ..Default::default()
+    };
+    assert_eq!(Err(Error::ErrInvalidChannelNumber), m.decode_for(PROTO_TCP));
+
+    Ok(())
+}
@@ -0,0 +1,44 @@
+#[cfg(test)]
+mod connid_test;
+
+use std::fmt;
+use stun::attributes::*;
+use stun::checks::*;
+use stun::message::*;
+
+const CONNECTION_ID_SIZE: usize = 4;
+
+// ConnectionId represents CONNECTION-ID attribute.
+//
+// The CONNECTION-ID attribute uniquely identifies a peer data
+// connection. It is a 32-bit unsigned integral value.
+//
+// RFC 6062 Section 6.2.1
+#[derive(Default, Eq, PartialEq, Debug, Copy, Clone, Hash)]
+pub struct ConnectionId(pub u32);
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Setter for ConnectionId {
+    // AddTo adds CONNECTION-ID to message.
+    fn add_to(&self, m: &mut Message) -> Result<(), stun::Error> {
+        m.add(ATTR_CONNECTION_ID, &self.0.to_be_bytes());
+        Ok(())
+    }
+}
+
+impl Getter for ConnectionId {
+    // GetFrom decodes CONNECTION-ID from message.
+    fn get_from(&mut self, m: &Message) -> Result<(), stun::Error> {
+        let v = m.get(ATTR_CONNECTION_ID)?;
+
+        check_size(ATTR_CONNECTION_ID, v.len(), CONNECTION_ID_SIZE)?;
+
+        self.0 = u32::from_be_bytes([v[0], v[1], v[2], v[3]]);
+        Ok(())
+    }
+}
@@ -0,0 +1,28 @@
+use super::*;
+
+#[test]
+fn test_connection_id_add_to() -> Result<(), stun::Error> {
+    let mut m = Message::new();
+    let id = ConnectionId(0xdead_beef);
+    id.add_to(&mut m)?;
+    m.write_header();
+
+    let mut decoded = Message::new();
+    decoded.write(&m.raw)?;
+
+    let mut id_decoded = ConnectionId::default();
+    id_decoded.get_from(&decoded)?;
+    assert_eq!(id, id_decoded);
+
+    // A CONNECTION-ID of the wrong size
+    let mut m = Message::new();
+    m.add(ATTR_CONNECTION_ID, &[1, 2]);
+    let mut id_decoded = ConnectionId::default();
+    let result = id_decoded.get_from(&m);
+    assert!(
+        is_attr_size_invalid(&result.unwrap_err()),
+        "IsAttrSizeInvalid should be true"
+    );
+
+    Ok(())
+}
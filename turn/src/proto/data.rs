@@ -1,6 +1,10 @@
 #[cfg(test)]
 mod data_test;
 
+use super::peeraddr::PeerAddress;
+
+use std::net::SocketAddr;
+use stun::agent::TransactionId;
 use stun::attributes::*;
 use stun::message::*;
 
@@ -31,3 +35,21 @@ impl Getter for Data {
         Ok(())
     }
 }
+
+// build_data_indication builds the Data indication relaying data received from peer
+// to the client, a new transaction id followed by XOR-PEER-ADDRESS and DATA.
+//
+// RFC 5766 Section 10.3
+pub fn build_data_indication(peer: &SocketAddr, data: &[u8]) -> Result<Message, stun::Error> {
+    let mut msg = Message::new();
+    msg.build(&[
+        Box::new(TransactionId::new()),
+        Box::new(MessageType::new(METHOD_DATA, CLASS_INDICATION)),
+        Box::new(PeerAddress {
+            ip: peer.ip(),
+            port: peer.port(),
+        }),
+        Box::new(Data(data.to_vec())),
+    ])?;
+    Ok(msg)
+}
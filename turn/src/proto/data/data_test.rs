@@ -1,5 +1,7 @@
 use super::*;
 
+use std::str::FromStr;
+
 #[test]
 fn test_data_add_to() -> Result<(), stun::Error> {
     let mut m = Message::new();
@@ -32,3 +34,29 @@ fn test_data_add_to() -> Result<(), stun::Error> {
     }
     Ok(())
 }
+
+#[test]
+fn test_build_data_indication() -> Result<(), stun::Error> {
+    for peer in ["203.0.113.7:5000", "[2001:db8::1]:6000"] {
+        let peer = SocketAddr::from_str(peer).unwrap();
+        let payload = vec![1, 2, 33, 44, 0x13, 0xaf];
+        let msg = build_data_indication(&peer, &payload)?;
+
+        let mut decoded = Message::new();
+        decoded.write(&msg.raw)?;
+        assert_eq!(MessageType::new(METHOD_DATA, CLASS_INDICATION), decoded.typ);
+
+        let mut peer_address = PeerAddress::default();
+        peer_address.get_from(&decoded)?;
+        assert_eq!(peer, SocketAddr::new(peer_address.ip, peer_address.port));
+
+        let mut data = Data::default();
+        data.get_from(&decoded)?;
+        assert_eq!(payload, data.0);
+
+        let types: Vec<AttrType> = decoded.attributes.0.iter().map(|a| a.typ).collect();
+        assert_eq!(vec![ATTR_XOR_PEER_ADDRESS, ATTR_DATA], types);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,21 @@
+#[cfg(test)]
+mod demux_test;
+
+// PacketKind is what a packet received on a TURN socket carries
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum PacketKind {
+    Stun,
+    ChannelData,
+    Unknown,
+}
+
+// demultiplex tells STUN messages and ChannelData apart by the two high bits of
+// the first byte, which are 0b00 for STUN and 0b01 for ChannelData, whose channel
+// numbers lie in 0x4000-0x7FFF. See RFC 5766 Section 11 and RFC 7983 Section 7.
+pub fn demultiplex(buf: &[u8]) -> PacketKind {
+    match buf.first().map(|b| b >> 6) {
+        Some(0b00) => PacketKind::Stun,
+        Some(0b01) => PacketKind::ChannelData,
+        _ => PacketKind::Unknown,
+    }
+}
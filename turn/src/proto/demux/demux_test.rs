@@ -0,0 +1,30 @@
+use super::*;
+use crate::error::Result;
+use crate::proto::chandata::ChannelData;
+use crate::proto::channum::{ChannelNumber, MAX_CHANNEL_NUMBER, MIN_CHANNEL_NUMBER};
+
+use stun::message::{Message, BINDING_REQUEST};
+
+#[test]
+fn test_demultiplex() -> Result<()> {
+    let mut m = Message::new();
+    m.build(&[Box::new(BINDING_REQUEST)])?;
+    assert_eq!(PacketKind::Stun, demultiplex(&m.raw));
+
+    for number in [MIN_CHANNEL_NUMBER, MAX_CHANNEL_NUMBER] {
+        let mut c = ChannelData {
+            data: vec![1, 2, 3],
+            number: ChannelNumber(number),
+            ..Default::default()
+        };
+        c.encode();
+        assert_eq!(PacketKind::ChannelData, demultiplex(&c.raw));
+    }
+
+    // RTP, DTLS and empty packets are neither
+    assert_eq!(PacketKind::Unknown, demultiplex(&[0x80, 0x60]));
+    assert_eq!(PacketKind::Unknown, demultiplex(&[0xc0]));
+    assert_eq!(PacketKind::Unknown, demultiplex(&[]));
+
+    Ok(())
+}
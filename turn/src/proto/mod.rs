@@ -4,7 +4,9 @@ mod proto_test;
 pub mod addr;
 pub mod chandata;
 pub mod channum;
+pub mod connid;
 pub mod data;
+pub mod demux;
 pub mod dontfrag;
 pub mod evenport;
 pub mod lifetime;
@@ -60,6 +60,30 @@ impl Getter for PeerAddress {
     }
 }
 
+impl PeerAddress {
+    // get_all_from decodes every XOR-PEER-ADDRESS in the message, in order. A
+    // CreatePermission may carry several of them while get_from only sees the first.
+    pub fn get_all_from(m: &Message) -> Result<Vec<PeerAddress>, stun::Error> {
+        let mut addrs = vec![];
+        for attr in &m.attributes.0 {
+            if attr.typ != ATTR_XOR_PEER_ADDRESS {
+                continue;
+            }
+
+            // The address is XORed with the transaction ID, so decode it from a
+            // message that holds just this attribute under the same ID.
+            let mut single = Message::new();
+            single.transaction_id = m.transaction_id;
+            single.add(ATTR_XOR_PEER_ADDRESS, &attr.value);
+
+            let mut peer_address = PeerAddress::default();
+            peer_address.get_from(&single)?;
+            addrs.push(peer_address);
+        }
+        Ok(addrs)
+    }
+}
+
 // XORPeerAddress implements XOR-PEER-ADDRESS attribute.
 //
 // The XOR-PEER-ADDRESS specifies the address and port of the peer as
@@ -24,3 +24,27 @@ fn test_peer_address() -> Result<(), stun::Error> {
 
     Ok(())
 }
+
+#[test]
+fn test_peer_address_get_all_from() -> Result<(), stun::Error> {
+    let first = PeerAddress {
+        ip: IpAddr::V4(Ipv4Addr::new(111, 11, 1, 2)),
+        port: 333,
+    };
+    let second = PeerAddress {
+        ip: "::1".parse().unwrap(),
+        port: 444,
+    };
+
+    let mut m = Message::new();
+    first.add_to(&mut m)?;
+    second.add_to(&mut m)?;
+    m.write_header();
+
+    let mut decoded = Message::new();
+    decoded.write(&m.raw)?;
+
+    assert_eq!(vec![first, second], PeerAddress::get_all_from(&decoded)?);
+
+    Ok(())
+}
@@ -1,14 +1,21 @@
+#[cfg(test)]
+mod relay_test;
+
 pub mod relay_none;
 pub mod relay_range;
 pub mod relay_static;
+mod relay_tcp;
 
-use crate::error::Result;
+use crate::error::*;
+use relay_tcp::TcpRelayConn;
 
+use util::vnet::net::*;
 use util::Conn;
 
 use async_trait::async_trait;
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
+use tokio::net::UdpSocket;
 
 // RelayAddressGenerator is used to generate a RelayAddress when creating an allocation.
 // You can use one of the provided ones or provide your own.
@@ -23,4 +30,133 @@ pub trait RelayAddressGenerator {
         use_ipv4: bool,
         requested_port: u16,
     ) -> Result<(Arc<dyn Conn + Send + Sync>, SocketAddr)>;
+    // Allocate a RelayAddress whose conn sets the DF bit on the datagrams it relays,
+    // generators that can't honor DONT-FRAGMENT return ErrNoDontFragmentSupport
+    async fn allocate_conn_dont_fragment(
+        &self,
+        _use_ipv4: bool,
+        _requested_port: u16,
+    ) -> Result<(Arc<dyn Conn + Send + Sync>, SocketAddr)> {
+        Err(Error::ErrNoDontFragmentSupport)
+    }
+    // Allocate a RelayAddress whose conn relays over TCP (RFC 6062), generators that
+    // can't bind TCP relays return ErrNoTcpRelaySupport
+    async fn allocate_tcp_conn(
+        &self,
+        _use_ipv4: bool,
+        _requested_port: u16,
+    ) -> Result<(Arc<dyn Conn + Send + Sync>, SocketAddr)> {
+        Err(Error::ErrNoTcpRelaySupport)
+    }
+    // supports_family reports whether relay addresses of the requested family
+    // (IPv4 when use_ipv4 is set, IPv6 otherwise) can be allocated
+    fn supports_family(&self, _use_ipv4: bool) -> bool {
+        true
+    }
+}
+
+// relay_bind_addr joins a listening address and port for resolving, IPv6
+// literals are wrapped in brackets so the port can be told apart
+pub(crate) fn relay_bind_addr(address: &str, port: u16) -> String {
+    if address.parse::<Ipv6Addr>().is_ok() {
+        format!("[{}]:{}", address, port)
+    } else {
+        format!("{}:{}", address, port)
+    }
+}
+
+// address_supports_family checks a listening address against the requested
+// family. Only IP literals are checked, host names are left to the resolver.
+pub(crate) fn address_supports_family(address: &str, use_ipv4: bool) -> bool {
+    match address.parse::<IpAddr>() {
+        Ok(ip) => ip.is_ipv4() == use_ipv4,
+        Err(_) => true,
+    }
+}
+
+// RelayKind selects the relay conn bind_relay_conn binds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RelayKind {
+    Udp,
+    // a UDP conn setting the DF bit on every datagram it sends, which only real
+    // sockets on Linux and Android support
+    UdpDontFragment,
+    // a TCP conn that connects to the first peer it relays to, which the virtual
+    // network doesn't support
+    Tcp,
+}
+
+// bind_relay_conn binds a relay conn of the given kind on addr
+pub(crate) async fn bind_relay_conn(
+    net: &Arc<Net>,
+    addr: SocketAddr,
+    kind: RelayKind,
+) -> Result<Arc<dyn Conn + Send + Sync>> {
+    match kind {
+        RelayKind::Udp => Ok(net.bind(addr).await?),
+        RelayKind::UdpDontFragment if net.is_virtual() => Err(Error::ErrNoDontFragmentSupport),
+        RelayKind::UdpDontFragment => {
+            let socket = UdpSocket::bind(addr).await?;
+            set_dont_fragment(&socket)?;
+            Ok(Arc::new(socket))
+        }
+        RelayKind::Tcp if net.is_virtual() => Err(Error::ErrNoTcpRelaySupport),
+        RelayKind::Tcp => Ok(Arc::new(TcpRelayConn::bind(addr)?)),
+    }
+}
+
+// EMSGSIZE is the error code for a datagram too large to send, WSAEMSGSIZE on Windows
+#[cfg(not(windows))]
+pub(crate) const EMSGSIZE: i32 = libc::EMSGSIZE;
+#[cfg(windows)]
+pub(crate) const EMSGSIZE: i32 = 10040;
+
+// is_datagram_too_large reports whether a send failed with EMSGSIZE, which is what a
+// datagram larger than the path MTU gets when the DF bit forbids fragmenting it
+pub(crate) fn is_datagram_too_large(err: &util::Error) -> bool {
+    match err {
+        util::Error::Io(err) => err.0.raw_os_error() == Some(EMSGSIZE),
+        _ => false,
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn set_dont_fragment(socket: &UdpSocket) -> Result<()> {
+    use std::os::raw::c_int;
+    use std::os::unix::io::AsRawFd;
+
+    let (level, name, value) = if socket.local_addr()?.is_ipv4() {
+        (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DO,
+        )
+    } else {
+        (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_DO,
+        )
+    };
+
+    // SAFETY: the fd is owned by socket and value outlives the call
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const c_int as *const libc::c_void,
+            std::mem::size_of::<c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) fn set_dont_fragment(_socket: &UdpSocket) -> Result<()> {
+    Err(Error::ErrNoDontFragmentSupport)
 }
@@ -11,6 +11,23 @@ pub struct RelayAddressGeneratorNone {
     pub net: Arc<Net>,
 }
 
+impl RelayAddressGeneratorNone {
+    async fn allocate(
+        &self,
+        use_ipv4: bool,
+        requested_port: u16,
+        kind: RelayKind,
+    ) -> Result<(Arc<dyn Conn + Send + Sync>, SocketAddr)> {
+        let addr = self
+            .net
+            .resolve_addr(use_ipv4, &relay_bind_addr(&self.address, requested_port))
+            .await?;
+        let conn = bind_relay_conn(&self.net, addr, kind).await?;
+        let relay_addr = conn.local_addr()?;
+        Ok((conn, relay_addr))
+    }
+}
+
 #[async_trait]
 impl RelayAddressGenerator for RelayAddressGeneratorNone {
     // validate confirms that the RelayAddressGenerator is properly initialized
@@ -28,12 +45,33 @@ impl RelayAddressGenerator for RelayAddressGeneratorNone {
         use_ipv4: bool,
         requested_port: u16,
     ) -> Result<(Arc<dyn Conn + Send + Sync>, SocketAddr)> {
-        let addr = self
-            .net
-            .resolve_addr(use_ipv4, &format!("{}:{}", self.address, requested_port))
-            .await?;
-        let conn = self.net.bind(addr).await?;
-        let relay_addr = conn.local_addr()?;
-        Ok((conn, relay_addr))
+        self.allocate(use_ipv4, requested_port, RelayKind::Udp)
+            .await
+    }
+
+    // Allocate a PacketConn (UDP) RelayAddress that doesn't fragment
+    async fn allocate_conn_dont_fragment(
+        &self,
+        use_ipv4: bool,
+        requested_port: u16,
+    ) -> Result<(Arc<dyn Conn + Send + Sync>, SocketAddr)> {
+        self.allocate(use_ipv4, requested_port, RelayKind::UdpDontFragment)
+            .await
+    }
+
+    // Allocate a TCP RelayAddress
+    async fn allocate_tcp_conn(
+        &self,
+        use_ipv4: bool,
+        requested_port: u16,
+    ) -> Result<(Arc<dyn Conn + Send + Sync>, SocketAddr)> {
+        self.allocate(use_ipv4, requested_port, RelayKind::Tcp)
+            .await
+    }
+
+    // supports_family reports whether the listening address can bind a relay of
+    // the requested family
+    fn supports_family(&self, use_ipv4: bool) -> bool {
+        address_supports_family(&self.address, use_ipv4)
     }
 }
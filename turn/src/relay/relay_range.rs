@@ -25,28 +25,12 @@ pub struct RelayAddressGeneratorRanges {
     pub net: Arc<Net>,
 }
 
-#[async_trait]
-impl RelayAddressGenerator for RelayAddressGeneratorRanges {
-    // validate confirms that the RelayAddressGenerator is properly initialized
-    fn validate(&self) -> Result<()> {
-        if self.min_port == 0 {
-            Err(Error::ErrMinPortNotZero)
-        } else if self.max_port == 0 {
-            Err(Error::ErrMaxPortNotZero)
-        } else if self.max_port < self.min_port {
-            Err(Error::ErrMaxPortLessThanMinPort)
-        } else if self.address.is_empty() {
-            Err(Error::ErrListeningAddressInvalid)
-        } else {
-            Ok(())
-        }
-    }
-
-    // Allocate a PacketConn (UDP) relay_address
-    async fn allocate_conn(
+impl RelayAddressGeneratorRanges {
+    async fn allocate(
         &self,
         use_ipv4: bool,
         requested_port: u16,
+        kind: RelayKind,
     ) -> Result<(Arc<dyn Conn + Send + Sync>, SocketAddr)> {
         let max_retries = if self.max_retries == 0 {
             10
@@ -57,22 +41,30 @@ impl RelayAddressGenerator for RelayAddressGeneratorRanges {
         if requested_port != 0 {
             let addr = self
                 .net
-                .resolve_addr(use_ipv4, &format!("{}:{}", self.address, requested_port))
+                .resolve_addr(use_ipv4, &relay_bind_addr(&self.address, requested_port))
                 .await?;
-            let conn = self.net.bind(addr).await?;
+            let conn = bind_relay_conn(&self.net, addr, kind).await?;
             let mut relay_addr = conn.local_addr()?;
             relay_addr.set_ip(self.relay_address);
             return Ok((conn, relay_addr));
         }
 
-        for _ in 0..max_retries {
-            let port = self.min_port + rand::random::<u16>() % (self.max_port - self.min_port + 1);
+        // Walk the range from a random offset so no port is tried twice, which lets
+        // a range smaller than max_retries be exhausted deterministically.
+        let range_len = u32::from(self.max_port - self.min_port) + 1;
+        let offset = rand::random::<u32>() % range_len;
+        for i in 0..u32::from(max_retries).min(range_len) {
+            let port = self.min_port + ((offset + i) % range_len) as u16;
             let addr = self
                 .net
-                .resolve_addr(use_ipv4, &format!("{}:{}", self.address, port))
+                .resolve_addr(use_ipv4, &relay_bind_addr(&self.address, port))
                 .await?;
-            let conn = match self.net.bind(addr).await {
+            let conn = match bind_relay_conn(&self.net, addr, kind).await {
                 Ok(conn) => conn,
+                // retrying another port won't help if the conn can't be bound at all
+                Err(err @ (Error::ErrNoDontFragmentSupport | Error::ErrNoTcpRelaySupport)) => {
+                    return Err(err)
+                }
                 Err(_) => continue,
             };
 
@@ -84,3 +76,57 @@ impl RelayAddressGenerator for RelayAddressGeneratorRanges {
         Err(Error::ErrMaxRetriesExceeded)
     }
 }
+
+#[async_trait]
+impl RelayAddressGenerator for RelayAddressGeneratorRanges {
+    // validate confirms that the RelayAddressGenerator is properly initialized
+    fn validate(&self) -> Result<()> {
+        if self.min_port == 0 {
+            Err(Error::ErrMinPortNotZero)
+        } else if self.max_port == 0 {
+            Err(Error::ErrMaxPortNotZero)
+        } else if self.max_port < self.min_port {
+            Err(Error::ErrMaxPortLessThanMinPort)
+        } else if self.address.is_empty() {
+            Err(Error::ErrListeningAddressInvalid)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Allocate a PacketConn (UDP) relay_address
+    async fn allocate_conn(
+        &self,
+        use_ipv4: bool,
+        requested_port: u16,
+    ) -> Result<(Arc<dyn Conn + Send + Sync>, SocketAddr)> {
+        self.allocate(use_ipv4, requested_port, RelayKind::Udp)
+            .await
+    }
+
+    // Allocate a PacketConn (UDP) relay_address that doesn't fragment
+    async fn allocate_conn_dont_fragment(
+        &self,
+        use_ipv4: bool,
+        requested_port: u16,
+    ) -> Result<(Arc<dyn Conn + Send + Sync>, SocketAddr)> {
+        self.allocate(use_ipv4, requested_port, RelayKind::UdpDontFragment)
+            .await
+    }
+
+    // Allocate a TCP relay_address
+    async fn allocate_tcp_conn(
+        &self,
+        use_ipv4: bool,
+        requested_port: u16,
+    ) -> Result<(Arc<dyn Conn + Send + Sync>, SocketAddr)> {
+        self.allocate(use_ipv4, requested_port, RelayKind::Tcp)
+            .await
+    }
+
+    // supports_family reports whether both the relay address and the listening
+    // address belong to the requested family
+    fn supports_family(&self, use_ipv4: bool) -> bool {
+        self.relay_address.is_ipv4() == use_ipv4 && address_supports_family(&self.address, use_ipv4)
+    }
+}
@@ -0,0 +1,142 @@
+use util::sync::Mutex as SyncMutex;
+use util::Conn;
+
+use async_trait::async_trait;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpSocket;
+use tokio::sync::{Mutex, Notify};
+
+// TcpRelayConn is the relay conn of a TCP allocation (RFC 6062). It is bound to the
+// relayed transport address and carries nothing until connect has opened its
+// connection to a peer, reads wait until then.
+pub(crate) struct TcpRelayConn {
+    local_addr: SocketAddr,
+    socket: SyncMutex<Option<TcpSocket>>,
+    remote_addr: SyncMutex<Option<SocketAddr>>,
+    reader: Mutex<Option<OwnedReadHalf>>,
+    writer: Mutex<Option<OwnedWriteHalf>>,
+    connected: Notify,
+    closed: AtomicBool,
+}
+
+impl TcpRelayConn {
+    // bind binds a TCP socket on addr, for connect to open the connection from
+    pub(crate) fn bind(addr: SocketAddr) -> std::io::Result<Self> {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        socket.bind(addr)?;
+
+        Ok(TcpRelayConn {
+            local_addr: socket.local_addr()?,
+            socket: SyncMutex::new(Some(socket)),
+            remote_addr: SyncMutex::new(None),
+            reader: Mutex::new(None),
+            writer: Mutex::new(None),
+            connected: Notify::new(),
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    // wait_connected waits for connect to open the connection
+    async fn wait_connected(&self) -> Result<(), util::Error> {
+        loop {
+            let connected = self.connected.notified();
+            if self.closed.load(Ordering::Acquire) {
+                return Err(util::Error::ErrUseClosedNetworkConn);
+            }
+            if self.remote_addr.lock().is_some() {
+                return Ok(());
+            }
+            connected.await;
+        }
+    }
+}
+
+#[async_trait]
+impl Conn for TcpRelayConn {
+    // connect opens the connection to addr. A conn is connected once, connecting
+    // again to the same peer does nothing.
+    async fn connect(&self, addr: SocketAddr) -> Result<(), util::Error> {
+        let mut writer = self.writer.lock().await;
+        if let Some(remote_addr) = *self.remote_addr.lock() {
+            return if remote_addr == addr {
+                Ok(())
+            } else {
+                Err(util::Error::Other(format!(
+                    "TCP relay is connected to {}",
+                    remote_addr
+                )))
+            };
+        }
+
+        let socket = self
+            .socket
+            .lock()
+            .take()
+            .ok_or(util::Error::ErrUseClosedNetworkConn)?;
+        let (read_half, write_half) = socket.connect(addr).await?.into_split();
+        *self.reader.lock().await = Some(read_half);
+        *writer = Some(write_half);
+        *self.remote_addr.lock() = Some(addr);
+        self.connected.notify_waiters();
+        Ok(())
+    }
+
+    // recv returns 0 once the peer has closed its side of the connection
+    async fn recv(&self, buf: &mut [u8]) -> Result<usize, util::Error> {
+        self.wait_connected().await?;
+        let mut reader = self.reader.lock().await;
+        match reader.as_mut() {
+            Some(reader) => Ok(reader.read(buf).await?),
+            None => Err(util::Error::ErrUseClosedNetworkConn),
+        }
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), util::Error> {
+        let n = self.recv(buf).await?;
+        let remote_addr = self
+            .remote_addr()
+            .ok_or(util::Error::ErrUseClosedNetworkConn)?;
+        Ok((n, remote_addr))
+    }
+
+    async fn send(&self, buf: &[u8]) -> Result<usize, util::Error> {
+        let mut writer = self.writer.lock().await;
+        match writer.as_mut() {
+            Some(writer) => {
+                writer.write_all(buf).await?;
+                Ok(buf.len())
+            }
+            None => Err(util::Error::Other("TCP relay is not connected".to_owned())),
+        }
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> Result<usize, util::Error> {
+        self.connect(target).await?;
+        self.send(buf).await
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, util::Error> {
+        Ok(self.local_addr)
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        *self.remote_addr.lock()
+    }
+
+    async fn close(&self) -> Result<(), util::Error> {
+        self.closed.store(true, Ordering::Release);
+        self.connected.notify_waiters();
+        self.socket.lock().take();
+        if let Some(mut writer) = self.writer.lock().await.take() {
+            let _ = writer.shutdown().await;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+use super::*;
+
+use std::net::IpAddr;
+use std::str::FromStr;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::os::raw::c_int;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn mtu_discover(socket: &UdpSocket) -> c_int {
+    use std::os::unix::io::AsRawFd;
+
+    let mut value: c_int = 0;
+    let mut len = std::mem::size_of::<c_int>() as libc::socklen_t;
+    // SAFETY: value and len are valid for writes for the duration of the call
+    let ret = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            &mut value as *mut c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    assert_eq!(0, ret);
+    value
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[tokio::test]
+async fn test_set_dont_fragment() -> Result<()> {
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+    assert_ne!(libc::IP_PMTUDISC_DO, mtu_discover(&socket));
+
+    set_dont_fragment(&socket)?;
+    assert_eq!(libc::IP_PMTUDISC_DO, mtu_discover(&socket));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bind_relay_conn_virtual_net() -> Result<()> {
+    let net = Arc::new(Net::new(Some(NetConfig::default())));
+    let addr: SocketAddr = "0.0.0.0:0".parse()?;
+
+    let result = bind_relay_conn(&net, addr, RelayKind::UdpDontFragment).await;
+    assert_eq!(Some(Error::ErrNoDontFragmentSupport), result.err());
+
+    let result = bind_relay_conn(&net, addr, RelayKind::Tcp).await;
+    assert_eq!(Some(Error::ErrNoTcpRelaySupport), result.err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_static_relay_address() -> Result<()> {
+    let relay_ip = IpAddr::from_str("203.0.113.1")?;
+    let generator = relay_static::RelayAddressGeneratorStatic {
+        relay_address: relay_ip,
+        address: "127.0.0.1".to_owned(),
+        net: Arc::new(Net::new(None)),
+    };
+    generator.validate()?;
+
+    let (conn, relay_addr) = generator.allocate_conn(true, 0).await?;
+    assert_eq!(relay_ip, relay_addr.ip());
+    assert_eq!(conn.local_addr()?.port(), relay_addr.port());
+    assert_eq!(IpAddr::from_str("127.0.0.1")?, conn.local_addr()?.ip());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_static_tcp_relay_address() -> Result<()> {
+    let relay_ip = IpAddr::from_str("203.0.113.1")?;
+    let generator = relay_static::RelayAddressGeneratorStatic {
+        relay_address: relay_ip,
+        address: "127.0.0.1".to_owned(),
+        net: Arc::new(Net::new(None)),
+    };
+
+    let (conn, relay_addr) = generator.allocate_tcp_conn(true, 0).await?;
+    assert_eq!(relay_ip, relay_addr.ip());
+    assert_eq!(conn.local_addr()?.port(), relay_addr.port());
+    assert_eq!(None, conn.remote_addr());
+
+    let peer = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    conn.send_to(b"hi", peer.local_addr()?).await?;
+    let (_, src_addr) = peer.accept().await?;
+    assert_eq!(conn.local_addr()?, src_addr);
+    assert_eq!(Some(peer.local_addr()?), conn.remote_addr());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_range_relay_address() -> Result<()> {
+    // Occupy the first port so the generator has to move on within the range
+    let taken = UdpSocket::bind("127.0.0.1:0").await?;
+    let min_port = taken.local_addr()?.port().min(u16::MAX - 9);
+    let max_port = min_port + 9;
+
+    let relay_ip = IpAddr::from_str("203.0.113.1")?;
+    let generator = relay_range::RelayAddressGeneratorRanges {
+        relay_address: relay_ip,
+        min_port,
+        max_port,
+        max_retries: 10,
+        address: "127.0.0.1".to_owned(),
+        net: Arc::new(Net::new(None)),
+    };
+    generator.validate()?;
+
+    let (conn, relay_addr) = generator.allocate_conn(true, 0).await?;
+    assert_eq!(relay_ip, relay_addr.ip());
+    assert!((min_port..=max_port).contains(&relay_addr.port()));
+    assert_ne!(taken.local_addr()?.port(), relay_addr.port());
+    assert_eq!(conn.local_addr()?.port(), relay_addr.port());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_range_relay_address_exhausted() -> Result<()> {
+    let taken = UdpSocket::bind("127.0.0.1:0").await?;
+    let port = taken.local_addr()?.port();
+
+    let generator = relay_range::RelayAddressGeneratorRanges {
+        relay_address: IpAddr::from_str("127.0.0.1")?,
+        min_port: port,
+        max_port: port,
+        max_retries: 10,
+        address: "127.0.0.1".to_owned(),
+        net: Arc::new(Net::new(None)),
+    };
+
+    let result = generator.allocate_conn(true, 0).await;
+    assert_eq!(Some(Error::ErrMaxRetriesExceeded), result.err());
+
+    Ok(())
+}
@@ -1,10 +1,13 @@
+use crate::allocation::peer_filter::PeerFilter;
 use crate::auth::*;
 use crate::error::*;
 use crate::relay::*;
 
 use util::Conn;
 
+use std::net::SocketAddr;
 use std::sync::Arc;
+use tokio::sync::mpsc;
 use tokio::time::Duration;
 
 // ConnConfig is used for UDP listeners
@@ -22,6 +25,53 @@ impl ConnConfig {
     }
 }
 
+// AllocationConfig configures the allocations the server creates, None leaves a
+// setting at its default
+#[derive(Default, Clone)]
+pub struct AllocationConfig {
+    // nonce_lifetime sets how long the nonces handed to clients stay valid. Defaults to 1 hour.
+    pub nonce_lifetime: Option<Duration>,
+
+    // peer_filter decides which peers allocations may relay to. PeerFilter::default()
+    // keeps clients from reaching unspecified, private, loopback and link-local
+    // addresses.
+    pub peer_filter: PeerFilter,
+
+    // min_lifetime and max_lifetime bound the lifetime granted to allocations, none
+    // below and 1 hour above by default. Clients asking for an hour or more get the
+    // default lifetime of 10 minutes.
+    pub min_lifetime: Option<Duration>,
+    pub max_lifetime: Option<Duration>,
+
+    // max_permissions and max_channel_bindings cap how many permissions and channel
+    // bindings a single allocation may hold, no limit by default
+    pub max_permissions: Option<usize>,
+    pub max_channel_bindings: Option<usize>,
+
+    // permission_timeout sets how long a permission lasts without being refreshed. Defaults to 5 minutes.
+    pub permission_timeout: Option<Duration>,
+
+    // data_listener receives a copy of every payload relayed to a client in a Data
+    // indication, along with the peer it came from, for all allocations
+    pub data_listener: Option<mpsc::Sender<(SocketAddr, Vec<u8>)>>,
+
+    // permission_refresh_on_traffic, see Allocation::with_permission_refresh_on_traffic
+    pub permission_refresh_on_traffic: bool,
+
+    // outbound_queue_size bounds the frames queued for each client, so that a slow
+    // client drops the oldest UDP frames rather than holding up its relay. By default
+    // every frame is written to the client before the next one is read.
+    pub outbound_queue_size: Option<usize>,
+
+    // too_large_listener receives the peer and size of every datagram a relay socket
+    // refused as larger than the path MTU, for allocations asking for DONT-FRAGMENT
+    pub too_large_listener: Option<mpsc::Sender<(SocketAddr, usize)>>,
+
+    // recv_buffer_size sets how many bytes of a datagram from a peer are relayed,
+    // longer ones are truncated. Defaults to 1500 bytes.
+    pub recv_buffer_size: Option<usize>,
+}
+
 // ServerConfig configures the Pion TURN Server
 pub struct ServerConfig {
     // conn_configs are a list of all the turn listeners
@@ -36,6 +86,9 @@ pub struct ServerConfig {
 
     // channel_bind_timeout sets the lifetime of channel binding. Defaults to 10 minutes.
     pub channel_bind_timeout: Duration,
+
+    // allocation configures the allocations of every listener
+    pub allocation: AllocationConfig,
 }
 
 impl ServerConfig {
@@ -20,7 +20,7 @@ use tokio::{
         broadcast::{self, error::RecvError},
         mpsc, oneshot, Mutex,
     },
-    time::{Duration, Instant},
+    time::Duration,
 };
 use util::Conn;
 
@@ -31,7 +31,6 @@ pub struct Server {
     auth_handler: Arc<dyn AuthHandler + Send + Sync>,
     realm: String,
     channel_bind_timeout: Duration,
-    pub(crate) nonces: Arc<Mutex<HashMap<String, Instant>>>,
     command_tx: Mutex<Option<broadcast::Sender<Command>>>,
 }
 
@@ -45,7 +44,6 @@ impl Server {
             auth_handler: config.auth_handler,
             realm: config.realm,
             channel_bind_timeout: config.channel_bind_timeout,
-            nonces: Arc::new(Mutex::new(HashMap::new())),
             command_tx: Mutex::new(Some(command_tx.clone())),
         };
 
@@ -53,8 +51,8 @@ impl Server {
             s.channel_bind_timeout = DEFAULT_LIFETIME;
         }
 
+        let allocation = config.allocation;
         for p in config.conn_configs.into_iter() {
-            let nonces = Arc::clone(&s.nonces);
             let auth_handler = Arc::clone(&s.auth_handler);
             let realm = s.realm.clone();
             let channel_bind_timeout = s.channel_bind_timeout;
@@ -62,12 +60,28 @@ impl Server {
             let conn = p.conn;
             let allocation_manager = Arc::new(Manager::new(ManagerConfig {
                 relay_addr_generator: p.relay_addr_generator,
+                expiry_notifier: None,
+                max_allocations: None,
+                max_allocations_per_source: None,
+                relay_rate_limit: None,
+                peer_filter: allocation.peer_filter.clone(),
+                nonce_lifetime: allocation.nonce_lifetime,
+                idle_timeout: None,
+                min_lifetime: allocation.min_lifetime,
+                max_lifetime: allocation.max_lifetime,
+                max_permissions: allocation.max_permissions,
+                max_channel_bindings: allocation.max_channel_bindings,
+                permission_timeout: allocation.permission_timeout,
+                data_listener: allocation.data_listener.clone(),
+                permission_refresh_on_traffic: allocation.permission_refresh_on_traffic,
+                outbound_queue_size: allocation.outbound_queue_size,
+                too_large_listener: allocation.too_large_listener.clone(),
+                recv_buffer_size: allocation.recv_buffer_size,
             }));
 
             tokio::spawn(Server::read_loop(
                 conn,
                 allocation_manager,
-                nonces,
                 auth_handler,
                 realm,
                 channel_bind_timeout,
@@ -142,7 +156,6 @@ impl Server {
     async fn read_loop(
         conn: Arc<dyn Conn + Send + Sync>,
         allocation_manager: Arc<Manager>,
-        nonces: Arc<Mutex<HashMap<String, Instant>>>,
         auth_handler: Arc<dyn AuthHandler + Send + Sync>,
         realm: String,
         channel_bind_timeout: Duration,
@@ -202,7 +215,6 @@ impl Server {
                 src_addr: addr,
                 buff: buf[..n].to_vec(),
                 allocation_manager: Arc::clone(&allocation_manager),
-                nonces: Arc::clone(&nonces),
                 auth_handler: Arc::clone(&auth_handler),
                 realm: realm.clone(),
                 channel_bind_timeout,
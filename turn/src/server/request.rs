@@ -4,16 +4,17 @@ mod request_test;
 use crate::allocation::allocation_manager::*;
 use crate::allocation::channel_bind::ChannelBind;
 use crate::allocation::five_tuple::*;
-use crate::allocation::permission::Permission;
 use crate::auth::*;
 use crate::error::*;
 use crate::proto::chandata::ChannelData;
 use crate::proto::channum::ChannelNumber;
 use crate::proto::data::Data;
+use crate::proto::demux::*;
 use crate::proto::evenport::EvenPort;
 use crate::proto::lifetime::*;
 use crate::proto::peeraddr::PeerAddress;
 use crate::proto::relayaddr::RelayedAddress;
+use crate::proto::reqfamily::*;
 use crate::proto::reqtrans::RequestedTransport;
 use crate::proto::rsrvtoken::ReservationToken;
 use crate::proto::*;
@@ -30,15 +31,13 @@ use stun::xoraddr::*;
 
 use util::Conn;
 
-use std::collections::HashMap;
 use std::marker::{Send, Sync};
 use std::net::SocketAddr;
 #[cfg(feature = "metrics")]
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::SystemTime;
-use tokio::sync::Mutex;
-use tokio::time::{Duration, Instant};
+use tokio::time::Duration;
 
 use md5::{Digest, Md5};
 
@@ -54,7 +53,6 @@ pub struct Request {
 
     // Server State
     pub allocation_manager: Arc<Manager>,
-    pub nonces: Arc<Mutex<HashMap<String, Instant>>>,
 
     // User Configuration
     pub auth_handler: Arc<dyn AuthHandler + Send + Sync>,
@@ -74,7 +72,6 @@ impl Request {
             src_addr,
             buff: vec![],
             allocation_manager,
-            nonces: Arc::new(Mutex::new(HashMap::new())),
             auth_handler,
             realm: String::new(),
             channel_bind_timeout: Duration::from_secs(0),
@@ -90,10 +87,11 @@ impl Request {
             self.conn.local_addr().await?
         );*/
 
-        if ChannelData::is_channel_data(&self.buff) {
-            self.handle_data_packet().await
-        } else {
-            self.handle_turn_packet().await
+        // STUN and ChannelData share the client's socket
+        match demultiplex(&self.buff) {
+            PacketKind::Stun => self.handle_turn_packet().await,
+            PacketKind::ChannelData => self.handle_data_packet().await,
+            PacketKind::Unknown => Err(Error::ErrUnknownPacketType),
         }
     }
 
@@ -103,7 +101,14 @@ impl Request {
             raw: self.buff.clone(),
             ..Default::default()
         };
-        c.decode()?;
+        if let Err(err) = c.decode_for(PROTO_UDP) {
+            log::debug!(
+                "dropping malformed ChannelData from {}: {}",
+                self.src_addr,
+                err
+            );
+            return Err(err);
+        }
         self.handle_channel_data(&c).await
     }
 
@@ -166,28 +171,14 @@ impl Request {
             return Ok(None);
         }
 
-        let to_be_deleted = {
-            // Assert Nonce exists and is not expired
-            let mut nonces = self.nonces.lock().await;
-
-            let to_be_deleted = if let Some(nonce_creation_time) = nonces.get(&nonce_attr.text) {
-                Instant::now()
-                    .checked_duration_since(*nonce_creation_time)
-                    .unwrap_or_else(|| Duration::from_secs(0))
-                    >= NONCE_LIFETIME
-            } else {
-                true
-            };
-
-            if to_be_deleted {
-                nonces.remove(&nonce_attr.text);
-            }
-            to_be_deleted
-        };
-
-        if to_be_deleted {
-            self.respond_with_nonce(m, calling_method, CODE_STALE_NONCE)
-                .await?;
+        if let Err(err) = self
+            .allocation_manager
+            .validate_nonce(&nonce_attr.text)
+            .await
+        {
+            log::debug!("rejecting nonce from {}: {}", self.src_addr, err);
+            let code = err.error_code().unwrap_or(CODE_UNAUTHORIZED);
+            self.respond_with_nonce(m, calling_method, code).await?;
             return Ok(None);
         }
 
@@ -221,10 +212,35 @@ impl Request {
         let mi = MessageIntegrity(our_key);
         if let Err(err) = mi.check(&mut m.clone()) {
             build_and_send_err(&self.conn, self.src_addr, bad_request_msg, err.into()).await?;
-            Ok(None)
-        } else {
-            Ok(Some((username_attr, mi)))
+            return Ok(None);
         }
+
+        // RFC 8656 Section 5: any request but an Allocate must use the credentials the
+        // allocation was created with, or is rejected with a 441 (Wrong Credentials) error
+        if calling_method != METHOD_ALLOCATE {
+            let five_tuple = FiveTuple {
+                src_addr: self.src_addr,
+                dst_addr: self.conn.local_addr()?,
+                protocol: PROTO_UDP,
+            };
+            if let Some(a) = self.allocation_manager.get_allocation(&five_tuple).await {
+                if a.username() != username_attr.text {
+                    let msg = build_msg(
+                        m.transaction_id,
+                        MessageType::new(calling_method, CLASS_ERROR_RESPONSE),
+                        vec![Box::new(ErrorCodeAttribute {
+                            code: CODE_WRONG_CREDENTIALS,
+                            reason: vec![],
+                        })],
+                    )?;
+                    build_and_send_err(&self.conn, self.src_addr, msg, Error::ErrWrongCredentials)
+                        .await?;
+                    return Ok(None);
+                }
+            }
+        }
+
+        Ok(Some((username_attr, mi)))
     }
 
     async fn respond_with_nonce(
@@ -233,16 +249,7 @@ impl Request {
         calling_method: Method,
         response_code: ErrorCode,
     ) -> Result<()> {
-        let nonce = build_nonce()?;
-
-        {
-            // Nonce has already been taken
-            let mut nonces = self.nonces.lock().await;
-            if nonces.contains_key(&nonce) {
-                return Err(Error::ErrDuplicatedNonce);
-            }
-            nonces.insert(nonce.clone(), Instant::now());
-        }
+        let nonce = self.allocation_manager.generate_nonce().await?;
 
         let msg = build_msg(
             m.transaction_id,
@@ -299,7 +306,9 @@ impl Request {
             dst_addr: self.conn.local_addr()?,
             protocol: PROTO_UDP,
         };
-        let mut requested_port = 0;
+        // A relay conn bound before the allocation is created, for a reservation
+        // token or an even port
+        let mut relay_conn = None;
         let mut reservation_token = "".to_owned();
 
         // 2. The server checks if the 5-tuple is currently in use by an
@@ -334,6 +343,9 @@ impl Request {
         //    Request) error.  Otherwise, if the attribute is included but
         //    specifies a protocol other that UDP, the server rejects the
         //    request with a 442 (Unsupported Transport Protocol) error.
+        //
+        // RFC 6062 only grants TCP allocations over a TCP control connection, which
+        // this server doesn't listen on, TCP relays are allocated with the Manager.
         let mut requested_transport = RequestedTransport::default();
         if let Err(err) = requested_transport.get_from(m) {
             let bad_request_msg = build_msg(
@@ -364,31 +376,34 @@ impl Request {
             .await;
         }
 
+        // RFC 6156 Section 4.2: the REQUESTED-ADDRESS-FAMILY attribute selects the
+        // family of the relayed transport address and defaults to IPv4. A family
+        // the server doesn't know or can't offer is rejected with a 440 (Address
+        // Family not Supported) error.
+        let mut requested_family = REQUESTED_FAMILY_IPV4;
+        if m.contains(ATTR_REQUESTED_ADDRESS_FAMILY) {
+            if let Err(err) = requested_family.get_from(m) {
+                let msg = build_msg(
+                    m.transaction_id,
+                    MessageType::new(METHOD_ALLOCATE, CLASS_ERROR_RESPONSE),
+                    vec![Box::new(ErrorCodeAttribute {
+                        code: CODE_ADDR_FAMILY_NOT_SUPPORTED,
+                        reason: vec![],
+                    })],
+                )?;
+                return build_and_send_err(&self.conn, self.src_addr, msg, err.into()).await;
+            }
+        }
+        let use_ipv4 = requested_family == REQUESTED_FAMILY_IPV4;
+
         // 4. The request may contain a DONT-FRAGMENT attribute.  If it does,
         //    but the server does not support sending UDP datagrams with the DF
         //    bit set to 1 (see Section 12), then the server treats the DONT-
         //    FRAGMENT attribute in the Allocate request as an unknown
         //    comprehension-required attribute.
-        if m.contains(ATTR_DONT_FRAGMENT) {
-            let msg = build_msg(
-                m.transaction_id,
-                MessageType::new(METHOD_ALLOCATE, CLASS_ERROR_RESPONSE),
-                vec![
-                    Box::new(ErrorCodeAttribute {
-                        code: CODE_UNKNOWN_ATTRIBUTE,
-                        reason: vec![],
-                    }),
-                    Box::new(UnknownAttributes(vec![ATTR_DONT_FRAGMENT])),
-                ],
-            )?;
-            return build_and_send_err(
-                &self.conn,
-                self.src_addr,
-                msg,
-                Error::ErrNoDontFragmentSupport,
-            )
-            .await;
-        }
+        //    The flag is passed on to the relay address generator, which
+        //    reports ErrNoDontFragmentSupport if it can't honor it.
+        let dont_fragment = m.contains(ATTR_DONT_FRAGMENT);
 
         // 5.  The server checks if the request contains a RESERVATION-TOKEN
         //     attribute.  If yes, and the request also contains an EVEN-PORT
@@ -418,6 +433,27 @@ impl Request {
                 )
                 .await;
             }
+
+            let token = String::from_utf8_lossy(&reservation_token_attr.0).to_string();
+            if let Some(reserved) = self.allocation_manager.take_reservation(&token).await {
+                relay_conn = Some(reserved);
+            } else {
+                let insufficent_capacity_msg = build_msg(
+                    m.transaction_id,
+                    MessageType::new(METHOD_ALLOCATE, CLASS_ERROR_RESPONSE),
+                    vec![Box::new(ErrorCodeAttribute {
+                        code: CODE_INSUFFICIENT_CAPACITY,
+                        reason: vec![],
+                    })],
+                )?;
+                return build_and_send_err(
+                    &self.conn,
+                    self.src_addr,
+                    insufficent_capacity_msg,
+                    Error::ErrInvalidReservationToken,
+                )
+                .await;
+            }
         }
 
         // 6. The server checks if the request contains an EVEN-PORT attribute.
@@ -426,35 +462,43 @@ impl Request {
         //    below).  If the server cannot satisfy the request, then the
         //    server rejects the request with a 508 (Insufficient Capacity)
         //    error.
+        //    The even port, and the port above it when that is reserved, stay
+        //    bound from here on so that no other allocation can take them.
+        let mut next_conn = None;
         let mut even_port = EvenPort::default();
         if even_port.get_from(m).is_ok() {
-            let mut random_port = 1;
-
-            while random_port % 2 != 0 {
-                random_port = match self.allocation_manager.get_random_even_port().await {
-                    Ok(port) => port,
-                    Err(err) => {
-                        let insufficent_capacity_msg = build_msg(
-                            m.transaction_id,
-                            MessageType::new(METHOD_ALLOCATE, CLASS_ERROR_RESPONSE),
-                            vec![Box::new(ErrorCodeAttribute {
-                                code: CODE_INSUFFICIENT_CAPACITY,
-                                reason: vec![],
-                            })],
-                        )?;
-                        return build_and_send_err(
-                            &self.conn,
-                            self.src_addr,
-                            insufficent_capacity_msg,
-                            err,
-                        )
-                        .await;
-                    }
-                };
+            match self
+                .allocation_manager
+                .allocate_even_port(use_ipv4, dont_fragment, even_port.reserve_port)
+                .await
+            {
+                Ok((even, next)) => {
+                    relay_conn = Some(even);
+                    next_conn = next;
+                }
+                Err(err) => {
+                    let error_code = err.error_code_attribute().unwrap_or(ErrorCodeAttribute {
+                        code: CODE_INSUFFICIENT_CAPACITY,
+                        reason: vec![],
+                    });
+                    let insufficent_capacity_msg = build_msg(
+                        m.transaction_id,
+                        MessageType::new(METHOD_ALLOCATE, CLASS_ERROR_RESPONSE),
+                        vec![Box::new(error_code)],
+                    )?;
+                    return build_and_send_err(
+                        &self.conn,
+                        self.src_addr,
+                        insufficent_capacity_msg,
+                        err,
+                    )
+                    .await;
+                }
             }
 
-            requested_port = random_port;
-            reservation_token = rand_seq(8);
+            if next_conn.is_some() {
+                reservation_token = rand_seq(8);
+            }
         }
 
         // 7. At any point, the server MAY choose to reject the request with a
@@ -469,27 +513,71 @@ impl Request {
         //    client to a different server.  The use of this error code and
         //    attribute follow the specification in [RFC5389].
         let lifetime_duration = allocation_lifetime(m);
-        let a = match self
-            .allocation_manager
-            .create_allocation(
-                five_tuple,
-                Arc::clone(&self.conn),
-                requested_port,
-                lifetime_duration,
-                username,
-            )
-            .await
-        {
+        let params = AllocationParams {
+            five_tuple,
+            lifetime: lifetime_duration,
+            username,
+            requested_transport: requested_transport.protocol,
+            dont_fragment,
+        };
+        let result = if let Some(relay_conn) = relay_conn {
+            self.allocation_manager
+                .create_allocation_with_relay_conn(params, Arc::clone(&self.conn), relay_conn)
+                .await
+        } else {
+            self.allocation_manager
+                .create_allocation_with_dont_fragment(params, Arc::clone(&self.conn), 0, use_ipv4)
+                .await
+        };
+        let a = match result {
             Ok(a) => a,
-            Err(err) => {
-                let insufficent_capacity_msg = build_msg(
+            Err(Error::ErrUnsupportedAddressFamily) => {
+                let msg = build_msg(
                     m.transaction_id,
                     MessageType::new(METHOD_ALLOCATE, CLASS_ERROR_RESPONSE),
                     vec![Box::new(ErrorCodeAttribute {
-                        code: CODE_INSUFFICIENT_CAPACITY,
+                        code: CODE_ADDR_FAMILY_NOT_SUPPORTED,
                         reason: vec![],
                     })],
                 )?;
+                return build_and_send_err(
+                    &self.conn,
+                    self.src_addr,
+                    msg,
+                    Error::ErrUnsupportedAddressFamily,
+                )
+                .await;
+            }
+            Err(Error::ErrNoDontFragmentSupport) => {
+                let msg = build_msg(
+                    m.transaction_id,
+                    MessageType::new(METHOD_ALLOCATE, CLASS_ERROR_RESPONSE),
+                    vec![
+                        Box::new(ErrorCodeAttribute {
+                            code: CODE_UNKNOWN_ATTRIBUTE,
+                            reason: vec![],
+                        }),
+                        Box::new(UnknownAttributes(vec![ATTR_DONT_FRAGMENT])),
+                    ],
+                )?;
+                return build_and_send_err(
+                    &self.conn,
+                    self.src_addr,
+                    msg,
+                    Error::ErrNoDontFragmentSupport,
+                )
+                .await;
+            }
+            Err(err) => {
+                let error_code = err.error_code_attribute().unwrap_or(ErrorCodeAttribute {
+                    code: CODE_INSUFFICIENT_CAPACITY,
+                    reason: vec![],
+                });
+                let insufficent_capacity_msg = build_msg(
+                    m.transaction_id,
+                    MessageType::new(METHOD_ALLOCATE, CLASS_ERROR_RESPONSE),
+                    vec![Box::new(error_code)],
+                )?;
                 return build_and_send_err(
                     &self.conn,
                     self.src_addr,
@@ -512,13 +600,17 @@ impl Request {
         //     and port (from the 5-tuple).
 
         let (src_ip, src_port) = (self.src_addr.ip(), self.src_addr.port());
-        let relay_ip = a.relay_addr.ip();
-        let relay_port = a.relay_addr.port();
+        let relay_addr = a.relay_addr();
+        let relay_ip = relay_addr.ip();
+        // The LIFETIME echoes what was granted, not what was asked for
+        let lifetime_duration = a.granted_lifetime(lifetime_duration);
+        let relay_port = relay_addr.port();
 
         let msg = {
-            if !reservation_token.is_empty() {
+            // The token reserves the next-higher port, to pair with the even one.
+            if let Some(next_conn) = next_conn {
                 self.allocation_manager
-                    .create_reservation(reservation_token.clone(), relay_port)
+                    .create_reservation(reservation_token.clone(), next_conn)
                     .await;
             }
 
@@ -562,7 +654,7 @@ impl Request {
                 return Ok(());
             };
 
-        let lifetime_duration = allocation_lifetime(m);
+        let mut lifetime_duration = allocation_lifetime(m);
         let five_tuple = FiveTuple {
             src_addr: self.src_addr,
             dst_addr: self.conn.local_addr()?,
@@ -572,7 +664,7 @@ impl Request {
         if lifetime_duration != Duration::from_secs(0) {
             let a = self.allocation_manager.get_allocation(&five_tuple).await;
             if let Some(a) = a {
-                a.refresh(lifetime_duration).await;
+                lifetime_duration = a.refresh(lifetime_duration).await;
             } else {
                 return Err(Error::ErrNoAllocationFound);
             }
@@ -580,14 +672,7 @@ impl Request {
             self.allocation_manager.delete_allocation(&five_tuple).await;
         }
 
-        let msg = build_msg(
-            m.transaction_id,
-            MessageType::new(METHOD_REFRESH, CLASS_SUCCESS_RESPONSE),
-            vec![
-                Box::new(Lifetime(lifetime_duration)),
-                Box::new(message_integrity),
-            ],
-        )?;
+        let msg = build_refresh_response(m.transaction_id, lifetime_duration, message_integrity)?;
 
         build_and_send(&self.conn, self.src_addr, msg).await
     }
@@ -614,44 +699,38 @@ impl Request {
                 log::debug!("no MessageIntegrity");
                 return Ok(());
             };
-            let mut add_count = 0;
-
-            {
-                for attr in &m.attributes.0 {
-                    if attr.typ != ATTR_XOR_PEER_ADDRESS {
-                        continue;
-                    }
-
-                    let mut peer_address = PeerAddress::default();
-                    if peer_address.get_from(m).is_err() {
-                        add_count = 0;
-                        break;
+            let mut resp_class = CLASS_SUCCESS_RESPONSE;
+            let mut error_code = None;
+            match PeerAddress::get_all_from(m) {
+                Ok(peer_addresses) if !peer_addresses.is_empty() => {
+                    let addrs: Vec<SocketAddr> = peer_addresses
+                        .iter()
+                        .map(|peer_address| SocketAddr::new(peer_address.ip, peer_address.port))
+                        .collect();
+
+                    log::debug!("adding permissions for {:?}", addrs);
+
+                    if let Err(err) = a.add_permissions(&addrs).await {
+                        log::debug!("failed to add permissions: {}", err);
+                        resp_class = CLASS_ERROR_RESPONSE;
+                        error_code = err.error_code_attribute();
                     }
-
-                    log::debug!(
-                        "adding permission for {}",
-                        format!("{}:{}", peer_address.ip, peer_address.port)
-                    );
-
-                    a.add_permission(Permission::new(SocketAddr::new(
-                        peer_address.ip,
-                        peer_address.port,
-                    )))
-                    .await;
-                    add_count += 1;
                 }
+                _ => resp_class = CLASS_ERROR_RESPONSE,
             }
 
-            let mut resp_class = CLASS_SUCCESS_RESPONSE;
-            if add_count == 0 {
-                resp_class = CLASS_ERROR_RESPONSE;
-            }
-
-            let msg = build_msg(
-                m.transaction_id,
-                MessageType::new(METHOD_CREATE_PERMISSION, resp_class),
-                vec![Box::new(message_integrity)],
-            )?;
+            let msg = {
+                let mut setters: Vec<Box<dyn Setter>> = vec![];
+                if let Some(error_code) = error_code {
+                    setters.push(Box::new(error_code));
+                }
+                setters.push(Box::new(message_integrity));
+                build_msg(
+                    m.transaction_id,
+                    MessageType::new(METHOD_CREATE_PERMISSION, resp_class),
+                    setters,
+                )?
+            };
 
             build_and_send(&self.conn, self.src_addr, msg).await
         } else {
@@ -685,7 +764,7 @@ impl Request {
                 return Err(Error::ErrNoPermission);
             }
 
-            let l = a.relay_socket.send_to(&data_attr.0, msg_dst).await?;
+            let l = a.write_to_relay(&data_attr.0, msg_dst).await?;
             if l != data_attr.0.len() {
                 Err(Error::ErrShortWrite)
             } else {
@@ -755,6 +834,14 @@ impl Request {
                 .await
             };
             if let Err(err) = result {
+                if let Some(error_code) = err.error_code_attribute() {
+                    let error_msg = build_msg(
+                        m.transaction_id,
+                        MessageType::new(METHOD_CHANNEL_BIND, CLASS_ERROR_RESPONSE),
+                        vec![Box::new(error_code)],
+                    )?;
+                    return build_and_send_err(&self.conn, self.src_addr, error_msg, err).await;
+                }
                 return build_and_send_err(&self.conn, self.src_addr, bad_request_msg, err).await;
             }
 
@@ -784,7 +871,7 @@ impl Request {
         if let Some(a) = a {
             let channel = a.get_channel_addr(&c.number).await;
             if let Some(peer) = channel {
-                let l = a.relay_socket.send_to(&c.data, peer).await?;
+                let l = a.write_to_relay(&c.data, peer).await?;
                 if l != c.data.len() {
                     Err(Error::ErrShortWrite)
                 } else {
@@ -875,6 +962,25 @@ pub(crate) fn build_msg(
     Ok(msg)
 }
 
+// build_refresh_response builds the success response to a Refresh request, telling the
+// client the lifetime it was granted. MESSAGE-INTEGRITY follows LIFETIME and FINGERPRINT
+// comes last, as both cover the attributes before them.
+pub(crate) fn build_refresh_response(
+    transaction_id: TransactionId,
+    granted: Duration,
+    message_integrity: MessageIntegrity,
+) -> Result<Message> {
+    build_msg(
+        transaction_id,
+        MessageType::new(METHOD_REFRESH, CLASS_SUCCESS_RESPONSE),
+        vec![
+            Box::new(Lifetime(granted)),
+            Box::new(message_integrity),
+            Box::new(FINGERPRINT),
+        ],
+    )
+}
+
 pub(crate) fn allocation_lifetime(m: &Message) -> Duration {
     let mut lifetime_duration = DEFAULT_LIFETIME;
 
@@ -1,4 +1,7 @@
 use super::*;
+use crate::allocation::peer_filter::PeerFilter;
+use crate::proto::channum::MIN_CHANNEL_NUMBER;
+use crate::proto::dontfrag::DontFragmentAttr;
 use crate::relay::relay_none::*;
 
 use std::{net::IpAddr, str::FromStr};
@@ -34,6 +37,32 @@ async fn test_allocation_lifetime_parsing() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_build_refresh_response() -> Result<()> {
+    let granted = Duration::from_secs(1234);
+    let integrity = MessageIntegrity::new_short_term_integrity(STATIC_KEY.to_owned());
+    let transaction_id = TransactionId::new();
+    let msg = build_refresh_response(transaction_id, granted, integrity.clone())?;
+
+    let mut decoded = Message::new();
+    decoded.raw = msg.raw;
+    decoded.decode()?;
+    assert_eq!(transaction_id, decoded.transaction_id);
+    assert_eq!(
+        MessageType::new(METHOD_REFRESH, CLASS_SUCCESS_RESPONSE),
+        decoded.typ
+    );
+
+    let mut lifetime = Lifetime::default();
+    lifetime.get_from(&decoded)?;
+    assert_eq!(granted, lifetime.0);
+
+    integrity.check(&mut decoded)?;
+    FINGERPRINT.check(&decoded)?;
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_allocation_lifetime_overflow() -> Result<()> {
     let lifetime = Lifetime(MAXIMUM_ALLOCATION_LIFETIME * 2);
@@ -69,16 +98,32 @@ async fn test_allocation_lifetime_deletion_zero_lifetime() -> Result<()> {
             address: "0.0.0.0".to_owned(),
             net: Arc::new(Net::new(None)),
         }),
+        expiry_notifier: None,
+        max_allocations: None,
+        max_allocations_per_source: None,
+        relay_rate_limit: None,
+        peer_filter: PeerFilter::default(),
+        nonce_lifetime: None,
+        idle_timeout: None,
+        min_lifetime: None,
+        max_lifetime: None,
+        max_permissions: None,
+        max_channel_bindings: None,
+        permission_timeout: None,
+        data_listener: None,
+        permission_refresh_on_traffic: false,
+        outbound_queue_size: None,
+        too_large_listener: None,
+        recv_buffer_size: None,
     }));
 
     let socket = SocketAddr::new(IpAddr::from_str("127.0.0.1")?, 5000);
 
     let mut r = Request::new(l, socket, allocation_manager, Arc::new(TestAuthHandler {}));
 
-    {
-        let mut nonces = r.nonces.lock().await;
-        nonces.insert(STATIC_KEY.to_owned(), Instant::now());
-    }
+    r.allocation_manager
+        .insert_nonce(STATIC_KEY, Instant::now())
+        .await;
 
     let five_tuple = FiveTuple {
         src_addr: r.src_addr,
@@ -92,7 +137,7 @@ async fn test_allocation_lifetime_deletion_zero_lifetime() -> Result<()> {
             Arc::clone(&r.conn),
             0,
             Duration::from_secs(3600),
-            TextAttribute::new(ATTR_USERNAME, "user".into()),
+            TextAttribute::new(ATTR_USERNAME, STATIC_KEY.into()),
         )
         .await?;
     assert!(r
@@ -117,3 +162,631 @@ async fn test_allocation_lifetime_deletion_zero_lifetime() -> Result<()> {
 
     Ok(())
 }
+
+fn new_test_manager_config() -> ManagerConfig {
+    ManagerConfig {
+        relay_addr_generator: Box::new(RelayAddressGeneratorNone {
+            address: "0.0.0.0".to_owned(),
+            net: Arc::new(Net::new(None)),
+        }),
+        expiry_notifier: None,
+        max_allocations: None,
+        max_allocations_per_source: None,
+        relay_rate_limit: None,
+        peer_filter: PeerFilter::default(),
+        nonce_lifetime: None,
+        idle_timeout: None,
+        min_lifetime: None,
+        max_lifetime: None,
+        max_permissions: None,
+        max_channel_bindings: None,
+        permission_timeout: None,
+        data_listener: None,
+        permission_refresh_on_traffic: false,
+        outbound_queue_size: None,
+        too_large_listener: None,
+        recv_buffer_size: None,
+    }
+}
+
+async fn new_test_request(client_addr: SocketAddr) -> Result<Request> {
+    let allocation_manager = Arc::new(Manager::new(new_test_manager_config()));
+
+    new_test_request_with_manager(client_addr, allocation_manager).await
+}
+
+async fn new_test_request_with_manager(
+    client_addr: SocketAddr,
+    allocation_manager: Arc<Manager>,
+) -> Result<Request> {
+    let l = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+
+    let r = Request::new(
+        l,
+        client_addr,
+        allocation_manager,
+        Arc::new(TestAuthHandler {}),
+    );
+
+    r.allocation_manager
+        .insert_nonce(STATIC_KEY, Instant::now())
+        .await;
+
+    Ok(r)
+}
+
+fn build_allocate_request(mut attrs: Vec<Box<dyn Setter>>) -> Result<Message> {
+    let mut setters: Vec<Box<dyn Setter>> = vec![
+        Box::new(TransactionId::new()),
+        Box::new(MessageType::new(METHOD_ALLOCATE, CLASS_REQUEST)),
+        Box::new(RequestedTransport {
+            protocol: PROTO_UDP,
+        }),
+    ];
+    setters.append(&mut attrs);
+    setters.push(Box::new(Username::new(
+        ATTR_USERNAME,
+        STATIC_KEY.to_owned(),
+    )));
+    setters.push(Box::new(Realm::new(ATTR_REALM, STATIC_KEY.to_owned())));
+    setters.push(Box::new(Nonce::new(ATTR_NONCE, STATIC_KEY.to_owned())));
+    setters.push(Box::new(MessageIntegrity(STATIC_KEY.as_bytes().to_vec())));
+
+    let mut m = Message::new();
+    m.build(&setters)?;
+    Ok(m)
+}
+
+async fn read_response(client: &UdpSocket) -> Result<Message> {
+    let mut buf = vec![0u8; 1500];
+    let (n, _) = client.recv_from(&mut buf).await?;
+
+    let mut m = Message::new();
+    m.raw = buf[..n].to_vec();
+    m.decode()?;
+    Ok(m)
+}
+
+#[tokio::test]
+async fn test_allocate_even_port() -> Result<()> {
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    let mut r = new_test_request(client.local_addr()?).await?;
+
+    let m = build_allocate_request(vec![Box::new(EvenPort {
+        reserve_port: false,
+    })])?;
+    r.handle_allocate_request(&m).await?;
+
+    let resp = read_response(&client).await?;
+    assert_eq!(CLASS_SUCCESS_RESPONSE, resp.typ.class);
+
+    let mut relayed_addr = RelayedAddress::default();
+    relayed_addr.get_from(&resp)?;
+    assert_eq!(0, relayed_addr.port % 2, "relay port should be even");
+
+    let mut token = ReservationToken::default();
+    assert!(
+        token.get_from(&resp).is_err(),
+        "should not reserve a port unless asked to"
+    );
+
+    r.allocation_manager.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_allocate_even_port_with_reservation() -> Result<()> {
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    let mut r = new_test_request(client.local_addr()?).await?;
+
+    let m = build_allocate_request(vec![Box::new(EvenPort { reserve_port: true })])?;
+    r.handle_allocate_request(&m).await?;
+
+    let resp = read_response(&client).await?;
+    assert_eq!(CLASS_SUCCESS_RESPONSE, resp.typ.class);
+
+    let mut relayed_addr = RelayedAddress::default();
+    relayed_addr.get_from(&resp)?;
+    assert_eq!(0, relayed_addr.port % 2, "relay port should be even");
+
+    let mut token = ReservationToken::default();
+    token.get_from(&resp)?;
+    let token = String::from_utf8_lossy(&token.0).to_string();
+    assert_eq!(
+        Some(relayed_addr.port + 1),
+        r.allocation_manager.get_reservation(&token).await,
+        "should reserve the next-higher port"
+    );
+    assert!(
+        UdpSocket::bind(("0.0.0.0", relayed_addr.port + 1))
+            .await
+            .is_err(),
+        "should hold the reserved port"
+    );
+
+    // Another client redeems the token for the reserved port
+    let other_client = UdpSocket::bind("127.0.0.1:0").await?;
+    let mut r = new_test_request_with_manager(
+        other_client.local_addr()?,
+        Arc::clone(&r.allocation_manager),
+    )
+    .await?;
+    let m = build_allocate_request(vec![Box::new(ReservationToken(token.into_bytes()))])?;
+    r.handle_allocate_request(&m).await?;
+
+    let resp = read_response(&other_client).await?;
+    assert_eq!(CLASS_SUCCESS_RESPONSE, resp.typ.class);
+    let mut reserved_addr = RelayedAddress::default();
+    reserved_addr.get_from(&resp)?;
+    assert_eq!(relayed_addr.port + 1, reserved_addr.port);
+
+    r.allocation_manager.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_allocate_unknown_reservation_token() -> Result<()> {
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    let mut r = new_test_request(client.local_addr()?).await?;
+
+    let m = build_allocate_request(vec![Box::new(ReservationToken(b"unknown!".to_vec()))])?;
+    let result = r.handle_allocate_request(&m).await;
+    assert_eq!(Err(Error::ErrInvalidReservationToken), result);
+
+    let resp = read_response(&client).await?;
+    assert_eq!(CLASS_ERROR_RESPONSE, resp.typ.class);
+
+    let mut code = ErrorCodeAttribute::default();
+    code.get_from(&resp)?;
+    assert!(CODE_INSUFFICIENT_CAPACITY == code.code);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_allocate_with_reservation_token() -> Result<()> {
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    let mut r = new_test_request(client.local_addr()?).await?;
+
+    let reserved = UdpSocket::bind("0.0.0.0:0").await?;
+    let reserved_addr = reserved.local_addr()?;
+    let port = reserved_addr.port();
+    r.allocation_manager
+        .create_reservation("reserved".to_owned(), (Arc::new(reserved), reserved_addr))
+        .await;
+
+    let m = build_allocate_request(vec![Box::new(ReservationToken(b"reserved".to_vec()))])?;
+    r.handle_allocate_request(&m).await?;
+
+    let resp = read_response(&client).await?;
+    assert_eq!(CLASS_SUCCESS_RESPONSE, resp.typ.class);
+
+    let mut relayed_addr = RelayedAddress::default();
+    relayed_addr.get_from(&resp)?;
+    assert_eq!(port, relayed_addr.port, "should allocate the reserved port");
+    assert_eq!(
+        None,
+        r.allocation_manager.get_reservation("reserved").await,
+        "reservation should be redeemed only once"
+    );
+
+    r.allocation_manager.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_allocate_allocation_mismatch() -> Result<()> {
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    let mut r = new_test_request(client.local_addr()?).await?;
+
+    r.handle_allocate_request(&build_allocate_request(vec![])?)
+        .await?;
+    let resp = read_response(&client).await?;
+    assert_eq!(CLASS_SUCCESS_RESPONSE, resp.typ.class);
+
+    let result = r
+        .handle_allocate_request(&build_allocate_request(vec![])?)
+        .await;
+    assert_eq!(Err(Error::ErrRelayAlreadyAllocatedForFiveTuple), result);
+    let resp = read_response(&client).await?;
+    assert_eq!(CLASS_ERROR_RESPONSE, resp.typ.class);
+    let mut code = ErrorCodeAttribute::default();
+    code.get_from(&resp)?;
+    assert_eq!(CODE_ALLOC_MISMATCH.0, code.code.0);
+
+    r.allocation_manager.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_allocate_quota_reached() -> Result<()> {
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    let allocation_manager = Arc::new(Manager::new(ManagerConfig {
+        relay_addr_generator: Box::new(RelayAddressGeneratorNone {
+            address: "0.0.0.0".to_owned(),
+            net: Arc::new(Net::new(None)),
+        }),
+        expiry_notifier: None,
+        max_allocations: None,
+        max_allocations_per_source: Some(1),
+        relay_rate_limit: None,
+        peer_filter: PeerFilter::default(),
+        nonce_lifetime: None,
+        idle_timeout: None,
+        min_lifetime: None,
+        max_lifetime: None,
+        max_permissions: None,
+        max_channel_bindings: None,
+        permission_timeout: None,
+        data_listener: None,
+        permission_refresh_on_traffic: false,
+        outbound_queue_size: None,
+        too_large_listener: None,
+        recv_buffer_size: None,
+    }));
+
+    // The same client allocates through two server sockets
+    let mut r =
+        new_test_request_with_manager(client.local_addr()?, Arc::clone(&allocation_manager))
+            .await?;
+    r.handle_allocate_request(&build_allocate_request(vec![])?)
+        .await?;
+    let resp = read_response(&client).await?;
+    assert_eq!(CLASS_SUCCESS_RESPONSE, resp.typ.class);
+
+    let mut r = new_test_request_with_manager(client.local_addr()?, allocation_manager).await?;
+    let result = r
+        .handle_allocate_request(&build_allocate_request(vec![])?)
+        .await;
+    assert_eq!(Err(Error::ErrAllocationQuotaReached), result);
+
+    let resp = read_response(&client).await?;
+    assert_eq!(CLASS_ERROR_RESPONSE, resp.typ.class);
+
+    let mut code = ErrorCodeAttribute::default();
+    code.get_from(&resp)?;
+    assert!(CODE_ALLOC_QUOTA_REACHED == code.code);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_allocate_dont_fragment_unsupported() -> Result<()> {
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    let allocation_manager = Arc::new(Manager::new(ManagerConfig {
+        relay_addr_generator: Box::new(RelayAddressGeneratorNone {
+            address: "0.0.0.0".to_owned(),
+            net: Arc::new(Net::new(Some(NetConfig::default()))),
+        }),
+        expiry_notifier: None,
+        max_allocations: None,
+        max_allocations_per_source: None,
+        relay_rate_limit: None,
+        peer_filter: PeerFilter::default(),
+        nonce_lifetime: None,
+        idle_timeout: None,
+        min_lifetime: None,
+        max_lifetime: None,
+        max_permissions: None,
+        max_channel_bindings: None,
+        permission_timeout: None,
+        data_listener: None,
+        permission_refresh_on_traffic: false,
+        outbound_queue_size: None,
+        too_large_listener: None,
+        recv_buffer_size: None,
+    }));
+
+    let mut r = new_test_request_with_manager(client.local_addr()?, allocation_manager).await?;
+    let result = r
+        .handle_allocate_request(&build_allocate_request(vec![Box::new(DontFragmentAttr)])?)
+        .await;
+    assert_eq!(Err(Error::ErrNoDontFragmentSupport), result);
+
+    let resp = read_response(&client).await?;
+    assert_eq!(CLASS_ERROR_RESPONSE, resp.typ.class);
+
+    let mut code = ErrorCodeAttribute::default();
+    code.get_from(&resp)?;
+    assert!(CODE_UNKNOWN_ATTRIBUTE == code.code);
+
+    let mut unknown = UnknownAttributes(vec![]);
+    unknown.get_from(&resp)?;
+    assert_eq!(vec![ATTR_DONT_FRAGMENT], unknown.0);
+
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[tokio::test]
+async fn test_allocate_dont_fragment() -> Result<()> {
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    let mut r = new_test_request(client.local_addr()?).await?;
+
+    r.handle_allocate_request(&build_allocate_request(vec![Box::new(DontFragmentAttr)])?)
+        .await?;
+    let resp = read_response(&client).await?;
+    assert_eq!(CLASS_SUCCESS_RESPONSE, resp.typ.class);
+
+    let five_tuple = FiveTuple {
+        src_addr: client.local_addr()?,
+        dst_addr: r.conn.local_addr()?,
+        protocol: PROTO_UDP,
+    };
+    let a = r
+        .allocation_manager
+        .get_allocation(&five_tuple)
+        .await
+        .expect("allocation should exist");
+    assert!(a.dont_fragment());
+
+    Ok(())
+}
+
+async fn allocate_with_family(
+    allocation_manager: Arc<Manager>,
+    family: RequestedAddressFamily,
+) -> Result<(Result<()>, Message)> {
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    let mut r = new_test_request_with_manager(client.local_addr()?, allocation_manager).await?;
+    let result = r
+        .handle_allocate_request(&build_allocate_request(vec![Box::new(family)])?)
+        .await;
+    let resp = read_response(&client).await?;
+    Ok((result, resp))
+}
+
+fn new_manager_with_relay_address(address: &str) -> Arc<Manager> {
+    Arc::new(Manager::new(ManagerConfig {
+        relay_addr_generator: Box::new(RelayAddressGeneratorNone {
+            address: address.to_owned(),
+            net: Arc::new(Net::new(None)),
+        }),
+        expiry_notifier: None,
+        max_allocations: None,
+        max_allocations_per_source: None,
+        relay_rate_limit: None,
+        peer_filter: PeerFilter::default(),
+        nonce_lifetime: None,
+        idle_timeout: None,
+        min_lifetime: None,
+        max_lifetime: None,
+        max_permissions: None,
+        max_channel_bindings: None,
+        permission_timeout: None,
+        data_listener: None,
+        permission_refresh_on_traffic: false,
+        outbound_queue_size: None,
+        too_large_listener: None,
+        recv_buffer_size: None,
+    }))
+}
+
+#[tokio::test]
+async fn test_allocate_requested_family_ipv4() -> Result<()> {
+    let (result, resp) = allocate_with_family(
+        new_manager_with_relay_address("0.0.0.0"),
+        REQUESTED_FAMILY_IPV4,
+    )
+    .await?;
+    result?;
+    assert_eq!(CLASS_SUCCESS_RESPONSE, resp.typ.class);
+
+    let mut relay_addr = RelayedAddress::default();
+    relay_addr.get_from(&resp)?;
+    assert!(relay_addr.ip.is_ipv4(), "relayed address should be IPv4");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_allocate_requested_family_ipv6() -> Result<()> {
+    let (result, resp) =
+        allocate_with_family(new_manager_with_relay_address("::1"), REQUESTED_FAMILY_IPV6).await?;
+    result?;
+    assert_eq!(CLASS_SUCCESS_RESPONSE, resp.typ.class);
+
+    let mut relay_addr = RelayedAddress::default();
+    relay_addr.get_from(&resp)?;
+    assert!(relay_addr.ip.is_ipv6(), "relayed address should be IPv6");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_allocate_requested_family_unsupported() -> Result<()> {
+    let (result, resp) = allocate_with_family(
+        new_manager_with_relay_address("0.0.0.0"),
+        REQUESTED_FAMILY_IPV6,
+    )
+    .await?;
+    assert_eq!(Err(Error::ErrUnsupportedAddressFamily), result);
+    assert_eq!(CLASS_ERROR_RESPONSE, resp.typ.class);
+
+    let mut code = ErrorCodeAttribute::default();
+    code.get_from(&resp)?;
+    assert!(CODE_ADDR_FAMILY_NOT_SUPPORTED == code.code);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_demultiplex_stun_and_channel_data() -> Result<()> {
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    let peer = UdpSocket::bind("127.0.0.1:0").await?;
+    let allocation_manager = Arc::new(Manager::new(ManagerConfig {
+        relay_addr_generator: Box::new(RelayAddressGeneratorNone {
+            address: "127.0.0.1".to_owned(),
+            net: Arc::new(Net::new(None)),
+        }),
+        expiry_notifier: None,
+        max_allocations: None,
+        max_allocations_per_source: None,
+        relay_rate_limit: None,
+        // the peer is on loopback
+        peer_filter: PeerFilter::allow_all(),
+        nonce_lifetime: None,
+        idle_timeout: None,
+        min_lifetime: None,
+        max_lifetime: None,
+        max_permissions: None,
+        max_channel_bindings: None,
+        permission_timeout: None,
+        data_listener: None,
+        permission_refresh_on_traffic: false,
+        outbound_queue_size: None,
+        too_large_listener: None,
+        recv_buffer_size: None,
+    }));
+    let mut r =
+        new_test_request_with_manager(client.local_addr()?, Arc::clone(&allocation_manager))
+            .await?;
+
+    let five_tuple = FiveTuple {
+        src_addr: client.local_addr()?,
+        dst_addr: r.conn.local_addr()?,
+        protocol: PROTO_UDP,
+    };
+    let a = allocation_manager
+        .create_allocation(
+            five_tuple,
+            Arc::clone(&r.conn),
+            0,
+            DEFAULT_LIFETIME,
+            TextAttribute::new(ATTR_USERNAME, "user".into()),
+        )
+        .await?;
+    a.add_channel_bind(
+        ChannelBind::new(ChannelNumber(MIN_CHANNEL_NUMBER), peer.local_addr()?),
+        DEFAULT_LIFETIME,
+    )
+    .await?;
+
+    // A STUN request is answered on the shared socket
+    let mut m = Message::new();
+    m.build(&[Box::new(TransactionId::new()), Box::new(BINDING_REQUEST)])?;
+    r.buff = m.raw.clone();
+    r.handle_request().await?;
+    let resp = read_response(&client).await?;
+    assert_eq!(BINDING_SUCCESS, resp.typ);
+    assert_eq!(m.transaction_id, resp.transaction_id);
+
+    // ChannelData arriving on the same socket is relayed to the bound peer
+    let mut c = ChannelData {
+        data: b"data".to_vec(),
+        number: ChannelNumber(MIN_CHANNEL_NUMBER),
+        ..Default::default()
+    };
+    c.encode();
+    r.buff = c.raw.clone();
+    r.handle_request().await?;
+    let mut buf = vec![0u8; 1500];
+    let (n, from) = peer.recv_from(&mut buf).await?;
+    assert_eq!(b"data", &buf[..n]);
+    assert_eq!(a.relay_addr().port(), from.port());
+
+    // Anything else is neither
+    r.buff = vec![0x80, 0x60, 0, 1];
+    assert_eq!(Err(Error::ErrUnknownPacketType), r.handle_request().await);
+
+    allocation_manager.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_allocate_lifetime_bounds() -> Result<()> {
+    let mut config = new_test_manager_config();
+    config.min_lifetime = Some(Duration::from_secs(60));
+    config.max_lifetime = Some(Duration::from_secs(300));
+    let allocation_manager = Arc::new(Manager::new(config));
+
+    for (requested, granted) in [(30, 60), (120, 120), (300, 300), (1200, 300)] {
+        let client = UdpSocket::bind("127.0.0.1:0").await?;
+        let mut r =
+            new_test_request_with_manager(client.local_addr()?, Arc::clone(&allocation_manager))
+                .await?;
+
+        let m = build_allocate_request(vec![Box::new(Lifetime(Duration::from_secs(requested)))])?;
+        r.handle_allocate_request(&m).await?;
+
+        let resp = read_response(&client).await?;
+        assert_eq!(CLASS_SUCCESS_RESPONSE, resp.typ.class);
+        let mut lifetime = Lifetime::default();
+        lifetime.get_from(&resp)?;
+        assert_eq!(
+            Duration::from_secs(granted),
+            lifetime.0,
+            "requested {}s",
+            requested
+        );
+
+        let five_tuple = FiveTuple {
+            src_addr: client.local_addr()?,
+            dst_addr: r.conn.local_addr()?,
+            protocol: PROTO_UDP,
+        };
+        let a = allocation_manager
+            .get_allocation(&five_tuple)
+            .await
+            .expect("allocation should exist");
+        let remaining = a.info().await.remaining_lifetime;
+        assert!(
+            remaining <= Duration::from_secs(granted)
+                && remaining > Duration::from_secs(granted - 5),
+            "should arm the timer with the granted lifetime"
+        );
+    }
+
+    allocation_manager.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_refresh_wrong_credentials() -> Result<()> {
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    let mut r = new_test_request(client.local_addr()?).await?;
+
+    r.handle_allocate_request(&build_allocate_request(vec![])?)
+        .await?;
+    let resp = read_response(&client).await?;
+    assert_eq!(CLASS_SUCCESS_RESPONSE, resp.typ.class);
+
+    let build_refresh_request = |username: &str| -> Result<Message> {
+        let mut m = Message::new();
+        m.build(&[
+            Box::new(TransactionId::new()),
+            Box::new(MessageType::new(METHOD_REFRESH, CLASS_REQUEST)),
+            Box::new(Lifetime(DEFAULT_LIFETIME)),
+            Box::new(Username::new(ATTR_USERNAME, username.to_owned())),
+            Box::new(Realm::new(ATTR_REALM, STATIC_KEY.to_owned())),
+            Box::new(Nonce::new(ATTR_NONCE, STATIC_KEY.to_owned())),
+            Box::new(MessageIntegrity(STATIC_KEY.as_bytes().to_vec())),
+        ])?;
+        Ok(m)
+    };
+
+    let result = r
+        .handle_refresh_request(&build_refresh_request("someone else")?)
+        .await;
+    assert_eq!(Err(Error::ErrWrongCredentials), result);
+    let resp = read_response(&client).await?;
+    assert_eq!(CLASS_ERROR_RESPONSE, resp.typ.class);
+    let mut code = ErrorCodeAttribute::default();
+    code.get_from(&resp)?;
+    assert!(CODE_WRONG_CREDENTIALS == code.code);
+
+    r.handle_refresh_request(&build_refresh_request(STATIC_KEY)?)
+        .await?;
+    let resp = read_response(&client).await?;
+    assert_eq!(CLASS_SUCCESS_RESPONSE, resp.typ.class);
+
+    r.allocation_manager.close().await?;
+
+    Ok(())
+}
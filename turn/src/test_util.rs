@@ -0,0 +1,161 @@
+use util::sync::Mutex as SyncMutex;
+use util::Conn;
+
+use async_trait::async_trait;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use tokio::sync::{mpsc, Mutex, Notify};
+
+type Datagram = (Vec<u8>, SocketAddr);
+
+// MockConn is an in-memory Conn for driving allocations without real sockets.
+// Datagrams pushed with push_datagram are handed out by recv/recv_from in order,
+// and everything written to it is recorded with its destination.
+pub(crate) struct MockConn {
+    local_addr: SocketAddr,
+    remote_addr: Option<SocketAddr>,
+    max_datagram_size: Option<usize>,
+    fail_long_reads: bool,
+    stalled: bool,
+    inbound_tx: SyncMutex<Option<mpsc::UnboundedSender<Datagram>>>,
+    inbound_rx: Mutex<mpsc::UnboundedReceiver<Datagram>>,
+    written: SyncMutex<VecDeque<Datagram>>,
+    write_notify: Notify,
+}
+
+impl MockConn {
+    pub(crate) fn new(local_addr: SocketAddr) -> Self {
+        let (inbound_tx, inbound_rx) = mpsc::unbounded_channel();
+        MockConn {
+            local_addr,
+            remote_addr: None,
+            max_datagram_size: None,
+            fail_long_reads: false,
+            stalled: false,
+            inbound_tx: SyncMutex::new(Some(inbound_tx)),
+            inbound_rx: Mutex::new(inbound_rx),
+            written: SyncMutex::new(VecDeque::new()),
+            write_notify: Notify::new(),
+        }
+    }
+
+    // with_remote_addr makes the conn look connected to remote_addr, as a TCP
+    // relay connection is. send writes to it and recv_from reports it as the source.
+    pub(crate) fn with_remote_addr(mut self, remote_addr: SocketAddr) -> Self {
+        self.remote_addr = Some(remote_addr);
+        self
+    }
+
+    // with_max_datagram_size makes send_to fail with EMSGSIZE for datagrams larger
+    // than max_datagram_size, as a socket with the DF bit set does beyond the path MTU
+    pub(crate) fn with_max_datagram_size(mut self, max_datagram_size: usize) -> Self {
+        self.max_datagram_size = Some(max_datagram_size);
+        self
+    }
+
+    // with_failing_long_reads makes recv_from fail with EMSGSIZE for datagrams longer
+    // than its buffer, as Windows does, rather than truncating them
+    pub(crate) fn with_failing_long_reads(mut self) -> Self {
+        self.fail_long_reads = true;
+        self
+    }
+
+    // with_stalled_writes makes send_to never complete, as a socket to a client that
+    // stopped reading does once its buffers are full
+    pub(crate) fn with_stalled_writes(mut self) -> Self {
+        self.stalled = true;
+        self
+    }
+
+    // push_datagram queues data as if it arrived from src
+    pub(crate) fn push_datagram(&self, data: &[u8], src: SocketAddr) {
+        if let Some(inbound_tx) = &*self.inbound_tx.lock() {
+            let _ = inbound_tx.send((data.to_vec(), src));
+        }
+    }
+
+    // pop_write takes the most recent write and its destination, if any
+    pub(crate) fn pop_write(&self) -> Option<Datagram> {
+        self.written.lock().pop_back()
+    }
+
+    // next_write waits for a write and takes the oldest one not taken yet
+    pub(crate) async fn next_write(&self) -> Datagram {
+        loop {
+            let notified = self.write_notify.notified();
+            if let Some(write) = self.written.lock().pop_front() {
+                return write;
+            }
+            notified.await;
+        }
+    }
+
+    // write_count returns how many writes haven't been taken yet
+    pub(crate) fn write_count(&self) -> usize {
+        self.written.lock().len()
+    }
+
+    fn record_write(&self, buf: &[u8], target: SocketAddr) {
+        self.written.lock().push_back((buf.to_vec(), target));
+        self.write_notify.notify_waiters();
+    }
+}
+
+#[async_trait]
+impl Conn for MockConn {
+    async fn connect(&self, _addr: SocketAddr) -> Result<(), util::Error> {
+        Err(util::Error::Other("not supported".to_owned()))
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> Result<usize, util::Error> {
+        let (n, _) = self.recv_from(buf).await?;
+        Ok(n)
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), util::Error> {
+        match self.inbound_rx.lock().await.recv().await {
+            Some((data, _)) if self.fail_long_reads && data.len() > buf.len() => {
+                Err(std::io::Error::from_raw_os_error(crate::relay::EMSGSIZE).into())
+            }
+            Some((data, src)) => {
+                let n = data.len().min(buf.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                Ok((n, self.remote_addr.unwrap_or(src)))
+            }
+            None => Err(util::Error::ErrUseClosedNetworkConn),
+        }
+    }
+
+    async fn send(&self, buf: &[u8]) -> Result<usize, util::Error> {
+        let target = self
+            .remote_addr
+            .ok_or_else(|| util::Error::Other("not connected".to_owned()))?;
+        self.record_write(buf, target);
+        Ok(buf.len())
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> Result<usize, util::Error> {
+        if self.stalled {
+            std::future::pending::<()>().await;
+        }
+        if self.max_datagram_size.map_or(false, |max| buf.len() > max) {
+            return Err(std::io::Error::from_raw_os_error(crate::relay::EMSGSIZE).into());
+        }
+        self.record_write(buf, target);
+        Ok(buf.len())
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, util::Error> {
+        Ok(self.local_addr)
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+
+    // close ends reading once the queued datagrams are drained
+    async fn close(&self) -> Result<(), util::Error> {
+        self.inbound_tx.lock().take();
+        Ok(())
+    }
+}
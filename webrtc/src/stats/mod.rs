@@ -179,6 +179,7 @@ pub struct ICECandidatePairStats {
     pub first_request_timestamp: Instant,
     #[serde(with = "serialize::instant_to_epoch_seconds")]
     pub last_request_timestamp: Instant,
+    pub priority: u64,
     pub retransmissions_sent: u64,
 }
 
@@ -202,6 +203,7 @@ impl From<CandidatePairStats> for ICECandidatePairStats {
             nominated: stats.nominated,
             packets_received: stats.packets_received,
             packets_sent: stats.packets_sent,
+            priority: stats.priority,
             remote_candidate_id: stats.remote_candidate_id,
             requests_received: stats.requests_received,
             requests_sent: stats.requests_sent,